@@ -0,0 +1,53 @@
+/* C declarations of the pipspeak matching ABI (see src/ffi.rs) */
+#ifndef PIPSPEAK_H
+#define PIPSPEAK_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <sys/types.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define PIPSPEAK_MATCHED 0
+#define PIPSPEAK_FILTERED_FIXED 5
+#define PIPSPEAK_FILTERED_UMI 6
+#define PIPSPEAK_ERROR -1
+
+typedef struct pipspeak_config pipspeak_config;
+
+typedef struct {
+    size_t indices[4];
+    size_t end;
+    size_t shift;
+    bool corrected[4];
+} PipspeakMatch;
+
+const char *pipspeak_last_error(void);
+
+pipspeak_config *pipspeak_config_load(const char *path, bool exact, bool linkers);
+
+void pipspeak_config_free(pipspeak_config *config);
+
+/* Returns the total UMI length of a config (the capacity needed by pipspeak_match_read) */
+size_t pipspeak_config_umi_len(const pipspeak_config *config, size_t umi_len);
+
+/* Returns PIPSPEAK_MATCHED, the 1-indexed failing tier, PIPSPEAK_FILTERED_FIXED,
+ * PIPSPEAK_FILTERED_UMI or PIPSPEAK_ERROR. A negative offset searches the whole read.
+ * The UMI length is written to umi_written and the UMI to umi only if it fits in
+ * umi_capacity (PIPSPEAK_ERROR otherwise). */
+int pipspeak_match_read(const pipspeak_config *config, const uint8_t *seq, size_t len,
+                        ssize_t offset, size_t umi_len, PipspeakMatch *out, uint8_t *umi,
+                        size_t umi_capacity, size_t *umi_written);
+
+/* Returns the length of the cell barcode, written to out only if it fits in capacity */
+size_t pipspeak_build_barcode(const pipspeak_config *config, const size_t *indices,
+                              uint8_t *out, size_t capacity);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
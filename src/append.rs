@@ -0,0 +1,386 @@
+use crate::{create_output, partial};
+use anyhow::{anyhow, bail, Context, Result};
+use pipspeak::{
+    config::Config,
+    error::{Classify, Error},
+    log::{AppendState, Log, Statistics},
+    whitelist::{self, SetOperation, WhitelistCounts},
+};
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+/// The lengths of the fastqs of the earlier deliveries of an appended sample, recorded
+/// in `<log>.append` while the new gzip members are concatenated onto them.
+///
+/// The fastqs are only extended once every output of the delivery is complete and are
+/// truncated back to their recorded lengths if the append fails before the log is
+/// renamed, so the earlier deliveries are never lost.
+struct AppendJournal {
+    path: String,
+    lengths: Vec<(String, u64)>,
+}
+impl AppendJournal {
+    fn path(log_path: &str) -> String {
+        format!("{}.append", log_path)
+    }
+
+    /// Truncates the fastqs of an append interrupted before its log was renamed back to
+    /// their earlier lengths
+    fn recover(log_path: &str) -> Result<()> {
+        let path = Self::path(log_path);
+        if !Path::new(&path).exists() {
+            return Ok(());
+        }
+        // a renamed log means the interrupted append had completed
+        if Path::new(&partial(log_path)).exists() {
+            let contents = std::fs::read_to_string(&path)?;
+            let lengths = contents
+                .lines()
+                .map(|line| {
+                    let (output, len) = line
+                        .rsplit_once('\t')
+                        .ok_or_else(|| anyhow!("Malformed append journal line: {}", line))?;
+                    Ok((output.to_string(), len.parse()?))
+                })
+                .collect::<Result<Vec<_>>>()
+                .with_context(|| format!("Failed to read the append journal: {}", path))?;
+            Self { path, lengths }.rollback()
+        } else {
+            Ok(std::fs::remove_file(&path)?)
+        }
+    }
+
+    /// Records the current lengths of the extended fastqs
+    fn begin(log_path: &str, outputs: &[String]) -> Result<Self> {
+        let lengths = outputs
+            .iter()
+            .map(|path| Ok((path.clone(), std::fs::metadata(path)?.len())))
+            .collect::<Result<Vec<_>>>()?;
+        let path = Self::path(log_path);
+        let mut file = File::create(&path)?;
+        for (output, len) in &lengths {
+            writeln!(file, "{}\t{}", output, len)?;
+        }
+        file.sync_all()?;
+        Ok(Self { path, lengths })
+    }
+
+    /// Appends the partial file of each extended fastq to it
+    fn concatenate(&self) -> Result<()> {
+        for (path, _) in &self.lengths {
+            let mut output = OpenOptions::new().append(true).open(path)?;
+            std::io::copy(&mut File::open(partial(path))?, &mut output)?;
+            output.sync_all()?;
+            std::fs::remove_file(partial(path))?;
+        }
+        Ok(())
+    }
+
+    /// Truncates the extended fastqs back to their recorded lengths
+    fn rollback(self) -> Result<()> {
+        for (path, len) in &self.lengths {
+            OpenOptions::new().write(true).open(path)?.set_len(*len)?;
+        }
+        self.finish()
+    }
+
+    fn finish(self) -> Result<()> {
+        Ok(std::fs::remove_file(&self.path)?)
+    }
+}
+
+/// The earlier deliveries of a sample extended with `--append` and the fastqs of this
+/// delivery which are concatenated onto theirs
+pub struct Append {
+    log_path: String,
+    log: serde_yaml::Value,
+    state: AppendState,
+    whitelist: Option<WhitelistCounts>,
+    whitelist_counts: Option<WhitelistCounts>,
+    extended: Vec<String>,
+}
+impl Append {
+    /// Reads the earlier log, merge state and whitelists of a sample, or returns None if
+    /// the sample has not been run yet.
+    ///
+    /// An earlier append interrupted while extending the fastqs is rolled back first.
+    pub fn open(
+        log_path: &str,
+        state_path: &str,
+        whitelist_path: Option<&str>,
+        whitelist_counts_path: Option<&str>,
+    ) -> Result<Option<Self>> {
+        AppendJournal::recover(log_path)
+            .context("Failed to roll back an interrupted append")
+            .classify(Error::Output)?;
+        if !Path::new(log_path).exists() {
+            return Ok(None);
+        }
+        let log = std::fs::read_to_string(log_path)
+            .map_err(anyhow::Error::from)
+            .and_then(|contents| Ok(serde_yaml::from_str(&contents)?))
+            .with_context(|| format!("Failed to read the log appended to: {}", log_path))
+            .classify(Error::Input)?;
+        if !Path::new(state_path).exists() {
+            bail!(Error::Usage(
+                format!(
+                    "The earlier outputs of {} were not written with `--append` (missing {})",
+                    log_path, state_path
+                )
+                .into()
+            ));
+        }
+        let state = AppendState::from_file(state_path)
+            .with_context(|| format!("Failed to read the append state: {}", state_path))
+            .classify(Error::Input)?;
+        let read = |path: Option<&str>| {
+            path.filter(|path| Path::new(path).exists())
+                .map(whitelist::read_counts)
+                .transpose()
+                .classify(Error::Input)
+        };
+        Ok(Some(Self {
+            log_path: log_path.to_string(),
+            log,
+            state,
+            whitelist: read(whitelist_path)?,
+            whitelist_counts: read(whitelist_counts_path)?,
+            extended: Vec::new(),
+        }))
+    }
+
+    /// Returns true if a fastq output of an earlier delivery exists, recording that it is
+    /// extended with the partial file of this delivery once the sample is complete
+    pub fn extends(&mut self, path: &str) -> bool {
+        let extended = Path::new(path).exists();
+        if extended {
+            self.extended.push(path.to_string());
+        }
+        extended
+    }
+
+    /// The fastq outputs extended in place
+    pub fn extended(&self) -> &[String] {
+        &self.extended
+    }
+
+    /// Checks that the earlier deliveries were processed with the same parameters, which
+    /// determine the emitted barcodes and UMIs
+    pub fn check_parameters(&self, parameters: &[(&str, serde_yaml::Value)]) -> Result<()> {
+        let render = |value: Option<&serde_yaml::Value>| {
+            value
+                .and_then(|value| serde_yaml::to_string(value).ok())
+                .map_or("none".to_string(), |yaml| yaml.trim_end().to_string())
+        };
+        for (name, value) in parameters {
+            let earlier = self.log.get("parameters").and_then(|p| p.get(name));
+            if earlier != Some(value) {
+                bail!(Error::Usage(format!(
+                    "Cannot append a delivery processed with a different `{}` ({}) than the earlier deliveries ({})",
+                    name,
+                    render(Some(value)),
+                    render(earlier)
+                )
+                .into()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Merges the barcodes and sketches of the earlier deliveries into the statistics of
+    /// this delivery (the counts of an external whitelist are merged on disk by
+    /// [`Append::merge_whitelists`])
+    pub fn merge_statistics(
+        &mut self,
+        statistics: &mut Statistics,
+        config: &Config,
+        expected_cells: Option<usize>,
+        in_memory: bool,
+    ) -> Result<()> {
+        let whitelist = if in_memory {
+            self.whitelist_counts.take()
+        } else {
+            None
+        };
+        statistics
+            .merge_appended(&self.state, whitelist.as_ref(), config, expected_cells)
+            .context("Failed to merge the earlier deliveries")
+            .classify(Error::Input)
+    }
+
+    /// Merges the whitelists of the earlier deliveries into the partial whitelist counts
+    /// and, for an external whitelist, the partial whitelist of this delivery
+    pub fn merge_whitelists(
+        &mut self,
+        statistics: &mut Statistics,
+        whitelist_counts_path: Option<&str>,
+        external_whitelist_path: Option<&str>,
+    ) -> Result<()> {
+        if let (Some(path), Some(previous)) = (whitelist_counts_path, self.whitelist_counts.take())
+        {
+            merge_whitelist(path, previous)?;
+        }
+        if let (Some(path), Some(previous)) = (external_whitelist_path, self.whitelist.take()) {
+            statistics.whitelist_size = merge_whitelist(path, previous)?;
+        }
+        Ok(())
+    }
+
+    /// Extends the log with the read counts and inputs of the earlier deliveries
+    pub fn accumulate(&self, log: &mut Log) -> Result<()> {
+        log.accumulate(&self.log)
+    }
+
+    /// Concatenates the partial files of this delivery onto the extended fastqs and then
+    /// runs `finalize` (which renames the remaining outputs and lastly the log), truncating
+    /// the fastqs back to their earlier lengths if either fails
+    pub fn commit(&self, finalize: impl FnOnce() -> Result<()>) -> Result<()> {
+        if self.extended.is_empty() {
+            return finalize();
+        }
+        let journal = AppendJournal::begin(&self.log_path, &self.extended)?;
+        match journal.concatenate().and_then(|()| finalize()) {
+            Ok(()) => journal.finish(),
+            Err(err) => {
+                journal
+                    .rollback()
+                    .context("Failed to roll back the extended fastqs")?;
+                Err(err)
+            }
+        }
+    }
+}
+
+/// Merges the partial whitelist of an output with the whitelist of the earlier deliveries
+/// of an appended sample, returning the number of merged barcodes
+fn merge_whitelist(path: &str, previous: WhitelistCounts) -> Result<usize> {
+    let current = whitelist::read_counts(&partial(path))?;
+    let merged = whitelist::combine(SetOperation::Union, &[previous, current]);
+    let mut output = create_output(path)?;
+    whitelist::write_counts(&merged, &mut output)?;
+    output.finish()?;
+    Ok(merged.len())
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use pipspeak::error;
+
+    /// Writes an earlier delivery of a sample: its log, merge state and an R1 fastq
+    fn earlier_delivery(dir: &Path) -> (String, String, String) {
+        let log_path = dir.join("sample_log.yaml").display().to_string();
+        let state_path = dir.join("sample_append_state.json").display().to_string();
+        let r1_path = dir.join("sample_R1.fq.gz").display().to_string();
+        std::fs::write(&log_path, "parameters:\n  umi_len: 12\n").unwrap();
+        AppendState::default()
+            .to_writer(File::create(&state_path).unwrap())
+            .unwrap();
+        std::fs::write(&r1_path, b"earlier").unwrap();
+        (log_path, state_path, r1_path)
+    }
+
+    #[test]
+    fn first_delivery() {
+        let dir = tempfile::tempdir().unwrap();
+        let log_path = dir.path().join("sample_log.yaml").display().to_string();
+        let append = Append::open(&log_path, "missing.json", None, None).unwrap();
+        assert!(append.is_none());
+    }
+
+    #[test]
+    fn missing_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let (log_path, _, _) = earlier_delivery(dir.path());
+        let err = Append::open(&log_path, "missing.json", None, None)
+            .err()
+            .unwrap();
+        assert_eq!(error::exit_code(&err), error::EXIT_USAGE);
+    }
+
+    #[test]
+    fn commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let (log_path, state_path, r1_path) = earlier_delivery(dir.path());
+        let mut append = Append::open(&log_path, &state_path, None, None)
+            .unwrap()
+            .unwrap();
+        assert!(append.extends(&r1_path));
+        assert!(!append.extends(&dir.path().join("new.fq.gz").display().to_string()));
+        assert_eq!(append.extended(), std::slice::from_ref(&r1_path));
+        std::fs::write(partial(&r1_path), b" later").unwrap();
+
+        append
+            .commit(|| {
+                assert!(Path::new(&AppendJournal::path(&log_path)).exists());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(std::fs::read(&r1_path).unwrap(), b"earlier later");
+        assert!(!Path::new(&partial(&r1_path)).exists());
+        assert!(!Path::new(&AppendJournal::path(&log_path)).exists());
+    }
+
+    #[test]
+    fn rollback() {
+        let dir = tempfile::tempdir().unwrap();
+        let (log_path, state_path, r1_path) = earlier_delivery(dir.path());
+        let mut append = Append::open(&log_path, &state_path, None, None)
+            .unwrap()
+            .unwrap();
+        append.extends(&r1_path);
+        std::fs::write(partial(&r1_path), b" later").unwrap();
+
+        let err = append
+            .commit(|| {
+                assert_eq!(std::fs::read(&r1_path).unwrap(), b"earlier later");
+                bail!("rename failed")
+            })
+            .unwrap_err();
+        assert_eq!(err.to_string(), "rename failed");
+        assert_eq!(std::fs::read(&r1_path).unwrap(), b"earlier");
+        assert!(!Path::new(&AppendJournal::path(&log_path)).exists());
+    }
+
+    #[test]
+    fn recover() {
+        let dir = tempfile::tempdir().unwrap();
+        let (log_path, state_path, r1_path) = earlier_delivery(dir.path());
+        let interrupt = || {
+            let journal = AppendJournal::begin(&log_path, std::slice::from_ref(&r1_path)).unwrap();
+            std::fs::write(partial(&r1_path), b" later").unwrap();
+            journal.concatenate().unwrap();
+        };
+
+        // an append interrupted before its log was renamed is rolled back
+        interrupt();
+        std::fs::write(partial(&log_path), "").unwrap();
+        Append::open(&log_path, &state_path, None, None).unwrap();
+        assert_eq!(std::fs::read(&r1_path).unwrap(), b"earlier");
+        assert!(!Path::new(&AppendJournal::path(&log_path)).exists());
+
+        // one whose log was renamed had completed and is kept
+        std::fs::remove_file(partial(&log_path)).unwrap();
+        interrupt();
+        Append::open(&log_path, &state_path, None, None).unwrap();
+        assert_eq!(std::fs::read(&r1_path).unwrap(), b"earlier later");
+        assert!(!Path::new(&AppendJournal::path(&log_path)).exists());
+    }
+
+    #[test]
+    fn check_parameters() {
+        let dir = tempfile::tempdir().unwrap();
+        let (log_path, state_path, _) = earlier_delivery(dir.path());
+        let append = Append::open(&log_path, &state_path, None, None)
+            .unwrap()
+            .unwrap();
+        append.check_parameters(&[("umi_len", 12.into())]).unwrap();
+        let err = append
+            .check_parameters(&[("umi_len", 10.into())])
+            .unwrap_err();
+        assert_eq!(error::exit_code(&err), error::EXIT_USAGE);
+    }
+}
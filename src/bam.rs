@@ -0,0 +1,512 @@
+use crate::longread::reverse_complement;
+use anyhow::{bail, Context, Result};
+use fxread::{FastxRead, Record};
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    io::{BufRead, Read, Write},
+    rc::Rc,
+};
+
+/// The magic bytes starting a BAM file
+pub const MAGIC: &[u8; 4] = b"BAM\x01";
+
+/// The magic bytes starting a CRAM file
+pub const CRAM_MAGIC: &[u8; 4] = b"CRAM";
+
+/// The flag of a read with a mate
+const FLAG_PAIRED: u16 = 0x1;
+
+/// The flag of a read stored reverse complemented
+const FLAG_REVERSE: u16 = 0x10;
+
+/// The flag of the first mate of a pair
+const FLAG_READ1: u16 = 0x40;
+
+/// The flag of the second mate of a pair
+const FLAG_READ2: u16 = 0x80;
+
+/// The flags of the secondary and supplementary records of a read
+const FLAG_NOT_PRIMARY: u16 = 0x100 | 0x800;
+
+/// The length of the fixed fields of a BAM record
+const FIXED_LEN: usize = 32;
+
+/// The quality written for bases of records without qualities
+const MISSING_QUAL: u8 = b'I';
+
+/// The flag of an unpaired unmapped read
+const FLAG_UNMAPPED: u16 = 0x4;
+
+/// The bin of an unmapped read without a position (`reg2bin(-1, 0)`)
+const UNMAPPED_BIN: u16 = 4680;
+
+/// The nucleotide codes of the 4-bit packed BAM sequence encoding
+const SEQ_CODES: &[u8; 16] = b"=ACMGRSVTWYHKDBN";
+
+/// The phred offset of FASTQ quality scores
+const PHRED_OFFSET: u8 = 33;
+
+/// The tag linking a record to its read group
+pub const READ_GROUP_TAG: &[u8; 2] = b"RG";
+
+/// The `@RG` read group of the records of a BAM
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadGroup {
+    pub id: String,
+    pub sample: Option<String>,
+    pub library: Option<String>,
+    pub platform: Option<String>,
+}
+
+/// Replaces the characters which cannot appear in a SAM header field
+fn header_field(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| if c == '\t' || c == '\n' { ' ' } else { c })
+        .collect()
+}
+
+/// Builds the SAM header of an unaligned BAM grouped by read name with an optional read
+/// group and the `@PG` line of the command line that wrote it
+pub fn header_text(read_group: Option<&ReadGroup>, command_line: Option<&str>) -> String {
+    let mut text = "@HD\tVN:1.6\tSO:unsorted\tGO:query\n".to_string();
+    if let Some(read_group) = read_group {
+        text.push_str(&format!("@RG\tID:{}", header_field(&read_group.id)));
+        for (tag, value) in [
+            ("SM", &read_group.sample),
+            ("LB", &read_group.library),
+            ("PL", &read_group.platform),
+        ] {
+            if let Some(value) = value {
+                text.push_str(&format!("\t{}:{}", tag, header_field(value)));
+            }
+        }
+        text.push('\n');
+    }
+    if let Some(command_line) = command_line {
+        text.push_str(&format!(
+            "@PG\tID:pipspeak\tPN:pipspeak\tVN:{}\tCL:{}\n",
+            env!("CARGO_PKG_VERSION"),
+            header_field(command_line)
+        ));
+    }
+    text
+}
+
+/// Writes the BAM header (without any reference sequences)
+pub fn write_header<W: Write>(writer: &mut W, text: &str) -> Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&(text.len() as u32).to_le_bytes())?;
+    writer.write_all(text.as_bytes())?;
+    writer.write_all(&0u32.to_le_bytes())?;
+    Ok(())
+}
+
+/// Maps a nucleotide to its 4-bit BAM code (anything unknown is `N`)
+fn encode_base(base: u8) -> u8 {
+    SEQ_CODES
+        .iter()
+        .position(|c| *c == base.to_ascii_uppercase())
+        .unwrap_or(15) as u8
+}
+
+/// Writes an unmapped read with string (`Z`) tags.
+///
+/// Only the read name up to the first whitespace is kept as SAM names cannot contain
+/// spaces. Qualities are expected as phred+33 characters.
+pub fn write_unmapped<W: Write + ?Sized>(
+    writer: &mut W,
+    name: &[u8],
+    seq: &[u8],
+    qual: &[u8],
+    tags: &[(&[u8; 2], &[u8])],
+) -> Result<()> {
+    let name = name
+        .split(|b| b.is_ascii_whitespace())
+        .next()
+        .unwrap_or_default();
+    if name.is_empty() || name.len() > 254 {
+        bail!("Invalid BAM read name: {}", String::from_utf8_lossy(name));
+    }
+    if seq.len() != qual.len() {
+        bail!("Sequence and quality lengths differ");
+    }
+    let mut record = Vec::with_capacity(32 + name.len() + seq.len() * 2);
+    record.extend_from_slice(&(-1i32).to_le_bytes()); // refID
+    record.extend_from_slice(&(-1i32).to_le_bytes()); // pos
+    record.push(name.len() as u8 + 1); // l_read_name
+    record.push(255); // mapq
+    record.extend_from_slice(&UNMAPPED_BIN.to_le_bytes());
+    record.extend_from_slice(&0u16.to_le_bytes()); // n_cigar_op
+    record.extend_from_slice(&FLAG_UNMAPPED.to_le_bytes());
+    record.extend_from_slice(&(seq.len() as u32).to_le_bytes());
+    record.extend_from_slice(&(-1i32).to_le_bytes()); // next refID
+    record.extend_from_slice(&(-1i32).to_le_bytes()); // next pos
+    record.extend_from_slice(&0i32.to_le_bytes()); // tlen
+    record.extend_from_slice(name);
+    record.push(0);
+    record.extend(
+        seq.chunks(2)
+            .map(|pair| (encode_base(pair[0]) << 4) | pair.get(1).map_or(0, |b| encode_base(*b))),
+    );
+    record.extend(qual.iter().map(|q| q.saturating_sub(PHRED_OFFSET)));
+    for (tag, value) in tags {
+        record.extend_from_slice(*tag);
+        record.push(b'Z');
+        record.extend_from_slice(value);
+        record.push(0);
+    }
+    writer.write_all(&(record.len() as u32).to_le_bytes())?;
+    writer.write_all(&record)?;
+    Ok(())
+}
+
+/// The mate of the read pairs extracted from an unaligned BAM
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Mate {
+    /// The first mates (and any unpaired reads)
+    #[default]
+    R1,
+    /// The second mates
+    R2,
+}
+impl Mate {
+    /// Returns the mate held by a record with the given flag, if it is a primary record
+    /// of either mate
+    fn of(flag: u16) -> Option<Self> {
+        if flag & FLAG_NOT_PRIMARY != 0 {
+            None
+        } else if flag & FLAG_PAIRED == 0 || flag & FLAG_READ1 != 0 {
+            Some(Self::R1)
+        } else if flag & FLAG_READ2 != 0 {
+            Some(Self::R2)
+        } else {
+            None
+        }
+    }
+
+    fn index(&self) -> usize {
+        match self {
+            Self::R1 => 0,
+            Self::R2 => 1,
+        }
+    }
+}
+
+/// Reads the records of one mate of a (decompressed) unaligned BAM grouped by read name,
+/// e.g. as delivered by sequencing cores instead of fastqs.
+///
+/// Reads stored reverse complemented are restored to their sequenced orientation.
+/// Secondary and supplementary records are skipped.
+pub struct BamReader<R> {
+    reader: R,
+    mate: Mate,
+    block: Vec<u8>,
+}
+impl<R: BufRead> BamReader<R> {
+    /// Reads the header of the BAM, leaving the reader at the first record
+    pub fn new(mut reader: R, mate: Mate) -> Result<Self> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            bail!("Missing BAM magic bytes");
+        }
+        let l_text = read_u32(&mut reader)?;
+        skip(&mut reader, l_text as u64)?;
+        for _ in 0..read_u32(&mut reader)? {
+            let l_name = read_u32(&mut reader)?;
+            // the name is followed by the length of the reference
+            skip(&mut reader, l_name as u64 + 4)?;
+        }
+        Ok(Self {
+            reader,
+            mate,
+            block: Vec::new(),
+        })
+    }
+
+    /// Reads the next record into the block buffer, returning false at the end of the BAM
+    fn next_block(&mut self) -> Result<bool> {
+        if self.reader.fill_buf()?.is_empty() {
+            return Ok(false);
+        }
+        let block_size = read_u32(&mut self.reader)? as usize;
+        if block_size < FIXED_LEN {
+            bail!("Truncated BAM record");
+        }
+        self.block.resize(block_size, 0);
+        self.reader
+            .read_exact(&mut self.block)
+            .context("Truncated BAM record")?;
+        Ok(true)
+    }
+
+    /// Decodes the name, sequence and qualities of the record in the block buffer if it
+    /// is a primary record of either mate
+    fn decode(&self) -> Result<Option<(Mate, Record)>> {
+        let block = &self.block;
+        let field = |pos: usize| u16::from_le_bytes([block[pos], block[pos + 1]]);
+        let flag = field(14);
+        let Some(mate) = Mate::of(flag) else {
+            return Ok(None);
+        };
+        let l_read_name = block[8] as usize;
+        let n_cigar_op = field(12) as usize;
+        let l_seq = u32::from_le_bytes(block[16..20].try_into().expect("4 bytes")) as usize;
+        let name_end = FIXED_LEN + l_read_name;
+        let seq_start = name_end + n_cigar_op * 4;
+        let qual_start = seq_start + l_seq.div_ceil(2);
+        if l_read_name == 0 || qual_start + l_seq > block.len() {
+            bail!("Truncated BAM record");
+        }
+        let name = &block[FIXED_LEN..name_end - 1];
+        let mut seq = (0..l_seq)
+            .map(|i| SEQ_CODES[((block[seq_start + i / 2] >> (4 * (1 - i % 2))) & 0xf) as usize])
+            .collect::<Vec<_>>();
+        let mut qual = match &block[qual_start..qual_start + l_seq] {
+            [0xff, ..] => vec![MISSING_QUAL; l_seq],
+            qual => qual.iter().map(|q| q + PHRED_OFFSET).collect(),
+        };
+        if flag & FLAG_REVERSE != 0 {
+            seq = reverse_complement(&seq);
+            qual.reverse();
+        }
+        Record::new_fastq_from_parts(name, &seq, &qual).map(|record| Some((mate, record)))
+    }
+
+    /// Splits the reader into readers of both mates, so a BAM holding both is only read
+    /// once.
+    ///
+    /// The records of one mate are buffered until read, so the mates should be read in
+    /// step (as the records of a pair are stored together).
+    pub fn into_mates(self) -> (Mates<R>, Mates<R>) {
+        let shared = Rc::new(RefCell::new(SharedReader {
+            reader: self,
+            pending: [VecDeque::new(), VecDeque::new()],
+        }));
+        (
+            Mates {
+                shared: Rc::clone(&shared),
+                mate: Mate::R1,
+            },
+            Mates {
+                shared,
+                mate: Mate::R2,
+            },
+        )
+    }
+}
+impl<R: BufRead> FastxRead for BamReader<R> {
+    fn next_record(&mut self) -> Result<Option<Record>> {
+        while self.next_block()? {
+            match self.decode()? {
+                Some((mate, record)) if mate == self.mate => return Ok(Some(record)),
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+}
+impl<R: BufRead> Iterator for BamReader<R> {
+    type Item = Result<Record>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+/// Reads the records of one mate of an unaligned BAM whose reader is shared with the
+/// other mate (see [`BamReader::into_mates`])
+pub struct Mates<R> {
+    shared: Rc<RefCell<SharedReader<R>>>,
+    mate: Mate,
+}
+
+/// A reader shared by both mates and the records it read ahead of each
+struct SharedReader<R> {
+    reader: BamReader<R>,
+    pending: [VecDeque<Record>; 2],
+}
+impl<R: BufRead> FastxRead for Mates<R> {
+    fn next_record(&mut self) -> Result<Option<Record>> {
+        let SharedReader { reader, pending } = &mut *self.shared.borrow_mut();
+        if let Some(record) = pending[self.mate.index()].pop_front() {
+            return Ok(Some(record));
+        }
+        while reader.next_block()? {
+            match reader.decode()? {
+                Some((mate, record)) if mate == self.mate => return Ok(Some(record)),
+                Some((mate, record)) => pending[mate.index()].push_back(record),
+                None => {}
+            }
+        }
+        Ok(None)
+    }
+}
+impl<R: BufRead> Iterator for Mates<R> {
+    type Item = Result<Record>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+/// Skips a number of bytes of the header
+fn skip<R: BufRead>(reader: &mut R, num_bytes: u64) -> Result<()> {
+    if std::io::copy(&mut reader.by_ref().take(num_bytes), &mut std::io::sink())? != num_bytes {
+        bail!("Truncated BAM header");
+    }
+    Ok(())
+}
+
+/// Reads a little-endian u32
+fn read_u32<R: BufRead>(reader: &mut R) -> Result<u32> {
+    let mut bytes = [0; 4];
+    reader
+        .read_exact(&mut bytes)
+        .context("Truncated BAM input")?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn header() {
+        let mut buffer = Vec::new();
+        write_header(&mut buffer, &header_text(None, None)).unwrap();
+        assert_eq!(&buffer[..4], MAGIC);
+        let l_text = u32::from_le_bytes(buffer[4..8].try_into().unwrap()) as usize;
+        assert_eq!(&buffer[8..8 + l_text], header_text(None, None).as_bytes());
+        assert_eq!(&buffer[8 + l_text..], &[0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn provenance() {
+        let read_group = ReadGroup {
+            id: "A".to_string(),
+            sample: Some("sample\tA".to_string()),
+            library: None,
+            platform: Some("ILLUMINA".to_string()),
+        };
+        let text = header_text(Some(&read_group), Some("pipspeak -c config.yaml"));
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(lines[1], "@RG\tID:A\tSM:sample A\tPL:ILLUMINA");
+        assert!(lines[2].starts_with("@PG\tID:pipspeak\tPN:pipspeak\tVN:"));
+        assert!(lines[2].ends_with("\tCL:pipspeak -c config.yaml"));
+    }
+
+    #[test]
+    fn unmapped_record() {
+        let mut buffer = Vec::new();
+        write_unmapped(
+            &mut buffer,
+            b"read1 2:N:0",
+            b"ACGTN",
+            b"I#III",
+            &[(b"XC", b"AACC"), (b"XM", b"GGTT")],
+        )
+        .unwrap();
+        let block_size = u32::from_le_bytes(buffer[..4].try_into().unwrap()) as usize;
+        assert_eq!(block_size, buffer.len() - 4);
+        let record = &buffer[4..];
+        assert_eq!(record[8], 6); // "read1\0"
+        assert_eq!(u16::from_le_bytes([record[14], record[15]]), FLAG_UNMAPPED);
+        assert_eq!(u32::from_le_bytes(record[16..20].try_into().unwrap()), 5);
+        assert_eq!(&record[32..38], b"read1\0");
+        assert_eq!(&record[38..41], &[0x12, 0x48, 0xf0]);
+        assert_eq!(&record[41..46], &[40, 2, 40, 40, 40]);
+        assert_eq!(&record[46..], b"XCZAACC\0XMZGGTT\0");
+    }
+
+    /// Writes an unmapped record with the given flag
+    fn write_flagged(buffer: &mut Vec<u8>, name: &[u8], seq: &[u8], qual: &[u8], flag: u16) {
+        let start = buffer.len();
+        write_unmapped(buffer, name, seq, qual, &[(b"RG", b"A")]).unwrap();
+        buffer[start + 18..start + 20].copy_from_slice(&(FLAG_UNMAPPED | flag).to_le_bytes());
+    }
+
+    #[test]
+    fn read_mates() {
+        let mut buffer = Vec::new();
+        write_header(&mut buffer, &header_text(None, Some("samtools import"))).unwrap();
+        let paired = FLAG_PAIRED | FLAG_UNMAPPED;
+        write_flagged(
+            &mut buffer,
+            b"read1",
+            b"ACGTA",
+            b"IIII#",
+            paired | FLAG_READ1,
+        );
+        write_flagged(
+            &mut buffer,
+            b"read1",
+            b"TTGCA",
+            b"ABCDE",
+            paired | FLAG_READ2,
+        );
+        write_flagged(
+            &mut buffer,
+            b"read1",
+            b"GG",
+            b"II",
+            paired | FLAG_READ2 | 0x800,
+        );
+        write_flagged(
+            &mut buffer,
+            b"read2",
+            b"AACC",
+            b"ABCD",
+            paired | FLAG_READ1 | FLAG_REVERSE,
+        );
+        write_flagged(&mut buffer, b"read2", b"GGTT", b"IIII", paired | FLAG_READ2);
+
+        let read = |mate| {
+            BamReader::new(buffer.as_slice(), mate)
+                .unwrap()
+                .map(|r| {
+                    let r = r.unwrap();
+                    (
+                        r.id().to_vec(),
+                        r.seq().to_vec(),
+                        r.qual().unwrap().to_vec(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            read(Mate::R1),
+            [
+                (b"read1".to_vec(), b"ACGTA".to_vec(), b"IIII#".to_vec()),
+                (b"read2".to_vec(), b"GGTT".to_vec(), b"DCBA".to_vec()),
+            ]
+        );
+        assert_eq!(
+            read(Mate::R2),
+            [
+                (b"read1".to_vec(), b"TTGCA".to_vec(), b"ABCDE".to_vec()),
+                (b"read2".to_vec(), b"GGTT".to_vec(), b"IIII".to_vec()),
+            ]
+        );
+        // both mates are read from a single reader, in any order
+        let (r1, r2) = BamReader::new(buffer.as_slice(), Mate::R1)
+            .unwrap()
+            .into_mates();
+        let seqs =
+            |mates: Mates<&[u8]>| mates.map(|r| r.unwrap().seq().to_vec()).collect::<Vec<_>>();
+        assert_eq!(seqs(r2), [b"TTGCA".to_vec(), b"GGTT".to_vec()]);
+        assert_eq!(seqs(r1), [b"ACGTA".to_vec(), b"GGTT".to_vec()]);
+        assert!(BamReader::new(&buffer[..6], Mate::R1).is_err());
+        // a truncated record is reported once it is reached
+        let mut truncated = BamReader::new(&buffer[..buffer.len() - 2], Mate::R2).unwrap();
+        assert!(truncated.next_record().is_ok());
+        assert!(truncated.next_record().is_err());
+    }
+
+    #[test]
+    fn invalid_record() {
+        let mut buffer = Vec::new();
+        assert!(write_unmapped(&mut buffer, b"", b"A", b"I", &[]).is_err());
+        assert!(write_unmapped(&mut buffer, b"read", b"AC", b"I", &[]).is_err());
+    }
+}
@@ -1,6 +1,7 @@
 use anyhow::Result;
 use disambiseq::Disambibyte;
 use hashbrown::{HashMap, HashSet};
+use serde::Serialize;
 use std::{
     fs::File,
     io::{BufRead, BufReader},
@@ -9,12 +10,53 @@ use std::{
 type BarcodeID = usize;
 type EndPos = usize;
 
+/// The number of barcodes listed as most affected by ambiguity
+const MOST_AFFECTED: usize = 10;
+
+/// Returns the sequences a single (ACGT) substitution away from a sequence
+pub(crate) fn mismatches(seq: &[u8]) -> impl Iterator<Item = Vec<u8>> + '_ {
+    (0..seq.len()).flat_map(move |pos| {
+        b"ACGT"
+            .iter()
+            .filter(move |base| **base != seq[pos])
+            .map(move |base| {
+                let mut child = seq.to_vec();
+                child[pos] = *base;
+                child
+            })
+    })
+}
+
+/// The one-mismatch neighbors of a barcode set which cannot be corrected
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Ambiguity {
+    /// The number of distinct one-mismatch neighbors of the barcodes
+    pub num_neighbors: usize,
+    /// The number of neighbors within one mismatch of several barcodes
+    pub num_ambiguous: usize,
+    /// The number of neighbors which are barcodes themselves
+    pub num_parental: usize,
+    /// The barcodes losing the most neighbors
+    pub most_affected: Vec<AffectedBarcode>,
+}
+
+/// A barcode with neighbors lost to ambiguity
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AffectedBarcode {
+    /// The barcode (without its linker)
+    pub barcode: String,
+    /// The number of its one-mismatch neighbors which are not corrected to it
+    pub num_dropped: usize,
+}
+
 #[derive(Debug)]
 pub struct Barcodes {
     map: HashMap<Vec<u8>, usize>,
     index: HashMap<usize, Vec<u8>>,
+    annotations: HashMap<usize, Vec<String>>,
     len: usize,
     spacer_len: Option<usize>,
+    lazy: bool,
 }
 impl Barcodes {
     pub fn from_file(path: &str, exact: bool) -> Result<Self> {
@@ -40,6 +82,10 @@ impl Barcodes {
 
     /// Parses a buffer and returns a Barcodes object
     /// If a spacer is given, it is appended to each barcode
+    ///
+    /// Lines may be tab or comma separated, in which case the first column is
+    /// taken as the barcode sequence and the remaining columns are kept as annotations.
+    /// A header line (whose first column is not a nucleotide sequence) is skipped.
     pub fn parse_buffer<R: BufRead>(
         reader: R,
         spacer: Option<&Spacer>,
@@ -47,13 +93,26 @@ impl Barcodes {
     ) -> Result<Self> {
         let mut map = HashMap::new();
         let mut index = HashMap::new();
+        let mut annotations = HashMap::new();
         let mut sizes = HashSet::new();
 
-        for (idx, line) in reader.lines().enumerate() {
-            let barcode = line.map(|l| Self::read_sequence(&l, spacer))?;
+        let mut idx = 0;
+        for (line_num, line) in reader.lines().enumerate() {
+            let line = line?;
+            let mut fields = line.trim().split(['\t', ',']).map(|f| f.trim());
+            let sequence = fields.next().unwrap_or_default();
+            if line_num == 0 && !Self::is_nucleotide(sequence) {
+                continue;
+            }
+            let barcode = Self::read_sequence(sequence, spacer);
+            let annotation = fields.map(|f| f.to_string()).collect::<Vec<_>>();
+            if !annotation.is_empty() {
+                annotations.insert(idx, annotation);
+            }
             sizes.insert(barcode.len());
             map.entry(barcode.clone()).or_insert(idx);
             index.entry(idx).or_insert(barcode);
+            idx += 1;
         }
 
         if !exact {
@@ -73,31 +132,87 @@ impl Barcodes {
             anyhow::bail!("Barcodes have different lengths");
         };
 
-        let spacer_len = if let Some(spacer) = spacer {
-            Some(spacer.seq().len())
-        } else {
-            None
-        };
+        let spacer_len = spacer.map(|s| s.seq().len());
 
         Ok(Self {
             map,
             index,
+            annotations,
             len,
             spacer_len,
+            lazy: false,
         })
     }
 
-    /// Reads a sequence from a line and appends a spacer if given
+    /// Checks if a field is a non-empty nucleotide sequence (in either case)
+    pub fn is_nucleotide(field: &str) -> bool {
+        !field.is_empty()
+            && field
+                .bytes()
+                .all(|c| matches!(c.to_ascii_uppercase(), b'A' | b'C' | b'G' | b'T' | b'N'))
+    }
+
+    /// Reads a sequence from a line (uppercased) and appends a spacer if given
     /// Returns the sequence as a vector of integer nucleotides
     fn read_sequence(line: &str, spacer: Option<&Spacer>) -> Vec<u8> {
-        let barcode = line.trim().as_bytes().to_vec();
+        let barcode = line.trim().to_ascii_uppercase().into_bytes();
         if let Some(spacer) = spacer {
-            let mut barcode_with_spacer = barcode.clone();
+            let mut barcode_with_spacer = barcode;
             barcode_with_spacer.extend_from_slice(spacer.seq());
-            return barcode_with_spacer;
+            barcode_with_spacer
         } else {
-            return barcode;
+            barcode
+        }
+    }
+
+    /// Matches one-mismatch children on demand rather than holding them in the index.
+    ///
+    /// This should only be used for barcodes parsed with exact matching, and trades a
+    /// slower lookup of mismatched sequences for the memory of the expanded index.
+    pub fn with_lazy_mismatches(mut self) -> Self {
+        self.lazy = true;
+        self
+    }
+
+    /// Looks up the barcode index of a sequence (including lazy one-mismatch children)
+    fn lookup(&self, window: &[u8]) -> Option<BarcodeID> {
+        match self.map.get(window) {
+            Some(idx) => Some(*idx),
+            None if self.lazy => self.lookup_mismatch(window),
+            None => None,
+        }
+    }
+
+    /// Finds the unique barcode a single substitution away from a sequence.
+    ///
+    /// Matches the eager expansion: only ACGT substitutions are considered
+    /// and sequences near multiple barcodes are ambiguous.
+    fn lookup_mismatch(&self, window: &[u8]) -> Option<BarcodeID> {
+        if !window
+            .iter()
+            .all(|b| matches!(b, b'A' | b'C' | b'G' | b'T'))
+        {
+            return None;
+        }
+        let mut child = window.to_vec();
+        let mut found = None;
+        for pos in 0..child.len() {
+            let original = child[pos];
+            for base in [b'A', b'C', b'G', b'T'] {
+                if base == original {
+                    continue;
+                }
+                child[pos] = base;
+                if let Some(idx) = self.map.get(&child) {
+                    if found.is_some_and(|f| f != *idx) {
+                        return None;
+                    }
+                    found = Some(*idx);
+                }
+            }
+            child[pos] = original;
         }
+        found
     }
 
     /// Checks if a sequence contains a barcode as a substring
@@ -109,13 +224,8 @@ impl Barcodes {
         }
         sequence
             .windows(self.len)
-            .position(|window| self.map.contains_key(window))
-            .map(|pos| {
-                (
-                    pos + self.len,
-                    *self.map.get(&sequence[pos..pos + self.len]).unwrap(),
-                )
-            })
+            .enumerate()
+            .find_map(|(pos, window)| self.lookup(window).map(|idx| (pos + self.len, idx)))
     }
 
     /// Matches a subsequence of a sequence
@@ -133,6 +243,16 @@ impl Barcodes {
         self.match_sequence(&sequence[start..end])
     }
 
+    /// Looks up a barcode starting exactly at `start` without scanning
+    /// and returns the position of the first nucleotide after the barcode
+    /// (relative to `start`) as well as the barcode index
+    pub fn match_at(&self, sequence: &[u8], start: usize) -> Option<(EndPos, BarcodeID)> {
+        sequence
+            .get(start..start + self.len)
+            .and_then(|window| self.lookup(window))
+            .map(|idx| (self.len, idx))
+    }
+
     /// Returns the barcode sequence for a given index
     pub fn get_barcode(&self, idx: usize, with_spacer: bool) -> Option<&[u8]> {
         let end_pos = if with_spacer {
@@ -147,22 +267,95 @@ impl Barcodes {
         self.index.get(&idx).map(|bc| &bc[..end_pos])
     }
 
+    /// Returns the auxiliary columns of the barcode file for a given index
+    pub fn get_annotation(&self, idx: usize) -> Option<&[String]> {
+        self.annotations.get(&idx).map(|a| a.as_slice())
+    }
+
+    /// Returns true if the barcode file carried auxiliary columns
+    pub fn has_annotations(&self) -> bool {
+        !self.annotations.is_empty()
+    }
+
     /// Returns the barcode index for a given sequence
-    #[allow(dead_code)]
     pub fn get_id(&self, barcode: &[u8]) -> Option<usize> {
-        self.map.get(barcode).map(|id| *id)
+        self.map.get(barcode).copied()
+    }
+
+    /// Returns the number of (parent) barcodes
+    pub fn num_barcodes(&self) -> usize {
+        self.index.len()
     }
 
     /// Returns the length of each barcode
     pub fn len(&self) -> usize {
         self.len
     }
+
+    /// Returns the length of the spacer appended to each barcode
+    pub fn spacer_len(&self) -> usize {
+        self.spacer_len.unwrap_or(0)
+    }
+
+    /// Returns true if there are no barcodes
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Counts the one-mismatch neighbors (of the barcodes with their linker) which are
+    /// dropped from correction, either because they are within one mismatch of several
+    /// barcodes or because they are barcodes themselves, and lists the barcodes losing
+    /// the most neighbors.
+    pub fn ambiguity(&self) -> Ambiguity {
+        let mut neighbors: HashMap<Vec<u8>, Vec<usize>> = HashMap::new();
+        for (idx, barcode) in &self.index {
+            for child in mismatches(barcode) {
+                neighbors.entry(child).or_default().push(*idx);
+            }
+        }
+        let parents = self.index.values().collect::<HashSet<_>>();
+        let mut ambiguity = Ambiguity {
+            num_neighbors: neighbors.len(),
+            ..Ambiguity::default()
+        };
+        let mut dropped: HashMap<usize, usize> = HashMap::new();
+        for (child, idxs) in &neighbors {
+            if parents.contains(child) {
+                ambiguity.num_parental += 1;
+            } else if idxs.len() > 1 {
+                ambiguity.num_ambiguous += 1;
+            } else {
+                continue;
+            }
+            for idx in idxs {
+                *dropped.entry(*idx).or_default() += 1;
+            }
+        }
+        let mut affected = dropped
+            .into_iter()
+            .filter_map(|(idx, num_dropped)| {
+                self.get_barcode(idx, false).map(|barcode| AffectedBarcode {
+                    barcode: String::from_utf8_lossy(barcode).to_string(),
+                    num_dropped,
+                })
+            })
+            .collect::<Vec<_>>();
+        affected.sort_unstable_by(|a, b| {
+            b.num_dropped
+                .cmp(&a.num_dropped)
+                .then_with(|| a.barcode.cmp(&b.barcode))
+        });
+        affected.truncate(MOST_AFFECTED);
+        ambiguity.most_affected = affected;
+        ambiguity
+    }
 }
 
 pub struct Spacer {
     seq: Vec<u8>,
 }
 impl Spacer {
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(seq: &str) -> Self {
         Self {
             seq: seq.as_bytes().to_vec(),
@@ -179,6 +372,9 @@ mod testing {
 
     const TEST_FILE: &str = "data/barcodes_v3/fb_v3_bc1.tsv";
     const TEST_BUFFER: &[u8] = b"AGAAACCA\nGATTTCCC\nAAGTCCAA\nGAGAAACC";
+    const ANNOTATED_BUFFER: &[u8] = b"AGAAACCA\tA01\nGATTTCCC\tA02\nAAGTCCAA\tA03\nGAGAAACC\tA04";
+    const CSV_BUFFER: &[u8] =
+        b"sequence,well,plate\nAGAAACCA,A01,P1\nGATTTCCC,A02,P1\nAAGTCCAA,A03,P1\nGAGAAACC,A04,P1\n";
     const MALFORMED_BUFFER: &[u8] = b"AGAAACCA\nGATTTCCC\nAAGTCCAA\nGAGAAACCC";
     const TEST_SPACER: &str = "ATG";
     const NOMATCH_SEQ: &[u8] = b"SHOULDNOTMATCHANYTHING";
@@ -195,6 +391,7 @@ mod testing {
         assert_eq!(barcodes.len(), 8);
         assert_eq!(barcodes.map.len(), 2360);
         assert_eq!(barcodes.index.len(), 96);
+        assert_eq!(barcodes.num_barcodes(), 96);
     }
 
     #[test]
@@ -228,6 +425,17 @@ mod testing {
         assert_eq!(barcodes.get_id(b"GAGAAACC").unwrap(), 3);
     }
 
+    #[test]
+    fn from_buffer_lowercase() {
+        let lowercase = TEST_BUFFER.to_ascii_lowercase();
+        let barcodes = Barcodes::from_buffer(lowercase.as_slice(), true).unwrap();
+        assert_eq!(barcodes.index.len(), 4);
+        assert_eq!(barcodes.get_barcode(0, true).unwrap(), b"AGAAACCA");
+        assert_eq!(barcodes.get_id(b"GATTTCCC").unwrap(), 1);
+        assert!(Barcodes::is_nucleotide("acgtn"));
+        assert!(!Barcodes::is_nucleotide("sequence"));
+    }
+
     #[test]
     fn from_buffer() {
         let barcodes = Barcodes::from_buffer(TEST_BUFFER, false).unwrap();
@@ -259,6 +467,44 @@ mod testing {
         assert_eq!(barcodes.get_id(b"CCGAAACC"), None);
     }
 
+    #[test]
+    fn ambiguity() {
+        let barcodes = Barcodes::from_buffer(b"AAAA\nAAAT\nCCCC".as_slice(), false).unwrap();
+        let ambiguity = barcodes.ambiguity();
+        assert_eq!(ambiguity.num_neighbors, 34);
+        assert_eq!(ambiguity.num_ambiguous, 2);
+        assert_eq!(ambiguity.num_parental, 2);
+        let affected = ambiguity
+            .most_affected
+            .iter()
+            .map(|a| (a.barcode.as_str(), a.num_dropped))
+            .collect::<Vec<_>>();
+        assert_eq!(affected, vec![("AAAA", 3), ("AAAT", 3)]);
+        // ambiguous neighbors are not corrected
+        assert_eq!(barcodes.match_sequence(b"AAAC"), None);
+        assert_eq!(barcodes.match_sequence(b"CCCA"), Some((4, 2)));
+    }
+
+    #[test]
+    fn lazy_mismatches() {
+        let eager = Barcodes::from_buffer(TEST_BUFFER, false).unwrap();
+        let lazy = Barcodes::from_buffer(TEST_BUFFER, true)
+            .unwrap()
+            .with_lazy_mismatches();
+        for seq in [
+            &b"AGAAACCA"[..],
+            b"AGAAACCT",
+            b"TGAAACCA",
+            b"GAGAAACA",
+            b"GATTTCNC",
+            b"TTTTTTTT",
+        ] {
+            assert_eq!(lazy.match_at(seq, 0), eager.match_at(seq, 0));
+        }
+        assert_eq!(lazy.match_at(b"AGAAACCT", 0), Some((8, 0)));
+        assert_eq!(lazy.match_sequence(b"NNAGAAACCT"), Some((10, 0)));
+    }
+
     #[test]
     fn from_file_with_spacer() {
         let spacer = Spacer::from_str(TEST_SPACER);
@@ -328,6 +574,40 @@ mod testing {
         assert_eq!(barcodes.get_id(b"GAGAAACCATG").unwrap(), 3);
     }
 
+    #[test]
+    fn from_buffer_annotated() {
+        let barcodes = Barcodes::from_buffer(ANNOTATED_BUFFER, false).unwrap();
+        assert_eq!(barcodes.len(), 8);
+        assert_eq!(barcodes.index.len(), 4);
+        assert_eq!(barcodes.get_barcode(0, true).unwrap(), b"AGAAACCA");
+        assert_eq!(barcodes.get_barcode(3, true).unwrap(), b"GAGAAACC");
+        assert_eq!(barcodes.get_annotation(0).unwrap(), &["A01".to_string()]);
+        assert_eq!(barcodes.get_annotation(3).unwrap(), &["A04".to_string()]);
+        assert_eq!(barcodes.get_annotation(4), None);
+        assert!(barcodes.has_annotations());
+    }
+
+    #[test]
+    fn from_buffer_csv_with_header() {
+        let spacer = Spacer::from_str(TEST_SPACER);
+        let barcodes = Barcodes::from_buffer_with_spacer(CSV_BUFFER, &spacer, true).unwrap();
+        assert_eq!(barcodes.len(), 11);
+        assert_eq!(barcodes.index.len(), 4);
+        assert_eq!(barcodes.get_barcode(0, true).unwrap(), b"AGAAACCAATG");
+        assert_eq!(barcodes.get_id(b"GATTTCCCATG").unwrap(), 1);
+        assert_eq!(
+            barcodes.get_annotation(1).unwrap(),
+            &["A02".to_string(), "P1".to_string()]
+        );
+    }
+
+    #[test]
+    fn from_buffer_unannotated() {
+        let barcodes = Barcodes::from_buffer(TEST_BUFFER, true).unwrap();
+        assert_eq!(barcodes.get_annotation(0), None);
+        assert!(!barcodes.has_annotations());
+    }
+
     #[test]
     fn size_variance() {
         let barcodes = Barcodes::from_buffer(MALFORMED_BUFFER, false);
@@ -366,7 +646,7 @@ mod testing {
         );
         assert_eq!(
             barcodes.match_sequence(STARTMATCH_SEQ),
-            Some((0 + barcodes.len(), 0))
+            Some((barcodes.len(), 0))
         );
         assert_eq!(
             barcodes.match_sequence(OFFSETMATCH_SEQ),
@@ -380,7 +660,7 @@ mod testing {
         );
         assert_eq!(
             barcodes.match_sequence(STARTMATCH_SEQ_1D),
-            Some((0 + barcodes.len(), 0))
+            Some((barcodes.len(), 0))
         );
         assert_eq!(
             barcodes.match_sequence(OFFSETMATCH_SEQ_1D),
@@ -400,7 +680,7 @@ mod testing {
         );
         assert_eq!(
             barcodes.match_sequence(STARTMATCH_SEQ),
-            Some((0 + barcodes.len(), 0))
+            Some((barcodes.len(), 0))
         );
         assert_eq!(
             barcodes.match_sequence(OFFSETMATCH_SEQ),
@@ -426,7 +706,7 @@ mod testing {
         );
         assert_eq!(
             barcodes.match_subsequence(ENDMATCH_SEQ, start_pos, end_pos),
-            Some((0 + barcodes.len(), 0))
+            Some((barcodes.len(), 0))
         );
         assert_eq!(
             barcodes.match_subsequence(STARTMATCH_SEQ, start_pos, end_pos),
@@ -440,7 +720,7 @@ mod testing {
         // with mismatch
         assert_eq!(
             barcodes.match_subsequence(ENDMATCH_SEQ_1D, start_pos, end_pos),
-            Some((0 + barcodes.len(), 0))
+            Some((barcodes.len(), 0))
         );
         assert_eq!(
             barcodes.match_subsequence(STARTMATCH_SEQ_1D, start_pos, end_pos),
@@ -465,7 +745,7 @@ mod testing {
         );
         assert_eq!(
             barcodes.match_subsequence(ENDMATCH_SEQ, start_pos, end_pos),
-            Some((0 + barcodes.len(), 0))
+            Some((barcodes.len(), 0))
         );
         assert_eq!(
             barcodes.match_subsequence(STARTMATCH_SEQ, start_pos, end_pos),
@@ -491,6 +771,38 @@ mod testing {
         );
     }
 
+    #[test]
+    fn match_at() {
+        let barcodes = Barcodes::from_buffer(TEST_BUFFER, false).unwrap();
+        assert_eq!(barcodes.match_at(NOMATCH_SEQ, 0), None);
+        assert_eq!(
+            barcodes.match_at(ENDMATCH_SEQ, 7),
+            Some((barcodes.len(), 0))
+        );
+        assert_eq!(
+            barcodes.match_at(ENDMATCH_SEQ_1D, 7),
+            Some((barcodes.len(), 0))
+        );
+        assert_eq!(
+            barcodes.match_at(OFFSETMATCH_SEQ, 3),
+            Some((barcodes.len(), 0))
+        );
+        assert_eq!(barcodes.match_at(OFFSETMATCH_SEQ, 2), None);
+        assert_eq!(barcodes.match_at(OFFSETMATCH_SEQ, 0), None);
+        assert_eq!(barcodes.match_at(ENDMATCH_SEQ, 8), None);
+        assert_eq!(barcodes.match_at(b"", 0), None);
+    }
+
+    #[test]
+    fn match_at_exact() {
+        let barcodes = Barcodes::from_buffer(TEST_BUFFER, true).unwrap();
+        assert_eq!(
+            barcodes.match_at(STARTMATCH_SEQ, 0),
+            Some((barcodes.len(), 0))
+        );
+        assert_eq!(barcodes.match_at(STARTMATCH_SEQ_1D, 0), None);
+    }
+
     #[test]
     fn match_empty() {
         let barcodes = Barcodes::from_buffer(TEST_BUFFER, false).unwrap();
@@ -0,0 +1,446 @@
+use hashbrown::HashSet;
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+/// The number of cells assumed when none are expected
+pub const DEFAULT_EXPECTED_CELLS: usize = 3000;
+
+/// The quantile of the top barcodes' read counts used as the reference count
+const ORDMAG_QUANTILE: f64 = 0.99;
+
+/// The ratio between the reference count and the minimum read count of a cell
+const ORDMAG_RATIO: f64 = 10.0;
+
+/// The default maximum read count of the barcodes pooled into the ambient profile
+pub const DEFAULT_AMBIENT_MAX_READS: usize = 100;
+
+/// The default false discovery rate below which a barcode is called a cell
+pub const DEFAULT_CELL_FDR: f64 = 0.01;
+
+/// The minimum number of ambient barcodes required to test against the ambient profile
+const MIN_AMBIENT_BARCODES: usize = 10;
+
+/// The outcome of testing a barcode against the ambient profile
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CellCall {
+    /// The number of reads of the barcode
+    pub reads: usize,
+    /// The probability of an ambient barcode having at least as many reads
+    pub p_value: f64,
+    /// The Benjamini-Hochberg adjusted p-value
+    pub fdr: f64,
+    /// Whether the barcode is called a cell
+    pub is_cell: bool,
+}
+
+/// Distinguishes cells from ambient barcodes in the spirit of emptyDrops.
+///
+/// Barcodes with at most `ambient_max_reads` reads are pooled into an ambient profile, a
+/// negative binomial fit by maximum likelihood to their read counts. As only the counts
+/// of `1..=ambient_max_reads` are pooled, the fit is truncated to that range, so the
+/// profile extends to the ambient barcodes above the cutoff. Every barcode above it is
+/// tested for having more reads than an (observed) ambient barcode would, and is called a
+/// cell if its FDR (adjusted over the ambient barcodes as well) is at most `fdr` or it
+/// passes the [`ordmag`] knee. Returns one call per
+/// tested barcode (None for ambient barcodes), or only the knee calls if there are too few
+/// ambient barcodes to fit a profile.
+pub fn call_cells(
+    counts: &[usize],
+    expected_cells: usize,
+    ambient_max_reads: usize,
+    fdr: f64,
+) -> Vec<Option<CellCall>> {
+    let num_cells = ordmag(counts, expected_cells);
+    let mut sorted = counts.to_vec();
+    sorted.sort_unstable_by(|a, b| b.cmp(a));
+    let knee = match num_cells {
+        0 => usize::MAX,
+        n => sorted[n - 1],
+    };
+
+    let mut histogram = vec![0; ambient_max_reads + 1];
+    counts
+        .iter()
+        .filter(|c| **c > 0 && **c <= ambient_max_reads)
+        .for_each(|c| histogram[*c] += 1);
+    let num_ambient = histogram.iter().sum::<usize>();
+    let max_tested = counts.iter().copied().max().unwrap_or(0);
+    let tail = (num_ambient >= MIN_AMBIENT_BARCODES)
+        .then(|| NegativeBinomial::fit_truncated(&histogram).tail(max_tested));
+
+    // the pooled ambient barcodes are tested too, as the barcodes above the cutoff are
+    // only the most extreme of them
+    let tested = counts
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| **c > 0)
+        .map(|(idx, c)| {
+            let p_value = tail
+                .as_ref()
+                .map_or(1.0, |tail| tail.get(*c).copied().unwrap_or(0.0));
+            (idx, p_value)
+        })
+        .collect::<Vec<_>>();
+    let adjusted = benjamini_hochberg(&tested.iter().map(|(_, p)| *p).collect::<Vec<_>>());
+
+    let mut calls = vec![None; counts.len()];
+    for ((idx, p_value), adjusted) in tested.into_iter().zip(adjusted) {
+        let reads = counts[idx];
+        if reads <= ambient_max_reads {
+            continue;
+        }
+        calls[idx] = Some(CellCall {
+            reads,
+            p_value,
+            fdr: adjusted,
+            is_cell: (tail.is_some() && adjusted <= fdr) || reads >= knee,
+        });
+    }
+    calls
+}
+
+/// The bounds of the (natural log of the) mean of the ambient profile relative to the
+/// cutoff of the ambient barcodes
+const LN_MEAN_RANGE: (f64, f64) = (-5.0, 5.0);
+
+/// The bounds of the (natural log of the) size of the ambient profile, whose upper end is
+/// indistinguishable from a Poisson distribution
+const LN_SIZE_RANGE: (f64, f64) = (-5.0, 14.0);
+
+/// The number of golden-section steps maximizing the likelihood along each parameter
+const FIT_STEPS: usize = 60;
+
+/// A negative binomial distribution of read counts with a given mean and size
+#[derive(Debug, Clone, Copy)]
+struct NegativeBinomial {
+    mean: f64,
+    size: f64,
+}
+impl NegativeBinomial {
+    /// Returns the log probabilities of 0 to `max_count` reads
+    fn ln_pmf(&self, max_count: usize) -> Vec<f64> {
+        let ln_p = (self.size / (self.size + self.mean)).ln();
+        let ln_q = (self.mean / (self.size + self.mean)).ln();
+        let mut ln_pmf = Vec::with_capacity(max_count + 1);
+        let mut ln_prob = self.size * ln_p;
+        for k in 0..=max_count {
+            ln_pmf.push(ln_prob);
+            let k = k as f64;
+            ln_prob += ((k + self.size) / (k + 1.0)).ln() + ln_q;
+        }
+        ln_pmf
+    }
+
+    /// Returns the log likelihood of a histogram of counts observed only within
+    /// `1..histogram.len()`
+    fn ln_likelihood_truncated(&self, histogram: &[usize]) -> f64 {
+        let ln_pmf = self.ln_pmf(histogram.len() - 1);
+        let observed = &ln_pmf[1..];
+        let max = observed.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        let ln_mass = max + observed.iter().map(|l| (l - max).exp()).sum::<f64>().ln();
+        let num = histogram.iter().sum::<usize>() as f64;
+        histogram
+            .iter()
+            .zip(&ln_pmf)
+            .skip(1)
+            .filter(|(n, _)| **n > 0)
+            .map(|(n, l)| *n as f64 * l)
+            .sum::<f64>()
+            - num * ln_mass
+    }
+
+    /// Fits the distribution by maximum likelihood to a histogram of counts observed only
+    /// within `1..histogram.len()` (the counts of the ambient barcodes)
+    fn fit_truncated(histogram: &[usize]) -> Self {
+        let cutoff = (histogram.len() - 1) as f64;
+        let build = |ln_mean: f64, ln_size: f64| Self {
+            mean: cutoff * ln_mean.exp(),
+            size: ln_size.exp(),
+        };
+        // the likelihood is maximized over the mean for each size
+        let best_mean = |ln_size: f64| {
+            golden_section(LN_MEAN_RANGE, |ln_mean| {
+                build(ln_mean, ln_size).ln_likelihood_truncated(histogram)
+            })
+        };
+        let ln_size = golden_section(LN_SIZE_RANGE, |ln_size| {
+            build(best_mean(ln_size), ln_size).ln_likelihood_truncated(histogram)
+        });
+        build(best_mean(ln_size), ln_size)
+    }
+
+    /// Returns the probability of at least `c` reads given at least one for every `c` up
+    /// to `max_count` (trailing probabilities which round to zero are omitted)
+    fn tail(&self, max_count: usize) -> Vec<f64> {
+        let pmf = self
+            .ln_pmf(max_count)
+            .into_iter()
+            .map(f64::exp)
+            .collect::<Vec<_>>();
+        let observed = 1.0 - pmf[0];
+        let mut tail = vec![1.0];
+        let mut cdf = pmf[0];
+        for p in pmf.iter().skip(1) {
+            let above = ((1.0 - cdf) / observed).clamp(0.0, 1.0);
+            if above == 0.0 {
+                break;
+            }
+            tail.push(above);
+            cdf += p;
+        }
+        tail
+    }
+}
+
+/// Returns the argument maximizing a unimodal function within a range
+fn golden_section<F: Fn(f64) -> f64>((mut lo, mut hi): (f64, f64), f: F) -> f64 {
+    let ratio = (5f64.sqrt() - 1.0) / 2.0;
+    let mut a = hi - ratio * (hi - lo);
+    let mut b = lo + ratio * (hi - lo);
+    let (mut fa, mut fb) = (f(a), f(b));
+    for _ in 0..FIT_STEPS {
+        if fa < fb {
+            lo = a;
+            a = b;
+            fa = fb;
+            b = lo + ratio * (hi - lo);
+            fb = f(b);
+        } else {
+            hi = b;
+            b = a;
+            fb = fa;
+            a = hi - ratio * (hi - lo);
+            fa = f(a);
+        }
+    }
+    (lo + hi) / 2.0
+}
+
+/// Adjusts p-values for multiple testing with the Benjamini-Hochberg procedure
+fn benjamini_hochberg(p_values: &[f64]) -> Vec<f64> {
+    let m = p_values.len();
+    let mut order = (0..m).collect::<Vec<_>>();
+    order.sort_by(|a, b| p_values[*a].total_cmp(&p_values[*b]));
+    let mut adjusted = vec![0.0; m];
+    let mut running = 1.0_f64;
+    for (rank, idx) in order.into_iter().enumerate().rev() {
+        running = running.min(p_values[idx] * m as f64 / (rank + 1) as f64);
+        adjusted[idx] = running;
+    }
+    adjusted
+}
+
+/// The accumulated QC metrics of the passing reads of a cell barcode
+#[derive(Debug, Default, Clone)]
+pub struct CellSummary {
+    /// The number of passing reads
+    pub reads: usize,
+    /// The hashes of the distinct UMIs
+    umis: HashSet<u64>,
+    /// The sum and number of the (phred) barcode qualities
+    barcode_qual: (u64, u64),
+    /// The sum and number of the (phred) R2 qualities
+    r2_qual: (u64, u64),
+    /// The number of barcode bases corrected by a mismatch
+    corrected_bases: u64,
+}
+impl CellSummary {
+    /// Records a passing read of the barcode with its phred+33 barcode and R2 qualities
+    /// and the number of its corrected tiers, returning whether its UMI is new to the barcode
+    pub fn add(
+        &mut self,
+        umi: &[u8],
+        barcode_qual: &[u8],
+        r2_qual: &[u8],
+        corrected: usize,
+    ) -> bool {
+        self.reads += 1;
+        let new_umi = self.umis.insert(hash_sequence(umi));
+        let phred_sum = |qual: &[u8]| {
+            qual.iter()
+                .map(|q| q.saturating_sub(33) as u64)
+                .sum::<u64>()
+        };
+        self.barcode_qual.0 += phred_sum(barcode_qual);
+        self.barcode_qual.1 += barcode_qual.len() as u64;
+        self.r2_qual.0 += phred_sum(r2_qual);
+        self.r2_qual.1 += r2_qual.len() as u64;
+        self.corrected_bases += corrected as u64;
+        new_umi
+    }
+
+    /// Returns the number of distinct UMIs
+    pub fn num_umis(&self) -> usize {
+        self.umis.len()
+    }
+
+    /// Returns the mean phred quality of the barcode bases
+    pub fn mean_barcode_qual(&self) -> f64 {
+        mean(self.barcode_qual)
+    }
+
+    /// Returns the mean phred quality of the R2 bases
+    pub fn mean_r2_qual(&self) -> f64 {
+        mean(self.r2_qual)
+    }
+
+    /// Returns the fraction of barcode bases corrected by a mismatch
+    pub fn fraction_corrected_bases(&self) -> f64 {
+        mean((self.corrected_bases, self.barcode_qual.1))
+    }
+}
+
+/// Hashes a sequence (e.g. a UMI or a barcode and UMI) for exact distinct counting
+pub fn hash_sequence(seq: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    seq.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn mean((sum, n): (u64, u64)) -> f64 {
+    if n == 0 {
+        0.0
+    } else {
+        sum as f64 / n as f64
+    }
+}
+
+/// Estimates the number of cells from the read counts of each barcode using the
+/// order-of-magnitude rule.
+///
+/// The 99th percentile of the read counts of the `expected_cells` largest barcodes is
+/// taken as a reference and every barcode within an order of magnitude of it is called
+/// a cell.
+pub fn ordmag(counts: &[usize], expected_cells: usize) -> usize {
+    let mut counts = counts
+        .iter()
+        .copied()
+        .filter(|c| *c > 0)
+        .collect::<Vec<_>>();
+    if counts.is_empty() {
+        return 0;
+    }
+    counts.sort_unstable_by(|a, b| b.cmp(a));
+    let top = expected_cells.clamp(1, counts.len());
+    let rank = ((1.0 - ORDMAG_QUANTILE) * (top - 1) as f64).round() as usize;
+    let cutoff = counts[rank] as f64 / ORDMAG_RATIO;
+    counts.iter().take_while(|c| **c as f64 >= cutoff).count()
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn separates_cells_from_background() {
+        let mut counts = vec![1000; 500];
+        counts.extend(vec![300; 100]);
+        counts.extend(vec![5; 10_000]);
+        assert_eq!(ordmag(&counts, DEFAULT_EXPECTED_CELLS), 600);
+        assert_eq!(ordmag(&counts, 500), 600);
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(ordmag(&[], DEFAULT_EXPECTED_CELLS), 0);
+        assert_eq!(ordmag(&[0, 0], DEFAULT_EXPECTED_CELLS), 0);
+        assert_eq!(ordmag(&[4], DEFAULT_EXPECTED_CELLS), 1);
+    }
+
+    #[test]
+    fn ambient_calls() {
+        // ambient barcodes with a handful of reads, a knee of large cells and a few
+        // small cells below the knee but well above the ambient profile
+        let mut counts = (0..5000).map(|i| 1 + i % 20).collect::<Vec<_>>();
+        counts.extend(vec![5000; 200]);
+        counts.extend(vec![300; 20]);
+        counts.extend([110]);
+        let calls = call_cells(
+            &counts,
+            DEFAULT_EXPECTED_CELLS,
+            DEFAULT_AMBIENT_MAX_READS,
+            DEFAULT_CELL_FDR,
+        );
+        assert!(calls[..5000].iter().all(|c| c.is_none()));
+        let called = calls.iter().flatten().filter(|c| c.is_cell).count();
+        assert_eq!(called, 221);
+        assert_eq!(ordmag(&counts, DEFAULT_EXPECTED_CELLS), 200);
+        assert!(calls[5200].unwrap().fdr < DEFAULT_CELL_FDR);
+    }
+
+    #[test]
+    fn truncated_fit() {
+        // the expected histogram of 100k ambient barcodes, of which only the counts up to
+        // the cutoff are observed
+        let truth = NegativeBinomial {
+            mean: 80.0,
+            size: 2.0,
+        };
+        let histogram = truth
+            .ln_pmf(DEFAULT_AMBIENT_MAX_READS)
+            .into_iter()
+            .map(|l| (l.exp() * 100_000.0).round() as usize)
+            .collect::<Vec<_>>();
+        let fit = NegativeBinomial::fit_truncated(&histogram);
+        assert!((fit.mean - truth.mean).abs() < 8.0, "{:?}", fit);
+        assert!((fit.size - truth.size).abs() < 0.2, "{:?}", fit);
+    }
+
+    #[test]
+    fn ambient_above_cutoff() {
+        // ambient barcodes whose counts extend past the cutoff are not cells
+        let mut counts = (0..5000)
+            .map(|i| 1 + (-(1.0 - (i as f64 + 0.5) / 5000.0).ln() * 50.0) as usize)
+            .collect::<Vec<_>>();
+        counts.extend(vec![5000; 200]);
+        let calls = call_cells(
+            &counts,
+            DEFAULT_EXPECTED_CELLS,
+            DEFAULT_AMBIENT_MAX_READS,
+            DEFAULT_CELL_FDR,
+        );
+        let ambient = calls[..5000].iter().flatten().collect::<Vec<_>>();
+        assert!(ambient.len() > 500);
+        assert!(ambient.iter().all(|c| c.reads >= 400 || !c.is_cell));
+        assert!(calls[5000..].iter().all(|c| c.unwrap().is_cell));
+        // only the most extreme ambient barcodes are called, within the FDR
+        let ambient_called = ambient.iter().filter(|c| c.is_cell).count();
+        assert!(ambient_called as f64 <= 2.0 * DEFAULT_CELL_FDR * (200 + ambient_called) as f64);
+    }
+
+    #[test]
+    fn benjamini_hochberg_adjustment() {
+        let adjusted = benjamini_hochberg(&[0.01, 0.04, 0.03, 0.5]);
+        let expected = [0.04, 0.04 * 4.0 / 3.0, 0.04 * 4.0 / 3.0, 0.5];
+        assert!(adjusted
+            .iter()
+            .zip(expected)
+            .all(|(a, e)| (a - e).abs() < 1e-12));
+    }
+
+    #[test]
+    fn no_ambient_profile() {
+        let counts = vec![10000, 9000, 150];
+        let calls = call_cells(
+            &counts,
+            DEFAULT_EXPECTED_CELLS,
+            DEFAULT_AMBIENT_MAX_READS,
+            DEFAULT_CELL_FDR,
+        );
+        assert!(calls[0].unwrap().is_cell);
+        assert!(!calls[2].unwrap().is_cell);
+        assert_eq!(calls[2].unwrap().fdr, 1.0);
+    }
+
+    #[test]
+    fn cell_summary() {
+        let mut summary = CellSummary::default();
+        assert!(summary.add(b"AAAA", b"IIII", b"5555", 1));
+        assert!(!summary.add(b"AAAA", b"????", b"55", 0));
+        assert!(summary.add(b"CCCC", b"IIII", b"", 0));
+        assert_eq!(summary.reads, 3);
+        assert_eq!(summary.num_umis(), 2);
+        assert!((summary.mean_barcode_qual() - 110.0 / 3.0).abs() < 1e-12);
+        assert_eq!(summary.mean_r2_qual(), 20.0);
+        assert_eq!(summary.fraction_corrected_bases(), 1.0 / 12.0);
+    }
+}
@@ -0,0 +1,225 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use digest::DynDigest;
+use md5::Md5;
+use sha2::Sha256;
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Write},
+    path::Path,
+    sync::{Arc, OnceLock},
+};
+
+/// The checksum written alongside each output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ChecksumAlgorithm {
+    Md5,
+    Sha256,
+}
+impl ChecksumAlgorithm {
+    /// Returns the extension of the sidecar file holding the checksum
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Md5 => "md5",
+            Self::Sha256 => "sha256",
+        }
+    }
+
+    /// Returns the path of the sidecar file of an output
+    pub fn sidecar_path(&self, path: &str) -> String {
+        format!("{}.{}", path, self.extension())
+    }
+
+    fn hasher(&self) -> Box<dyn DynDigest + Send> {
+        match self {
+            Self::Md5 => Box::new(Md5::default()),
+            Self::Sha256 => Box::new(Sha256::default()),
+        }
+    }
+}
+
+/// The running hashes of each algorithm over the same bytes
+struct Hashers(Vec<(ChecksumAlgorithm, Box<dyn DynDigest + Send>)>);
+impl Hashers {
+    fn new(algorithms: &[ChecksumAlgorithm]) -> Self {
+        Self(algorithms.iter().map(|a| (*a, a.hasher())).collect())
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.0
+            .iter_mut()
+            .for_each(|(_, hasher)| hasher.update(data));
+    }
+
+    /// Returns the hex digest of each algorithm
+    fn hex(self) -> Vec<(ChecksumAlgorithm, String)> {
+        self.0
+            .into_iter()
+            .map(|(algorithm, hasher)| {
+                let digest = hasher.finalize();
+                (
+                    algorithm,
+                    digest.iter().map(|b| format!("{:02x}", b)).collect(),
+                )
+            })
+            .collect()
+    }
+}
+
+/// The checksums of the bytes passed through a `Hashed` writer, set once the writer is
+/// dropped
+#[derive(Debug, Clone, Default)]
+pub struct Checksums(Arc<OnceLock<Vec<(ChecksumAlgorithm, String)>>>);
+impl Checksums {
+    /// Returns the hex digest of each algorithm, or `None` while the writer is still open
+    pub fn hex(&self) -> Option<Vec<(ChecksumAlgorithm, String)>> {
+        self.0.get().cloned()
+    }
+}
+
+/// Wraps a writer and hashes the bytes passing through it, so the checksums of an output
+/// are known once it is written without reading it back
+pub struct Hashed<W> {
+    inner: W,
+    hashers: Option<Hashers>,
+    checksums: Checksums,
+}
+impl<W> Hashed<W> {
+    pub fn new(inner: W, algorithms: &[ChecksumAlgorithm]) -> Self {
+        Self {
+            inner,
+            hashers: Some(Hashers::new(algorithms)),
+            checksums: Checksums::default(),
+        }
+    }
+
+    /// Returns a handle to the checksums, which are set once the wrapper is dropped (e.g.
+    /// when the compression thread owning it finishes)
+    pub fn checksums(&self) -> Checksums {
+        self.checksums.clone()
+    }
+}
+impl<W: Write> Write for Hashed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let num_bytes = self.inner.write(buf)?;
+        if let Some(hashers) = self.hashers.as_mut() {
+            hashers.update(&buf[..num_bytes]);
+        }
+        Ok(num_bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+impl<W> Drop for Hashed<W> {
+    fn drop(&mut self) {
+        if let Some(hashers) = self.hashers.take() {
+            let _ = self.checksums.0.set(hashers.hex());
+        }
+    }
+}
+
+/// Returns the hex digest of each algorithm over the content of a file
+pub fn file_checksums(
+    path: &str,
+    algorithms: &[ChecksumAlgorithm],
+) -> Result<Vec<(ChecksumAlgorithm, String)>> {
+    let mut reader = File::open(path).map(BufReader::new)?;
+    let mut hashers = Hashers::new(algorithms);
+    let mut buffer = vec![0; 1 << 16];
+    loop {
+        match reader.read(&mut buffer)? {
+            0 => break,
+            num_bytes => hashers.update(&buffer[..num_bytes]),
+        }
+    }
+    Ok(hashers.hex())
+}
+
+/// Writes a sidecar in the `md5sum`/`sha256sum` format (`<digest>  <file name>`), so
+/// `md5sum -c` verifies the output from its directory
+pub fn write_sidecar(sidecar: &str, output: &str, digest: &str) -> Result<()> {
+    let name = Path::new(output)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(output);
+    let mut writer = File::create(sidecar)?;
+    writeln!(writer, "{}  {}", digest, name)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    fn hex(algorithm: ChecksumAlgorithm, data: &[u8]) -> String {
+        let mut hashers = Hashers::new(&[algorithm]);
+        hashers.update(data);
+        hashers.hex().remove(0).1
+    }
+
+    #[test]
+    fn known_digests() {
+        let long = vec![b'a'; 1000];
+        for (data, md5, sha256) in [
+            (
+                b"".as_slice(),
+                "d41d8cd98f00b204e9800998ecf8427e",
+                "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            ),
+            (
+                b"abc",
+                "900150983cd24fb0d6963f7d28e17f72",
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            ),
+            (
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+                "8215ef0796a20bcaaae116d3876c664a",
+                "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+            ),
+            (
+                &long,
+                "cabe45dcc9ae5b66ba86600cca6b8ba8",
+                "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3",
+            ),
+        ] {
+            assert_eq!(hex(ChecksumAlgorithm::Md5, data), md5);
+            assert_eq!(hex(ChecksumAlgorithm::Sha256, data), sha256);
+        }
+    }
+
+    #[test]
+    fn hashed_writer() {
+        let algorithms = [ChecksumAlgorithm::Md5, ChecksumAlgorithm::Sha256];
+        let mut writer = Hashed::new(Vec::new(), &algorithms);
+        let checksums = writer.checksums();
+        // writes of every size straddle the block boundaries
+        let data = (0..5000u32).map(|i| i as u8).collect::<Vec<_>>();
+        for chunk in data.chunks(37) {
+            writer.write_all(chunk).unwrap();
+        }
+        assert_eq!(writer.inner, data);
+        // the checksums are only known once the writer is dropped
+        assert_eq!(checksums.hex(), None);
+        drop(writer);
+        let expected = algorithms
+            .iter()
+            .map(|a| (*a, hex(*a, &data)))
+            .collect::<Vec<_>>();
+        assert_eq!(checksums.hex(), Some(expected));
+    }
+
+    #[test]
+    fn parse_algorithm() {
+        assert_eq!(
+            ChecksumAlgorithm::from_str("sha256", false),
+            Ok(ChecksumAlgorithm::Sha256)
+        );
+        assert_eq!(
+            ChecksumAlgorithm::Md5.sidecar_path("x_R1.fq.gz"),
+            "x_R1.fq.gz.md5"
+        );
+        assert!(ChecksumAlgorithm::from_str("crc32", false).is_err());
+    }
+}
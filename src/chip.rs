@@ -0,0 +1,108 @@
+#[cfg(feature = "io")]
+use crate::log::Statistics;
+use serde::Deserialize;
+use std::{fmt, str::FromStr};
+
+/// The factor above the nominal capacity of a chip at which the estimated number of
+/// cells is suspicious
+#[cfg(feature = "io")]
+const CAPACITY_FACTOR: f64 = 1.5;
+
+/// The scale of a PIPseq chip, which determines the number of cells it is loaded with and
+/// the thresholds suited to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Chip {
+    T2,
+    T20,
+    T100,
+}
+impl Chip {
+    /// Returns the nominal number of cells captured by the chip, used as the expected
+    /// number of cells unless `--expected-cells` is given
+    pub fn expected_cells(&self) -> usize {
+        match self {
+            Self::T2 => 2_000,
+            Self::T20 => 20_000,
+            Self::T100 => 100_000,
+        }
+    }
+
+    /// Returns the maximum read count of the barcodes pooled into the ambient profile.
+    ///
+    /// Larger chips spread the sequencing depth over more partitions, so their ambient
+    /// barcodes carry fewer reads.
+    pub fn ambient_max_reads(&self) -> usize {
+        match self {
+            Self::T2 => 200,
+            Self::T20 => 100,
+            Self::T100 => 50,
+        }
+    }
+
+    /// Returns a warning if the estimated number of cells exceeds the capacity of the chip
+    #[cfg(feature = "io")]
+    pub fn check_capacity(&self, statistics: &Statistics) -> Option<String> {
+        let cells = statistics.called_cells.or(statistics.estimated_cells)?;
+        let capacity = self.expected_cells() as f64 * CAPACITY_FACTOR;
+        (cells as f64 > capacity).then(|| {
+            format!(
+                "Estimated cells ({}) exceed the capacity of a {} chip ({} cells); check the chip in the config",
+                cells,
+                self,
+                self.expected_cells()
+            )
+        })
+    }
+}
+impl FromStr for Chip {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "T2" => Ok(Self::T2),
+            "T20" => Ok(Self::T20),
+            "T100" => Ok(Self::T100),
+            _ => Err(format!("expected `T2`, `T20` or `T100`, found '{}'", s)),
+        }
+    }
+}
+impl TryFrom<String> for Chip {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+impl fmt::Display for Chip {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::T2 => write!(f, "T2"),
+            Self::T20 => write!(f, "T20"),
+            Self::T100 => write!(f, "T100"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn parse_chip() {
+        assert_eq!("t20".parse(), Ok(Chip::T20));
+        assert_eq!(Chip::T100.to_string(), "T100");
+        assert!("T10000".parse::<Chip>().is_err());
+        assert_eq!(serde_yaml::from_str::<Chip>("T2").unwrap(), Chip::T2);
+    }
+
+    #[test]
+    #[cfg(feature = "io")]
+    fn capacity() {
+        let mut statistics = Statistics::new();
+        assert_eq!(Chip::T2.check_capacity(&statistics), None);
+        statistics.estimated_cells = Some(2_500);
+        assert_eq!(Chip::T2.check_capacity(&statistics), None);
+        statistics.estimated_cells = Some(5_000);
+        assert!(Chip::T2.check_capacity(&statistics).is_some());
+        assert_eq!(Chip::T20.check_capacity(&statistics), None);
+    }
+}
@@ -1,31 +1,316 @@
-use clap::Parser;
+use clap::{ArgGroup, Args, Parser, Subcommand, ValueEnum};
+use pipspeak::{
+    checksum::ChecksumAlgorithm,
+    config::Matcher,
+    contaminant::{self, ContaminantAction},
+    dedup::DedupMode,
+    downsample, extsort, features, hashtags, longread,
+    memory::MemoryBudget,
+    parser::ParserBackend,
+    process::{BarcodeQual, ChimeraAction, NameFormat, OutputFormat},
+    shard::ShardMode,
+    trim,
+    whitelist::SetOperation,
+};
+use std::{str::FromStr, time::Duration};
+
+/// The naming convention of the output fastq files
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Naming {
+    /// `<prefix>_R[12].fq.gz`
+    Pipspeak,
+    /// `<prefix>_S1_L00<lane>_R[12]_001.fastq.gz` as expected by cellranger-style pipelines
+    Tenx,
+}
+impl Naming {
+    /// Builds the output filename of a read (`R1` or `R2`) for a prefix
+    pub fn filename(&self, prefix: &str, read: &str, lane: usize) -> String {
+        match self {
+            Self::Pipspeak => format!("{}_{}.fq.gz", prefix, read),
+            Self::Tenx => format!("{}_S1_L{:03}_{}_001.fastq.gz", prefix, lane, read),
+        }
+    }
+
+    /// Builds the output filename of a 0-indexed shard of a read for a prefix
+    pub fn shard_filename(&self, prefix: &str, read: &str, lane: usize, shard: usize) -> String {
+        match self {
+            Self::Pipspeak => format!("{}_{}_{:03}.fq.gz", prefix, read, shard + 1),
+            Self::Tenx => format!(
+                "{}_S1_L{:03}_{}_{:03}.fastq.gz",
+                prefix,
+                lane,
+                read,
+                shard + 1
+            ),
+        }
+    }
+}
+
+/// The arrangement of the output files
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layout {
+    /// All outputs share the output prefix
+    Flat,
+    /// A 10x-style `<prefix>/outs/` directory with `barcodes.tsv.gz` and Illumina-named
+    /// fastqs under `outs/fastq/`
+    Tenx,
+}
+
+/// The window to search for the first barcode in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Offset {
+    /// A fixed number of nucleotides
+    Fixed(usize),
+    /// Estimated from the leading reads of each sample
+    Auto,
+}
+impl FromStr for Offset {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "auto" {
+            Ok(Self::Auto)
+        } else {
+            s.parse()
+                .map(Self::Fixed)
+                .map_err(|_| format!("expected a number or `auto`, found '{}'", s))
+        }
+    }
+}
+
+/// The division of the available threads between the processing stages
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Threads {
+    /// The number of inputs decompressed on background threads (0-2: R2 first, then R1)
+    pub decompress: usize,
+    /// The number of gzip compression threads shared between the R1 and R2 outputs
+    pub compress: usize,
+}
 
 #[derive(Parser, Debug)]
-#[clap(author, version, about)]
+#[clap(
+    author,
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 pub struct Cli {
-    /// Input file for R1
-    #[clap(short = 'i', long, value_parser)]
-    pub r1: String,
+    #[clap(subcommand)]
+    pub command: Option<Command>,
 
-    /// Input file for R2
-    #[clap(short = 'I', long, value_parser)]
-    pub r2: String,
+    /// Input file(s) or glob patterns for R1 (multiple lanes are processed in order as a
+    /// single sample)
+    #[clap(
+        short = 'i',
+        long,
+        value_parser,
+        num_args = 1..
+    )]
+    pub r1: Vec<String>,
+
+    /// Input file(s) or glob patterns for R2 (in the same order as R1)
+    #[clap(
+        short = 'I',
+        long,
+        value_parser,
+        num_args = 1..
+    )]
+    pub r2: Vec<String>,
+
+    /// Unaligned BAM file(s) grouped by read name whose first and second mates are used as
+    /// R1 and R2 (instead of `-i`/`-I`)
+    #[clap(
+        long,
+        value_parser,
+        num_args = 1..,
+        conflicts_with_all = ["r1", "r2", "fastq", "samplesheet", "i1"]
+    )]
+    pub ubam: Vec<String>,
+
+    /// File listing the R1 inputs one per line (instead of `-i`)
+    #[clap(
+        long,
+        requires = "r2_fofn",
+        conflicts_with_all = ["r1", "r2", "fastq", "samplesheet", "ubam"]
+    )]
+    pub r1_fofn: Option<String>,
+
+    /// File listing the R2 inputs one per line, in the same order as `--r1-fofn`
+    #[clap(long, requires = "r1_fofn")]
+    pub r2_fofn: Option<String>,
+
+    /// Directory (or `<dir>/<prefix>` for the `<prefix>_*` files) whose `*_R1_*`/`*_R2_*`
+    /// FASTQ files are paired across lanes and processed as a single sample
+    #[clap(long, conflicts_with_all = ["r1", "r2", "i1", "samplesheet"])]
+    pub fastq: Option<String>,
+
+    /// Index read (I1) file(s), in the same order as R1, whose sequences are folded into the
+    /// read names of the outputs (and the `BC` tag of BAM output). Index reads are paired
+    /// in order, so they cannot be combined with `--repair`
+    #[clap(long, value_parser, num_args = 1.., conflicts_with_all = ["samplesheet", "repair"])]
+    pub i1: Vec<String>,
+
+    /// Second index read (I2) file(s), in the same order as R1
+    #[clap(long, value_parser, num_args = 1.., requires = "i1")]
+    pub i2: Vec<String>,
+
+    /// Samplesheet (TSV/CSV) with the columns `sample, R1, R2[, prefix]` to process
+    /// multiple samples in one invocation (the prefix defaults to the sample name and
+    /// rows sharing a sample name are merged as lanes)
+    #[clap(short = 'S', long, conflicts_with_all = ["r1", "r2"])]
+    pub samplesheet: Option<String>,
+
+    /// Number of samples (or config libraries) to process in parallel when using a
+    /// samplesheet
+    #[clap(short = 'j', long, default_value = "1")]
+    pub jobs: usize,
 
     /// Output file prefix (output files will be named <prefix>_R[12].fq.gz)
     #[clap(short = 'p', long, value_parser, default_value = "pipspeak")]
     pub prefix: String,
 
-    /// Number of threads to use in gzip compression (0 = all threads)
+    /// Naming convention of the output fastq files
+    #[clap(long, value_enum, default_value = "pipspeak")]
+    pub naming: Naming,
+
+    /// Arrangement of the output files (`tenx` writes into `<prefix>/outs/`)
+    #[clap(long, value_enum, default_value = "flat", conflicts_with = "naming")]
+    pub layout: Layout,
+
+    /// Format of the emitted reads: `fastq` or `dropseq-bam`, an unaligned BAM of R2
+    /// grouped by read name with the barcode and UMI in the `XC`/`XM` tags (Drop-seq tools
+    /// conventions) written to `<prefix>_unaligned_tagged.bam` instead of the fastqs
+    #[clap(long, default_value = "fastq", conflicts_with_all = ["zumis", "no_r1_out"])]
+    pub output_format: OutputFormat,
+
+    /// The ID of the `@RG` read group of BAM output (defaults to the sample name)
+    #[clap(long)]
+    pub rg_id: Option<String>,
+
+    /// The sample (`SM`) of the BAM read group (defaults to the sample name, or the prefix
+    /// for config libraries)
+    #[clap(long)]
+    pub rg_sample: Option<String>,
+
+    /// The library (`LB`) of the BAM read group (defaults to the name of config libraries)
+    #[clap(long)]
+    pub rg_library: Option<String>,
+
+    /// The platform (`PL`) of the BAM read group
+    #[clap(long, default_value = "ILLUMINA")]
+    pub rg_platform: String,
+
+    /// Format of the read names of both outputs: `original` or `umitools`, which appends
+    /// `_<barcode>_<UMI>` to each read name for UMI-tools dedup/count workflows
+    #[clap(long, default_value = "original")]
+    pub name_format: NameFormat,
+
+    /// Drop the descriptions after the first whitespace of the read names (e.g. the
+    /// Illumina `1:N:0:TAAGGCGA`) in both outputs, keeping only the sample index folded in
+    /// from `--i1`/`--i2` as a `BC:Z:` comment
+    #[clap(long)]
+    pub strip_comments: bool,
+
+    /// Replace the read names with short serial IDs (identical across R1 and R2) and write
+    /// the original name of each ID to `<prefix>_read_names.tsv.gz`
+    #[clap(long, conflicts_with = "reads_per_cell")]
+    pub rename_reads: bool,
+
+    /// Do not write the synthetic barcode read (R1) as the barcode and UMI are carried in the
+    /// read names of R2
+    #[clap(long)]
+    pub no_r1_out: bool,
+
+    /// With `--no-r1-out`, write the read name, barcode and UMI of each read pair to
+    /// `<prefix>_barcodes.tsv.gz` (sharded as R2) instead of carrying them in the read names
+    #[clap(long, requires = "no_r1_out")]
+    pub barcode_table: bool,
+
+    /// Write a zUMIs config snippet (`<prefix>_zUMIs.yaml`) with the barcode, UMI and cDNA
+    /// ranges of the outputs
+    #[clap(long, conflicts_with = "no_r1_out")]
+    pub zumis: bool,
+
+    /// Write `<output>.md5` and/or `<output>.sha256` checksum files next to every output
+    /// (hashed while writing the fastqs)
+    #[clap(long, num_args = 1.., value_name = "md5|sha256")]
+    pub checksums: Vec<ChecksumAlgorithm>,
+
+    /// Append to the outputs of an earlier run of the same prefix (e.g. a later delivery
+    /// of a sample): the fastqs are extended with new gzip members, the whitelist is
+    /// merged and the read counts of the log are accumulated
+    #[clap(
+        long,
+        conflicts_with_all = [
+            "rename_reads",
+            "cell_summary",
+            "cell_metadata",
+            "r1_composition",
+            "well_counts",
+            "features",
+        ]
+    )]
+    pub append: bool,
+
+    /// Split the output fastqs into N pairs of shards (`<prefix>_R[12]_<shard>.fq.gz`, or
+    /// the chunk number of `--naming tenx`) to parallelize downstream alignment
+    #[clap(long, default_value = "1", value_parser = clap::value_parser!(u64).range(1..), conflicts_with = "zumis")]
+    pub shards: u64,
+
+    /// How read pairs are assigned to `--shards`: `round-robin` or by a hash of their
+    /// `barcode`, so all reads of a cell land in the same shard
+    #[clap(long, default_value = "round-robin")]
+    pub shard_by: ShardMode,
+
+    /// The number of consecutive read pairs written to a round-robin shard before the next
+    /// (1 for round-robin)
+    #[clap(long, default_value = "1", value_parser = clap::value_parser!(u64).range(1..))]
+    pub shard_block_size: u64,
+
+    /// Lane number used in the output filenames with `--naming tenx`
+    #[clap(long, default_value = "1")]
+    pub lane: usize,
+
+    /// Write the fastqs of each input pair (lane) separately, keeping its lane number
+    /// (`_L00<lane>`) in the filenames, while sharing a single whitelist and log
+    #[clap(long, conflicts_with_all = ["zumis", "reads_per_cell"])]
+    pub split_lanes: bool,
+
+    /// Number of threads to use (0 = all threads), divided between decompression,
+    /// matching and compression unless overridden
     #[clap(short = 't', long, default_value = "1")]
     pub threads: usize,
 
+    /// Number of inputs of a pair to decompress on background threads (0-2)
+    #[clap(long, value_parser = clap::value_parser!(u8).range(0..=2))]
+    pub decompress_threads: Option<u8>,
+
+    /// Number of threads to use in gzip compression of the outputs
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub compress_threads: Option<u64>,
+
+    /// The FASTQ parser: `fxread` or `native`, which parses whole records out of the read
+    /// buffer with a single scan and allocation and reports malformed records
+    #[clap(long, default_value = "fxread")]
+    pub parser: ParserBackend,
+
     /// The amount of nucleotides away from the start of R1 to accept a barcode
+    ///
+    /// With `auto` the smallest window capturing 99.5% of the first barcode matches in
+    /// the leading reads of each sample is used.
     #[clap(short = 's', long, default_value = "5")]
-    pub offset: usize,
+    pub offset: Offset,
+
+    /// Match the first barcode exactly at its expected position (ignores `--offset`)
+    ///
+    /// This skips the sliding window search and is significantly faster for chemistries
+    /// without offset variability.
+    #[clap(short = 'a', long)]
+    pub anchored: bool,
 
     /// The yaml config file describing the file paths of the 4 barcodes and the spacers
-    #[clap(short = 'c', long, value_parser)]
-    pub config: String,
+    #[clap(short = 'c', long, value_parser, required = true)]
+    pub config: Option<String>,
 
     /// The length of the UMI
     #[clap(short = 'u', long, default_value = "12")]
@@ -35,11 +320,671 @@ pub struct Cli {
     #[clap(short = 'x', long)]
     pub exact: bool,
 
+    /// The barcode matcher: `hamming` (substitutions only) or `levenshtein` (which also
+    /// tolerates a single insertion or deletion within a barcode and its linker)
+    #[clap(long, default_value = "hamming")]
+    pub matcher: Matcher,
+
     /// Include linkers in the output
     #[clap(short = 'l', long)]
     pub linkers: bool,
 
+    /// Qualities of the barcode region in R1: `keep` copies the original R1 qualities and
+    /// a single character (e.g. `~`) writes that constant quality for every barcode base
+    #[clap(long, default_value = "keep")]
+    pub barcode_qual: BarcodeQual,
+
+    /// Quality cutoff for BWA-style trimming of the 3' end of R2 (no trimming if unset)
+    #[clap(long)]
+    pub trim_qual: Option<u8>,
+
+    /// Minimum length of R2 (after any trimming) to keep a read pair
+    #[clap(long, default_value = "0")]
+    pub min_r2_len: usize,
+
+    /// FASTA or plain list (one sequence per line) of adapters to trim from the 3' end of R2
+    #[clap(long)]
+    pub adapters: Option<String>,
+
+    /// Maximum fraction of mismatches over the overlap of an adapter match
+    #[clap(long, default_value_t = trim::DEFAULT_ADAPTER_ERROR_RATE)]
+    pub adapter_error_rate: f64,
+
+    /// Minimum overlap of an adapter with the 3' end of R2 to trim it
+    #[clap(long, default_value_t = trim::DEFAULT_ADAPTER_MIN_OVERLAP)]
+    pub adapter_min_overlap: usize,
+
+    /// FASTA of contaminant sequences (e.g. PhiX, adapters) to screen R2 against
+    #[clap(long)]
+    pub contaminants: Option<String>,
+
+    /// The k-mer size of the contaminant screen (at most 32)
+    #[clap(long, default_value_t = contaminant::DEFAULT_K)]
+    pub contaminant_k: usize,
+
+    /// What happens to read pairs whose R2 shares a k-mer with a contaminant: `drop` them
+    /// or `flag` them by appending ` contaminant` to the R2 header
+    #[clap(long, default_value = "drop")]
+    pub contaminant_action: ContaminantAction,
+
+    /// Detect read pairs whose R1 holds a second tier 1 barcode (with its linker)
+    /// downstream of the matched one, a signature of ligation chimeras: `count` them,
+    /// `flag` them by appending ` chimera` to the R2 header (tagged `XJ:Z:1` in BAM
+    /// output) or `drop` them
+    #[clap(long)]
+    pub chimeras: Option<ChimeraAction>,
+
+    /// Verify that the read names of every R1/R2 pair match (and that both files have
+    /// the same number of records), failing on the first mismatch
+    #[clap(long, conflicts_with = "repair")]
+    pub check_pairing: bool,
+
+    /// Re-synchronize R1 and R2 by read name, dropping reads whose mate is missing
+    #[clap(long)]
+    pub repair: bool,
+
+    /// Cap the number of read pairs emitted per barcode, sampling them uniformly
+    /// (reservoir sampling) so very deep cells do not dominate
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub reads_per_cell: Option<u64>,
+
+    /// Emit only the first read pair of each molecule (barcode and UMI), remembering
+    /// molecules in an `exact` set of hashes or a fixed-size `bloom` filter
+    #[clap(long, num_args = 0..=1, default_missing_value = "exact")]
+    pub dedup: Option<DedupMode>,
+
+    /// Include the first N bases of R2 in the molecule key of `--dedup`
+    #[clap(long, default_value = "0", requires = "dedup")]
+    pub dedup_r2_prefix: usize,
+
+    /// The number of distinct molecules the `--dedup bloom` filter is sized for (at a
+    /// 0.1% false positive rate)
+    #[clap(long, default_value = "100000000", requires = "dedup")]
+    pub dedup_capacity: usize,
+
+    /// The seed of the `--reads-per-cell` downsampling
+    #[clap(long, default_value_t = downsample::DEFAULT_SEED)]
+    pub seed: u64,
+
+    /// A whitelist of the barcodes expected in the run (e.g. from a kit lot or a previous
+    /// run), overriding any `expected_whitelist` of the config. The log reports the
+    /// fraction of passing read pairs whose barcode is in it.
+    #[clap(long)]
+    pub expected_whitelist: Option<String>,
+
+    /// A fixed 10x barcode whitelist (e.g. `737K-august-2016.txt`): each barcode is
+    /// replaced in the outputs by an entry derived from its barcode indices and the mapping
+    /// is written to `<prefix>_tenx_translation.tsv`. The whitelist must have at least as
+    /// many barcodes as the config can build, and the translated barcodes are written with
+    /// a constant `F` quality
+    #[clap(long, conflicts_with_all = ["zumis", "barcode_qual"])]
+    pub tenx_whitelist: Option<String>,
+
+    /// Drop read pairs whose barcode is not in the expected whitelist
+    #[clap(long)]
+    pub reject_unexpected: bool,
+
+    /// Append ` unexpected` to the R2 names of read pairs whose barcode is not in the
+    /// expected whitelist (tagged `XU:Z:1` in BAM output)
+    #[clap(long, conflicts_with = "reject_unexpected")]
+    pub flag_unexpected: bool,
+
+    /// A feature reference of `id, name, sequence` rows or a 10x-style
+    /// `feature_reference.csv` (e.g. CITE-seq antibodies or hashtags) matched in R2. A
+    /// feature by barcode UMI count matrix is written.
+    #[clap(long)]
+    pub features: Option<String>,
+
+    /// The position of the feature barcode in R2 (10x references use their patterns)
+    #[clap(long, default_value_t = 0, requires = "features")]
+    pub feature_offset: usize,
+
+    /// The number of mismatches allowed when matching a feature barcode
+    #[clap(long, default_value_t = features::DEFAULT_MISMATCHES, requires = "features")]
+    pub feature_mismatches: usize,
+
+    /// Classify each barcode by its hashtag (HTO) counts in the feature matrix as a sample,
+    /// doublet or negative and write the cells of each hashtag
+    #[clap(long)]
+    pub demux_hashtags: bool,
+
+    /// The minimum number of hashtag UMIs of a barcode to be classified
+    #[clap(long, default_value_t = hashtags::DEFAULT_MIN_UMIS, requires = "demux_hashtags")]
+    pub hashtag_min_umis: usize,
+
+    /// Drop reads whose UMI is a single-base homopolymer
+    #[clap(long)]
+    pub filter_homopolymer_umi: bool,
+
+    /// Keep read pairs whose R2 is empty, writing a single `N` base in its place
+    /// (they are skipped by default)
+    #[clap(long)]
+    pub keep_empty_r2: bool,
+
+    /// Minimum Shannon entropy (in bits, 0-2) of the UMI to keep a read
+    #[clap(long)]
+    pub min_umi_entropy: Option<f64>,
+
+    /// Number of passing reads between measurements of the whitelist growth curve
+    #[clap(long, default_value = "1000000")]
+    pub growth_interval: usize,
+
+    /// Do not collect the whitelist of observed barcodes to keep memory usage flat
+    ///
+    /// This skips writing the whitelist, whitelist growth, translation and well files.
+    #[clap(long)]
+    pub no_whitelist: bool,
+
+    /// Count the whitelist on disk by spilling sorted chunks and merging them at the end
+    ///
+    /// This keeps memory bounded for very large runs and additionally writes the number of
+    /// reads of each barcode. The translation, growth and well files are not written.
+    #[clap(long, conflicts_with = "no_whitelist")]
+    pub external_whitelist: bool,
+
+    /// Maximum number of distinct barcodes held in memory per chunk with `--external-whitelist`
+    /// [default: 10000000, or the whitelist share of `--max-memory`]
+    #[clap(long)]
+    pub chunk_size: Option<usize>,
+
+    /// Bound the memory of a run (e.g. `512M` or `4G`): a quarter is shared by the chunks
+    /// buffered by the decompression threads and the rest by the barcodes, molecules and
+    /// sampled reads held in memory. Only an external whitelist spills to disk: anything
+    /// else outgrowing its share aborts the run with a usage error (exit status 2)
+    #[clap(long)]
+    pub max_memory: Option<MemoryBudget>,
+
+    /// Directory for the temporary chunks of `--external-whitelist` and the reads spilled by
+    /// `--reads-per-cell` (defaults to the system temporary directory)
+    #[clap(long)]
+    pub tmp_dir: Option<String>,
+
+    /// Minimum fraction of passing reads; below this pipspeak still writes all outputs
+    /// but exits with status 3
+    #[clap(long)]
+    pub min_pass_rate: Option<f64>,
+
+    /// The expected number of cells, used to sanity-check the whitelist size (defaults to
+    /// the capacity of the `chip` of the config)
+    #[clap(long)]
+    pub expected_cells: Option<usize>,
+
+    /// Call cells against the ambient profile of low-count barcodes (emptyDrops-style)
+    /// and write the calls with their FDR to <prefix>_cell_calls.tsv
+    #[clap(long, conflicts_with_all = ["no_whitelist", "external_whitelist"])]
+    pub call_cells: bool,
+
+    /// Write the reads, distinct UMIs, mean barcode and R2 qualities and fraction of
+    /// corrected barcode bases of each barcode to <prefix>_cell_summary.tsv
+    #[clap(long, conflicts_with_all = ["no_whitelist", "external_whitelist"])]
+    pub cell_summary: bool,
+
+    /// Write the wells of each tier, reads, distinct UMIs and sample of each barcode to
+    /// <prefix>_cell_metadata.csv, to be loaded as the cell metadata of scanpy or Seurat
+    #[clap(long, conflicts_with_all = ["no_whitelist", "external_whitelist"])]
+    pub cell_metadata: bool,
+
+    /// Write the per-position nucleotide composition of R1 to <prefix>_r1_composition.tsv
+    #[clap(long)]
+    pub r1_composition: bool,
+
+    /// Write the passing reads of each barcode of each tier to <prefix>_well_counts.tsv
+    #[clap(long)]
+    pub well_counts: bool,
+
+    /// The maximum read count of the barcodes pooled into the ambient profile [default:
+    /// 100, or the preset of the `chip` of the config]
+    #[clap(long)]
+    pub ambient_max_reads: Option<usize>,
+
+    /// The false discovery rate below which a barcode is called a cell (between 0 and 1)
+    #[clap(long, default_value = "0.01", value_parser = parse_probability)]
+    pub cell_fdr: f64,
+
+    /// Show a live dashboard of the pass rate, filter rates, whitelist size and throughput
+    #[clap(long, conflicts_with = "quiet")]
+    pub tui: bool,
+
+    /// Periodically write the cumulative statistics as JSON lines to this file (`-` for stderr)
+    #[clap(long)]
+    pub progress_json: Option<String>,
+
+    /// Number of seconds between lines of the JSON progress stream (positive)
+    #[clap(long, default_value = "10", value_parser = parse_seconds)]
+    pub progress_interval: f64,
+
     /// Do not write anything to stderr
     #[clap(short = 'q', long)]
     pub quiet: bool,
 }
+
+/// Utilities beside the processing of reads
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Generate synthetic R1/R2 fastqs of a chemistry with known barcodes and UMIs
+    Simulate(SimulateArgs),
+    /// Compare the logs (and optionally the whitelists) of two runs
+    Compare(CompareArgs),
+    /// Combine whitelists (e.g. of technical replicates or an empty-lane control)
+    Whitelist(WhitelistArgs),
+    /// Print the `kb count -x`/`bustools` technology string of the outputs of a config
+    TechString(TechStringArgs),
+    /// Extract the barcodes, UMI and cDNA of long (ONT/PacBio) reads into R1/R2 fastqs
+    LongRead(LongReadArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct LongReadArgs {
+    /// The yaml config file of the chemistry
+    #[clap(short = 'c', long)]
+    pub config: String,
+
+    /// The long-read fastq(s), optionally compressed (reads of both strands)
+    #[clap(short = 'i', long, num_args = 1.., required = true)]
+    pub inputs: Vec<String>,
+
+    /// Output file prefix (writes <prefix>_R[12].fq.gz, <prefix>_whitelist.txt and
+    /// <prefix>_log.yaml)
+    #[clap(short = 'p', long, default_value = "long_read")]
+    pub prefix: String,
+
+    /// The number of edits (substitutions, insertions or deletions) tolerated within each
+    /// barcode and fixed sequence
+    #[clap(short = 'd', long, default_value_t = longread::DEFAULT_LONG_READ_DISTANCE)]
+    pub max_distance: usize,
+
+    /// The number of nucleotides at the start of each strand searched for the first barcode
+    #[clap(short = 'w', long, default_value_t = longread::DEFAULT_LONG_READ_WINDOW)]
+    pub window: usize,
+
+    /// The length of the UMI (for read structures without an explicit UMI length)
+    #[clap(short = 'u', long, default_value = "12")]
+    pub umi_len: usize,
+
+    /// Include the linkers in the emitted R1
+    #[clap(short = 'l', long)]
+    pub linkers: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct TechStringArgs {
+    /// The yaml config file of the chemistry
+    #[clap(short = 'c', long)]
+    pub config: String,
+
+    /// Whether the outputs include the linkers (as with `--linkers`)
+    #[clap(short = 'l', long)]
+    pub linkers: bool,
+
+    /// The length of the UMI (for read structures without an explicit UMI length)
+    #[clap(short = 'u', long, default_value = "12")]
+    pub umi_len: usize,
+
+    /// The 10x whitelist the barcodes are translated to (as with `--tenx-whitelist`)
+    #[clap(long, conflicts_with = "linkers")]
+    pub tenx_whitelist: Option<String>,
+}
+
+#[derive(Args, Debug)]
+#[clap(group(ArgGroup::new("operation").required(true)))]
+pub struct WhitelistArgs {
+    /// The whitelists to combine, optionally with read counts in a second column
+    #[clap(num_args = 2.., required = true)]
+    pub inputs: Vec<String>,
+
+    /// Keep the barcodes present in every whitelist
+    #[clap(long, group = "operation")]
+    pub intersect: bool,
+
+    /// Keep the barcodes present in any whitelist
+    #[clap(long, group = "operation")]
+    pub union: bool,
+
+    /// Keep the barcodes of the first whitelist absent from all others
+    #[clap(long, group = "operation")]
+    pub subtract: bool,
+
+    /// Write the combined whitelist to a file instead of stdout
+    #[clap(short = 'o', long)]
+    pub output: Option<String>,
+}
+impl WhitelistArgs {
+    /// Returns the selected set operation
+    pub fn operation(&self) -> SetOperation {
+        if self.intersect {
+            SetOperation::Intersect
+        } else if self.union {
+            SetOperation::Union
+        } else {
+            SetOperation::Subtract
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct CompareArgs {
+    /// The log of the first run (`<prefix>_log.yaml`)
+    pub log_a: String,
+
+    /// The log of the second run
+    pub log_b: String,
+
+    /// The whitelist of the first run, optionally with read counts in a second column
+    /// (e.g. `<prefix>_whitelist_counts.tsv` or a list of PIPseeker barcodes)
+    #[clap(long, requires = "whitelist_b")]
+    pub whitelist_a: Option<String>,
+
+    /// The whitelist of the second run
+    #[clap(long, requires = "whitelist_a")]
+    pub whitelist_b: Option<String>,
+
+    /// Write the comparison to a file instead of stdout
+    #[clap(short = 'o', long)]
+    pub output: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct SimulateArgs {
+    /// The yaml config file of the chemistry to simulate
+    #[clap(short = 'c', long)]
+    pub config: String,
+
+    /// Output file prefix (writes <prefix>_R[12].fq.gz, <prefix>_truth.tsv.gz and
+    /// <prefix>_cells.txt)
+    #[clap(short = 'p', long, default_value = "simulated")]
+    pub prefix: String,
+
+    /// The number of cells
+    #[clap(short = 'n', long, default_value = "100")]
+    pub cells: usize,
+
+    /// The number of read pairs
+    #[clap(short = 'r', long, default_value = "10000")]
+    pub reads: usize,
+
+    /// The number of distinct UMIs of each cell
+    #[clap(long, default_value = "50")]
+    pub umis_per_cell: usize,
+
+    /// The length of the UMI (for read structures without an explicit UMI length)
+    #[clap(short = 'u', long, default_value = "12")]
+    pub umi_len: usize,
+
+    /// The length of the R2 reads
+    #[clap(long, default_value = "50")]
+    pub r2_len: usize,
+
+    /// The maximum number of random nucleotides preceding the barcodes in R1
+    #[clap(long, default_value = "0")]
+    pub max_offset: usize,
+
+    /// The probability of a substitution at each nucleotide of R1 (at its first cycle if
+    /// `--final-error-rate` is given)
+    #[clap(short = 'e', long, default_value = "0.001")]
+    pub error_rate: f64,
+
+    /// The substitution rate at the last cycle of R1, interpolated linearly from
+    /// `--error-rate` to model the quality decay across cycles
+    #[clap(long)]
+    pub final_error_rate: Option<f64>,
+
+    /// The probability of an insertion after each nucleotide of R1
+    #[clap(long, default_value = "0")]
+    pub insertion_rate: f64,
+
+    /// The probability of each nucleotide of R1 being deleted
+    #[clap(long, default_value = "0")]
+    pub deletion_rate: f64,
+
+    /// The factor scaling the indel rates within homopolymers (of at least 3 nucleotides)
+    #[clap(long, default_value = "1")]
+    pub homopolymer_factor: f64,
+
+    /// Include linkers in the ground truth barcodes (as `--linkers` does in the outputs)
+    #[clap(short = 'l', long)]
+    pub linkers: bool,
+
+    /// The seed of the simulation
+    #[clap(long, default_value_t = downsample::DEFAULT_SEED)]
+    pub seed: u64,
+}
+
+/// Parses a probability strictly between 0 and 1 (e.g. `--cell-fdr`)
+fn parse_probability(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(p) if p > 0.0 && p < 1.0 => Ok(p),
+        Ok(_) => Err(format!("{} is not between 0 and 1", s)),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Parses a positive, finite number of seconds (e.g. `--progress-interval`)
+fn parse_seconds(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(secs) if secs > 0.0 && Duration::try_from_secs_f64(secs).is_ok() => Ok(secs),
+        Ok(_) => Err(format!("{} is not a positive number of seconds", s)),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+impl Cli {
+    /// Returns the maximum number of distinct barcodes held in memory per chunk of
+    /// `--external-whitelist`
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+            .or(self.max_memory.map(|budget| budget.whitelist_entries()))
+            .unwrap_or(extsort::DEFAULT_CHUNK_SIZE)
+    }
+
+    /// Divides `--threads` between the processing stages.
+    ///
+    /// One thread is reserved for matching, up to two of the remaining threads decompress
+    /// the inputs (one per three available threads) and the rest compress the outputs.
+    /// `--decompress-threads` and `--compress-threads` override the split.
+    pub fn threads(&self) -> Threads {
+        let total = if self.threads == 0 {
+            num_cpus::get()
+        } else {
+            self.threads
+        };
+        let available = total.saturating_sub(1);
+        let decompress = self
+            .decompress_threads
+            .map(usize::from)
+            .unwrap_or((available / 3).min(2));
+        let compress = self
+            .compress_threads
+            .map(|n| n as usize)
+            .unwrap_or(available.saturating_sub(decompress).max(1));
+        Threads {
+            decompress,
+            compress,
+        }
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Cli {
+        let mut argv = vec!["pipspeak", "-c", "config.yaml", "-i", "r1", "-I", "r2"];
+        argv.extend_from_slice(args);
+        Cli::parse_from(argv)
+    }
+
+    #[test]
+    fn thread_allocation() {
+        let threads = |args: &[&str]| {
+            let t = parse(args).threads();
+            (t.decompress, t.compress)
+        };
+        assert_eq!(threads(&[]), (0, 1));
+        assert_eq!(threads(&["-t", "2"]), (0, 1));
+        assert_eq!(threads(&["-t", "4"]), (1, 2));
+        assert_eq!(threads(&["-t", "8"]), (2, 5));
+        assert_eq!(threads(&["-t", "32"]), (2, 29));
+        assert_eq!(threads(&["-t", "8", "--compress-threads", "2"]), (2, 2));
+        assert_eq!(threads(&["-t", "8", "--decompress-threads", "0"]), (0, 7));
+        assert!(Cli::try_parse_from([
+            "pipspeak",
+            "-c",
+            "c",
+            "-i",
+            "a",
+            "-I",
+            "b",
+            "--decompress-threads",
+            "3"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn cell_fdr() {
+        let parse = |fdr: &str| {
+            Cli::try_parse_from([
+                "pipspeak",
+                "-c",
+                "c",
+                "-i",
+                "a",
+                "-I",
+                "b",
+                "--cell-fdr",
+                fdr,
+            ])
+        };
+        assert_eq!(parse("0.05").unwrap().cell_fdr, 0.05);
+        for fdr in ["0", "1", "-0.1", "NaN", "x"] {
+            assert!(parse(fdr).is_err(), "{}", fdr);
+        }
+    }
+
+    #[test]
+    fn progress_interval() {
+        let parse = |secs: &str| {
+            Cli::try_parse_from([
+                "pipspeak",
+                "-c",
+                "c",
+                "-i",
+                "a",
+                "-I",
+                "b",
+                "--progress-interval",
+                secs,
+            ])
+        };
+        assert_eq!(parse("0.5").unwrap().progress_interval, 0.5);
+        for secs in ["0", "-1", "NaN", "inf", "1e30"] {
+            assert!(parse(secs).is_err(), "{}", secs);
+        }
+    }
+
+    #[test]
+    fn naming() {
+        assert_eq!(
+            Naming::Pipspeak.filename("out/a", "R1", 1),
+            "out/a_R1.fq.gz"
+        );
+        assert_eq!(
+            Naming::Tenx.filename("out/a", "R2", 1),
+            "out/a_S1_L001_R2_001.fastq.gz"
+        );
+        assert_eq!(
+            Naming::Tenx.filename("a", "R1", 12),
+            "a_S1_L012_R1_001.fastq.gz"
+        );
+        assert_eq!(
+            Naming::Pipspeak.shard_filename("a", "R2", 1, 0),
+            "a_R2_001.fq.gz"
+        );
+        assert_eq!(
+            Naming::Tenx.shard_filename("a", "R1", 2, 9),
+            "a_S1_L002_R1_010.fastq.gz"
+        );
+    }
+
+    #[test]
+    fn simulate_subcommand() {
+        let cli = Cli::parse_from(["pipspeak", "simulate", "-c", "config.yaml", "-n", "5"]);
+        assert!(cli.config.is_none());
+        match cli.command {
+            Some(Command::Simulate(args)) => assert_eq!((args.cells, args.reads), (5, 10000)),
+            _ => panic!("Expected the simulate subcommand"),
+        }
+        assert!(Cli::try_parse_from(["pipspeak", "-i", "r1", "-I", "r2"]).is_err());
+    }
+
+    #[test]
+    fn input_sources() {
+        let parse =
+            |argv: &[&str]| Cli::try_parse_from([&["pipspeak", "-c", "c.yaml"], argv].concat());
+        assert!(parse(&["--r1-fofn", "r1.txt", "--r2-fofn", "r2.txt"]).is_ok());
+        assert!(parse(&["--r1-fofn", "r1.txt"]).is_err());
+        assert!(parse(&["--r1-fofn", "r1.txt", "--r2-fofn", "r2.txt", "-i", "r1"]).is_err());
+        assert!(parse(&["--fastq", "run1/.", "-i", "r1"]).is_err());
+        assert!(parse(&["--fastq", "run1/.", "--split-lanes"]).is_ok());
+        assert!(parse(&[
+            "--fastq",
+            "run1/.",
+            "--split-lanes",
+            "--reads-per-cell",
+            "10"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn tech_string_subcommand() {
+        let cli = Cli::parse_from(["pipspeak", "tech-string", "-c", "c.yaml", "-u", "10"]);
+        match cli.command {
+            Some(Command::TechString(args)) => assert_eq!(args.umi_len, 10),
+            _ => panic!("Expected the tech-string subcommand"),
+        }
+    }
+
+    #[test]
+    fn long_read_subcommand() {
+        let cli = Cli::parse_from([
+            "pipspeak",
+            "long-read",
+            "-c",
+            "c.yaml",
+            "-i",
+            "a.fq",
+            "b.fq",
+        ]);
+        match cli.command {
+            Some(Command::LongRead(args)) => {
+                assert_eq!(args.inputs, ["a.fq", "b.fq"]);
+                assert_eq!(args.max_distance, longread::DEFAULT_LONG_READ_DISTANCE);
+            }
+            _ => panic!("Expected the long-read subcommand"),
+        }
+        assert!(Cli::try_parse_from(["pipspeak", "long-read", "-c", "c.yaml"]).is_err());
+    }
+
+    #[test]
+    fn compare_subcommand() {
+        let cli = Cli::parse_from(["pipspeak", "compare", "a_log.yaml", "b_log.yaml"]);
+        assert!(matches!(cli.command, Some(Command::Compare(_))));
+        assert!(Cli::try_parse_from([
+            "pipspeak",
+            "compare",
+            "a_log.yaml",
+            "b_log.yaml",
+            "--whitelist-a",
+            "a.txt"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn offset() {
+        assert_eq!("7".parse::<Offset>(), Ok(Offset::Fixed(7)));
+        assert_eq!("auto".parse::<Offset>(), Ok(Offset::Auto));
+        assert!("-1".parse::<Offset>().is_err());
+    }
+}
@@ -0,0 +1,179 @@
+use crate::whitelist::WhitelistCounts;
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use serde_yaml::Value;
+
+/// The metrics of a run compared between runs (from the `statistics` of its log)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RunMetrics {
+    pub total_reads: usize,
+    pub fraction_passing: f64,
+    /// The fraction of reads failing each barcode tier
+    pub fraction_filtered: [f64; 4],
+    pub whitelist_size: usize,
+    pub estimated_cells: Option<usize>,
+}
+impl RunMetrics {
+    /// Reads the metrics of a run from the contents of its `<prefix>_log.yaml`
+    pub fn from_log(contents: &str) -> Result<Self> {
+        let log = serde_yaml::from_str::<Value>(contents)?;
+        let statistics = log
+            .get("statistics")
+            .ok_or_else(|| anyhow!("Log has no `statistics` section"))?;
+        let field = |name: &str| {
+            statistics
+                .get(name)
+                .and_then(Value::as_f64)
+                .ok_or_else(|| anyhow!("Log statistics are missing `{}`", name))
+        };
+        let total_reads = field("total_reads")?;
+        let fraction = |count: f64| {
+            if total_reads > 0.0 {
+                count / total_reads
+            } else {
+                0.0
+            }
+        };
+        Ok(Self {
+            total_reads: total_reads as usize,
+            fraction_passing: fraction(field("passing_reads")?),
+            fraction_filtered: [
+                fraction(field("num_filtered_1")?),
+                fraction(field("num_filtered_2")?),
+                fraction(field("num_filtered_3")?),
+                fraction(field("num_filtered_4")?),
+            ],
+            whitelist_size: field("whitelist_size")? as usize,
+            estimated_cells: field("estimated_cells").ok().map(|n| n as usize),
+        })
+    }
+}
+
+/// The overlap of the whitelists of two runs
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WhitelistComparison {
+    pub size_a: usize,
+    pub size_b: usize,
+    pub shared: usize,
+    /// The size of the intersection over the size of the union
+    pub jaccard: f64,
+    /// The Pearson correlation of the read counts of the shared barcodes (if both
+    /// whitelists have counts)
+    pub count_correlation: Option<f64>,
+}
+impl WhitelistComparison {
+    pub fn new(a: &WhitelistCounts, b: &WhitelistCounts) -> Self {
+        let shared = a.keys().filter(|barcode| b.contains_key(*barcode)).count();
+        let union = a.len() + b.len() - shared;
+        let pairs = a
+            .iter()
+            .filter_map(|(barcode, count)| {
+                Some(((*count)? as f64, b.get(barcode).copied()?? as f64))
+            })
+            .collect::<Vec<_>>();
+        Self {
+            size_a: a.len(),
+            size_b: b.len(),
+            shared,
+            jaccard: if union > 0 {
+                shared as f64 / union as f64
+            } else {
+                0.0
+            },
+            count_correlation: pearson(&pairs),
+        }
+    }
+}
+
+/// The differences between two runs (`b - a`)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Comparison {
+    pub a: RunMetrics,
+    pub b: RunMetrics,
+    pub delta_fraction_passing: f64,
+    pub delta_fraction_filtered: [f64; 4],
+    pub whitelists: Option<WhitelistComparison>,
+}
+impl Comparison {
+    pub fn new(a: RunMetrics, b: RunMetrics, whitelists: Option<WhitelistComparison>) -> Self {
+        let delta_fraction_filtered =
+            [0, 1, 2, 3].map(|tier| b.fraction_filtered[tier] - a.fraction_filtered[tier]);
+        Self {
+            delta_fraction_passing: b.fraction_passing - a.fraction_passing,
+            delta_fraction_filtered,
+            a,
+            b,
+            whitelists,
+        }
+    }
+}
+
+/// Returns the Pearson correlation of paired values (or None if either is constant)
+fn pearson(pairs: &[(f64, f64)]) -> Option<f64> {
+    if pairs.len() < 2 {
+        return None;
+    }
+    let n = pairs.len() as f64;
+    let mean_x = pairs.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = pairs.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let (mut cov, mut var_x, mut var_y) = (0.0, 0.0, 0.0);
+    for (x, y) in pairs {
+        cov += (x - mean_x) * (y - mean_y);
+        var_x += (x - mean_x).powi(2);
+        var_y += (y - mean_y).powi(2);
+    }
+    if var_x == 0.0 || var_y == 0.0 {
+        return None;
+    }
+    Some(cov / (var_x * var_y).sqrt())
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::whitelist::read_counts_from_buffer;
+
+    const LOG: &str = "statistics:
+  total_reads: 200
+  passing_reads: 150
+  fraction_passing: 0.75
+  whitelist_size: 10
+  estimated_cells: 8
+  num_filtered_1: 20
+  num_filtered_2: 10
+  num_filtered_3: 10
+  num_filtered_4: 10
+";
+
+    #[test]
+    fn metrics() {
+        let metrics = RunMetrics::from_log(LOG).unwrap();
+        assert_eq!(metrics.total_reads, 200);
+        assert_eq!(metrics.fraction_passing, 0.75);
+        assert_eq!(metrics.fraction_filtered, [0.1, 0.05, 0.05, 0.05]);
+        assert_eq!(metrics.estimated_cells, Some(8));
+        assert!(RunMetrics::from_log("statistics: {}").is_err());
+
+        let better = RunMetrics {
+            fraction_passing: 0.8,
+            ..metrics.clone()
+        };
+        let comparison = Comparison::new(metrics, better, None);
+        assert!((comparison.delta_fraction_passing - 0.05).abs() < 1e-12);
+    }
+
+    #[test]
+    fn whitelists() {
+        let a = read_counts_from_buffer("AAAA\t10\nCCCC\t20\nGGGG\t30\n".as_bytes()).unwrap();
+        let b = read_counts_from_buffer("AAAA\t1\nCCCC\t2\nGGGG\t3\nTTTT\t4\n".as_bytes()).unwrap();
+        let comparison = WhitelistComparison::new(&a, &b);
+        assert_eq!(comparison.shared, 3);
+        assert_eq!(comparison.jaccard, 0.75);
+        assert!((comparison.count_correlation.unwrap() - 1.0).abs() < 1e-12);
+
+        let c = read_counts_from_buffer("AAAA\nTTTT\n".as_bytes()).unwrap();
+        let comparison = WhitelistComparison::new(&a, &c);
+        assert_eq!(comparison.jaccard, 0.25);
+        assert_eq!(comparison.count_correlation, None);
+    }
+}
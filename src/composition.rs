@@ -0,0 +1,187 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+};
+
+/// The nucleotides tracked in a composition (anything else is counted as `N`)
+const BASES: [u8; 5] = [b'A', b'C', b'G', b'T', b'N'];
+
+/// The minimum fraction of the majority base for it to be reported uppercase in the consensus
+const CONSENSUS_THRESHOLD: f64 = 0.75;
+
+/// Maps a nucleotide to its index in a composition array (A, C, G, T, other)
+pub fn base_index(base: u8) -> usize {
+    match base {
+        b'A' | b'a' => 0,
+        b'C' | b'c' => 1,
+        b'G' | b'g' => 2,
+        b'T' | b't' => 3,
+        _ => 4,
+    }
+}
+
+/// Per-position nucleotide counts accumulated over many sequences
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Composition {
+    counts: Vec<[usize; 5]>,
+}
+impl Composition {
+    /// Adds the nucleotides of a sequence to the per-position counts
+    pub fn add(&mut self, seq: &[u8]) {
+        if self.counts.len() < seq.len() {
+            self.counts.resize(seq.len(), [0; 5]);
+        }
+        seq.iter()
+            .zip(self.counts.iter_mut())
+            .for_each(|(b, c)| c[base_index(*b)] += 1);
+    }
+
+    /// Adds the per-position counts of another composition
+    pub fn merge(&mut self, other: &Self) {
+        if self.counts.len() < other.counts.len() {
+            self.counts.resize(other.counts.len(), [0; 5]);
+        }
+        self.counts
+            .iter_mut()
+            .zip(&other.counts)
+            .for_each(|(c, o)| c.iter_mut().zip(o).for_each(|(c, o)| *c += o));
+    }
+
+    /// Returns the number of positions observed
+    pub fn len(&self) -> usize {
+        self.counts.len()
+    }
+
+    /// Returns true if no positions have been observed
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+
+    /// Returns the fraction of each nucleotide (A, C, G, T, N) at a position
+    pub fn fractions(&self, pos: usize) -> [f64; 5] {
+        let counts = self.counts[pos];
+        let total = counts.iter().sum::<usize>().max(1) as f64;
+        counts.map(|c| c as f64 / total)
+    }
+
+    /// Returns the Shannon entropy (in bits) of the nucleotides observed at a position
+    pub fn entropy(&self, pos: usize) -> f64 {
+        self.fractions(pos)
+            .iter()
+            .filter(|f| **f > 0.0)
+            .map(|f| -f * f.log2())
+            .sum()
+    }
+
+    /// Returns the mean per-position Shannon entropy (in bits) over all positions
+    pub fn mean_entropy(&self) -> f64 {
+        if self.is_empty() {
+            return 0.0;
+        }
+        (0..self.len()).map(|pos| self.entropy(pos)).sum::<f64>() / self.len() as f64
+    }
+
+    /// Builds a compact consensus string of the majority base at each position.
+    ///
+    /// Positions dominated by a single nucleotide are uppercase and mixed positions are lowercase.
+    pub fn consensus(&self) -> String {
+        (0..self.len())
+            .map(|pos| {
+                let fractions = self.fractions(pos);
+                let (idx, max) = fractions.iter().enumerate().fold((4, 0.0), |acc, (i, f)| {
+                    if *f > acc.1 {
+                        (i, *f)
+                    } else {
+                        acc
+                    }
+                });
+                let base = BASES[idx] as char;
+                if max >= CONSENSUS_THRESHOLD {
+                    base
+                } else {
+                    base.to_ascii_lowercase()
+                }
+            })
+            .collect()
+    }
+
+    /// Writes the per-position nucleotide fractions as a TSV
+    pub fn to_file(&self, path: &str) -> Result<()> {
+        let mut writer = File::create(path).map(BufWriter::new)?;
+        writeln!(writer, "position\tA\tC\tG\tT\tN")?;
+        for pos in 0..self.len() {
+            let [a, c, g, t, n] = self.fractions(pos);
+            writeln!(
+                writer,
+                "{}\t{:.4}\t{:.4}\t{:.4}\t{:.4}\t{:.4}",
+                pos, a, c, g, t, n
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn accumulate() {
+        let mut composition = Composition::default();
+        composition.add(b"ACGT");
+        composition.add(b"ACG");
+        composition.add(b"ATGTN");
+        assert_eq!(composition.len(), 5);
+        assert_eq!(composition.fractions(0), [1.0, 0.0, 0.0, 0.0, 0.0]);
+        assert_eq!(composition.fractions(3), [0.0, 0.0, 0.0, 1.0, 0.0]);
+        assert_eq!(composition.fractions(4), [0.0, 0.0, 0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn merge() {
+        let mut composition = Composition::default();
+        composition.add(b"AC");
+        let mut other = Composition::default();
+        other.add(b"ACG");
+        other.add(b"TCG");
+        composition.merge(&other);
+        assert_eq!(composition.len(), 3);
+        assert_eq!(
+            composition.fractions(0),
+            [2.0 / 3.0, 0.0, 0.0, 1.0 / 3.0, 0.0]
+        );
+        assert_eq!(composition.fractions(2), [0.0, 0.0, 1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn consensus() {
+        let mut composition = Composition::default();
+        composition.add(b"ACGT");
+        composition.add(b"ACGA");
+        composition.add(b"ACTC");
+        composition.add(b"ATTG");
+        assert_eq!(composition.consensus(), "ACga");
+    }
+
+    #[test]
+    fn entropy() {
+        let mut composition = Composition::default();
+        composition.add(b"AA");
+        composition.add(b"CA");
+        composition.add(b"GA");
+        composition.add(b"TA");
+        assert_eq!(composition.entropy(0), 2.0);
+        assert_eq!(composition.entropy(1), 0.0);
+        assert_eq!(composition.mean_entropy(), 1.0);
+    }
+
+    #[test]
+    fn empty() {
+        let composition = Composition::default();
+        assert_eq!(composition.len(), 0);
+        assert_eq!(composition.consensus(), "");
+        assert_eq!(composition.mean_entropy(), 0.0);
+    }
+}
@@ -1,11 +1,35 @@
-use crate::barcodes::{Barcodes, Spacer};
-use anyhow::Result;
-use serde::Deserialize;
+use crate::{
+    barcodes::{mismatches, Ambiguity, Barcodes, Spacer},
+    chip::Chip,
+    schema,
+    structure::{Element, ReadStructure},
+};
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use hashbrown::HashMap;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufRead, BufReader},
+    sync::Arc,
+};
 
 #[derive(Debug, Deserialize)]
 pub struct ConfigYaml {
     barcodes: ConfigBarcodes,
-    spacers: ConfigSpacers,
+    #[serde(default)]
+    spacers: HashMap<String, String>,
+    structure: Option<String>,
+    #[serde(default)]
+    libraries: Vec<Library>,
+    /// The barcodes expected for the kit lot (valid tier combinations as emitted barcodes)
+    expected_whitelist: Option<String>,
+    /// The scale of the chip the library was prepared on
+    chip: Option<Chip>,
+    /// Append the bases of the random (`N`) positions of spacers to the UMI
+    #[serde(default)]
+    capture_random_spacers: bool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -16,53 +40,900 @@ pub struct ConfigBarcodes {
     bc4: String,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct ConfigSpacers {
-    s1: String,
-    s2: String,
-    s3: String,
+/// A library (e.g. GEX, ADT or HTO) of a multi-modal run sharing the cell barcodes of the
+/// config
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct Library {
+    /// The name of the library, appended to the output prefix
+    pub name: String,
+    /// The type of the library (e.g. `Gene Expression` or `Antibody Capture`)
+    #[serde(rename = "type", default)]
+    pub library_type: Option<String>,
+    /// The input file(s) for R1
+    #[serde(deserialize_with = "one_or_many")]
+    pub r1: Vec<String>,
+    /// The input file(s) for R2
+    #[serde(deserialize_with = "one_or_many")]
+    pub r2: Vec<String>,
+    /// A feature reference matched in the R2 of this library
+    #[serde(default)]
+    pub features: Option<String>,
+}
+
+/// Accepts either a single path or a list of paths
+fn one_or_many<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(path) => vec![path],
+        OneOrMany::Many(paths) => paths,
+    })
+}
+
+/// A single matchable segment of the read structure
+#[derive(Debug)]
+enum Segment {
+    /// A barcode tier (including any trailing spacers)
+    Tier(usize),
+    /// A fixed sequence not directly following a barcode
+    Fixed(Vec<u8>),
+    /// The UMI with an optional explicit length
+    Umi(Option<usize>),
+    /// The random (`N`) positions of a spacer, matched as wildcards and optionally
+    /// captured into the UMI
+    Random { len: usize, capture: bool },
+}
+impl Segment {
+    fn is_anchor(&self) -> bool {
+        !matches!(self, Self::Umi(_) | Self::Random { .. })
+    }
+
+    /// Returns the length of a UMI or random segment
+    fn random_len(&self, umi_len: usize) -> usize {
+        match self {
+            Self::Umi(len) => len.unwrap_or(umi_len),
+            Self::Random { len, .. } => *len,
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// How the one-mismatch children of a barcode tier are matched
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Expansion {
+    /// Only exact matches are accepted
+    Exact,
+    /// All children are expanded into the index at load time
+    Eager,
+    /// Children are generated on demand when an exact lookup fails
+    Lazy,
+}
+
+/// How the barcodes of each tier are matched.
+///
+/// Both matchers share the per-tier barcode index; `Levenshtein` only adds a lookup in a
+/// `DeletionIndex` when the Hamming match fails, rather than a Levenshtein automaton.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Matcher {
+    /// Exact or (unless exact matching is requested) one-substitution matches
+    #[default]
+    Hamming,
+    /// Additionally tolerates a single insertion or deletion within a barcode and its
+    /// linker (through its one-deletion variants) when no Hamming match is found
+    Levenshtein,
+}
+
+/// The one-deletion variants of the barcodes of a tier (with their linker), looked up when
+/// a read is missing a nucleotide of its barcode. Variants shared by several barcodes are
+/// ambiguous (`None`).
+///
+/// Only `n * len` variants are held, rather than the automaton of every sequence within
+/// one edit of a barcode, and insertions are found by deleting each nucleotide of the read
+/// instead.
+#[derive(Debug)]
+struct DeletionIndex(HashMap<Vec<u8>, Option<usize>>);
+impl DeletionIndex {
+    fn new(barcodes: &Barcodes) -> Self {
+        let mut variants: HashMap<Vec<u8>, Option<usize>> = HashMap::new();
+        for idx in 0..barcodes.num_barcodes() {
+            let Some(barcode) = barcodes.get_barcode(idx, true) else {
+                continue;
+            };
+            for pos in 0..barcode.len() {
+                let variant = [&barcode[..pos], &barcode[pos + 1..]].concat();
+                variants
+                    .entry(variant)
+                    .and_modify(|parent| {
+                        if *parent != Some(idx) {
+                            *parent = None;
+                        }
+                    })
+                    .or_insert(Some(idx));
+            }
+        }
+        Self(variants)
+    }
+}
+
+/// Returns the edit distance of a pattern to its best-matching prefix of a sequence and
+/// the length of that prefix (preferring the length closest to that of the pattern)
+fn prefix_distance(pattern: &[u8], seq: &[u8]) -> (usize, usize) {
+    let mut prev = (0..=seq.len()).collect::<Vec<_>>();
+    let mut curr = vec![0; seq.len() + 1];
+    for (i, p) in pattern.iter().enumerate() {
+        curr[0] = i + 1;
+        for (j, s) in seq.iter().enumerate() {
+            let substitution = prev[j] + usize::from(p != s);
+            curr[j + 1] = substitution.min(prev[j + 1] + 1).min(curr[j] + 1);
+        }
+        std::mem::swap(&mut prev, &mut curr);
+    }
+    prev.iter()
+        .enumerate()
+        .map(|(len, distance)| (*distance, len.abs_diff(pattern.len()), len))
+        .min()
+        .map(|(distance, _, len)| (distance, len))
+        .expect("Distances are never empty")
+}
+
+/// The reason a read failed to match the read structure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filtered {
+    /// A barcode tier could not be matched
+    Tier(usize),
+    /// A fixed sequence could not be matched
+    Fixed,
+    /// The read was too short to contain the UMI
+    Umi,
+}
+
+/// The maximum number of barcodes of a tier (so its indices fit a [`BarcodeKey`])
+pub const MAX_TIER_BARCODES: usize = 1 << 16;
+
+/// The within-set barcode indices of the 4 tiers of a barcode packed into a single
+/// integer, which identifies a barcode in a few bytes (the sequence is rebuilt with
+/// [`Config::build_barcode_into`] when written out)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BarcodeKey(u64);
+impl BarcodeKey {
+    pub fn new(indices: [usize; 4]) -> Self {
+        Self(
+            indices
+                .iter()
+                .fold(0, |key, idx| (key << 16) | (*idx as u64 & 0xffff)),
+        )
+    }
+
+    /// Returns the within-set barcode indices of the 4 tiers
+    pub fn indices(&self) -> [usize; 4] {
+        [48, 32, 16, 0].map(|shift| ((self.0 >> shift) & 0xffff) as usize)
+    }
+}
+
+/// The barcode indices and UMI of a read matching the read structure
+#[derive(Debug, Default)]
+pub struct ReadMatch {
+    /// The within-set barcode index for each of the 4 tiers
+    pub indices: [usize; 4],
+    /// The UMI sequence
+    pub umi: Vec<u8>,
+    /// The position of the first nucleotide after the read structure
+    pub end: usize,
+    /// The shift of the first anchoring segment from its expected position
+    pub shift: usize,
+    /// Whether each barcode tier was matched with a mismatch
+    pub corrected: [bool; 4],
+    /// The start and end positions of the matched barcode (with its linker) of each tier
+    pub ranges: [(usize, usize); 4],
+    /// The start and end positions of each UMI segment (in the order of the UMI)
+    pub umi_ranges: Vec<(usize, usize)>,
+}
+
+/// A barcode within the matching distance of a sequence of another tier, which could
+/// assign a read to the wrong barcode if the tiers are read out of place
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Collision {
+    /// The tier of the barcode (1-indexed)
+    pub tier: usize,
+    /// The barcode (with its linker)
+    pub barcode: String,
+    /// The tier of the colliding sequence (1-indexed)
+    pub other_tier: usize,
+    /// The colliding sequence: a barcode of the other tier (with its linker) or one
+    /// prefixed by the spacer preceding it, truncated to the length of the barcode
+    pub other: String,
 }
 
 pub struct Config {
-    bc1: Barcodes,
-    bc2: Barcodes,
-    bc3: Barcodes,
-    bc4: Barcodes,
+    bc1: Arc<Barcodes>,
+    bc2: Arc<Barcodes>,
+    bc3: Arc<Barcodes>,
+    bc4: Arc<Barcodes>,
     linkers: bool,
+    structure: ReadStructure,
+    segments: Vec<Segment>,
+    paths: [String; 4],
+    libraries: Vec<Library>,
+    expected_whitelist: Option<String>,
+    chip: Option<Chip>,
+    collisions: Vec<Collision>,
+    ambiguity: BTreeMap<String, Ambiguity>,
+    /// The deletion variants of each tier if single indels are tolerated
+    deletions: Option<Vec<DeletionIndex>>,
 }
 impl Config {
     pub fn from_file(path: &str, exact: bool, linkers: bool) -> Result<Self> {
-        let contents = std::fs::read_to_string(path)?;
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config: {}", path))?;
+        schema::validate(path, &contents)?;
         let yaml = serde_yaml::from_str::<ConfigYaml>(&contents)?;
         Self::from_yaml(yaml, exact, linkers)
     }
 
     pub fn from_yaml(yaml: ConfigYaml, exact: bool, linkers: bool) -> Result<Self> {
-        let spacer1 = Spacer::from_str(&yaml.spacers.s1);
-        let spacer2 = Spacer::from_str(&yaml.spacers.s2);
-        let spacer3 = Spacer::from_str(&yaml.spacers.s3);
-        let bc1 = Self::load_barcode(&yaml.barcodes.bc1, Some(&spacer1), exact)?;
-        let bc2 = Self::load_barcode(&yaml.barcodes.bc2, Some(&spacer2), exact)?;
-        let bc3 = Self::load_barcode(&yaml.barcodes.bc3, Some(&spacer3), exact)?;
-        let bc4 = Self::load_barcode(&yaml.barcodes.bc4, None, exact)?;
-        Ok(Self {
+        Self::build(yaml, exact, linkers, &|path| {
+            Ok(Box::new(BufReader::new(File::open(path)?)))
+        })
+    }
+
+    /// Builds a config from the contents of a config file and its barcode files without
+    /// touching the filesystem.
+    ///
+    /// The barcode files are looked up by the paths given in the config, which makes this
+    /// usable from environments without file I/O (e.g. a browser).
+    pub fn from_buffers(
+        contents: &str,
+        barcodes: &HashMap<String, Vec<u8>>,
+        exact: bool,
+        linkers: bool,
+    ) -> Result<Self> {
+        let yaml = serde_yaml::from_str::<ConfigYaml>(contents)?;
+        Self::build(yaml, exact, linkers, &|path| match barcodes.get(path) {
+            Some(buffer) => Ok(Box::new(buffer.as_slice())),
+            None => bail!("No buffer given for barcode file: {}", path),
+        })
+    }
+
+    /// Builds a config, opening each barcode file with `open`
+    fn build<'a>(
+        yaml: ConfigYaml,
+        exact: bool,
+        linkers: bool,
+        open: &dyn Fn(&str) -> Result<Box<dyn BufRead + 'a>>,
+    ) -> Result<Self> {
+        let structure = match &yaml.structure {
+            Some(s) => s.parse::<ReadStructure>()?,
+            None => ReadStructure::default(),
+        };
+        let (segments, suffixes) =
+            Self::build_segments(&structure, &yaml.spacers, yaml.capture_random_spacers)?;
+        // only the anchoring tier is searched in a window, so the remaining tiers
+        // expand their one-mismatch children lazily to save memory
+        let anchor = segments.iter().find(|s| s.is_anchor());
+        let expansion = |tier: usize| match anchor {
+            _ if exact => Expansion::Exact,
+            Some(Segment::Tier(t)) if *t == tier => Expansion::Eager,
+            _ => Expansion::Lazy,
+        };
+        let mut cache = HashMap::new();
+        let paths = [
+            &yaml.barcodes.bc1,
+            &yaml.barcodes.bc2,
+            &yaml.barcodes.bc3,
+            &yaml.barcodes.bc4,
+        ];
+        let [bc1, bc2, bc3, bc4] = [0, 1, 2, 3].map(|tier| {
+            Self::load_barcode(
+                paths[tier],
+                &suffixes[tier],
+                expansion(tier),
+                &mut cache,
+                open,
+            )
+        });
+        let [bc1, bc2, bc3, bc4] = [bc1?, bc2?, bc3?, bc4?];
+        for (tier, barcodes) in [&bc1, &bc2, &bc3, &bc4].into_iter().enumerate() {
+            if barcodes.num_barcodes() > MAX_TIER_BARCODES {
+                bail!(
+                    "Barcode tier {} has {} barcodes but at most {} are supported",
+                    tier + 1,
+                    barcodes.num_barcodes(),
+                    MAX_TIER_BARCODES
+                );
+            }
+        }
+        let mut config = Self {
             bc1,
             bc2,
             bc3,
             bc4,
             linkers,
-        })
+            structure,
+            segments,
+            paths: paths.map(|p| p.to_string()),
+            libraries: yaml.libraries,
+            expected_whitelist: yaml.expected_whitelist,
+            chip: yaml.chip,
+            collisions: Vec::new(),
+            ambiguity: BTreeMap::new(),
+            deletions: None,
+        };
+        config.collisions = config.find_collisions(if exact { 0 } else { 1 });
+        if !exact {
+            config.ambiguity = (0..4)
+                .map(|tier| (format!("bc{}", tier + 1), config.tier(tier).ambiguity()))
+                .collect();
+        }
+        Ok(config)
     }
 
-    fn load_barcode(path: &str, spacer: Option<&Spacer>, exact: bool) -> Result<Barcodes> {
-        if let Some(spacer) = spacer {
-            Barcodes::from_file_with_spacer(path, spacer, exact)
+    /// Selects how the barcodes of each tier are matched
+    pub fn with_matcher(mut self, matcher: Matcher) -> Self {
+        self.deletions = match matcher {
+            Matcher::Hamming => None,
+            Matcher::Levenshtein => Some(
+                (0..4)
+                    .map(|tier| DeletionIndex::new(self.tier(tier)))
+                    .collect(),
+            ),
+        };
+        self
+    }
+
+    /// Returns how the barcodes of each tier are matched
+    pub fn matcher(&self) -> Matcher {
+        if self.deletions.is_some() {
+            Matcher::Levenshtein
         } else {
-            Barcodes::from_file(path, exact)
+            Matcher::Hamming
+        }
+    }
+
+    /// Finds the barcodes within `distance` substitutions of a sequence of another tier.
+    ///
+    /// Each barcode (with its linker) is compared to the barcodes of the other tiers of the
+    /// same length and to the barcodes of the other tiers prefixed by the spacer preceding
+    /// them. Tiers sharing their barcodes (the same file and linker) are not compared.
+    fn find_collisions(&self, distance: usize) -> Vec<Collision> {
+        let patterns = |tier: usize| {
+            let bc = self.tier(tier);
+            (0..bc.num_barcodes()).filter_map(move |idx| bc.get_barcode(idx, true))
+        };
+        let mut collisions = Vec::new();
+        for tier in 0..4 {
+            let len = self.tier_match_len(tier);
+            let mut others = HashMap::new();
+            let shared = |other: &usize| {
+                self.paths[*other] == self.paths[tier]
+                    && self.tier_linker(*other) == self.tier_linker(tier)
+            };
+            for other in (0..4).filter(|t| *t != tier && !shared(t)) {
+                // direct collisions are symmetric so each pair of tiers is reported once
+                if other > tier && self.tier_match_len(other) == len {
+                    for pattern in patterns(other) {
+                        others.entry(pattern.to_vec()).or_insert(other);
+                    }
+                }
+                let spacer = self.preceding_spacer(other);
+                if spacer.is_empty() || spacer.len() + self.tier_match_len(other) < len {
+                    continue;
+                }
+                for pattern in patterns(other) {
+                    let prefixed = [spacer, pattern].concat();
+                    others.entry(prefixed[..len].to_vec()).or_insert(other);
+                }
+            }
+            if others.is_empty() {
+                continue;
+            }
+            for barcode in patterns(tier) {
+                let mut neighbors = vec![barcode.to_vec()];
+                if distance > 0 {
+                    neighbors.extend(mismatches(barcode));
+                }
+                for neighbor in neighbors {
+                    if let Some(other) = others.get(&neighbor) {
+                        collisions.push(Collision {
+                            tier: tier + 1,
+                            barcode: String::from_utf8_lossy(barcode).to_string(),
+                            other_tier: other + 1,
+                            other: String::from_utf8_lossy(&neighbor).to_string(),
+                        });
+                    }
+                }
+            }
+        }
+        collisions
+    }
+
+    /// Returns the fixed sequence directly preceding a tier in the read structure
+    fn preceding_spacer(&self, tier: usize) -> &[u8] {
+        let Some(pos) = self
+            .segments
+            .iter()
+            .position(|s| matches!(s, Segment::Tier(t) if *t == tier))
+        else {
+            return &[];
+        };
+        match pos.checked_sub(1).map(|p| &self.segments[p]) {
+            Some(Segment::Tier(previous)) => self.tier_linker(*previous),
+            Some(Segment::Fixed(fixed)) => fixed,
+            _ => &[],
+        }
+    }
+
+    /// Returns the barcodes within the matching distance of a sequence of another tier
+    pub fn collisions(&self) -> &[Collision] {
+        &self.collisions
+    }
+
+    /// Returns the one-mismatch neighbors lost to ambiguity by each tier (`bc1`-`bc4`),
+    /// which is empty with exact matching
+    pub fn ambiguity(&self) -> &BTreeMap<String, Ambiguity> {
+        &self.ambiguity
+    }
+
+    /// Collapses the read structure into matchable segments.
+    ///
+    /// Spacers and fixed sequences directly following a barcode are appended to
+    /// that barcode tier (returned as the per-tier suffixes) so they are matched jointly.
+    /// Their random (`N`) positions are split off into random segments, so only the
+    /// nucleotides preceding the first random position join the barcode.
+    fn build_segments(
+        structure: &ReadStructure,
+        spacers: &HashMap<String, String>,
+        capture_random: bool,
+    ) -> Result<(Vec<Segment>, [String; 4])> {
+        let mut segments = Vec::new();
+        let mut suffixes: [String; 4] = Default::default();
+        for element in structure.elements() {
+            let fixed = match element {
+                Element::Barcode(tier) => {
+                    segments.push(Segment::Tier(*tier));
+                    continue;
+                }
+                Element::Umi(len) => {
+                    segments.push(Segment::Umi(*len));
+                    continue;
+                }
+                Element::Spacer(name) => match spacers.get(name) {
+                    Some(seq) => seq.as_str(),
+                    None => bail!(
+                        "Spacer '{}' in read structure is not defined in config",
+                        name
+                    ),
+                },
+                Element::Fixed(seq) => seq.as_str(),
+            };
+            let runs = fixed
+                .as_bytes()
+                .chunk_by(|a, b| (*a == b'N') == (*b == b'N'));
+            for run in runs {
+                if run[0] == b'N' {
+                    match segments.last_mut() {
+                        Some(Segment::Random { len, .. }) => *len += run.len(),
+                        _ => segments.push(Segment::Random {
+                            len: run.len(),
+                            capture: capture_random,
+                        }),
+                    }
+                    continue;
+                }
+                match segments.last_mut() {
+                    Some(Segment::Tier(tier)) => {
+                        suffixes[*tier].push_str(std::str::from_utf8(run)?)
+                    }
+                    Some(Segment::Fixed(seq)) => seq.extend_from_slice(run),
+                    _ => segments.push(Segment::Fixed(run.to_vec())),
+                }
+            }
         }
+        Ok((segments, suffixes))
+    }
+
+    /// Loads the barcodes of a tier, sharing the index between tiers which reference
+    /// the same file with the same suffix
+    fn load_barcode<'a>(
+        path: &str,
+        suffix: &str,
+        expansion: Expansion,
+        cache: &mut HashMap<(String, String, Expansion), Arc<Barcodes>>,
+        open: &dyn Fn(&str) -> Result<Box<dyn BufRead + 'a>>,
+    ) -> Result<Arc<Barcodes>> {
+        let key = (path.to_string(), suffix.to_string(), expansion);
+        if let Some(barcodes) = cache.get(&key) {
+            return Ok(barcodes.clone());
+        }
+        let exact = expansion != Expansion::Eager;
+        let barcodes = if suffix.is_empty() {
+            Barcodes::from_buffer(open(path)?, exact)?
+        } else {
+            Barcodes::from_buffer_with_spacer(open(path)?, &Spacer::from_str(suffix), exact)?
+        };
+        let barcodes = match expansion {
+            Expansion::Lazy => barcodes.with_lazy_mismatches(),
+            _ => barcodes,
+        };
+        let barcodes = Arc::new(barcodes);
+        cache.insert(key, barcodes.clone());
+        Ok(barcodes)
+    }
+
+    /// Returns the read structure used for matching
+    pub fn structure(&self) -> &ReadStructure {
+        &self.structure
+    }
+
+    /// Returns the libraries of a multi-modal run defined in the config
+    pub fn libraries(&self) -> &[Library] {
+        &self.libraries
+    }
+
+    /// Returns the path of the whitelist of barcodes expected for the kit lot, if given
+    pub fn expected_whitelist(&self) -> Option<&str> {
+        self.expected_whitelist.as_deref()
+    }
+
+    /// Returns the scale of the chip the library was prepared on, if given
+    pub fn chip(&self) -> Option<Chip> {
+        self.chip
+    }
+
+    fn tier(&self, set_idx: usize) -> &Barcodes {
+        match set_idx {
+            0 => &self.bc1,
+            1 => &self.bc2,
+            2 => &self.bc3,
+            3 => &self.bc4,
+            _ => panic!("Invalid set index: {}", set_idx),
+        }
+    }
+
+    /// Matches a read against the read structure.
+    ///
+    /// The first non-UMI segment is searched for within `offset` nucleotides of its
+    /// expected position (or exactly at it if no offset is given) and all following
+    /// segments are expected directly adjacent.
+    /// Any UMI preceding the first anchoring segment is taken relative to its match.
+    pub fn match_read(
+        &self,
+        seq: &[u8],
+        offset: Option<usize>,
+        umi_len: usize,
+    ) -> std::result::Result<ReadMatch, Filtered> {
+        let mut read_match = ReadMatch {
+            umi: Vec::with_capacity(umi_len),
+            ..ReadMatch::default()
+        };
+        self.match_read_into(seq, offset, umi_len, &mut read_match)?;
+        Ok(read_match)
+    }
+
+    /// Matches a read against the read structure as [`Config::match_read`], reusing the
+    /// UMI buffer of a previous match
+    pub fn match_read_into(
+        &self,
+        seq: &[u8],
+        offset: Option<usize>,
+        umi_len: usize,
+        read_match: &mut ReadMatch,
+    ) -> std::result::Result<(), Filtered> {
+        let mut indices = [0; 4];
+        let mut corrected = [false; 4];
+        let mut ranges = [(0, 0); 4];
+        let umi = &mut read_match.umi;
+        let umi_ranges = &mut read_match.umi_ranges;
+        umi.clear();
+        umi_ranges.clear();
+        let anchor = self
+            .segments
+            .iter()
+            .position(|s| s.is_anchor())
+            .expect("Read structure contains no barcodes");
+        let lead = self.segments[..anchor]
+            .iter()
+            .map(|s| s.random_len(umi_len))
+            .sum::<usize>();
+
+        let (start, mut pos) = self.match_segment(
+            seq,
+            &self.segments[anchor],
+            lead,
+            offset,
+            &mut indices,
+            &mut corrected,
+        )?;
+        Self::record_range(&self.segments[anchor], (start, pos), &mut ranges);
+        let mut lead_pos = start - lead;
+        for segment in &self.segments[..anchor] {
+            lead_pos = Self::extract_umi(seq, segment, lead_pos, umi_len, umi, umi_ranges)?;
+        }
+        for segment in &self.segments[anchor + 1..] {
+            let range = match segment {
+                Segment::Umi(_) | Segment::Random { .. } => (
+                    pos,
+                    Self::extract_umi(seq, segment, pos, umi_len, umi, umi_ranges)?,
+                ),
+                _ => self.match_segment(seq, segment, pos, None, &mut indices, &mut corrected)?,
+            };
+            Self::record_range(segment, range, &mut ranges);
+            pos = range.1;
+        }
+        read_match.indices = indices;
+        read_match.end = pos;
+        read_match.shift = start - lead;
+        read_match.corrected = corrected;
+        read_match.ranges = ranges;
+        Ok(())
+    }
+
+    /// Matches a read against the read structure tolerating up to `max_distance` edits
+    /// (substitutions, insertions or deletions) within each barcode and fixed sequence, as
+    /// needed for long error-prone reads.
+    ///
+    /// The first anchoring segment is searched for within the first `window` nucleotides
+    /// following any leading UMI and each following segment within `max_distance`
+    /// nucleotides of where the previous one ended. A segment matched equally well by
+    /// several barcodes is not resolved.
+    pub fn match_read_edit(
+        &self,
+        seq: &[u8],
+        window: usize,
+        max_distance: usize,
+        umi_len: usize,
+    ) -> std::result::Result<ReadMatch, Filtered> {
+        let mut indices = [0; 4];
+        let mut corrected = [false; 4];
+        let mut ranges = [(0, 0); 4];
+        let mut umi = Vec::with_capacity(umi_len);
+        let mut umi_ranges = Vec::new();
+        let anchor = self
+            .segments
+            .iter()
+            .position(|s| s.is_anchor())
+            .expect("Read structure contains no barcodes");
+        let lead = self.segments[..anchor]
+            .iter()
+            .map(|s| s.random_len(umi_len))
+            .sum::<usize>();
+
+        let (start, mut pos) = self.match_segment_edit(
+            seq,
+            &self.segments[anchor],
+            (lead, lead, lead + window),
+            max_distance,
+            &mut indices,
+            &mut corrected,
+        )?;
+        Self::record_range(&self.segments[anchor], (start, pos), &mut ranges);
+        let mut lead_pos = start - lead;
+        for segment in &self.segments[..anchor] {
+            lead_pos =
+                Self::extract_umi(seq, segment, lead_pos, umi_len, &mut umi, &mut umi_ranges)?;
+        }
+        for segment in &self.segments[anchor + 1..] {
+            let range = match segment {
+                Segment::Umi(_) | Segment::Random { .. } => (
+                    pos,
+                    Self::extract_umi(seq, segment, pos, umi_len, &mut umi, &mut umi_ranges)?,
+                ),
+                _ => {
+                    let range = (pos, pos.saturating_sub(max_distance), pos + max_distance);
+                    self.match_segment_edit(
+                        seq,
+                        segment,
+                        range,
+                        max_distance,
+                        &mut indices,
+                        &mut corrected,
+                    )?
+                }
+            };
+            Self::record_range(segment, range, &mut ranges);
+            pos = range.1;
+        }
+        Ok(ReadMatch {
+            indices,
+            umi,
+            end: pos,
+            shift: start - lead,
+            corrected,
+            ranges,
+            umi_ranges,
+        })
+    }
+
+    /// Matches a barcode tier or fixed segment within `max_distance` edits starting
+    /// between `lo` and `hi`, preferring fewer edits and then starts closer to `expected`.
+    /// Returns the start and end nucleotide positions of the match
+    fn match_segment_edit(
+        &self,
+        seq: &[u8],
+        segment: &Segment,
+        (expected, lo, hi): (usize, usize, usize),
+        max_distance: usize,
+        indices: &mut [usize; 4],
+        corrected: &mut [bool; 4],
+    ) -> std::result::Result<(usize, usize), Filtered> {
+        let (candidates, filtered) = match segment {
+            Segment::Tier(tier) => {
+                let bc = self.tier(*tier);
+                let candidates = (0..bc.num_barcodes())
+                    .filter_map(|idx| bc.get_barcode(idx, true).map(|barcode| (idx, barcode)))
+                    .collect::<Vec<_>>();
+                (candidates, Filtered::Tier(*tier))
+            }
+            Segment::Fixed(fixed) => (vec![(0, fixed.as_slice())], Filtered::Fixed),
+            Segment::Umi(_) | Segment::Random { .. } => unreachable!(),
+        };
+        // the (distance, deviation) of the best match alongside its index, start and end
+        let mut best: Option<((usize, usize), usize, usize, usize)> = None;
+        let mut ambiguous = false;
+        for start in lo..=hi.min(seq.len()) {
+            let deviation = start.abs_diff(expected);
+            if best.is_some_and(|((distance, dev), ..)| distance == 0 && deviation > dev) {
+                break;
+            }
+            for (idx, pattern) in &candidates {
+                let stop = (start + pattern.len() + max_distance).min(seq.len());
+                let (distance, len) = prefix_distance(pattern, &seq[start..stop]);
+                if distance > max_distance {
+                    continue;
+                }
+                let key = (distance, deviation);
+                match best {
+                    Some((best_key, ..)) if key > best_key => {}
+                    Some((best_key, best_idx, ..)) if key == best_key => {
+                        ambiguous |= best_idx != *idx;
+                    }
+                    _ => {
+                        best = Some((key, *idx, start, start + len));
+                        ambiguous = false;
+                    }
+                }
+            }
+        }
+        match best {
+            Some(((distance, _), idx, start, end)) if !ambiguous => {
+                if let Segment::Tier(tier) = segment {
+                    indices[*tier] = idx;
+                    corrected[*tier] = distance > 0;
+                }
+                Ok((start, end))
+            }
+            _ => Err(filtered),
+        }
+    }
+
+    /// Matches a barcode tier or fixed segment at `pos` (optionally within an offset window).
+    /// Returns the start and end nucleotide positions of the match
+    fn match_segment(
+        &self,
+        seq: &[u8],
+        segment: &Segment,
+        pos: usize,
+        offset: Option<usize>,
+        indices: &mut [usize; 4],
+        corrected: &mut [bool; 4],
+    ) -> std::result::Result<(usize, usize), Filtered> {
+        match segment {
+            Segment::Tier(tier) => {
+                let bc = self.tier(*tier);
+                let (start, end, idx) = match self.match_subsequence(seq, *tier, pos, offset) {
+                    Some((end, idx)) => (pos + end - bc.len(), pos + end, idx),
+                    None => (pos..=pos + offset.unwrap_or(0))
+                        .find_map(|start| {
+                            self.match_indel(seq, *tier, start)
+                                .map(|(end, idx)| (start, end, idx))
+                        })
+                        .ok_or(Filtered::Tier(*tier))?,
+                };
+                indices[*tier] = idx;
+                corrected[*tier] = bc.get_barcode(idx, true) != Some(&seq[start..end]);
+                Ok((start, end))
+            }
+            Segment::Fixed(fixed) => {
+                let end = (pos + fixed.len() + offset.unwrap_or(0)).min(seq.len());
+                if pos > end {
+                    return Err(Filtered::Fixed);
+                }
+                seq[pos..end]
+                    .windows(fixed.len())
+                    .position(|window| window == fixed.as_slice())
+                    .map(|start| (pos + start, pos + start + fixed.len()))
+                    .ok_or(Filtered::Fixed)
+            }
+            Segment::Umi(_) | Segment::Random { .. } => unreachable!(),
+        }
+    }
+
+    /// Matches a barcode of a tier with a single insertion or deletion starting exactly at
+    /// `start` (if indels are tolerated).
+    /// Returns the end nucleotide position of the match and the within-set barcode index,
+    /// unless the edits disagree on the barcode or its end
+    fn match_indel(&self, seq: &[u8], tier: usize, start: usize) -> Option<(usize, usize)> {
+        let deletions = &self.deletions.as_ref()?[tier];
+        let bc = self.tier(tier);
+        let len = bc.len();
+        let mut found = None;
+        // a nucleotide of the barcode is missing from the read
+        if let Some(window) = seq.get(start..start + len - 1) {
+            match deletions.0.get(window) {
+                Some(Some(idx)) => found = Some((start + len - 1, *idx)),
+                Some(None) => return None,
+                None => {}
+            }
+        }
+        // the read has an extra nucleotide within the barcode
+        if let Some(window) = seq.get(start..start + len + 1) {
+            for pos in 0..window.len() {
+                let candidate = [&window[..pos], &window[pos + 1..]].concat();
+                let Some(idx) = bc
+                    .get_id(&candidate)
+                    .filter(|idx| bc.get_barcode(*idx, true) == Some(candidate.as_slice()))
+                else {
+                    continue;
+                };
+                match found {
+                    Some(other) if other != (start + len + 1, idx) => return None,
+                    _ => found = Some((start + len + 1, idx)),
+                }
+            }
+        }
+        found
+    }
+
+    /// Returns true if a tier 1 barcode (with its linker) occurs exactly downstream of the
+    /// tier 1 barcode of a match, the signature of a ligation chimera
+    pub fn is_chimeric(&self, seq: &[u8], read_match: &ReadMatch) -> bool {
+        let bc = self.tier(0);
+        seq.get(read_match.ranges[0].1..)
+            .unwrap_or_default()
+            .windows(bc.len())
+            .any(|window| {
+                bc.get_id(window)
+                    .is_some_and(|idx| bc.get_barcode(idx, true) == Some(window))
+            })
+    }
+
+    /// Records the start and end positions at which a barcode tier was matched
+    fn record_range(segment: &Segment, range: (usize, usize), ranges: &mut [(usize, usize); 4]) {
+        if let Segment::Tier(tier) = segment {
+            ranges[*tier] = range;
+        }
+    }
+
+    /// Extracts the UMI (or skips the random spacer) at `pos` and returns the position
+    /// following it. Captured random spacers are appended to the UMI.
+    fn extract_umi(
+        seq: &[u8],
+        segment: &Segment,
+        pos: usize,
+        umi_len: usize,
+        umi: &mut Vec<u8>,
+        umi_ranges: &mut Vec<(usize, usize)>,
+    ) -> std::result::Result<usize, Filtered> {
+        let (len, captured, filtered) = match segment {
+            Segment::Umi(len) => (len.unwrap_or(umi_len), true, Filtered::Umi),
+            Segment::Random { len, capture } => (*len, *capture, Filtered::Fixed),
+            _ => unreachable!(),
+        };
+        if seq.len() < pos + len {
+            return Err(filtered);
+        }
+        if captured {
+            umi.extend_from_slice(&seq[pos..pos + len]);
+            umi_ranges.push((pos, pos + len));
+        }
+        Ok(pos + len)
     }
 
     /// Matches a subsequence starting from `pos` against one of the barcode sets.
+    /// If no offset is given the barcode is looked up directly at `pos`.
     /// Returns the end nucleotide position of the match and the within-set barcode index
     pub fn match_subsequence(
         &self,
@@ -71,17 +942,11 @@ impl Config {
         pos: usize,
         offset: Option<usize>,
     ) -> Option<(usize, usize)> {
-        let bc = match set_idx {
-            0 => &self.bc1,
-            1 => &self.bc2,
-            2 => &self.bc3,
-            3 => &self.bc4,
-            _ => panic!("Invalid set index: {}", set_idx),
-        };
+        let bc = self.tier(set_idx);
         if let Some(off) = offset {
             bc.match_subsequence(seq, pos, pos + bc.len() + off)
         } else {
-            bc.match_subsequence(seq, pos, pos + bc.len())
+            bc.match_at(seq, pos)
         }
     }
 
@@ -92,31 +957,214 @@ impl Config {
         b2_idx: usize,
         b3_idx: usize,
         b4_idx: usize,
+    ) -> Vec<u8> {
+        self.assemble(b1_idx, b2_idx, b3_idx, b4_idx, self.linkers)
+    }
+
+    /// Builds the barcode of the 4 barcode indices as [`Config::build_barcode`] into a
+    /// reused buffer
+    pub fn build_barcode_into(&self, indices: [usize; 4], barcode: &mut Vec<u8>) {
+        barcode.clear();
+        self.assemble_into(indices, self.linkers, barcode);
+    }
+
+    /// Returns the barcode indices of an emitted barcode (the inverse of
+    /// [`Config::build_barcode`]), or None if it is not built from the barcodes of each tier
+    pub fn barcode_key(&self, barcode: &[u8]) -> Option<BarcodeKey> {
+        let mut indices = [0; 4];
+        let mut rest = barcode;
+        let mut seq = Vec::new();
+        for (tier, idx) in indices.iter_mut().enumerate() {
+            let (head, tail) = rest.split_at_checked(self.emitted_len(tier))?;
+            seq.clear();
+            seq.extend_from_slice(head);
+            if !self.linkers {
+                seq.extend_from_slice(self.tier_linker(tier));
+            }
+            *idx = self.tier(tier).get_id(&seq)?;
+            rest = tail;
+        }
+        rest.is_empty().then(|| BarcodeKey::new(indices))
+    }
+
+    /// Appends the qualities of the construct of a match (the barcode as built by
+    /// [`Config::build_barcode_into`] followed by the UMI) to a buffer.
+    ///
+    /// Each tier takes the qualities of the bases it was matched at (up to the linker if
+    /// linkers are not emitted) and the UMI those of its segments. A tier matched with a
+    /// deletion is padded with the lowest quality of its bases.
+    pub fn build_qual_into(&self, read_match: &ReadMatch, qual: &[u8], buf: &mut Vec<u8>) {
+        for (tier, (start, end)) in read_match.ranges.into_iter().enumerate() {
+            let len = self.emitted_len(tier);
+            let matched = &qual[start..end.min(start + len)];
+            buf.extend_from_slice(matched);
+            let padding = matched.iter().min().copied().unwrap_or(b'!');
+            buf.resize(buf.len() + len - matched.len(), padding);
+        }
+        for (start, end) in &read_match.umi_ranges {
+            buf.extend_from_slice(&qual[*start..*end]);
+        }
+    }
+
+    /// Builds the full barcode construct (always including the linkers)
+    /// from the 4 barcode indices
+    pub fn build_construct(
+        &self,
+        b1_idx: usize,
+        b2_idx: usize,
+        b3_idx: usize,
+        b4_idx: usize,
+    ) -> Vec<u8> {
+        self.assemble(b1_idx, b2_idx, b3_idx, b4_idx, true)
+    }
+
+    /// Lays out the R1 sequence of a barcode and UMI following the read structure (the
+    /// inverse of [`Config::match_read`]).
+    ///
+    /// The UMI is split over the UMI segments in order, unsized segments taking `umi_len`.
+    /// Captured random spacer positions take their bases from the UMI as well, the others
+    /// are laid out as `N`.
+    pub fn build_read(&self, indices: [usize; 4], umi: &[u8], umi_len: usize) -> Vec<u8> {
+        let mut seq = Vec::new();
+        let mut umi = umi;
+        for segment in &self.segments {
+            match segment {
+                Segment::Tier(tier) => seq.extend_from_slice(
+                    self.tier(*tier)
+                        .get_barcode(indices[*tier], true)
+                        .expect("Invalid barcode index"),
+                ),
+                Segment::Fixed(fixed) => seq.extend_from_slice(fixed),
+                Segment::Umi(_) | Segment::Random { capture: true, .. } => {
+                    let len = segment.random_len(umi_len);
+                    let (head, tail) = umi.split_at(len.min(umi.len()));
+                    seq.extend_from_slice(head);
+                    umi = tail;
+                }
+                Segment::Random { len, .. } => seq.extend(std::iter::repeat_n(b'N', *len)),
+            }
+        }
+        seq
+    }
+
+    /// Returns true if any barcode tier carries annotations (e.g. well IDs)
+    pub fn has_annotations(&self) -> bool {
+        (0..4).any(|tier| self.tier(tier).has_annotations())
+    }
+
+    /// Returns the per-tier well coordinates (the first annotation column of each tier)
+    /// joined by `-`, using `NA` for tiers without an annotation
+    pub fn wells(&self, indices: [usize; 4]) -> String {
+        indices
+            .iter()
+            .enumerate()
+            .map(|(tier, idx)| self.well(tier, *idx).unwrap_or("NA"))
+            .collect::<Vec<_>>()
+            .join("-")
+    }
+
+    /// Returns the well of a barcode (the first annotation column of its tier) if any
+    pub fn well(&self, tier: usize, idx: usize) -> Option<&str> {
+        self.tier(tier)
+            .get_annotation(idx)
+            .and_then(|a| a.first())
+            .map(|w| w.as_str())
+    }
+
+    /// Returns the number of barcodes in each tier
+    pub fn tier_sizes(&self) -> [usize; 4] {
+        [0, 1, 2, 3].map(|tier| self.tier(tier).num_barcodes())
+    }
+
+    /// Returns the barcode sequence (without linkers) of a tier
+    pub fn tier_barcode(&self, tier: usize, idx: usize) -> Option<&[u8]> {
+        self.tier(tier).get_barcode(idx, false)
+    }
+
+    /// Returns the barcode file of a tier
+    pub fn tier_path(&self, tier: usize) -> &str {
+        &self.paths[tier]
+    }
+
+    /// Returns the length of the barcodes of a tier (without linkers)
+    pub fn tier_len(&self, tier: usize) -> usize {
+        self.tier(tier).len() - self.tier(tier).spacer_len()
+    }
+
+    /// Returns the number of nucleotides compared when matching a tier (including its linker)
+    pub fn tier_match_len(&self, tier: usize) -> usize {
+        self.tier(tier).len()
+    }
+
+    /// Returns the linker following the barcodes of a tier (empty if none)
+    pub fn tier_linker(&self, tier: usize) -> &[u8] {
+        let bc = self.tier(tier);
+        bc.get_barcode(0, true)
+            .map(|seq| &seq[seq.len() - bc.spacer_len()..])
+            .unwrap_or_default()
+    }
+
+    /// Returns the length of the emitted barcodes (including linkers if they are emitted)
+    pub fn barcode_len(&self) -> usize {
+        (0..4).map(|tier| self.emitted_len(tier)).sum()
+    }
+
+    /// Returns the length of the emitted barcodes of a tier
+    fn emitted_len(&self, tier: usize) -> usize {
+        self.tier_len(tier)
+            + if self.linkers {
+                self.tier_linker(tier).len()
+            } else {
+                0
+            }
+    }
+
+    /// Returns true if the linkers are included in the emitted barcodes
+    pub fn linkers(&self) -> bool {
+        self.linkers
+    }
+
+    /// Returns the total length of the UMI given the default length of unsized UMI segments
+    /// (including the captured random spacer positions)
+    pub fn umi_len(&self, default: usize) -> usize {
+        self.segments
+            .iter()
+            .map(|s| match s {
+                Segment::Umi(_) | Segment::Random { capture: true, .. } => s.random_len(default),
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// Returns true if the emitted barcodes differ from the full barcode construct
+    pub fn translates(&self) -> bool {
+        !self.linkers
+    }
+
+    fn assemble(
+        &self,
+        b1_idx: usize,
+        b2_idx: usize,
+        b3_idx: usize,
+        b4_idx: usize,
+        linkers: bool,
     ) -> Vec<u8> {
         let mut bc =
             Vec::with_capacity(self.bc1.len() + self.bc2.len() + self.bc3.len() + self.bc4.len());
-        bc.extend_from_slice(
-            self.bc1
-                .get_barcode(b1_idx, self.linkers)
-                .expect("Invalid barcode index in bc1"),
-        );
-        bc.extend_from_slice(
-            self.bc2
-                .get_barcode(b2_idx, self.linkers)
-                .expect("Invalid barcode index in bc2"),
-        );
-        bc.extend_from_slice(
-            self.bc3
-                .get_barcode(b3_idx, self.linkers)
-                .expect("Invalid barcode index in bc3"),
-        );
-        bc.extend_from_slice(
-            self.bc4
-                .get_barcode(b4_idx, self.linkers)
-                .expect("Invalid barcode index in bc4"),
-        );
+        self.assemble_into([b1_idx, b2_idx, b3_idx, b4_idx], linkers, &mut bc);
         bc
     }
+
+    /// Appends the barcodes of the 4 barcode indices to a buffer
+    fn assemble_into(&self, indices: [usize; 4], linkers: bool, bc: &mut Vec<u8>) {
+        for (tier, idx) in indices.into_iter().enumerate() {
+            bc.extend_from_slice(
+                self.tier(tier)
+                    .get_barcode(idx, linkers)
+                    .unwrap_or_else(|| panic!("Invalid barcode index in bc{}", tier + 1)),
+            );
+        }
+    }
 }
 
 #[cfg(test)]
@@ -132,6 +1180,14 @@ mod testing {
         assert!(config.is_ok());
     }
 
+    #[test]
+    fn missing_yaml() {
+        let Err(err) = Config::from_file("data/missing.yaml", false, false) else {
+            panic!("a missing config should fail to load");
+        };
+        assert_eq!(err.to_string(), "Failed to read config: data/missing.yaml");
+    }
+
     #[test]
     fn load_yaml_exact() {
         let config = Config::from_file(TEST_PATH, true, false);
@@ -269,4 +1325,575 @@ mod testing {
         .concat();
         assert_eq!(bc, exp);
     }
+
+    #[test]
+    fn construct_translation() {
+        let config = Config::from_file(TEST_PATH, false, false).unwrap();
+        assert!(config.translates());
+        let bc = config.build_construct(0, 95, 0, 95);
+        let exp = [
+            "AGAAACCAATG".as_bytes(),
+            "GTAATCGAG".as_bytes(),
+            "AAAGTGTCGAG".as_bytes(),
+            "AAACTACA".as_bytes(),
+        ]
+        .concat();
+        assert_eq!(bc, exp);
+    }
+
+    #[test]
+    fn construct_translation_linkers() {
+        let config = Config::from_file(TEST_PATH, false, true).unwrap();
+        assert!(!config.translates());
+        assert_eq!(
+            config.build_barcode(0, 95, 0, 95),
+            config.build_construct(0, 95, 0, 95)
+        );
+    }
+
+    const TEST_READ: &[u8] = b"NNAGAAACCAATGTCTGTGGAGAAAGTGTCGAGCTGGGTATACGTACGTACGTTTTT";
+
+    fn config_with_structure(structure: &str) -> Config {
+        config_with_linkers(structure, false)
+    }
+
+    fn config_with_linkers(structure: &str, linkers: bool) -> Config {
+        let yaml = format!(
+            "barcodes:
+  bc1: data/barcodes_v3/fb_v3_bc1.tsv
+  bc2: data/barcodes_v3/fb_v3_bc2.tsv
+  bc3: data/barcodes_v3/fb_v3_bc3.tsv
+  bc4: data/barcodes_v3/fb_v3_bc4.tsv
+spacers:
+  s1: ATG
+  s2: GAG
+  s3: TCGAG
+structure: {}
+",
+            structure
+        );
+        let yaml = serde_yaml::from_str::<ConfigYaml>(&yaml).unwrap();
+        Config::from_yaml(yaml, false, linkers).unwrap()
+    }
+
+    #[test]
+    fn libraries() {
+        let yaml = "barcodes:
+  bc1: data/barcodes_v3/fb_v3_bc1.tsv
+  bc2: data/barcodes_v3/fb_v3_bc2.tsv
+  bc3: data/barcodes_v3/fb_v3_bc3.tsv
+  bc4: data/barcodes_v3/fb_v3_bc4.tsv
+spacers:
+  s1: ATG
+  s2: GAG
+  s3: TCGAG
+libraries:
+  - name: gex
+    type: Gene Expression
+    r1: [gex_L1_R1.fq.gz, gex_L2_R1.fq.gz]
+    r2: [gex_L1_R2.fq.gz, gex_L2_R2.fq.gz]
+  - name: adt
+    r1: adt_R1.fq.gz
+    r2: adt_R2.fq.gz
+    features: adt.csv
+";
+        let yaml = serde_yaml::from_str::<ConfigYaml>(yaml).unwrap();
+        let config = Config::from_yaml(yaml, false, false).unwrap();
+        let libraries = config.libraries();
+        assert_eq!(libraries.len(), 2);
+        assert_eq!(
+            libraries[0].library_type.as_deref(),
+            Some("Gene Expression")
+        );
+        assert_eq!(libraries[0].r2.len(), 2);
+        assert_eq!(libraries[1].r1, vec!["adt_R1.fq.gz"]);
+        assert_eq!(libraries[1].features.as_deref(), Some("adt.csv"));
+    }
+
+    #[test]
+    fn shared_tiers() {
+        let yaml = "barcodes:
+  bc1: data/barcodes_v3/fb_v3_bc2.tsv
+  bc2: data/barcodes_v3/fb_v3_bc2.tsv
+  bc3: data/barcodes_v3/fb_v3_bc2.tsv
+  bc4: data/barcodes_v3/fb_v3_bc4.tsv
+spacers:
+  s1: GAG
+  s2: GAG
+  s3: GAG
+";
+        let yaml = serde_yaml::from_str::<ConfigYaml>(yaml).unwrap();
+        let config = Config::from_yaml(yaml, false, false).unwrap();
+        assert!(Arc::ptr_eq(&config.bc2, &config.bc3));
+        assert!(!Arc::ptr_eq(&config.bc3, &config.bc4));
+        // the anchoring tier is expanded eagerly so is not shared with the lazy tiers
+        assert!(!Arc::ptr_eq(&config.bc1, &config.bc2));
+
+        let yaml = "barcodes:
+  bc1: data/barcodes_v3/fb_v3_bc2.tsv
+  bc2: data/barcodes_v3/fb_v3_bc2.tsv
+  bc3: data/barcodes_v3/fb_v3_bc2.tsv
+  bc4: data/barcodes_v3/fb_v3_bc4.tsv
+spacers:
+  s1: GAG
+  s2: GAG
+  s3: GAG
+";
+        let yaml = serde_yaml::from_str::<ConfigYaml>(yaml).unwrap();
+        let config = Config::from_yaml(yaml, true, false).unwrap();
+        assert!(Arc::ptr_eq(&config.bc1, &config.bc2));
+        assert!(Arc::ptr_eq(&config.bc2, &config.bc3));
+    }
+
+    #[test]
+    fn match_read() {
+        let config = Config::from_file(TEST_PATH, false, false).unwrap();
+        let read_match = config.match_read(TEST_READ, Some(5), 12).unwrap();
+        assert_eq!(read_match.indices, [0, 0, 0, 0]);
+        assert_eq!(read_match.umi, b"ACGTACGTACGT");
+        assert_eq!(read_match.end, TEST_READ.len() - 4);
+        assert_eq!(read_match.shift, 2);
+        assert_eq!(read_match.corrected, [false; 4]);
+    }
+
+    #[test]
+    fn chimeric() {
+        let config = Config::from_file(TEST_PATH, false, false).unwrap();
+        let read_match = config.match_read(TEST_READ, Some(5), 12).unwrap();
+        assert!(!config.is_chimeric(TEST_READ, &read_match));
+
+        // a second tier 1 barcode with its linker follows the structure
+        let read = [TEST_READ, b"AGAAACCAATG"].concat();
+        let read_match = config.match_read(&read, Some(5), 12).unwrap();
+        assert_eq!(read_match.indices, [0, 0, 0, 0]);
+        assert!(config.is_chimeric(&read, &read_match));
+
+        // without its linker or with a mismatch it is not taken as a chimera
+        for tail in [&b"AGAAACCAACC"[..], b"AGAAACCTATG"] {
+            let read = [TEST_READ, tail].concat();
+            let read_match = config.match_read(&read, Some(5), 12).unwrap();
+            assert!(!config.is_chimeric(&read, &read_match));
+        }
+    }
+
+    #[test]
+    fn match_read_corrected() {
+        let config = Config::from_file(TEST_PATH, false, false).unwrap();
+        let mut read = TEST_READ.to_vec();
+        read[2] = b'T';
+        read[20] = b'T';
+        let read_match = config.match_read(&read, Some(5), 12).unwrap();
+        assert_eq!(read_match.indices, [0, 0, 0, 0]);
+        assert_eq!(read_match.corrected, [true, true, false, false]);
+    }
+
+    #[test]
+    fn match_read_indels() {
+        let config = Config::from_file(TEST_PATH, false, false).unwrap();
+        let mut read = TEST_READ.to_vec();
+        // a deletion in bc2 and an insertion in bc3
+        read.remove(15);
+        read.insert(23, b'C');
+        assert_eq!(
+            config.match_read(&read, Some(5), 12).unwrap_err(),
+            Filtered::Tier(1)
+        );
+        let config = config.with_matcher(Matcher::Levenshtein);
+        assert_eq!(config.matcher(), Matcher::Levenshtein);
+        let read_match = config.match_read(&read, Some(5), 12).unwrap();
+        assert_eq!(read_match.indices, [0, 0, 0, 0]);
+        assert_eq!(read_match.umi, b"ACGTACGTACGT");
+        assert_eq!(read_match.corrected, [false, true, true, false]);
+
+        // a deletion of the first nucleotide of an anchored barcode
+        let read_match = config.match_read(&TEST_READ[3..], None, 12).unwrap();
+        assert_eq!(read_match.indices, [0, 0, 0, 0]);
+        assert_eq!(read_match.shift, 0);
+        assert_eq!(read_match.umi, b"ACGTACGTACGT");
+    }
+
+    #[test]
+    fn barcode_key() {
+        let indices = [0, 95, 65535, 7];
+        assert_eq!(BarcodeKey::new(indices).indices(), indices);
+        assert_ne!(BarcodeKey::new([1, 0, 0, 0]), BarcodeKey::new([0, 0, 0, 1]));
+    }
+
+    #[test]
+    fn match_read_into() {
+        let config = Config::from_file(TEST_PATH, false, false).unwrap();
+        let mut read_match = config.match_read(TEST_READ, Some(5), 12).unwrap();
+        read_match.umi.extend_from_slice(b"stale");
+        config
+            .match_read_into(TEST_READ, Some(5), 12, &mut read_match)
+            .unwrap();
+        assert_eq!(read_match.umi, b"ACGTACGTACGT");
+        let mut barcode = b"stale".to_vec();
+        config.build_barcode_into(read_match.indices, &mut barcode);
+        assert_eq!(barcode, config.build_barcode(0, 0, 0, 0));
+    }
+
+    #[test]
+    fn match_read_edit() {
+        let config = Config::from_file(TEST_PATH, false, false).unwrap();
+        // an adapter precedes the structure and tier 1 holds two substitutions
+        let mut read = [b"ACGTTGCAACGT".as_slice(), TEST_READ].concat();
+        read[14] = b'T';
+        read[16] = b'C';
+        assert!(config.match_read(&read, Some(5), 12).is_err());
+        let read_match = config.match_read_edit(&read, 32, 2, 12).unwrap();
+        assert_eq!(read_match.indices, [0, 0, 0, 0]);
+        assert_eq!(read_match.umi, b"ACGTACGTACGT");
+        assert_eq!(read_match.shift, 14);
+        assert_eq!(read_match.corrected, [true, false, false, false]);
+
+        // a deletion within tier 3 shifts the remaining segments
+        let mut read = TEST_READ.to_vec();
+        read.remove(30);
+        let read_match = config.match_read_edit(&read, 5, 2, 12).unwrap();
+        assert_eq!(read_match.indices, [0, 0, 0, 0]);
+        assert_eq!(read_match.umi, b"ACGTACGTACGT");
+        assert_eq!(read_match.end, TEST_READ.len() - 5);
+        assert!(config.match_read_edit(b"ACGTACGT", 5, 2, 12).is_err());
+    }
+
+    #[test]
+    fn match_read_anchored() {
+        let config = Config::from_file(TEST_PATH, false, false).unwrap();
+        assert_eq!(
+            config.match_read(TEST_READ, None, 12).unwrap_err(),
+            Filtered::Tier(0)
+        );
+        let read_match = config.match_read(&TEST_READ[2..], None, 12).unwrap();
+        assert_eq!(read_match.indices, [0, 0, 0, 0]);
+        assert_eq!(read_match.umi, b"ACGTACGTACGT");
+        assert_eq!(read_match.end, TEST_READ.len() - 6);
+    }
+
+    #[test]
+    fn match_read_filtered() {
+        let config = Config::from_file(TEST_PATH, false, false).unwrap();
+        assert_eq!(
+            config.match_read(TEST_READ, Some(1), 12).unwrap_err(),
+            Filtered::Tier(0)
+        );
+        assert_eq!(
+            config
+                .match_read(&TEST_READ[..40], Some(5), 12)
+                .unwrap_err(),
+            Filtered::Tier(3)
+        );
+        assert_eq!(
+            config.match_read(TEST_READ, Some(5), 20).unwrap_err(),
+            Filtered::Umi
+        );
+    }
+
+    #[test]
+    fn match_read_explicit_structure() {
+        let config = config_with_structure("bc1-s1-bc2-s2-bc3-s3-bc4-umi(8)");
+        let read_match = config.match_read(TEST_READ, Some(5), 12).unwrap();
+        assert_eq!(read_match.umi, b"ACGTACGT");
+        assert_eq!(
+            config.structure().to_string(),
+            "bc1-s1-bc2-s2-bc3-s3-bc4-umi(8)"
+        );
+    }
+
+    #[test]
+    fn match_read_umi_first() {
+        let config = config_with_structure("umi(4)-bc1-s1-bc2-s2-bc3-s3-bc4-ACGT");
+        let read = b"TTTTAGAAACCAATGTCTGTGGAGAAAGTGTCGAGCTGGGTATACGT";
+        let read_match = config.match_read(read, Some(5), 12).unwrap();
+        assert_eq!(read_match.indices, [0, 0, 0, 0]);
+        assert_eq!(read_match.umi, b"TTTT");
+        assert_eq!(read_match.end, read.len());
+
+        // the trailing fixed sequence is matched jointly with bc4
+        assert_eq!(
+            config
+                .match_read(&read[..read.len() - 1], Some(5), 12)
+                .unwrap_err(),
+            Filtered::Tier(3)
+        );
+    }
+
+    #[test]
+    fn construct_qual() {
+        let structure = "umi(4)-bc1-s1-bc2-s2-bc3-s3-bc4-ACGT";
+        let read = b"TTTTAGAAACCAATGTCTGTGGAGAAAGTGTCGAGCTGGGTATACGT";
+        // each base has a distinct quality so the source of each quality is known
+        let qual = (0..read.len() as u8).map(|i| b'!' + i).collect::<Vec<_>>();
+
+        // with linkers the construct covers the whole structure after the leading UMI
+        let config = config_with_linkers(structure, true);
+        let read_match = config.match_read(read, Some(5), 12).unwrap();
+        assert_eq!(read_match.umi_ranges, vec![(0, 4)]);
+        let mut with_linkers = Vec::new();
+        config.build_qual_into(&read_match, &qual, &mut with_linkers);
+        assert_eq!(with_linkers, [&qual[4..], &qual[..4]].concat());
+
+        // without linkers the linker qualities are skipped
+        let config = config_with_structure(structure);
+        let read_match = config.match_read(read, Some(5), 12).unwrap();
+        let mut without_linkers = Vec::new();
+        config.build_qual_into(&read_match, &qual, &mut without_linkers);
+        let mut barcode = Vec::new();
+        config.build_barcode_into(read_match.indices, &mut barcode);
+        assert_eq!(without_linkers.len(), barcode.len() + 4);
+        let expected = (0..4)
+            .flat_map(|tier| {
+                let start = read_match.ranges[tier].0 - 4;
+                with_linkers[start..start + config.tier_len(tier)].to_vec()
+            })
+            .chain(qual[..4].to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(without_linkers, expected);
+    }
+
+    #[test]
+    fn match_read_reordered() {
+        let config = config_with_structure("bc4-bc1-s1-bc2-s2-bc3-s3-umi");
+        let read = b"CTGGGTATAGAAACCAATGGTAATCGAGAAAGTGTCGAGACGTACGTACGT";
+        let read_match = config.match_read(read, Some(0), 12).unwrap();
+        assert_eq!(read_match.indices, [0, 95, 0, 0]);
+        assert_eq!(read_match.umi, b"ACGTACGTACGT");
+    }
+
+    #[test]
+    fn match_read_split_umi() {
+        // the UMI is split around bc4 and around a fixed sequence
+        for structure in [
+            "bc1-s1-bc2-s2-bc3-s3-umi(4)-bc4-umi(8)",
+            "bc1-s1-bc2-s2-bc3-s3-bc4-umi(4)-ACGT-umi(8)",
+        ] {
+            let config = config_with_structure(structure);
+            assert_eq!(config.umi_len(12), 12);
+            let read = config.build_read([0, 95, 0, 0], b"TTTTCCCCGGGG", 12);
+            let read_match = config.match_read(&read, Some(0), 12).unwrap();
+            assert_eq!(read_match.indices, [0, 95, 0, 0]);
+            assert_eq!(read_match.umi, b"TTTTCCCCGGGG");
+            assert_eq!(read_match.end, read.len());
+            let lens = read_match
+                .umi_ranges
+                .iter()
+                .map(|(start, end)| end - start)
+                .collect::<Vec<_>>();
+            assert_eq!(lens, vec![4, 8]);
+        }
+
+        let config = config_with_structure("bc1-s1-bc2-s2-bc3-s3-bc4-umi(4)-ACGT-umi(8)");
+        let mut read = config.build_read([0, 0, 0, 0], b"TTTTCCCCGGGG", 12);
+        let fixed = read.len() - 12;
+        read[fixed..fixed + 4].copy_from_slice(b"AAAA");
+        assert_eq!(
+            config.match_read(&read, Some(0), 12).unwrap_err(),
+            Filtered::Fixed
+        );
+    }
+
+    #[test]
+    fn match_read_random_spacer() {
+        for capture in [false, true] {
+            let yaml = format!(
+                "barcodes:
+  bc1: data/barcodes_v3/fb_v3_bc1.tsv
+  bc2: data/barcodes_v3/fb_v3_bc2.tsv
+  bc3: data/barcodes_v3/fb_v3_bc3.tsv
+  bc4: data/barcodes_v3/fb_v3_bc4.tsv
+spacers:
+  s1: ATG
+  s2: GNNNAG
+  s3: TCGAG
+structure: bc1-s1-bc2-s2-bc3-s3-bc4-umi
+capture_random_spacers: {}
+",
+                capture
+            );
+            let yaml = serde_yaml::from_str::<ConfigYaml>(&yaml).unwrap();
+            let config = Config::from_yaml(yaml, false, true).unwrap();
+            assert_eq!(config.umi_len(12), if capture { 15 } else { 12 });
+
+            let mut read = if capture {
+                config.build_read([0, 95, 0, 0], b"TTTCCCCGGGGAAAA", 12)
+            } else {
+                config.build_read([0, 95, 0, 0], b"CCCCGGGGAAAA", 12)
+            };
+            let random = config.tier_match_len(0) + config.tier_match_len(1);
+            assert_eq!(
+                &read[random..random + 3],
+                if capture { b"TTT" } else { b"NNN" }
+            );
+            // the random positions match any bases
+            read[random..random + 3].copy_from_slice(b"ACG");
+            let read_match = config.match_read(&read, Some(0), 12).unwrap();
+            assert_eq!(read_match.indices, [0, 95, 0, 0]);
+            if capture {
+                assert_eq!(read_match.umi, b"ACGCCCCGGGGAAAA");
+                assert_eq!(read_match.umi_ranges[0], (random, random + 3));
+            } else {
+                assert_eq!(read_match.umi, b"CCCCGGGGAAAA");
+                assert_eq!(read_match.umi_ranges.len(), 1);
+            }
+            // only the fixed positions preceding the random ones join the barcode
+            assert_eq!(config.tier_linker(1), b"G");
+            assert!(!config.build_barcode(0, 95, 0, 0).contains(&b'N'));
+        }
+    }
+
+    #[test]
+    fn match_read_standalone_fixed() {
+        let config = config_with_structure("bc1-s1-bc2-s2-bc3-s3-umi(4)-ACGT-bc4");
+        let read = b"AGAAACCAATGTCTGTGGAGAAAGTGTCGAGTTTTACGTCTGGGTAT";
+        let read_match = config.match_read(read, Some(0), 12).unwrap();
+        assert_eq!(read_match.umi, b"TTTT");
+        assert_eq!(read_match.end, read.len());
+
+        let read = b"AGAAACCAATGTCTGTGGAGAAAGTGTCGAGTTTTAAAACTGGGTAT";
+        assert_eq!(
+            config.match_read(read, Some(0), 12).unwrap_err(),
+            Filtered::Fixed
+        );
+    }
+
+    #[test]
+    fn undefined_spacer() {
+        let yaml = "barcodes:
+  bc1: data/barcodes_v3/fb_v3_bc1.tsv
+  bc2: data/barcodes_v3/fb_v3_bc2.tsv
+  bc3: data/barcodes_v3/fb_v3_bc3.tsv
+  bc4: data/barcodes_v3/fb_v3_bc4.tsv
+structure: bc1-s1-bc2-bc3-bc4-umi
+";
+        let yaml = serde_yaml::from_str::<ConfigYaml>(yaml).unwrap();
+        assert!(Config::from_yaml(yaml, false, false).is_err());
+    }
+
+    #[test]
+    fn collisions() {
+        let config = Config::from_file(TEST_PATH, false, false).unwrap();
+        assert!(config
+            .collisions()
+            .iter()
+            .all(|c| c.tier != c.other_tier && c.barcode.len() == c.other.len()));
+
+        let dir = tempfile::tempdir().unwrap();
+        let tiers = ["AAAAAA\nCCCCCC", "AAAAAT\nGGACAA", "TTTTTT", "ACACAC"];
+        let mut paths = Vec::new();
+        for (tier, barcodes) in tiers.iter().enumerate() {
+            let path = dir.path().join(format!("bc{}.tsv", tier + 1));
+            std::fs::write(&path, barcodes).unwrap();
+            paths.push(path.to_str().unwrap().to_string());
+        }
+        let yaml = format!(
+            "barcodes:
+  bc1: {}
+  bc2: {}
+  bc3: {}
+  bc4: {}
+spacers:
+  s1: ''
+  s2: ''
+  s3: GG
+",
+            paths[0], paths[1], paths[2], paths[3]
+        );
+        let load = |exact: bool| {
+            let yaml = serde_yaml::from_str::<ConfigYaml>(&yaml).unwrap();
+            Config::from_yaml(yaml, exact, false).unwrap()
+        };
+        let config = load(false);
+        let collisions = config.collisions();
+        assert!(collisions.contains(&Collision {
+            tier: 1,
+            barcode: "AAAAAA".to_string(),
+            other_tier: 2,
+            other: "AAAAAT".to_string(),
+        }));
+        // bc4 is preceded by the spacer of bc3 (`TTTTTTGG` then `ACACAC`)
+        assert!(collisions.contains(&Collision {
+            tier: 2,
+            barcode: "GGACAA".to_string(),
+            other_tier: 4,
+            other: "GGACAC".to_string(),
+        }));
+        assert_eq!(collisions.len(), 2);
+        assert!(load(true).collisions().is_empty());
+    }
+
+    #[test]
+    fn wells() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut paths = Vec::new();
+        for tier in 1..=4 {
+            let source = format!("data/barcodes_v3/fb_v3_bc{}.tsv", tier);
+            let contents = std::fs::read_to_string(source).unwrap();
+            let annotated = contents
+                .lines()
+                .enumerate()
+                .map(|(idx, line)| {
+                    if tier == 4 {
+                        line.to_string()
+                    } else {
+                        format!(
+                            "{}\t{}{:02}",
+                            line,
+                            (b'A' + tier as u8 - 1) as char,
+                            idx + 1
+                        )
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+            let path = dir.path().join(format!("bc{}.tsv", tier));
+            std::fs::write(&path, annotated).unwrap();
+            paths.push(path.to_str().unwrap().to_string());
+        }
+        let yaml = format!(
+            "barcodes:
+  bc1: {}
+  bc2: {}
+  bc3: {}
+  bc4: {}
+spacers:
+  s1: ATG
+  s2: GAG
+  s3: TCGAG
+",
+            paths[0], paths[1], paths[2], paths[3]
+        );
+        let yaml = serde_yaml::from_str::<ConfigYaml>(&yaml).unwrap();
+        let config = Config::from_yaml(yaml, false, false).unwrap();
+
+        assert!(config.has_annotations());
+        assert_eq!(config.wells([0, 95, 6, 0]), "A01-B96-C07-NA");
+        assert_eq!(config.well(0, 0), Some("A01"));
+        assert_eq!(config.well(3, 0), None);
+    }
+
+    #[test]
+    fn no_wells() {
+        let config = Config::from_file(TEST_PATH, false, false).unwrap();
+        assert!(!config.has_annotations());
+        assert_eq!(config.wells([0, 0, 0, 0]), "NA-NA-NA-NA");
+        assert_eq!(config.tier_sizes(), [96, 96, 96, 96]);
+        assert_eq!(config.tier_barcode(0, 0).unwrap(), b"AGAAACCA");
+    }
+
+    #[test]
+    fn barcode_key_of_barcode() {
+        for linkers in [false, true] {
+            let config = Config::from_file(TEST_PATH, false, linkers).unwrap();
+            let barcode = config.build_barcode(1, 95, 6, 42);
+            assert_eq!(
+                config.barcode_key(&barcode),
+                Some(BarcodeKey::new([1, 95, 6, 42]))
+            );
+            assert_eq!(config.barcode_key(&barcode[1..]), None);
+            assert_eq!(
+                config.barcode_key(&[barcode.as_slice(), b"A"].concat()),
+                None
+            );
+        }
+    }
 }
@@ -0,0 +1,179 @@
+use crate::input;
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use hashbrown::HashSet;
+
+/// The default k-mer size of the contaminant screen
+pub const DEFAULT_K: usize = 21;
+
+/// The SAM tag flagging the unaligned BAM records of contaminant hits (`XK:Z:1`), as BAM
+/// read names cannot carry the ` contaminant` flag of fastq headers
+pub const CONTAMINANT_TAG: &[u8; 2] = b"XK";
+
+/// What happens to read pairs whose R2 hits a contaminant
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ContaminantAction {
+    /// Drop the read pair
+    Drop,
+    /// Keep the read pair and append ` contaminant` to the R2 header (or tag it with
+    /// `XK:Z:1` in BAM output)
+    Flag,
+}
+
+/// Maps a nucleotide to its 2-bit encoding (None for ambiguous bases)
+fn encode(base: u8) -> Option<u64> {
+    match base {
+        b'A' | b'a' => Some(0),
+        b'C' | b'c' => Some(1),
+        b'G' | b'g' => Some(2),
+        b'T' | b't' => Some(3),
+        _ => None,
+    }
+}
+
+/// Iterates over the canonical (minimum of forward and reverse complement) 2-bit encoded
+/// k-mers of a sequence, skipping any k-mer containing an ambiguous base
+fn canonical_kmers(seq: &[u8], k: usize) -> impl Iterator<Item = u64> + '_ {
+    let mask = if k == 32 {
+        u64::MAX
+    } else {
+        (1 << (2 * k)) - 1
+    };
+    let shift = 2 * (k as u64 - 1);
+    let mut fwd = 0;
+    let mut rev = 0;
+    let mut valid = 0;
+    seq.iter().filter_map(move |base| match encode(*base) {
+        Some(code) => {
+            fwd = ((fwd << 2) | code) & mask;
+            rev = (rev >> 2) | ((3 - code) << shift);
+            valid += 1;
+            (valid >= k).then(|| fwd.min(rev))
+        }
+        None => {
+            valid = 0;
+            None
+        }
+    })
+}
+
+/// A k-mer screen of contaminant sequences (e.g. PhiX or adapters).
+///
+/// Both strands of the contaminants are indexed so a read hits whether it is sequenced
+/// in the forward or reverse orientation.
+#[derive(Debug, Clone)]
+pub struct ContaminantScreen {
+    kmers: HashSet<u64>,
+    k: usize,
+}
+impl ContaminantScreen {
+    /// Indexes the k-mers of every record of a (possibly compressed) FASTA or FASTQ file
+    pub fn from_file(path: &str, k: usize) -> Result<Self> {
+        let sequences = input::open(path)?
+            .reader
+            .map(|r| r.map(|r| r.seq().to_vec()))
+            .collect::<Result<Vec<_>>>()?;
+        let screen = Self::from_sequences(sequences, k)?;
+        if screen.is_empty() {
+            bail!("No {}-mers found in the contaminants: {}", k, path);
+        }
+        Ok(screen)
+    }
+
+    /// Indexes the k-mers of a set of sequences
+    pub fn from_sequences<I, S>(sequences: I, k: usize) -> Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<[u8]>,
+    {
+        if !(1..=32).contains(&k) {
+            bail!(
+                "The contaminant k-mer size must be between 1 and 32, found {}",
+                k
+            );
+        }
+        let mut kmers = HashSet::new();
+        for seq in sequences {
+            kmers.extend(canonical_kmers(seq.as_ref(), k));
+        }
+        Ok(Self { kmers, k })
+    }
+
+    /// Returns true if any k-mer of the sequence is a contaminant k-mer
+    pub fn hits(&self, seq: &[u8]) -> bool {
+        canonical_kmers(seq, self.k).any(|kmer| self.kmers.contains(&kmer))
+    }
+
+    /// Returns the number of distinct contaminant k-mers
+    pub fn len(&self) -> usize {
+        self.kmers.len()
+    }
+
+    /// Returns true if no contaminant k-mers were indexed
+    pub fn is_empty(&self) -> bool {
+        self.kmers.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    const ADAPTER: &[u8] = b"AGATCGGAAGAGCACACGTCTGAACTCCAGTCAC";
+
+    #[test]
+    fn screen() {
+        let screen = ContaminantScreen::from_sequences([ADAPTER], 21).unwrap();
+        assert_eq!(screen.len(), ADAPTER.len() - 21 + 1);
+
+        let mut read = b"TTTTGCAGCATCACGATGC".to_vec();
+        read.extend_from_slice(&ADAPTER[5..30]);
+        assert!(screen.hits(&read));
+        assert!(!screen.hits(b"TTTTGCAGCATCACGATGCTTTTGCAGCATCACGATGC"));
+        assert!(!screen.hits(&ADAPTER[..20]));
+    }
+
+    #[test]
+    fn reverse_complement() {
+        let screen = ContaminantScreen::from_sequences([ADAPTER], 21).unwrap();
+        let rc = ADAPTER
+            .iter()
+            .rev()
+            .map(|b| match b {
+                b'A' => b'T',
+                b'C' => b'G',
+                b'G' => b'C',
+                _ => b'A',
+            })
+            .collect::<Vec<_>>();
+        assert!(screen.hits(&rc));
+    }
+
+    #[test]
+    fn ambiguous_bases() {
+        let screen = ContaminantScreen::from_sequences([b"ACGTNACGT"], 4).unwrap();
+        assert_eq!(screen.len(), 1);
+        assert!(screen.hits(b"ACGT"));
+        assert!(!screen.hits(b"CGTNA"));
+    }
+
+    #[test]
+    fn invalid_k() {
+        assert!(ContaminantScreen::from_sequences([ADAPTER], 0).is_err());
+        assert!(ContaminantScreen::from_sequences([ADAPTER], 33).is_err());
+        assert!(ContaminantScreen::from_sequences([ADAPTER], 32).is_ok());
+    }
+
+    #[test]
+    fn parse_action() {
+        assert_eq!(
+            ContaminantAction::from_str("drop", false),
+            Ok(ContaminantAction::Drop)
+        );
+        assert_eq!(
+            ContaminantAction::from_str("flag", false),
+            Ok(ContaminantAction::Flag)
+        );
+        assert!(ContaminantAction::from_str("keep", false).is_err());
+    }
+}
@@ -0,0 +1,79 @@
+use std::{
+    io::{Read, Result, Write},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+/// A shared count of the bytes passed through a `Counted` reader or writer
+#[derive(Debug, Clone, Default)]
+pub struct ByteCounter(Arc<AtomicUsize>);
+impl ByteCounter {
+    /// Returns the number of bytes counted so far
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn add(&self, num_bytes: usize) {
+        self.0.fetch_add(num_bytes, Ordering::Relaxed);
+    }
+}
+
+/// Wraps a reader or writer and counts the bytes passing through it
+pub struct Counted<T> {
+    inner: T,
+    counter: ByteCounter,
+}
+impl<T> Counted<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            counter: ByteCounter::default(),
+        }
+    }
+
+    /// Returns a handle to the count which remains valid after the wrapper is consumed
+    pub fn counter(&self) -> ByteCounter {
+        self.counter.clone()
+    }
+}
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let num_bytes = self.inner.read(buf)?;
+        self.counter.add(num_bytes);
+        Ok(num_bytes)
+    }
+}
+impl<W: Write> Write for Counted<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let num_bytes = self.inner.write(buf)?;
+        self.counter.add(num_bytes);
+        Ok(num_bytes)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn count_bytes() {
+        let mut reader = Counted::new(&b"ACGTACGT"[..]);
+        let read_counter = reader.counter();
+        let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer).unwrap();
+        assert_eq!(read_counter.get(), 8);
+
+        let mut writer = Counted::new(Vec::new());
+        let write_counter = writer.counter();
+        writer.write_all(&buffer).unwrap();
+        writer.write_all(b"AC").unwrap();
+        drop(writer);
+        assert_eq!(write_counter.get(), 10);
+    }
+}
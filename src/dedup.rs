@@ -0,0 +1,185 @@
+use crate::{cells::hash_sequence, downsample::SplitMix64, memory};
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use hashbrown::HashSet;
+
+/// The default number of distinct molecules a bloom filter is sized for
+pub const DEFAULT_DEDUP_CAPACITY: usize = 100_000_000;
+
+/// The false positive rate a bloom filter is sized for at its capacity
+const BLOOM_FALSE_POSITIVE_RATE: f64 = 0.001;
+
+/// How the molecules already emitted are remembered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DedupMode {
+    /// A set of the (64-bit) hashes of every molecule, growing with the number of molecules
+    Exact,
+    /// A fixed-size bloom filter, which wrongly drops a small fraction of distinct molecules
+    Bloom,
+}
+
+/// The parameters of the deduplication of passing reads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DedupOptions {
+    pub mode: DedupMode,
+    /// The number of leading R2 bases included in the molecule key
+    pub r2_prefix: usize,
+    /// The number of distinct molecules a bloom filter is sized for
+    pub capacity: usize,
+}
+impl Default for DedupOptions {
+    fn default() -> Self {
+        Self {
+            mode: DedupMode::Exact,
+            r2_prefix: 0,
+            capacity: DEFAULT_DEDUP_CAPACITY,
+        }
+    }
+}
+
+/// A bloom filter over 64-bit hashes using double hashing
+#[derive(Debug)]
+struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+}
+impl BloomFilter {
+    fn new(capacity: usize, false_positive_rate: f64) -> Self {
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits =
+            (-(capacity.max(1) as f64) * false_positive_rate.ln() / (ln2 * ln2)).ceil() as u64;
+        let num_bits = num_bits.max(64);
+        let num_hashes = ((num_bits as f64 / capacity.max(1) as f64) * ln2).round() as u32;
+        Self {
+            bits: vec![0; num_bits.div_ceil(64) as usize],
+            num_bits,
+            num_hashes: num_hashes.max(1),
+        }
+    }
+
+    /// Inserts a hash, returning whether it may have been inserted before
+    fn insert(&mut self, hash: u64) -> bool {
+        let step = SplitMix64(hash).next_u64() | 1;
+        let mut present = true;
+        for i in 0..self.num_hashes as u64 {
+            let bit = hash.wrapping_add(i.wrapping_mul(step)) % self.num_bits;
+            let (word, mask) = ((bit / 64) as usize, 1 << (bit % 64));
+            present &= self.bits[word] & mask != 0;
+            self.bits[word] |= mask;
+        }
+        present
+    }
+}
+
+#[derive(Debug)]
+enum Seen {
+    Exact(HashSet<u64>),
+    Bloom(BloomFilter),
+}
+
+/// Remembers the molecules (barcode, UMI and optionally an R2 prefix) already emitted
+#[derive(Debug)]
+pub struct Deduplicator {
+    seen: Seen,
+    r2_prefix: usize,
+    key: Vec<u8>,
+}
+impl Deduplicator {
+    pub fn new(opts: &DedupOptions) -> Result<Self> {
+        let seen = match opts.mode {
+            DedupMode::Exact => Seen::Exact(HashSet::new()),
+            DedupMode::Bloom if opts.capacity == 0 => {
+                bail!("The capacity of the deduplication bloom filter must be positive")
+            }
+            DedupMode::Bloom => {
+                Seen::Bloom(BloomFilter::new(opts.capacity, BLOOM_FALSE_POSITIVE_RATE))
+            }
+        };
+        Ok(Self {
+            seen,
+            r2_prefix: opts.r2_prefix,
+            key: Vec::new(),
+        })
+    }
+
+    /// Records a molecule, returning whether it was already seen
+    pub fn is_duplicate(&mut self, barcode: &[u8], umi: &[u8], r2: &[u8]) -> bool {
+        self.key.clear();
+        self.key.extend_from_slice(barcode);
+        self.key.push(b'_');
+        self.key.extend_from_slice(umi);
+        self.key.push(b'_');
+        self.key
+            .extend_from_slice(&r2[..self.r2_prefix.min(r2.len())]);
+        let hash = hash_sequence(&self.key);
+        match &mut self.seen {
+            Seen::Exact(set) => !set.insert(hash),
+            Seen::Bloom(filter) => filter.insert(hash),
+        }
+    }
+
+    /// Returns an upper estimate of the memory held by the molecules seen so far
+    pub fn memory_estimate(&self) -> usize {
+        match &self.seen {
+            Seen::Exact(set) => set.len() * memory::HASH_ENTRY_BYTES,
+            Seen::Bloom(filter) => filter.bits.len() * 8,
+        }
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    fn count_duplicates(opts: &DedupOptions) -> usize {
+        let mut dedup = Deduplicator::new(opts).unwrap();
+        let mut duplicates = 0;
+        for i in 0..2000u32 {
+            let umi = (i % 1000).to_string();
+            let r2 = if i % 1000 < 10 && i >= 1000 {
+                b"TTTT"
+            } else {
+                b"AAAA"
+            };
+            duplicates += dedup.is_duplicate(b"ACGT", umi.as_bytes(), r2) as usize;
+        }
+        duplicates
+    }
+
+    #[test]
+    fn exact() {
+        assert_eq!(count_duplicates(&DedupOptions::default()), 1000);
+        let opts = DedupOptions {
+            r2_prefix: 2,
+            ..DedupOptions::default()
+        };
+        assert_eq!(count_duplicates(&opts), 990);
+    }
+
+    #[test]
+    fn bloom() {
+        let opts = DedupOptions {
+            mode: DedupMode::Bloom,
+            capacity: 10_000,
+            ..DedupOptions::default()
+        };
+        // the filter is far from its capacity so false positives are unlikely
+        assert_eq!(count_duplicates(&opts), 1000);
+        assert!(Deduplicator::new(&DedupOptions {
+            capacity: 0,
+            ..opts
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn parse_mode() {
+        assert_eq!(DedupMode::from_str("bloom", false), Ok(DedupMode::Bloom));
+        assert_eq!(
+            DedupMode::Exact.to_possible_value().unwrap().get_name(),
+            "exact"
+        );
+        assert!(DedupMode::from_str("fuzzy", false).is_err());
+    }
+}
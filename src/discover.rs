@@ -0,0 +1,350 @@
+use anyhow::{bail, Context, Result};
+use std::path::{Path, PathBuf};
+
+/// The marker of R1 files in Illumina file names (e.g. `S1_L001_R1_001.fastq.gz`)
+const R1_MARKER: &str = "_R1_";
+
+/// The marker of R2 files in Illumina file names
+const R2_MARKER: &str = "_R2_";
+
+/// The extensions of the FASTQ files picked up in a directory
+const FASTQ_EXTENSIONS: [&str; 4] = [".fastq.gz", ".fq.gz", ".fastq", ".fq"];
+
+/// The R1/R2 input pairs discovered for a sample, sorted by file name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Discovered {
+    pub r1: Vec<String>,
+    pub r2: Vec<String>,
+}
+
+/// Discovers the `*_R1_*`/`*_R2_*` FASTQ files (`.fastq[.gz]` or `.fq[.gz]`) of a
+/// directory, or of the files of a directory named by a sample prefix followed by `_`
+/// (e.g. `run/sample1` picks up `sample1_L001_R1_001.fastq.gz` but not
+/// `sample10_L001_R1_001.fastq.gz`), and pairs them across lanes.
+///
+/// Each R1 file is paired with the file whose name has its last `_R1_` replaced by
+/// `_R2_`, and an R1 or R2 file without its mate is an error.
+pub fn discover(path: &str) -> Result<Discovered> {
+    let (dir, prefix) = if Path::new(path).is_dir() {
+        (Path::new(path), "")
+    } else {
+        let dir = match Path::new(path).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let prefix = Path::new(path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default();
+        (dir, prefix)
+    };
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read input directory: {}", dir.display()))?
+    {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        if let Some(name) = entry.file_name().to_str() {
+            if is_sample_fastq(name, prefix) {
+                names.push(name.to_string());
+            }
+        }
+    }
+    names.sort();
+
+    // normalizes `dir/.` (as in `--fastq dir/.`) to `dir`
+    let dir = dir.components().collect::<PathBuf>();
+    let mut discovered = Discovered {
+        r1: Vec::new(),
+        r2: Vec::new(),
+    };
+    let mut mates = Vec::new();
+    for name in names.iter().filter(|name| name.contains(R1_MARKER)) {
+        let idx = name.rfind(R1_MARKER).expect("R1 marker was found");
+        let mate = format!(
+            "{}{}{}",
+            &name[..idx],
+            R2_MARKER,
+            &name[idx + R1_MARKER.len()..]
+        );
+        if names.binary_search(&mate).is_err() {
+            bail!("No R2 file found for {} (expected {})", name, mate);
+        }
+        discovered
+            .r1
+            .push(dir.join(name).to_string_lossy().to_string());
+        discovered
+            .r2
+            .push(dir.join(&mate).to_string_lossy().to_string());
+        mates.push(mate);
+    }
+    if let Some(orphan) = names
+        .iter()
+        .find(|name| name.contains(R2_MARKER) && !mates.contains(name))
+    {
+        bail!("No R1 file found for {}", orphan);
+    }
+    if discovered.r1.is_empty() {
+        bail!("No `*{}*` files found for input: {}", R1_MARKER, path);
+    }
+    Ok(discovered)
+}
+
+/// Returns whether a file name is a FASTQ file of the sample named by a prefix (any sample
+/// if the prefix is empty)
+fn is_sample_fastq(name: &str, prefix: &str) -> bool {
+    let of_sample = match name.strip_prefix(prefix) {
+        Some(rest) => prefix.is_empty() || prefix.ends_with('_') || rest.starts_with('_'),
+        None => false,
+    };
+    of_sample && FASTQ_EXTENSIONS.iter().any(|ext| name.ends_with(ext))
+}
+
+/// Returns whether an input contains glob metacharacters
+pub fn is_glob(input: &str) -> bool {
+    input.contains(['*', '?', '['])
+}
+
+/// Expands the glob patterns among the inputs (sorted by path), keeping the remaining
+/// inputs as given. A pattern matching no files is an error.
+pub fn expand_globs(inputs: &[String]) -> Result<Vec<String>> {
+    let mut expanded = Vec::new();
+    for input in inputs {
+        if !is_glob(input) {
+            expanded.push(input.clone());
+            continue;
+        }
+        let mut paths = glob::glob(input)
+            .with_context(|| format!("Invalid glob pattern: {}", input))?
+            .map(|path| path.map(|p| p.to_string_lossy().to_string()))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        if paths.is_empty() {
+            bail!("No files match the glob pattern: {}", input);
+        }
+        paths.sort();
+        expanded.extend(paths);
+    }
+    Ok(expanded)
+}
+
+/// Reads a file-of-filenames listing one input per line.
+///
+/// Blank lines and lines starting with `#` are skipped.
+pub fn read_fofn(path: &str) -> Result<Vec<String>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read file of filenames: {}", path))?;
+    let inputs = contents
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_string())
+        .collect::<Vec<_>>();
+    if inputs.is_empty() {
+        bail!("No inputs listed in file of filenames: {}", path);
+    }
+    Ok(inputs)
+}
+
+/// Returns the lane number of an Illumina file name (e.g. 2 for `S1_L002_R1_001.fastq.gz`)
+pub fn lane_number(path: &str) -> Option<usize> {
+    let name = Path::new(path).file_name()?.to_str()?;
+    name.match_indices("_L").find_map(|(idx, _)| {
+        let lane = name.get(idx + 2..idx + 5)?;
+        let digits = lane.bytes().all(|b| b.is_ascii_digit());
+        (digits && name[idx + 5..].starts_with('_')).then(|| lane.parse().ok())?
+    })
+}
+
+/// Returns the lane number of each input, or their ordinals (from 1) unless every input
+/// names a distinct lane
+pub fn lane_numbers(inputs: &[String]) -> Vec<usize> {
+    let lanes = inputs
+        .iter()
+        .map(|path| lane_number(path))
+        .collect::<Option<Vec<_>>>();
+    match lanes {
+        Some(lanes)
+            if lanes
+                .iter()
+                .enumerate()
+                .all(|(idx, lane)| !lanes[..idx].contains(lane)) =>
+        {
+            lanes
+        }
+        _ => (1..=inputs.len()).collect(),
+    }
+}
+
+/// Validates that each R1 input is paired with the R2 input of the same name, i.e. the
+/// two names only differ where the R1 name has a `1` and the R2 name a `2`.
+pub fn check_mates(r1: &[String], r2: &[String]) -> Result<()> {
+    if r1.len() != r2.len() {
+        bail!(
+            "Expected the same number of R1 and R2 inputs (found {} and {})",
+            r1.len(),
+            r2.len()
+        );
+    }
+    for (r1, r2) in r1.iter().zip(r2) {
+        let paired = r1.len() == r2.len()
+            && r1
+                .bytes()
+                .zip(r2.bytes())
+                .all(|(a, b)| a == b || (a == b'1' && b == b'2'));
+        if !paired || r1 == r2 {
+            bail!("R1 input {} is not paired with R2 input {}", r1, r2);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    fn touch_all(files: &[&str]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for file in files {
+            std::fs::write(dir.path().join(file), "").unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn directory() {
+        let dir = touch_all(&[
+            "S1_L002_R2_001.fastq.gz",
+            "S1_L001_R1_001.fastq.gz",
+            "S1_L002_R1_001.fastq.gz",
+            "S1_L001_R2_001.fastq.gz",
+            "S1_L001_I1_001.fastq.gz",
+        ]);
+        let discovered = discover(dir.path().to_str().unwrap()).unwrap();
+        let names = |paths: &[String]| {
+            paths
+                .iter()
+                .map(|p| {
+                    Path::new(p)
+                        .file_name()
+                        .unwrap()
+                        .to_str()
+                        .unwrap()
+                        .to_string()
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(&discovered.r1),
+            ["S1_L001_R1_001.fastq.gz", "S1_L002_R1_001.fastq.gz"]
+        );
+        assert_eq!(
+            names(&discovered.r2),
+            ["S1_L001_R2_001.fastq.gz", "S1_L002_R2_001.fastq.gz"]
+        );
+    }
+
+    #[test]
+    fn prefix() {
+        let dir = touch_all(&[
+            "S1_L001_R1_001.fastq.gz",
+            "S1_L001_R2_001.fastq.gz",
+            "S2_L001_R1_001.fastq.gz",
+            "S2_L001_R2_001.fastq.gz",
+        ]);
+        let discovered = discover(dir.path().join("S2").to_str().unwrap()).unwrap();
+        assert_eq!(discovered.r1.len(), 1);
+        assert!(discovered.r1[0].ends_with("S2_L001_R1_001.fastq.gz"));
+        assert!(discover(dir.path().join("S3").to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn prefix_boundary() {
+        let dir = touch_all(&[
+            "S1_L001_R1_001.fastq.gz",
+            "S1_L001_R2_001.fastq.gz",
+            "S10_L001_R1_001.fastq.gz",
+            "S10_L001_R2_001.fastq.gz",
+        ]);
+        let discovered = discover(dir.path().join("S1").to_str().unwrap()).unwrap();
+        assert_eq!(discovered.r1.len(), 1);
+        assert!(discovered.r1[0].ends_with("S1_L001_R1_001.fastq.gz"));
+        assert_eq!(
+            discover(dir.path().join("S1_").to_str().unwrap()).unwrap(),
+            discovered
+        );
+    }
+
+    #[test]
+    fn fastq_extensions() {
+        let dir = touch_all(&[
+            "S1_L001_R1_001.fastq.gz",
+            "S1_L001_R2_001.fastq.gz",
+            "S1_L001_R1_001.fastq.gz.md5",
+            "S1_L002_R1_001.fq",
+            "S1_L002_R2_001.fq",
+            "S1_L003_R1_001.txt",
+        ]);
+        let discovered = discover(dir.path().to_str().unwrap()).unwrap();
+        assert_eq!(discovered.r1.len(), 2);
+        assert!(discovered.r1[1].ends_with("S1_L002_R1_001.fq"));
+    }
+
+    #[test]
+    fn unpaired() {
+        let dir = touch_all(&["S1_L001_R1_001.fastq.gz", "S1_L002_R2_001.fastq.gz"]);
+        assert!(discover(dir.path().to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn globs() {
+        let dir = touch_all(&[
+            "S1_L002_R1_001.fastq.gz",
+            "S1_L001_R1_001.fastq.gz",
+            "S1_L001_R2_001.fastq.gz",
+            "S1_L002_R2_001.fastq.gz",
+        ]);
+        let pattern =
+            |read: &str| vec![format!("{}/*_{}_001.fastq.gz", dir.path().display(), read)];
+        let r1 = expand_globs(&pattern("R1")).unwrap();
+        let r2 = expand_globs(&pattern("R2")).unwrap();
+        assert_eq!(r1.len(), 2);
+        assert!(r1[0].ends_with("S1_L001_R1_001.fastq.gz"));
+        assert!(check_mates(&r1, &r2).is_ok());
+        assert!(check_mates(&r1, &[r2[1].clone(), r2[0].clone()]).is_err());
+        assert!(check_mates(&r1, &r2[..1]).is_err());
+        assert!(expand_globs(&pattern("I1")).is_err());
+        assert_eq!(expand_globs(&["a.fq".to_string()]).unwrap(), ["a.fq"]);
+    }
+
+    #[test]
+    fn lanes() {
+        assert_eq!(lane_number("run1/S1_L002_R1_001.fastq.gz"), Some(2));
+        assert_eq!(lane_number("run_L1/S1_R1.fq.gz"), None);
+        assert_eq!(lane_number("S1_L01_R1.fq.gz"), None);
+        let inputs = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            lane_numbers(&inputs(&["S1_L003_R1_001.fq", "S1_L004_R1_001.fq"])),
+            [3, 4]
+        );
+        assert_eq!(
+            lane_numbers(&inputs(&["A_L001_R1_001.fq", "B_L001_R1_001.fq"])),
+            [1, 2]
+        );
+        assert_eq!(lane_numbers(&inputs(&["a_R1.fq", "b_R1.fq"])), [1, 2]);
+    }
+
+    #[test]
+    fn fofn() {
+        let dir = touch_all(&[]);
+        let path = dir.path().join("r1.fofn");
+        std::fs::write(&path, "# lanes\nL001_R1.fq.gz\n\n  L002_R1.fq.gz  \n").unwrap();
+        assert_eq!(
+            read_fofn(path.to_str().unwrap()).unwrap(),
+            ["L001_R1.fq.gz", "L002_R1.fq.gz"]
+        );
+        std::fs::write(&path, "# empty\n").unwrap();
+        assert!(read_fofn(path.to_str().unwrap()).is_err());
+    }
+}
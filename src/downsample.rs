@@ -0,0 +1,248 @@
+use crate::memory;
+use anyhow::{Context, Result};
+use hashbrown::HashMap;
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// The default seed of the per-cell downsampling
+pub const DEFAULT_SEED: u64 = 42;
+
+/// A small seedable generator (SplitMix64) so downsampling is reproducible
+#[derive(Debug, Clone)]
+pub(crate) struct SplitMix64(pub(crate) u64);
+impl SplitMix64 {
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Draws an integer uniformly from `0..n`
+    pub(crate) fn below(&mut self, n: usize) -> usize {
+        ((self.next_u64() as u128 * n as u128) >> 64) as usize
+    }
+
+    /// Draws a float uniformly from `[0, 1)`
+    pub(crate) fn uniform(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// The ordinals of the reads sampled for a single barcode
+#[derive(Debug, Default)]
+struct Reservoir {
+    seen: usize,
+    ordinals: Vec<u64>,
+}
+
+/// Distinguishes the spill files of downsamplers within the same process
+static NEXT_DOWNSAMPLER_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Caps the number of reads of each barcode with reservoir sampling.
+///
+/// The formatted reads are spilled to a temporary file as they are offered and only the
+/// ordinals of the sampled reads are held in memory. Once every read has been seen the
+/// sampled reads are copied out of the spill file in a second pass.
+#[derive(Debug)]
+pub struct Downsampler {
+    target: usize,
+    rng: SplitMix64,
+    num_reads: u64,
+    reservoirs: HashMap<Vec<u8>, Reservoir>,
+    /// An upper estimate of the memory held by the reservoirs
+    bytes: usize,
+    path: PathBuf,
+    spill: BufWriter<File>,
+}
+impl Downsampler {
+    /// Creates a downsampler spilling the offered reads into the given directory
+    pub fn new(target: usize, seed: u64, tmp_dir: &Path) -> Result<Self> {
+        let path = tmp_dir.join(format!(
+            "pipspeak.{}.{}.downsample",
+            std::process::id(),
+            NEXT_DOWNSAMPLER_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        let spill = File::create(&path)
+            .map(BufWriter::new)
+            .with_context(|| format!("Failed to create spill file: {}", path.display()))?;
+        Ok(Self {
+            target,
+            rng: SplitMix64(seed),
+            num_reads: 0,
+            reservoirs: HashMap::new(),
+            bytes: 0,
+            path,
+            spill,
+        })
+    }
+
+    /// Offers the formatted read pair of a barcode (and the group of shards it is
+    /// written to) to its reservoir
+    pub fn insert(&mut self, group: usize, barcode: &[u8], r1: &[u8], r2: &[u8]) -> Result<()> {
+        for field in [&(group as u64).to_le_bytes()[..], barcode, r1, r2] {
+            self.spill.write_all(&(field.len() as u64).to_le_bytes())?;
+            self.spill.write_all(field)?;
+        }
+        let ordinal = self.num_reads;
+        self.num_reads += 1;
+        let reservoir = match self.reservoirs.get_mut(barcode) {
+            Some(reservoir) => reservoir,
+            None => {
+                self.bytes += memory::RESERVOIR_BYTES + barcode.len();
+                self.reservoirs.entry(barcode.to_vec()).or_default()
+            }
+        };
+        reservoir.seen += 1;
+        if reservoir.ordinals.len() < self.target {
+            let capacity = reservoir.ordinals.capacity();
+            reservoir.ordinals.push(ordinal);
+            self.bytes += (reservoir.ordinals.capacity() - capacity) * 8;
+        } else {
+            let idx = self.rng.below(reservoir.seen);
+            if idx < self.target {
+                reservoir.ordinals[idx] = ordinal;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns an upper estimate of the memory held by the reservoirs
+    pub fn memory_estimate(&self) -> usize {
+        self.bytes
+    }
+
+    /// Returns the number of read pairs dropped so far to stay within the target
+    pub fn num_dropped(&self) -> usize {
+        self.reservoirs
+            .values()
+            .map(|reservoir| reservoir.seen - reservoir.ordinals.len())
+            .sum()
+    }
+
+    /// Passes the sampled read pairs (with their group and barcode) to `emit` in the
+    /// order they were offered and removes the spill file
+    pub fn finish<F>(mut self, mut emit: F) -> Result<()>
+    where
+        F: FnMut(usize, &[u8], &[u8], &[u8]) -> Result<()>,
+    {
+        self.spill.flush()?;
+        let mut sampled = self
+            .reservoirs
+            .drain()
+            .flat_map(|(_, reservoir)| reservoir.ordinals)
+            .collect::<Vec<_>>();
+        sampled.sort_unstable();
+        let mut reader = File::open(&self.path).map(BufReader::new)?;
+        let mut fields = [Vec::new(), Vec::new(), Vec::new(), Vec::new()];
+        let mut sampled = sampled.into_iter().peekable();
+        for ordinal in 0..self.num_reads {
+            let Some(&next) = sampled.peek() else {
+                break;
+            };
+            for field in fields.iter_mut() {
+                let mut len = [0; 8];
+                reader.read_exact(&mut len)?;
+                field.resize(u64::from_le_bytes(len) as usize, 0);
+                reader.read_exact(field)?;
+            }
+            if ordinal == next {
+                sampled.next();
+                let group = u64::from_le_bytes(fields[0].as_slice().try_into()?) as usize;
+                emit(group, &fields[1], &fields[2], &fields[3])?;
+            }
+        }
+        Ok(())
+    }
+}
+impl Drop for Downsampler {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.path).ok();
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    fn downsampler(target: usize, seed: u64) -> Downsampler {
+        Downsampler::new(target, seed, &std::env::temp_dir()).unwrap()
+    }
+
+    /// A sampled read pair with its group and barcode
+    type Sampled = (usize, Vec<u8>, Vec<u8>, Vec<u8>);
+
+    fn reads(downsampler: Downsampler) -> Vec<Sampled> {
+        let mut reads = Vec::new();
+        downsampler
+            .finish(|group, barcode, r1, r2| {
+                reads.push((group, barcode.to_vec(), r1.to_vec(), r2.to_vec()));
+                Ok(())
+            })
+            .unwrap();
+        reads
+    }
+
+    fn sample(seed: u64) -> Vec<Vec<u8>> {
+        let mut downsampler = downsampler(3, seed);
+        for i in 0..100u8 {
+            let barcode: &[u8] = if i % 10 == 0 { b"AAAA" } else { b"CCCC" };
+            downsampler.insert(0, barcode, &[i], &[]).unwrap();
+        }
+        assert_eq!(downsampler.num_dropped(), (10 - 3) + (90 - 3));
+        reads(downsampler)
+            .into_iter()
+            .map(|(_, _, r1, _)| r1)
+            .collect()
+    }
+
+    #[test]
+    fn reservoirs() {
+        let reads = sample(DEFAULT_SEED);
+        assert_eq!(reads.len(), 6);
+        assert!(reads.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(reads.iter().filter(|r| r[0] % 10 == 0).count(), 3);
+    }
+
+    #[test]
+    fn seeded() {
+        assert_eq!(sample(1), sample(1));
+        assert_ne!(sample(1), sample(2));
+    }
+
+    #[test]
+    fn below_target() {
+        let mut downsampler = downsampler(5, DEFAULT_SEED);
+        downsampler.insert(2, b"AAAA", b"r1", b"r2").unwrap();
+        let path = downsampler.path.clone();
+        assert_eq!(downsampler.num_dropped(), 0);
+        assert_eq!(
+            reads(downsampler),
+            vec![(2, b"AAAA".to_vec(), b"r1".to_vec(), b"r2".to_vec())]
+        );
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn uniform() {
+        let mut counts = [0; 10];
+        for seed in 0..2000 {
+            let mut downsampler = downsampler(1, seed);
+            for i in 0..10u8 {
+                downsampler.insert(0, b"AAAA", &[i], &[]).unwrap();
+            }
+            let (_, _, r1, _) = reads(downsampler).remove(0);
+            counts[r1[0] as usize] += 1;
+        }
+        assert!(
+            counts.iter().all(|c| (140..260).contains(c)),
+            "{:?}",
+            counts
+        );
+    }
+}
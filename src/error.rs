@@ -0,0 +1,115 @@
+use std::error::Error as StdError;
+use thiserror::Error;
+
+/// The exit code of errors which are not otherwise classified
+pub const EXIT_FAILURE: u8 = 1;
+
+/// The exit code of invalid or conflicting arguments (as used by the argument parser)
+pub const EXIT_USAGE: u8 = 2;
+
+/// The exit code of a run whose pass rate is below `--min-pass-rate`
+pub const EXIT_LOW_PASS_RATE: u8 = 3;
+
+/// The exit code of an invalid config or reference file
+pub const EXIT_CONFIG: u8 = 4;
+
+/// The exit code of a missing, unreadable or corrupt input
+pub const EXIT_INPUT: u8 = 5;
+
+/// The exit code of R1 and R2 inputs whose records are not paired
+pub const EXIT_PAIRING: u8 = 6;
+
+/// The exit code of outputs which could not be written (e.g. a full disk)
+pub const EXIT_OUTPUT: u8 = 7;
+
+/// The underlying cause of a classified error
+pub type Source = Box<dyn StdError + Send + Sync + 'static>;
+
+/// The class of an error, which determines the exit code of the command line.
+///
+/// Each variant wraps the original error (including any context) and displays it
+/// unchanged.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Invalid or conflicting arguments
+    #[error(transparent)]
+    Usage(Source),
+    /// An invalid config, barcode list or reference file
+    #[error(transparent)]
+    Config(Source),
+    /// A missing, unreadable or corrupt input
+    #[error(transparent)]
+    Input(Source),
+    /// R1 and R2 inputs whose records are not paired
+    #[error(transparent)]
+    Pairing(Source),
+    /// An output which could not be written
+    #[error(transparent)]
+    Output(Source),
+}
+impl Error {
+    /// Returns the exit code of the class of the error
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self::Usage(_) => EXIT_USAGE,
+            Self::Config(_) => EXIT_CONFIG,
+            Self::Input(_) => EXIT_INPUT,
+            Self::Pairing(_) => EXIT_PAIRING,
+            Self::Output(_) => EXIT_OUTPUT,
+        }
+    }
+}
+
+/// Classifies the error of a result, e.g. `Config::from_file(path, ..).classify(Error::Config)`
+pub trait Classify<T> {
+    fn classify(self, class: fn(Source) -> Error) -> anyhow::Result<T>;
+}
+impl<T, E: Into<Source>> Classify<T> for Result<T, E> {
+    fn classify(self, class: fn(Source) -> Error) -> anyhow::Result<T> {
+        self.map_err(|err| class(err.into()).into())
+    }
+}
+
+/// Returns the exit code of an error from its outermost classified cause.
+///
+/// Unclassified I/O errors are taken as output errors: the inputs are classified both
+/// when they are opened and for every record read from them (see [`crate::input`]), and
+/// every config, reference or log is classified with its path where it is loaded.
+pub fn exit_code(err: &anyhow::Error) -> u8 {
+    if let Some(class) = err.chain().find_map(|cause| cause.downcast_ref::<Error>()) {
+        return class.exit_code();
+    }
+    if err.chain().any(|cause| cause.is::<std::io::Error>()) {
+        return EXIT_OUTPUT;
+    }
+    EXIT_FAILURE
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use anyhow::{anyhow, Context};
+
+    #[test]
+    fn classified() {
+        let err = Err::<(), _>(anyhow!("unknown tier"))
+            .classify(Error::Config)
+            .context("Failed to load config")
+            .unwrap_err();
+        assert_eq!(exit_code(&err), EXIT_CONFIG);
+        assert_eq!(format!("{:#}", err), "Failed to load config: unknown tier");
+
+        let err = Err::<(), _>(anyhow!("out of sync"))
+            .classify(Error::Pairing)
+            .unwrap_err();
+        assert_eq!(exit_code(&err), EXIT_PAIRING);
+    }
+
+    #[test]
+    fn unclassified() {
+        assert_eq!(exit_code(&anyhow!("failed")), EXIT_FAILURE);
+        let full = std::io::Error::other("No space left on device");
+        let err = anyhow::Error::from(full).context("Failed to write R1");
+        assert_eq!(exit_code(&err), EXIT_OUTPUT);
+    }
+}
@@ -0,0 +1,214 @@
+use anyhow::Result;
+use hashbrown::HashMap;
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// The default maximum number of distinct sequences held in memory per chunk
+pub const DEFAULT_CHUNK_SIZE: usize = 10_000_000;
+
+/// Distinguishes the chunk files of counters within the same process
+static NEXT_COUNTER_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts distinct sequences by spilling sorted chunks to disk and merging them at the end.
+///
+/// At most `chunk_size` distinct sequences are held in memory at any time.
+#[derive(Debug, Clone)]
+pub struct ExternalCounter {
+    buffer: HashMap<Vec<u8>, usize>,
+    chunk_size: usize,
+    tmp_dir: PathBuf,
+    id: usize,
+    chunks: Vec<PathBuf>,
+}
+impl ExternalCounter {
+    /// Creates a counter spilling its chunks into the given directory
+    pub fn new(tmp_dir: PathBuf, chunk_size: usize) -> Self {
+        Self {
+            buffer: HashMap::new(),
+            chunk_size: chunk_size.max(1),
+            tmp_dir,
+            id: NEXT_COUNTER_ID.fetch_add(1, Ordering::Relaxed),
+            chunks: Vec::new(),
+        }
+    }
+
+    /// Counts an observation of a sequence
+    pub fn insert(&mut self, seq: &[u8]) -> Result<()> {
+        if let Some(count) = self.buffer.get_mut(seq) {
+            *count += 1;
+        } else {
+            self.buffer.insert(seq.to_vec(), 1);
+            if self.buffer.len() >= self.chunk_size {
+                self.spill()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the buffered counts as a sorted chunk and clears the buffer
+    fn spill(&mut self) -> Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let mut entries = self.buffer.drain().collect::<Vec<_>>();
+        entries.sort_unstable();
+        let path = self.tmp_dir.join(format!(
+            "pipspeak.{}.{}.chunk{}",
+            std::process::id(),
+            self.id,
+            self.chunks.len()
+        ));
+        let mut writer = File::create(&path).map(BufWriter::new)?;
+        for (seq, count) in entries {
+            writer.write_all(&seq)?;
+            writeln!(writer, "\t{}", count)?;
+        }
+        writer.flush()?;
+        self.chunks.push(path);
+        Ok(())
+    }
+
+    /// Merges all chunks and writes the deduplicated sequences (one per line) and their
+    /// counts (TSV). Returns the number of distinct sequences and removes the chunks.
+    pub fn finish<W1: Write, W2: Write>(
+        mut self,
+        mut whitelist: W1,
+        mut counts: W2,
+    ) -> Result<usize> {
+        self.spill()?;
+        let mut readers = self
+            .chunks
+            .iter()
+            .map(|path| File::open(path).map(BufReader::new))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let mut heap = BinaryHeap::new();
+        for (idx, reader) in readers.iter_mut().enumerate() {
+            if let Some((seq, count)) = next_entry(reader)? {
+                heap.push(Reverse((seq, count, idx)));
+            }
+        }
+
+        writeln!(counts, "barcode\treads")?;
+        let mut write_entry = |seq: &[u8], count: usize| -> Result<()> {
+            whitelist.write_all(seq)?;
+            whitelist.write_all(b"\n")?;
+            counts.write_all(seq)?;
+            writeln!(counts, "\t{}", count)?;
+            Ok(())
+        };
+
+        let mut num_distinct = 0;
+        let mut current: Option<(Vec<u8>, usize)> = None;
+        while let Some(Reverse((seq, count, idx))) = heap.pop() {
+            if let Some((next_seq, next_count)) = next_entry(&mut readers[idx])? {
+                heap.push(Reverse((next_seq, next_count, idx)));
+            }
+            match current.as_mut() {
+                Some((current_seq, total)) if *current_seq == seq => *total += count,
+                _ => {
+                    if let Some((prev_seq, total)) = current.replace((seq, count)) {
+                        write_entry(&prev_seq, total)?;
+                        num_distinct += 1;
+                    }
+                }
+            }
+        }
+        if let Some((seq, total)) = current {
+            write_entry(&seq, total)?;
+            num_distinct += 1;
+        }
+        whitelist.flush()?;
+        counts.flush()?;
+        for path in &self.chunks {
+            std::fs::remove_file(path)?;
+        }
+        Ok(num_distinct)
+    }
+}
+
+/// Reads the next `sequence<TAB>count` entry of a chunk
+fn next_entry<R: BufRead>(reader: &mut R) -> Result<Option<(Vec<u8>, usize)>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let (seq, count) = line
+        .trim_end()
+        .split_once('\t')
+        .ok_or_else(|| anyhow::anyhow!("Malformed whitelist chunk line: {}", line))?;
+    Ok(Some((seq.as_bytes().to_vec(), count.parse()?)))
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn merge_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut counter = ExternalCounter::new(dir.path().to_path_buf(), 2);
+        for seq in [
+            "ACGT", "TTTT", "ACGT", "GGGG", "AAAA", "TTTT", "ACGT", "CCCC",
+        ] {
+            counter.insert(seq.as_bytes()).unwrap();
+        }
+        assert!(counter.chunks.len() > 1);
+
+        let whitelist_path = dir.path().join("whitelist.txt");
+        let counts_path = dir.path().join("counts.tsv");
+        let num_distinct = counter
+            .finish(
+                File::create(&whitelist_path).unwrap(),
+                File::create(&counts_path).unwrap(),
+            )
+            .unwrap();
+        assert_eq!(num_distinct, 5);
+
+        let whitelist = std::fs::read_to_string(&whitelist_path).unwrap();
+        assert_eq!(whitelist, "AAAA\nACGT\nCCCC\nGGGG\nTTTT\n");
+        let counts = std::fs::read_to_string(&counts_path).unwrap();
+        assert_eq!(
+            counts,
+            "barcode\treads\nAAAA\t1\nACGT\t3\nCCCC\t1\nGGGG\t1\nTTTT\t2\n"
+        );
+        // only the outputs remain once the chunks are merged
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn concurrent_counters() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut first = ExternalCounter::new(dir.path().to_path_buf(), 1);
+        let mut second = ExternalCounter::new(dir.path().to_path_buf(), 1);
+        for seq in ["AAAA", "CCCC", "AAAA"] {
+            first.insert(seq.as_bytes()).unwrap();
+        }
+        for seq in ["GGGG", "TTTT", "GGGG", "TTTT"] {
+            second.insert(seq.as_bytes()).unwrap();
+        }
+        // the chunks of counters sharing a directory never collide
+        assert!(first
+            .chunks
+            .iter()
+            .all(|path| !second.chunks.contains(path)));
+
+        let mut whitelist = Vec::new();
+        let mut counts = Vec::new();
+        assert_eq!(first.finish(&mut whitelist, &mut counts).unwrap(), 2);
+        assert_eq!(whitelist, b"AAAA\nCCCC\n");
+        assert_eq!(counts, b"barcode\treads\nAAAA\t2\nCCCC\t1\n");
+
+        let mut whitelist = Vec::new();
+        let mut counts = Vec::new();
+        assert_eq!(second.finish(&mut whitelist, &mut counts).unwrap(), 2);
+        assert_eq!(whitelist, b"GGGG\nTTTT\n");
+        assert_eq!(counts, b"barcode\treads\nGGGG\t2\nTTTT\t2\n");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,462 @@
+use crate::{barcodes::Barcodes, memory};
+use anyhow::{anyhow, bail, Result};
+use hashbrown::{HashMap, HashSet};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+};
+
+/// The feature type reported for features without one
+pub const DEFAULT_FEATURE_TYPE: &str = "Antibody Capture";
+
+/// The default number of mismatches allowed when matching a feature barcode
+pub const DEFAULT_MISMATCHES: usize = 1;
+
+/// A feature (e.g. an antibody or hashtag) identified by a barcode in R2
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Feature {
+    pub id: String,
+    pub name: String,
+    pub sequence: Vec<u8>,
+    pub feature_type: String,
+    /// The offset of the feature barcode in R2
+    pub offset: usize,
+    /// Whether the barcode is found exactly at its offset or anywhere after it
+    pub anchored: bool,
+}
+impl Feature {
+    /// Returns the start positions in a read where the feature barcode may be
+    fn positions(&self, seq: &[u8]) -> std::ops::Range<usize> {
+        let last = seq.len().checked_sub(self.sequence.len()).map(|l| l + 1);
+        match last {
+            Some(last) if self.offset < last => {
+                if self.anchored {
+                    self.offset..self.offset + 1
+                } else {
+                    self.offset..last
+                }
+            }
+            _ => 0..0,
+        }
+    }
+}
+
+/// Parses the position of the barcode from a 10x feature pattern (e.g. `^NNNNNNNNNN(BC)`
+/// or `5PNNNNNNNNNN(BC)`) as its offset and whether it is anchored to the start of the
+/// read
+fn parse_pattern(pattern: &str) -> Result<(usize, bool)> {
+    let (anchored, rest) = if let Some(rest) = pattern.strip_prefix('^') {
+        (true, rest)
+    } else if let Some(rest) = pattern.strip_prefix("5P") {
+        (true, rest)
+    } else {
+        (false, pattern)
+    };
+    let offset = rest.bytes().take_while(|b| *b == b'N').count();
+    if !rest[offset..].starts_with("(BC)") {
+        bail!(
+            "Unsupported feature pattern '{}' (expected e.g. `^NNNNNNNNNN(BC)`)",
+            pattern
+        );
+    }
+    Ok((offset, anchored))
+}
+
+/// A reference of feature barcodes matched at fixed positions of R2
+#[derive(Debug, Clone)]
+pub struct FeatureReference {
+    features: Vec<Feature>,
+    exact: HashMap<Vec<u8>, usize>,
+    anchors: Vec<(usize, usize)>,
+    mismatches: usize,
+}
+impl FeatureReference {
+    pub fn from_file(path: &str, offset: usize, mismatches: usize) -> Result<Self> {
+        let reader = File::open(path).map(BufReader::new)?;
+        Self::from_buffer(reader, offset, mismatches)
+    }
+
+    /// Parses a feature reference.
+    ///
+    /// A 10x-style `feature_reference.csv` (with an `id,name,read,pattern,sequence,
+    /// feature_type` header in any order) places each barcode by its pattern. Otherwise
+    /// the reference is a list of `id, name, sequence[, feature_type]` rows (tab or comma
+    /// separated) matched at `offset`, where rows of `name, sequence` use the name as the
+    /// ID and a header line is skipped.
+    pub fn from_buffer<R: BufRead>(reader: R, offset: usize, mismatches: usize) -> Result<Self> {
+        let mut features = Vec::new();
+        let mut columns = None;
+        for (line_num, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields = line
+                .trim()
+                .split(['\t', ','])
+                .map(|f| f.trim())
+                .collect::<Vec<_>>();
+            if line_num == 0 && fields.contains(&"pattern") {
+                columns = Some(TenxColumns::from_header(&fields)?);
+                continue;
+            }
+            if let Some(columns) = &columns {
+                features.push(columns.parse(&fields, line_num)?);
+                continue;
+            }
+            let (id, name, sequence, feature_type) = match fields.as_slice() {
+                [name, sequence] => (*name, *name, *sequence, DEFAULT_FEATURE_TYPE),
+                [id, name, sequence] => (*id, *name, *sequence, DEFAULT_FEATURE_TYPE),
+                [id, name, sequence, feature_type, ..] => (*id, *name, *sequence, *feature_type),
+                _ => bail!(
+                    "Expected `id, name, sequence` on line {} of the feature reference",
+                    line_num + 1
+                ),
+            };
+            if !Barcodes::is_nucleotide(sequence) {
+                if line_num == 0 {
+                    continue;
+                }
+                bail!(
+                    "Invalid feature barcode '{}' on line {} of the feature reference",
+                    sequence,
+                    line_num + 1
+                );
+            }
+            features.push(Feature {
+                id: id.to_string(),
+                name: name.to_string(),
+                sequence: sequence.to_ascii_uppercase().into_bytes(),
+                feature_type: feature_type.to_string(),
+                offset,
+                anchored: true,
+            });
+        }
+        Self::new(features, mismatches)
+    }
+
+    /// Indexes a set of features
+    pub fn new(features: Vec<Feature>, mismatches: usize) -> Result<Self> {
+        if features.is_empty() {
+            bail!("The feature reference is empty");
+        }
+        let mut exact = HashMap::new();
+        for (idx, feature) in features.iter().enumerate() {
+            if exact.insert(feature.sequence.clone(), idx).is_some() {
+                bail!(
+                    "Feature barcode {} is listed more than once",
+                    String::from_utf8_lossy(&feature.sequence)
+                );
+            }
+        }
+        let mut anchors = features
+            .iter()
+            .filter(|f| f.anchored)
+            .map(|f| (f.offset, f.sequence.len()))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        anchors.sort_unstable();
+        Ok(Self {
+            features,
+            exact,
+            anchors,
+            mismatches,
+        })
+    }
+
+    /// Matches the feature barcode of a read.
+    ///
+    /// Exact matches are taken first, otherwise the feature with the fewest mismatches
+    /// (within the tolerance) is returned as long as it is unique.
+    pub fn match_read(&self, seq: &[u8]) -> Option<usize> {
+        for (offset, len) in &self.anchors {
+            if let Some(idx) = seq
+                .get(*offset..offset + len)
+                .and_then(|s| self.exact.get(s))
+                .filter(|idx| self.features[**idx].positions(seq).contains(offset))
+            {
+                return Some(*idx);
+            }
+        }
+        let mut best = None;
+        let mut best_dist = self.mismatches + 1;
+        let mut ties = 0;
+        for (idx, feature) in self.features.iter().enumerate() {
+            if feature.anchored && self.mismatches == 0 {
+                continue;
+            }
+            let len = feature.sequence.len();
+            let Some(dist) = feature
+                .positions(seq)
+                .map(|pos| {
+                    seq[pos..pos + len]
+                        .iter()
+                        .zip(&feature.sequence)
+                        .filter(|(a, b)| a != b)
+                        .count()
+                })
+                .min()
+            else {
+                continue;
+            };
+            if dist < best_dist {
+                best = Some(idx);
+                best_dist = dist;
+                ties = 0;
+            } else if dist == best_dist {
+                ties += 1;
+            }
+        }
+        best.filter(|_| ties == 0)
+    }
+
+    pub fn features(&self) -> &[Feature] {
+        &self.features
+    }
+
+    pub fn len(&self) -> usize {
+        self.features.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.features.is_empty()
+    }
+}
+
+/// The columns of a 10x-style `feature_reference.csv`
+struct TenxColumns {
+    id: usize,
+    name: usize,
+    read: usize,
+    pattern: usize,
+    sequence: usize,
+    feature_type: usize,
+}
+impl TenxColumns {
+    fn from_header(header: &[&str]) -> Result<Self> {
+        let column = |name: &str| match header.iter().position(|h| *h == name) {
+            Some(idx) => Ok(idx),
+            None => Err(anyhow!(
+                "The feature reference is missing the `{}` column",
+                name
+            )),
+        };
+        Ok(Self {
+            id: column("id")?,
+            name: column("name")?,
+            read: column("read")?,
+            pattern: column("pattern")?,
+            sequence: column("sequence")?,
+            feature_type: column("feature_type")?,
+        })
+    }
+
+    fn parse(&self, fields: &[&str], line_num: usize) -> Result<Feature> {
+        let field = |idx: usize| match fields.get(idx) {
+            Some(field) => Ok(*field),
+            None => Err(anyhow!(
+                "Missing columns on line {} of the feature reference",
+                line_num + 1
+            )),
+        };
+        let sequence = field(self.sequence)?;
+        if !Barcodes::is_nucleotide(sequence) {
+            bail!(
+                "Invalid feature barcode '{}' on line {} of the feature reference",
+                sequence,
+                line_num + 1
+            );
+        }
+        // R1 holds the cell barcode and UMI so features can only be matched in R2
+        let read = field(self.read)?;
+        if read != "R2" {
+            bail!(
+                "Feature barcodes must be in R2 but line {} of the feature reference is in {}",
+                line_num + 1,
+                read
+            );
+        }
+        let (offset, anchored) = parse_pattern(field(self.pattern)?)?;
+        Ok(Feature {
+            id: field(self.id)?.to_string(),
+            name: field(self.name)?.to_string(),
+            sequence: sequence.to_ascii_uppercase().into_bytes(),
+            feature_type: field(self.feature_type)?.to_string(),
+            offset,
+            anchored,
+        })
+    }
+}
+
+/// A `(feature, barcode index, UMIs)` entry of the count matrix
+pub type MatrixEntry = (usize, usize, usize);
+
+/// The distinct UMIs observed for each barcode and feature
+#[derive(Debug, Default, Clone)]
+pub struct FeatureCounts {
+    umis: HashSet<(Vec<u8>, usize, Vec<u8>)>,
+    /// An upper estimate of the memory held by the distinct UMIs
+    bytes: usize,
+}
+impl FeatureCounts {
+    pub fn insert(&mut self, barcode: &[u8], feature: usize, umi: &[u8]) {
+        if self.umis.insert((barcode.to_vec(), feature, umi.to_vec())) {
+            self.bytes += memory::FEATURE_UMI_BYTES + barcode.len() + umi.len();
+        }
+    }
+
+    /// Returns an upper estimate of the memory held by the distinct UMIs
+    pub fn memory_estimate(&self) -> usize {
+        self.bytes
+    }
+
+    /// Counts the UMIs of each barcode and feature, returning the sorted barcodes and the
+    /// matrix entries
+    pub fn matrix(&self) -> (Vec<&[u8]>, Vec<MatrixEntry>) {
+        let mut counts = HashMap::<(&[u8], usize), usize>::new();
+        for (barcode, feature, _umi) in &self.umis {
+            *counts.entry((barcode.as_slice(), *feature)).or_default() += 1;
+        }
+        let mut barcodes = counts
+            .keys()
+            .map(|(barcode, _)| *barcode)
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        barcodes.sort_unstable();
+        let columns = barcodes
+            .iter()
+            .enumerate()
+            .map(|(idx, barcode)| (*barcode, idx))
+            .collect::<HashMap<_, _>>();
+        let mut entries = counts
+            .into_iter()
+            .map(|((barcode, feature), umis)| (feature, columns[barcode], umis))
+            .collect::<Vec<_>>();
+        entries.sort_unstable();
+        (barcodes, entries)
+    }
+
+    /// Writes the feature by barcode UMI count matrix as a 10x-style triplet of a
+    /// MatrixMarket matrix, its barcodes (columns) and its features (rows)
+    pub fn to_files(
+        &self,
+        reference: &FeatureReference,
+        matrix_path: &str,
+        barcodes_path: &str,
+        features_path: &str,
+    ) -> Result<()> {
+        let (barcodes, entries) = self.matrix();
+        let mut writer = File::create(matrix_path).map(BufWriter::new)?;
+        writeln!(writer, "%%MatrixMarket matrix coordinate integer general")?;
+        writeln!(
+            writer,
+            "{} {} {}",
+            reference.len(),
+            barcodes.len(),
+            entries.len()
+        )?;
+        for (feature, barcode, umis) in entries {
+            writeln!(writer, "{} {} {}", feature + 1, barcode + 1, umis)?;
+        }
+        writer.flush()?;
+
+        let mut writer = File::create(barcodes_path).map(BufWriter::new)?;
+        for barcode in barcodes {
+            writer.write_all(barcode)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+
+        let mut writer = File::create(features_path).map(BufWriter::new)?;
+        for feature in reference.features() {
+            writeln!(
+                writer,
+                "{}\t{}\t{}",
+                feature.id, feature.name, feature.feature_type
+            )?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    const REFERENCE: &str =
+        "id,name,sequence\nCD3,CD3_TotalSeqB,AACAAGACCCTTGAG\nCD4,CD4_TotalSeqB,TACCCGTAATAGCGT\n";
+
+    #[test]
+    fn parse() {
+        let reference = FeatureReference::from_buffer(REFERENCE.as_bytes(), 0, 1).unwrap();
+        assert_eq!(reference.len(), 2);
+        assert_eq!(reference.features()[1].name, "CD4_TotalSeqB");
+        assert_eq!(reference.features()[1].feature_type, DEFAULT_FEATURE_TYPE);
+
+        let reference = FeatureReference::from_buffer("HTO1\tACGT\n".as_bytes(), 0, 0).unwrap();
+        assert_eq!(reference.features()[0].id, "HTO1");
+        assert!(FeatureReference::from_buffer("a,ACGT\nb,ACGT\n".as_bytes(), 0, 0).is_err());
+        assert!(FeatureReference::from_buffer("a,ACGT\nb,XYZ\n".as_bytes(), 0, 0).is_err());
+    }
+
+    #[test]
+    fn matching() {
+        let reference = FeatureReference::from_buffer(REFERENCE.as_bytes(), 2, 1).unwrap();
+        assert_eq!(reference.match_read(b"NNAACAAGACCCTTGAGTTTT"), Some(0));
+        assert_eq!(reference.match_read(b"NNTACCCGTAATAGCGA"), Some(1));
+        assert_eq!(reference.match_read(b"NNTACCCGTAATAGCAA"), None);
+        assert_eq!(reference.match_read(b"NNTACCCGTAATAG"), None);
+
+        let exact = FeatureReference::from_buffer(REFERENCE.as_bytes(), 2, 0).unwrap();
+        assert_eq!(exact.match_read(b"NNTACCCGTAATAGCGA"), None);
+    }
+
+    #[test]
+    fn ambiguous() {
+        let reference = FeatureReference::from_buffer("a,AAAA\nb,AATT\n".as_bytes(), 0, 1).unwrap();
+        assert_eq!(reference.match_read(b"AAAT"), None);
+        assert_eq!(reference.match_read(b"AAAC"), Some(0));
+    }
+
+    #[test]
+    fn tenx_reference() {
+        let csv = "id,name,read,pattern,sequence,feature_type\n\
+            CD3,CD3_TotalSeqB,R2,5PNNNNNNNNNN(BC),AACAAGACCCTTGAG,Antibody Capture\n\
+            HTO1,Hashtag1,R2,^(BC),GTCAACTCTTTAGCG,Antibody Capture\n\
+            CD4,CD4_TotalSeqB,R2,(BC),TACCCGTAATAGCGT,Antibody Capture\n";
+        let reference = FeatureReference::from_buffer(csv.as_bytes(), 5, 0).unwrap();
+        assert_eq!(reference.features()[0].offset, 10);
+        assert_eq!(reference.features()[1].offset, 0);
+        assert!(!reference.features()[2].anchored);
+        assert_eq!(
+            reference.match_read(b"NNNNNNNNNNAACAAGACCCTTGAGTT"),
+            Some(0)
+        );
+        assert_eq!(reference.match_read(b"AACAAGACCCTTGAGTT"), None);
+        assert_eq!(reference.match_read(b"GTCAACTCTTTAGCGTT"), Some(1));
+        assert_eq!(reference.match_read(b"TTTTACCCGTAATAGCGTT"), Some(2));
+
+        let csv =
+            "id,name,read,pattern,sequence,feature_type\nCD3,CD3,R1,^(BC),ACGT,Antibody Capture\n";
+        assert!(FeatureReference::from_buffer(csv.as_bytes(), 0, 0).is_err());
+        let csv =
+            "id,name,read,pattern,sequence,feature_type\nCD3,CD3,R2,(BC)3P,ACGT,Antibody Capture\n";
+        assert!(FeatureReference::from_buffer(csv.as_bytes(), 0, 0).is_ok());
+        assert!(parse_pattern("NN[BC]").is_err());
+    }
+
+    #[test]
+    fn counts() {
+        let mut counts = FeatureCounts::default();
+        counts.insert(b"TTTT", 1, b"AAA");
+        counts.insert(b"TTTT", 1, b"AAA");
+        counts.insert(b"TTTT", 1, b"CCC");
+        counts.insert(b"GGGG", 0, b"AAA");
+        let (barcodes, entries) = counts.matrix();
+        assert_eq!(barcodes, vec![b"GGGG".as_slice(), b"TTTT".as_slice()]);
+        assert_eq!(entries, vec![(0, 0, 1), (1, 1, 2)]);
+    }
+}
@@ -0,0 +1,317 @@
+//! A C ABI over config loading and per-read matching.
+//!
+//! Configs are opaque handles returned by [`pipspeak_config_load`] and released with
+//! [`pipspeak_config_free`]. Functions that can fail record a message retrievable with
+//! [`pipspeak_last_error`] on the calling thread. The declarations are in
+//! `include/pipspeak.h`.
+
+use crate::config::{Config, Filtered};
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_int, CStr, CString},
+    ptr, slice,
+};
+
+/// The return code of a matched read
+pub const PIPSPEAK_MATCHED: c_int = 0;
+/// The return code of a read failing a fixed sequence
+pub const PIPSPEAK_FILTERED_FIXED: c_int = 5;
+/// The return code of a read too short to contain the UMI
+pub const PIPSPEAK_FILTERED_UMI: c_int = 6;
+/// The return code of invalid arguments or a UMI which does not fit its buffer
+pub const PIPSPEAK_ERROR: c_int = -1;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+}
+
+/// The barcode indices of a matched read
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct PipspeakMatch {
+    /// The within-set barcode index for each of the 4 tiers
+    pub indices: [usize; 4],
+    /// The position of the first nucleotide after the read structure
+    pub end: usize,
+    /// The shift of the first anchoring segment from its expected position
+    pub shift: usize,
+    /// Whether each barcode tier was matched with a mismatch
+    pub corrected: [bool; 4],
+}
+
+/// Returns the last error recorded on the calling thread (or null if none).
+///
+/// The message remains valid until the next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn pipspeak_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |m| m.as_ptr()))
+}
+
+/// Loads a config file, returning null on failure.
+///
+/// # Safety
+///
+/// `path` must be a valid nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn pipspeak_config_load(
+    path: *const c_char,
+    exact: bool,
+    linkers: bool,
+) -> *mut Config {
+    if path.is_null() {
+        set_error("Config path is null".to_string());
+        return ptr::null_mut();
+    }
+    let path = match CStr::from_ptr(path).to_str() {
+        Ok(path) => path,
+        Err(e) => {
+            set_error(format!("Config path is not valid UTF-8: {}", e));
+            return ptr::null_mut();
+        }
+    };
+    match Config::from_file(path, exact, linkers) {
+        Ok(config) => Box::into_raw(Box::new(config)),
+        Err(e) => {
+            set_error(format!("{:#}", e));
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Releases a config returned by [`pipspeak_config_load`].
+///
+/// # Safety
+///
+/// `config` must be null or a handle returned by [`pipspeak_config_load`] which has not
+/// already been released.
+#[no_mangle]
+pub unsafe extern "C" fn pipspeak_config_free(config: *mut Config) {
+    if !config.is_null() {
+        drop(Box::from_raw(config));
+    }
+}
+
+/// Returns the total length of the UMI of a config given the default length of unsized
+/// UMI segments, or 0 if `config` is null.
+///
+/// This includes split UMI segments and captured random spacers, and is the capacity
+/// needed for the UMI of [`pipspeak_match_read`].
+///
+/// # Safety
+///
+/// `config` must be null or a live handle.
+#[no_mangle]
+pub unsafe extern "C" fn pipspeak_config_umi_len(config: *const Config, umi_len: usize) -> usize {
+    if config.is_null() {
+        set_error("Config is null".to_string());
+        return 0;
+    }
+    (*config).umi_len(umi_len)
+}
+
+/// Matches a read against the read structure of a config.
+///
+/// A negative `offset` searches for the anchoring segment anywhere in the read. The UMI
+/// is copied to `umi` if it is not null and its length written to `umi_written` if it is
+/// not null. A UMI longer than `umi_capacity` is not copied and returns
+/// [`PIPSPEAK_ERROR`] (with its length still written to `umi_written`); size the buffer
+/// with [`pipspeak_config_umi_len`].
+///
+/// Returns [`PIPSPEAK_MATCHED`] on a match, the 1-indexed tier a read failed to match,
+/// [`PIPSPEAK_FILTERED_FIXED`], [`PIPSPEAK_FILTERED_UMI`], or [`PIPSPEAK_ERROR`] on
+/// invalid arguments.
+///
+/// # Safety
+///
+/// `config` must be a live handle, `seq` must point to `len` readable bytes, `out` and
+/// `umi_written` must be null or writable, and `umi` must be null or point to
+/// `umi_capacity` writable bytes.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn pipspeak_match_read(
+    config: *const Config,
+    seq: *const u8,
+    len: usize,
+    offset: isize,
+    umi_len: usize,
+    out: *mut PipspeakMatch,
+    umi: *mut u8,
+    umi_capacity: usize,
+    umi_written: *mut usize,
+) -> c_int {
+    if config.is_null() || (seq.is_null() && len > 0) {
+        set_error("Config or sequence is null".to_string());
+        return PIPSPEAK_ERROR;
+    }
+    let seq = if len == 0 {
+        &[]
+    } else {
+        slice::from_raw_parts(seq, len)
+    };
+    let offset = usize::try_from(offset).ok();
+    match (*config).match_read(seq, offset, umi_len) {
+        Ok(read_match) => {
+            if !umi_written.is_null() {
+                *umi_written = read_match.umi.len();
+            }
+            if !umi.is_null() && read_match.umi.len() > umi_capacity {
+                set_error(format!(
+                    "UMI of {} bytes does not fit in a buffer of {} bytes",
+                    read_match.umi.len(),
+                    umi_capacity
+                ));
+                return PIPSPEAK_ERROR;
+            }
+            if !out.is_null() {
+                *out = PipspeakMatch {
+                    indices: read_match.indices,
+                    end: read_match.end,
+                    shift: read_match.shift,
+                    corrected: read_match.corrected,
+                };
+            }
+            if !umi.is_null() {
+                ptr::copy_nonoverlapping(read_match.umi.as_ptr(), umi, read_match.umi.len());
+            }
+            PIPSPEAK_MATCHED
+        }
+        Err(Filtered::Tier(tier)) => tier as c_int + 1,
+        Err(Filtered::Fixed) => PIPSPEAK_FILTERED_FIXED,
+        Err(Filtered::Umi) => PIPSPEAK_FILTERED_UMI,
+    }
+}
+
+/// Writes the cell barcode of the 4 barcode indices to `out`.
+///
+/// Returns the length of the barcode, which is only written if it fits in `capacity`
+/// bytes (recording an error otherwise), or 0 on invalid arguments.
+///
+/// # Safety
+///
+/// `config` must be a live handle, `indices` must point to 4 indices and `out` must be
+/// null or point to `capacity` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn pipspeak_build_barcode(
+    config: *const Config,
+    indices: *const usize,
+    out: *mut u8,
+    capacity: usize,
+) -> usize {
+    if config.is_null() || indices.is_null() {
+        set_error("Config or indices are null".to_string());
+        return 0;
+    }
+    let indices = slice::from_raw_parts(indices, 4);
+    let config = &*config;
+    if indices
+        .iter()
+        .zip(config.tier_sizes())
+        .any(|(idx, size)| *idx >= size)
+    {
+        set_error("Barcode index out of range".to_string());
+        return 0;
+    }
+    let barcode = config.build_barcode(indices[0], indices[1], indices[2], indices[3]);
+    if !out.is_null() {
+        if barcode.len() <= capacity {
+            ptr::copy_nonoverlapping(barcode.as_ptr(), out, barcode.len());
+        } else {
+            set_error(format!(
+                "Barcode of {} bytes does not fit in a buffer of {} bytes",
+                barcode.len(),
+                capacity
+            ));
+        }
+    }
+    barcode.len()
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    const TEST_READ: &[u8] = b"NNAGAAACCAATGTCTGTGGAGAAAGTGTCGAGCTGGGTATACGTACGTACGTTTTT";
+
+    #[test]
+    fn match_read() {
+        let path = CString::new("data/config_v3.yaml").unwrap();
+        unsafe {
+            let config = pipspeak_config_load(path.as_ptr(), false, false);
+            assert!(!config.is_null());
+
+            assert_eq!(pipspeak_config_umi_len(config, 12), 12);
+
+            let mut out = PipspeakMatch::default();
+            let mut umi = [0u8; 12];
+            let mut written = 0;
+            let code = pipspeak_match_read(
+                config,
+                TEST_READ.as_ptr(),
+                TEST_READ.len(),
+                5,
+                12,
+                &mut out,
+                umi.as_mut_ptr(),
+                umi.len(),
+                &mut written,
+            );
+            assert_eq!(code, PIPSPEAK_MATCHED);
+            assert_eq!(written, 12);
+            assert_eq!(&umi, b"ACGTACGTACGT");
+
+            // a UMI which does not fit its buffer is an error rather than an overflow
+            let mut short = [0u8; 8];
+            let code = pipspeak_match_read(
+                config,
+                TEST_READ.as_ptr(),
+                TEST_READ.len(),
+                5,
+                12,
+                &mut out,
+                short.as_mut_ptr(),
+                short.len(),
+                &mut written,
+            );
+            assert_eq!(code, PIPSPEAK_ERROR);
+            assert_eq!(written, 12);
+            assert_eq!(short, [0u8; 8]);
+
+            let mut barcode = [0u8; 64];
+            let len = pipspeak_build_barcode(
+                config,
+                out.indices.as_ptr(),
+                barcode.as_mut_ptr(),
+                barcode.len(),
+            );
+            assert_eq!(&barcode[..len], b"AGAAACCATCTGTGAAAGTGCTGGGTAT");
+
+            let code = pipspeak_match_read(
+                config,
+                b"ACGT".as_ptr(),
+                4,
+                5,
+                12,
+                &mut out,
+                ptr::null_mut(),
+                0,
+                ptr::null_mut(),
+            );
+            assert_eq!(code, 1);
+            pipspeak_config_free(config);
+        }
+    }
+
+    #[test]
+    fn load_error() {
+        let path = CString::new("missing.yaml").unwrap();
+        unsafe {
+            assert!(pipspeak_config_load(path.as_ptr(), false, false).is_null());
+            assert!(!pipspeak_last_error().is_null());
+        }
+    }
+}
@@ -0,0 +1,288 @@
+use crate::features::{FeatureCounts, FeatureReference};
+use anyhow::Result;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufWriter, Write},
+};
+
+/// The feature type of hashtags in a 10x-style feature reference
+pub const HASHTAG_FEATURE_TYPE: &str = "Multiplexing Capture";
+
+/// The default minimum number of hashtag UMIs of a barcode to be classified
+pub const DEFAULT_MIN_UMIS: usize = 10;
+
+/// The classification of a barcode by its hashtag counts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Assignment {
+    /// No hashtag is enriched
+    Negative,
+    /// A single hashtag (the index into the hashtags) is enriched
+    Singlet(usize),
+    /// More than one hashtag is enriched
+    Doublet,
+}
+
+/// The number of barcodes of each classification
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct DemuxSummary {
+    pub num_classified: usize,
+    pub num_singlets: usize,
+    pub num_doublets: usize,
+    pub num_negatives: usize,
+    /// The number of singlets of each hashtag
+    pub singlets: BTreeMap<String, usize>,
+}
+
+/// The hashtag classification of each barcode with enough hashtag UMIs
+#[derive(Debug)]
+pub struct Demultiplexed {
+    /// The names of the hashtags
+    hashtags: Vec<String>,
+    /// The barcodes with their hashtag UMIs, positive hashtags and assignment
+    barcodes: Vec<(Vec<u8>, usize, Vec<usize>, Assignment)>,
+}
+impl Demultiplexed {
+    /// Classifies the barcodes by their hashtag UMI counts.
+    ///
+    /// The counts of each hashtag are normalized with a centered log-ratio across the
+    /// barcodes and split into a background and an enriched group with a two-medoid
+    /// clustering. Barcodes enriched for exactly one hashtag are assigned to it, barcodes
+    /// enriched for several are doublets and the rest are negatives. If the reference
+    /// has `Multiplexing Capture` features only those are used as hashtags.
+    pub fn new(counts: &FeatureCounts, reference: &FeatureReference, min_umis: usize) -> Self {
+        let features = reference.features();
+        let mut columns = features
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| f.feature_type == HASHTAG_FEATURE_TYPE)
+            .map(|(idx, _)| idx)
+            .collect::<Vec<_>>();
+        if columns.is_empty() {
+            columns = (0..features.len()).collect();
+        }
+        let hashtags = columns
+            .iter()
+            .map(|idx| features[*idx].name.clone())
+            .collect::<Vec<_>>();
+
+        let (barcodes, entries) = counts.matrix();
+        let mut matrix = vec![vec![0; columns.len()]; barcodes.len()];
+        for (feature, barcode, umis) in entries {
+            if let Some(col) = columns.iter().position(|c| *c == feature) {
+                matrix[barcode][col] = umis;
+            }
+        }
+        let (barcodes, matrix): (Vec<_>, Vec<_>) = barcodes
+            .into_iter()
+            .zip(matrix)
+            .filter(|(_, row)| row.iter().sum::<usize>() >= min_umis)
+            .unzip();
+
+        let mut positives = vec![Vec::new(); barcodes.len()];
+        for col in 0..hashtags.len() {
+            let clr = clr(&matrix.iter().map(|row| row[col]).collect::<Vec<_>>());
+            if let Some(threshold) = two_medoid_threshold(&clr) {
+                for (row, value) in clr.iter().enumerate() {
+                    if *value >= threshold {
+                        positives[row].push(col);
+                    }
+                }
+            }
+        }
+        let barcodes = barcodes
+            .into_iter()
+            .zip(matrix)
+            .zip(positives)
+            .map(|((barcode, row), positive)| {
+                let assignment = match positive.as_slice() {
+                    [] => Assignment::Negative,
+                    [col] => Assignment::Singlet(*col),
+                    _ => Assignment::Doublet,
+                };
+                (barcode.to_vec(), row.iter().sum(), positive, assignment)
+            })
+            .collect();
+        Self { hashtags, barcodes }
+    }
+
+    /// Returns the names of the hashtags
+    pub fn hashtags(&self) -> &[String] {
+        &self.hashtags
+    }
+
+    /// Returns the classified barcodes and their assignments
+    pub fn assignments(&self) -> impl Iterator<Item = (&[u8], Assignment)> {
+        self.barcodes
+            .iter()
+            .map(|(barcode, _, _, assignment)| (barcode.as_slice(), *assignment))
+    }
+
+    pub fn summary(&self) -> DemuxSummary {
+        let mut summary = DemuxSummary {
+            num_classified: self.barcodes.len(),
+            singlets: self.hashtags.iter().map(|h| (h.clone(), 0)).collect(),
+            ..DemuxSummary::default()
+        };
+        for (_, assignment) in self.assignments() {
+            match assignment {
+                Assignment::Negative => summary.num_negatives += 1,
+                Assignment::Doublet => summary.num_doublets += 1,
+                Assignment::Singlet(col) => {
+                    summary.num_singlets += 1;
+                    *summary
+                        .singlets
+                        .entry(self.hashtags[col].clone())
+                        .or_default() += 1;
+                }
+            }
+        }
+        summary
+    }
+
+    /// Writes the classification of every barcode as a TSV of the barcode, its
+    /// classification (a hashtag, `Doublet` or `Negative`), its hashtag UMIs and its
+    /// enriched hashtags
+    pub fn assignments_to_file(&self, path: &str) -> Result<()> {
+        let mut writer = File::create(path).map(BufWriter::new)?;
+        writeln!(writer, "barcode\tclassification\thashtag_umis\tpositive")?;
+        for (barcode, umis, positive, assignment) in &self.barcodes {
+            let classification = match assignment {
+                Assignment::Negative => "Negative",
+                Assignment::Doublet => "Doublet",
+                Assignment::Singlet(col) => self.hashtags[*col].as_str(),
+            };
+            let positive = positive
+                .iter()
+                .map(|col| self.hashtags[*col].as_str())
+                .collect::<Vec<_>>()
+                .join(",");
+            writer.write_all(barcode)?;
+            writeln!(writer, "\t{}\t{}\t{}", classification, umis, positive)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Writes the singlet barcodes of a hashtag (one per line)
+    pub fn cells_to_file(&self, hashtag: usize, path: &str) -> Result<()> {
+        let mut writer = File::create(path).map(BufWriter::new)?;
+        for (barcode, assignment) in self.assignments() {
+            if assignment == Assignment::Singlet(hashtag) {
+                writer.write_all(barcode)?;
+                writer.write_all(b"\n")?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Normalizes counts with a centered log-ratio: `ln(1 + x / g)` where `g` is the
+/// geometric mean of `1 + x` over all values
+fn clr(counts: &[usize]) -> Vec<f64> {
+    if counts.is_empty() {
+        return Vec::new();
+    }
+    let log_mean = counts.iter().map(|c| (*c as f64).ln_1p()).sum::<f64>() / counts.len() as f64;
+    let geometric_mean = log_mean.exp();
+    counts
+        .iter()
+        .map(|c| (*c as f64 / geometric_mean).ln_1p())
+        .collect()
+}
+
+/// Splits values into two clusters around their medoids (minimizing the total absolute
+/// deviation) and returns the smallest value of the upper cluster, or `None` if the
+/// values are all equal
+fn two_medoid_threshold(values: &[f64]) -> Option<f64> {
+    let mut sorted = values.to_vec();
+    sorted.sort_unstable_by(|a, b| a.total_cmp(b));
+    let n = sorted.len();
+    if n < 2 || sorted[0] == sorted[n - 1] {
+        return None;
+    }
+    let mut prefix = vec![0.0; n + 1];
+    for (idx, value) in sorted.iter().enumerate() {
+        prefix[idx + 1] = prefix[idx] + value;
+    }
+    // the total absolute deviation of `sorted[start..end]` around its median
+    let cost = |start: usize, end: usize| {
+        let mid = start + (end - start) / 2;
+        let median = sorted[mid];
+        (median * (mid - start) as f64 - (prefix[mid] - prefix[start]))
+            + ((prefix[end] - prefix[mid]) - median * (end - mid) as f64)
+    };
+    (1..n)
+        .filter(|split| sorted[split - 1] < sorted[*split])
+        .min_by(|a, b| (cost(0, *a) + cost(*a, n)).total_cmp(&(cost(0, *b) + cost(*b, n))))
+        .map(|split| sorted[split])
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn thresholds() {
+        assert_eq!(
+            two_medoid_threshold(&[0.1, 0.2, 0.15, 3.0, 3.2, 0.0]),
+            Some(3.0)
+        );
+        assert_eq!(two_medoid_threshold(&[1.0, 1.0, 1.0]), None);
+        assert_eq!(two_medoid_threshold(&[1.0]), None);
+        let clr = clr(&[0, 1, 100]);
+        assert!(clr[0] == 0.0 && clr[1] < clr[2]);
+    }
+
+    #[test]
+    fn demultiplex() {
+        let reference = FeatureReference::from_buffer(
+            "HTO1,AAAAAA\nHTO2,CCCCCC\nHTO3,GGGGGG\n".as_bytes(),
+            0,
+            0,
+        )
+        .unwrap();
+        let mut counts = FeatureCounts::default();
+        let mut umi = 0u32;
+        let mut add = |barcode: &[u8], feature: usize, n: usize| {
+            for _ in 0..n {
+                umi += 1;
+                counts.insert(barcode, feature, &umi.to_le_bytes());
+            }
+        };
+        // singlets of each hashtag with a little background
+        for (idx, barcode) in [b"AAAC", b"AAAG", b"AAAT", b"CCCA", b"CCCG", b"GGGA"]
+            .iter()
+            .enumerate()
+        {
+            let hashtag = idx / 2;
+            add(*barcode, hashtag, 100);
+            add(*barcode, (hashtag + 1) % 3, 2);
+        }
+        add(b"TTTA", 0, 80);
+        add(b"TTTA", 1, 90);
+        add(b"TTTC", 0, 3);
+        add(b"TTTC", 1, 3);
+        add(b"TTTC", 2, 4);
+        add(b"TTTG", 2, 1);
+
+        let demux = Demultiplexed::new(&counts, &reference, 10);
+        let assignments = demux
+            .assignments()
+            .map(|(b, a)| (b.to_vec(), a))
+            .collect::<BTreeMap<_, _>>();
+        assert_eq!(assignments.len(), 8);
+        assert_eq!(assignments[b"AAAC".as_slice()], Assignment::Singlet(0));
+        assert_eq!(assignments[b"CCCG".as_slice()], Assignment::Singlet(2));
+        assert_eq!(assignments[b"TTTA".as_slice()], Assignment::Doublet);
+        assert_eq!(assignments[b"TTTC".as_slice()], Assignment::Negative);
+
+        let summary = demux.summary();
+        assert_eq!(summary.num_singlets, 6);
+        assert_eq!(summary.num_doublets, 1);
+        assert_eq!(summary.num_negatives, 1);
+        assert_eq!(summary.singlets["HTO2"], 2);
+    }
+}
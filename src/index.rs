@@ -0,0 +1,300 @@
+use crate::{error::Error, pairing::read_name};
+use anyhow::{bail, Result};
+use fxread::Record;
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    rc::Rc,
+    sync::{Arc, Mutex},
+};
+
+/// The SAM tag of the sample index (as used by `samtools import -T` and `bwa mem -C`)
+pub const INDEX_TAG: &[u8; 2] = b"BC";
+
+/// Returns true if a read description follows the Illumina
+/// `<read>:<filtered>:<control>:<index>` convention
+fn is_illumina(description: &[u8]) -> bool {
+    let fields = description.split(|b| *b == b':').collect::<Vec<_>>();
+    fields.len() == 4
+        && fields[0].iter().all(|b| b.is_ascii_digit())
+        && matches!(fields[1], b"Y" | b"N")
+}
+
+/// Folds an index sequence into a read ID.
+///
+/// An Illumina description has its index field replaced, otherwise the index is
+/// appended to the description as a `BC:Z:<index>` comment.
+pub fn fold(id: &[u8], index: &[u8]) -> Vec<u8> {
+    let mut folded = Vec::new();
+    fold_into(id, index, &mut folded);
+    folded
+}
+
+/// Folds an index sequence into a read ID (see [`fold`]), writing it into a buffer
+fn fold_into(id: &[u8], index: &[u8], folded: &mut Vec<u8>) {
+    folded.clear();
+    let end = id
+        .iter()
+        .position(|b| b.is_ascii_whitespace())
+        .unwrap_or(id.len());
+    let (name, description) = (&id[..end], id.get(end + 1..).unwrap_or_default());
+    if is_illumina(description) {
+        let field = description.iter().rposition(|b| *b == b':').unwrap_or(0);
+        folded.extend_from_slice(name);
+        folded.push(b' ');
+        folded.extend_from_slice(&description[..=field]);
+    } else {
+        folded.extend_from_slice(id);
+        folded.extend_from_slice(b" BC:Z:");
+    }
+    folded.extend_from_slice(index);
+}
+
+/// Returns the index folded into a read ID (see [`fold`]) if there is one
+pub fn index_of(id: &[u8]) -> Option<&[u8]> {
+    let end = id.iter().position(|b| b.is_ascii_whitespace())?;
+    let description = &id[end + 1..];
+    if is_illumina(description) {
+        let field = description.iter().rposition(|b| *b == b':')?;
+        return Some(&description[field + 1..]).filter(|index| !index.is_empty());
+    }
+    description
+        .split(|b| b.is_ascii_whitespace())
+        .find_map(|field| field.strip_prefix(b"BC:Z:"))
+}
+
+/// The first problem found while folding the index reads into a read input
+#[derive(Debug, Default, Clone)]
+pub struct IndexError(Arc<Mutex<Option<String>>>);
+impl IndexError {
+    fn set(&self, message: String) {
+        let mut error = self.0.lock().expect("Index error lock poisoned");
+        error.get_or_insert(message);
+    }
+
+    /// Fails if the index reads were out of sync with their reads
+    pub fn check(&self) -> Result<()> {
+        match self.0.lock().expect("Index error lock poisoned").as_ref() {
+            Some(message) => bail!(Error::Pairing(message.clone().into())),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The index reads of an input pair, read once and shared by the R1 and R2 inputs.
+///
+/// Each index (with the name of its read) is kept until both inputs have folded it, so
+/// at most the reads one input runs ahead of the other are buffered.
+struct IndexReads<I> {
+    i1: I,
+    i2: Option<I>,
+    /// The read names and indices read ahead of each of the two inputs
+    pending: [VecDeque<(Vec<u8>, Vec<u8>)>; 2],
+    num_reads: usize,
+    error: IndexError,
+}
+impl<I> IndexReads<I>
+where
+    I: Iterator<Item = Result<Record>>,
+{
+    /// Returns the read name and index of the next read of an input, or None (setting the
+    /// error) if the index reads ran out or are out of sync
+    fn next(&mut self, input: usize) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        if let Some(next) = self.pending[input].pop_front() {
+            return Ok(Some(next));
+        }
+        let (mut name, mut index) = (Vec::new(), Vec::new());
+        for (read, reads) in [(1, Some(&mut self.i1)), (2, self.i2.as_mut())] {
+            let Some(reads) = reads else {
+                continue;
+            };
+            let Some(record) = reads.next().transpose()? else {
+                self.error.set(format!(
+                    "I{} has fewer records than its reads ({} read)",
+                    read, self.num_reads
+                ));
+                return Ok(None);
+            };
+            if read == 1 {
+                name.extend_from_slice(read_name(record.id()));
+            } else if read_name(record.id()) != name {
+                self.error.set(format!(
+                    "I2 is out of sync with I1 at read {}: '{}' and '{}'",
+                    self.num_reads + 1,
+                    String::from_utf8_lossy(read_name(record.id())),
+                    String::from_utf8_lossy(&name),
+                ));
+                return Ok(None);
+            } else {
+                index.push(b'+');
+            }
+            index.extend_from_slice(record.seq());
+        }
+        self.num_reads += 1;
+        self.pending[1 - input].push_back((name.clone(), index.clone()));
+        Ok(Some((name, index)))
+    }
+}
+
+/// Folds the sequences of the index reads (I1 and optionally I2, joined by `+`) into the
+/// IDs of the records of a read input.
+///
+/// The index reads must be in the same order as the reads. The iteration stops at the
+/// first read whose index is missing or named differently, which is reported through
+/// the [`IndexError`].
+pub struct Indexed<R, I> {
+    reads: R,
+    index: Rc<RefCell<IndexReads<I>>>,
+    /// Which of the two inputs sharing the index reads this is
+    input: usize,
+    error: IndexError,
+    num_reads: usize,
+    id: Vec<u8>,
+}
+impl<R, I> Indexed<R, I>
+where
+    R: Iterator<Item = Result<Record>>,
+    I: Iterator<Item = Result<Record>>,
+{
+    /// Folds the index reads into the records of both inputs of a pair, reading the index
+    /// files once
+    pub fn pair(r1: R, r2: R, i1: I, i2: Option<I>, error: IndexError) -> (Self, Self) {
+        let index = Rc::new(RefCell::new(IndexReads {
+            i1,
+            i2,
+            pending: [VecDeque::new(), VecDeque::new()],
+            num_reads: 0,
+            error: error.clone(),
+        }));
+        let indexed = |reads, input| Self {
+            reads,
+            index: Rc::clone(&index),
+            input,
+            error: error.clone(),
+            num_reads: 0,
+            id: Vec::new(),
+        };
+        (indexed(r1, 0), indexed(r2, 1))
+    }
+}
+impl<R, I> Iterator for Indexed<R, I>
+where
+    R: Iterator<Item = Result<Record>>,
+    I: Iterator<Item = Result<Record>>,
+{
+    type Item = Result<Record>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = match self.reads.next()? {
+            Ok(record) => record,
+            Err(err) => return Some(Err(err)),
+        };
+        let next = self.index.borrow_mut().next(self.input);
+        let (name, index) = match next {
+            Ok(Some(next)) => next,
+            Ok(None) => return None,
+            Err(err) => return Some(Err(err)),
+        };
+        if name != read_name(record.id()) {
+            self.error.set(format!(
+                "The index reads are out of sync with their reads at read {}: '{}' and '{}'",
+                self.num_reads + 1,
+                String::from_utf8_lossy(&name),
+                String::from_utf8_lossy(read_name(record.id())),
+            ));
+            return None;
+        }
+        self.num_reads += 1;
+        fold_into(record.id(), &index, &mut self.id);
+        match Record::new_fastq_from_parts(
+            &self.id,
+            record.seq(),
+            record.qual().unwrap_or_default(),
+        ) {
+            Ok(record) => Some(Ok(record)),
+            Err(err) => {
+                self.error.set(err.to_string());
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use fxread::FastqReader;
+
+    fn records(fastq: &str) -> Vec<Result<Record>> {
+        FastqReader::new(fastq.as_bytes()).map(Ok).collect()
+    }
+
+    #[test]
+    fn folding() {
+        let id = fold(b"read1 1:N:0:1", b"ACGT+TTGG");
+        assert_eq!(id, b"read1 1:N:0:ACGT+TTGG");
+        assert_eq!(index_of(&id), Some(b"ACGT+TTGG".as_slice()));
+        let id = fold(b"read1", b"ACGT");
+        assert_eq!(id, b"read1 BC:Z:ACGT");
+        assert_eq!(index_of(&id), Some(b"ACGT".as_slice()));
+        assert_eq!(index_of(b"read1 extra"), None);
+        assert_eq!(index_of(b"read1"), None);
+    }
+
+    #[test]
+    fn indexed() {
+        let r1 = records("@a 1:N:0:0\nAAAA\n+\nIIII\n@b 1:N:0:0\nCCCC\n+\nIIII\n");
+        let r2 = records("@a 2:N:0:0\nGGGG\n+\nIIII\n@b 2:N:0:0\nTTTT\n+\nIIII\n");
+        let i1 = records("@a 1:N:0:0\nGG\n+\nII\n@b 1:N:0:0\nTT\n+\nII\n");
+        let i2 = records("@a 4:N:0:0\nAC\n+\nII\n@b 4:N:0:0\nCA\n+\nII\n");
+        let error = IndexError::default();
+        let (mut r1, r2) = Indexed::pair(
+            r1.into_iter(),
+            r2.into_iter(),
+            i1.into_iter(),
+            Some(i2.into_iter()),
+            error.clone(),
+        );
+        // R1 runs ahead of R2, which folds the buffered indices
+        let r1 = r1.by_ref().collect::<Result<Vec<_>>>().unwrap();
+        let r2 = r2.collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(r1.len(), 2);
+        assert_eq!(r1[1].id(), b"b 1:N:0:TT+CA");
+        assert_eq!(r1[1].seq(), b"CCCC");
+        assert_eq!(r2.len(), 2);
+        assert_eq!(r2[0].id(), b"a 2:N:0:GG+AC");
+        assert!(error.check().is_ok());
+    }
+
+    fn fold_reads(reads: &str, i1: &str, i2: Option<&str>) -> (Vec<Record>, IndexError) {
+        let error = IndexError::default();
+        let (r1, _) = Indexed::pair(
+            records(reads).into_iter(),
+            records(reads).into_iter(),
+            records(i1).into_iter(),
+            i2.map(|i2| records(i2).into_iter()),
+            error.clone(),
+        );
+        (r1.collect::<Result<Vec<_>>>().unwrap(), error)
+    }
+
+    #[test]
+    fn out_of_sync() {
+        let reads = "@a\nAAAA\n+\nIIII\n@b\nCCCC\n+\nIIII\n";
+        let (indexed, error) = fold_reads(reads, "@b\nGG\n+\nII\n", None);
+        assert!(indexed.is_empty());
+        assert!(error
+            .check()
+            .unwrap_err()
+            .to_string()
+            .contains("out of sync"));
+
+        let (indexed, error) = fold_reads(reads, "@a\nGG\n+\nII\n", None);
+        assert_eq!(indexed.len(), 1);
+        assert!(error.check().is_err());
+
+        let i1 = "@a\nGG\n+\nII\n@b\nGG\n+\nII\n";
+        let (indexed, error) = fold_reads(reads, i1, Some("@b\nAC\n+\nII\n"));
+        assert!(indexed.is_empty());
+        assert!(error.check().unwrap_err().to_string().contains("I2"));
+    }
+}
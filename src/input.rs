@@ -0,0 +1,435 @@
+use crate::{
+    bam::{self, BamReader, Mate},
+    counted::{ByteCounter, Counted},
+    error::{Classify, Error},
+    parser::{FastqParser, ParserBackend},
+};
+use anyhow::{bail, Context, Result};
+use fxread::{FastaReader, FastqReader, FastxRead, Record};
+use niffler::compression::Format;
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Read},
+    sync::mpsc::{sync_channel, Receiver},
+};
+
+/// The size of the buffer used when reading input files
+pub const BUFFER_SIZE: usize = 4096 * 68;
+
+/// The default number of decompressed chunks buffered ahead by a background decompression
+/// thread
+pub const CHANNEL_CAPACITY: usize = 16;
+
+/// How the records of an input are read
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InputOptions {
+    /// The parser of FASTQ records
+    pub parser: ParserBackend,
+    /// The mate read from unaligned BAM inputs
+    pub mate: Mate,
+}
+
+/// A boxed iterator over the records of an input, yielding an [`Error::Input`] for
+/// truncated, corrupt or malformed content
+pub type Records = Box<dyn Iterator<Item = Result<Record>>>;
+
+/// An opened FASTQ/FASTA/uBAM input and the compression detected from its content
+pub struct Input {
+    /// The records of the input
+    pub reader: Records,
+    /// The compression of the input
+    pub compression: Format,
+    /// The number of (compressed) bytes read from the input so far
+    pub bytes_read: ByteCounter,
+}
+
+impl Input {
+    /// Names the path of the input in the errors reading its records
+    fn with_path(mut self, path: &str) -> Self {
+        let path = path.to_string();
+        self.reader = Box::new(
+            self.reader
+                .map(move |r| r.with_context(|| format!("Failed to read input: {}", path))),
+        );
+        self
+    }
+}
+
+/// Opens a (possibly compressed) FASTQ, FASTA or unaligned BAM file.
+///
+/// Both the compression and the record format are detected from the leading bytes
+/// of the content rather than the file extension. The input is only ever read
+/// sequentially so named pipes and process substitutions are supported.
+pub fn open(path: &str) -> Result<Input> {
+    open_with(path, InputOptions::default())
+}
+
+/// Opens a (possibly compressed) FASTQ, FASTA or unaligned BAM file, reading its records
+/// with the given options
+pub fn open_with(path: &str, opts: InputOptions) -> Result<Input> {
+    let (stream, compression, bytes_read) = decompress(path, None)?;
+    with_compression(stream, compression, bytes_read, opts)
+        .map(|input| input.with_path(path))
+        .with_context(|| format!("Failed to read input: {}", path))
+        .classify(Error::Input)
+}
+
+/// Opens a (possibly compressed) FASTQ, FASTA or unaligned BAM file and decompresses it
+/// on a background thread so decompression overlaps with processing the records.
+///
+/// The thread buffers at most `capacity` decompressed chunks of [`BUFFER_SIZE`] bytes ahead.
+pub fn open_threaded(path: &str, capacity: usize, opts: InputOptions) -> Result<Input> {
+    let (stream, compression, bytes_read) = decompress(path, Some(capacity))?;
+    with_compression(stream, compression, bytes_read, opts)
+        .map(|input| input.with_path(path))
+        .with_context(|| format!("Failed to read input: {}", path))
+        .classify(Error::Input)
+}
+
+/// Opens a (possibly compressed) unaligned BAM file holding both mates of the read pairs,
+/// reading it once for both (on a background thread given a `capacity`, see
+/// [`open_threaded`]).
+///
+/// The bytes read are only counted by the first input.
+pub fn open_mates(path: &str, capacity: Option<usize>) -> Result<(Input, Input)> {
+    let (stream, compression, bytes_read) = decompress(path, capacity)?;
+    let mates = || -> Result<_> {
+        let mut buffer = BufReader::with_capacity(BUFFER_SIZE, stream);
+        if !buffer.fill_buf()?.starts_with(bam::MAGIC) {
+            bail!("Both mates are only read from a single input if it is an unaligned BAM");
+        }
+        Ok(BamReader::new(buffer, Mate::R1)?.into_mates())
+    };
+    let (r1, r2) = mates()
+        .with_context(|| format!("Failed to read input: {}", path))
+        .classify(Error::Input)?;
+    let input = |reader: Records, bytes_read| {
+        Input {
+            reader,
+            compression,
+            bytes_read,
+        }
+        .with_path(path)
+    };
+    Ok((
+        input(Box::new(Fallible(r1)), bytes_read),
+        input(Box::new(Fallible(r2)), ByteCounter::default()),
+    ))
+}
+
+/// Opens and decompresses a file, on a background thread given a `capacity`, returning
+/// the decompressed stream, its compression and the counter of the bytes read
+fn decompress(path: &str, capacity: Option<usize>) -> Result<(Box<dyn Read>, Format, ByteCounter)> {
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open input: {}", path))
+        .classify(Error::Input)?;
+    let stream = Counted::new(file);
+    let bytes_read = stream.counter();
+    let Some(capacity) = capacity else {
+        let (stream, compression) = niffler::get_reader(Box::new(stream))
+            .with_context(|| format!("Failed to read input: {}", path))
+            .classify(Error::Input)?;
+        return Ok((stream, compression, bytes_read));
+    };
+    let (compression_tx, compression_rx) = sync_channel(1);
+    let (chunk_tx, chunk_rx) = sync_channel(capacity);
+    std::thread::spawn(move || {
+        let mut stream = match niffler::get_reader(Box::new(stream)) {
+            Ok((stream, compression)) => {
+                let _ = compression_tx.send(Ok(compression));
+                stream
+            }
+            Err(err) => {
+                let _ = compression_tx.send(Err(err));
+                return;
+            }
+        };
+        loop {
+            let mut chunk = vec![0; BUFFER_SIZE];
+            match stream.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(num_bytes) => {
+                    chunk.truncate(num_bytes);
+                    if chunk_tx.send(Ok(chunk)).is_err() {
+                        break;
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    let _ = chunk_tx.send(Err(err));
+                    break;
+                }
+            }
+        }
+    });
+    let compression = compression_rx
+        .recv()
+        .context("The decompression thread exited early")?
+        .with_context(|| format!("Failed to read input: {}", path))
+        .classify(Error::Input)?;
+    let reader = ChannelReader {
+        chunks: chunk_rx,
+        chunk: Vec::new(),
+        pos: 0,
+    };
+    Ok((Box::new(reader), compression, bytes_read))
+}
+
+/// Reads the chunks of bytes sent by a background decompression thread
+struct ChannelReader {
+    chunks: Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.chunk.len() {
+            match self.chunks.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk?;
+                    self.pos = 0;
+                }
+                // the sender is dropped once the input is exhausted
+                Err(_) => return Ok(0),
+            }
+        }
+        let num_bytes = buf.len().min(self.chunk.len() - self.pos);
+        buf[..num_bytes].copy_from_slice(&self.chunk[self.pos..self.pos + num_bytes]);
+        self.pos += num_bytes;
+        Ok(num_bytes)
+    }
+}
+
+/// Wraps a (possibly compressed) stream of FASTQ, FASTA or unaligned BAM records
+pub fn from_reader(stream: Box<dyn Read>) -> Result<Input> {
+    from_reader_with(stream, InputOptions::default())
+}
+
+/// Wraps a (possibly compressed) stream of FASTQ, FASTA or unaligned BAM records, reading
+/// them with the given options
+pub fn from_reader_with(stream: Box<dyn Read>, opts: InputOptions) -> Result<Input> {
+    let stream = Counted::new(stream);
+    let bytes_read = stream.counter();
+    let (stream, compression) = niffler::get_reader(Box::new(stream))?;
+    with_compression(stream, compression, bytes_read, opts)
+}
+
+/// Yields the records of a reader, classifying the errors reading them as input errors
+struct Fallible<R>(R);
+impl<R: FastxRead> Iterator for Fallible<R> {
+    type Item = Result<Record>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next_record().classify(Error::Input).transpose()
+    }
+}
+
+/// Wraps an already decompressed stream of FASTQ, FASTA or unaligned BAM records
+fn with_compression(
+    stream: Box<dyn Read>,
+    compression: Format,
+    bytes_read: ByteCounter,
+    opts: InputOptions,
+) -> Result<Input> {
+    let mut buffer = BufReader::with_capacity(BUFFER_SIZE, stream);
+    let leading = buffer.fill_buf()?;
+    let reader: Records = match (leading.first(), opts.parser) {
+        (Some(b'@'), ParserBackend::Fxread) => Box::new(Fallible(FastqReader::new(buffer))),
+        (Some(b'@'), ParserBackend::Native) => Box::new(Fallible(FastqParser::new(buffer))),
+        (Some(b'>'), _) => Box::new(Fallible(FastaReader::new(buffer))),
+        _ if leading.starts_with(bam::MAGIC) => {
+            Box::new(Fallible(BamReader::new(buffer, opts.mate)?))
+        }
+        _ if leading.starts_with(bam::CRAM_MAGIC) => {
+            bail!("CRAM inputs are not supported, convert them to BAM first (`samtools view -b`)")
+        }
+        (Some(_), _) => bail!("Unrecognized record format (expected FASTQ, FASTA or BAM)"),
+        (None, _) => bail!("No data in input"),
+    };
+    Ok(Input {
+        reader,
+        compression,
+        bytes_read,
+    })
+}
+
+/// Returns a human readable name of a compression format
+pub fn compression_name(format: Format) -> &'static str {
+    match format {
+        Format::Gzip => "gzip",
+        Format::Bzip => "bzip2",
+        Format::Lzma => "xz",
+        Format::Zstd => "zstd",
+        Format::No => "none",
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use std::io::{Cursor, Write};
+
+    const FASTQ: &[u8] = b"@read1\nACGT\n+\nIIII\n@read2\nTTTT\n+\nIIII\n";
+
+    /// Writes the test records with a given compression to a temporary file
+    fn write_fixture(name: &str, format: Format) -> tempfile::NamedTempFile {
+        let fixture = tempfile::Builder::new().suffix(name).tempfile().unwrap();
+        let file = fixture.reopen().unwrap();
+        let mut writer = niffler::get_writer(Box::new(file), format, niffler::Level::One).unwrap();
+        writer.write_all(FASTQ).unwrap();
+        drop(writer);
+        fixture
+    }
+
+    fn read_ids(path: &str) -> (Vec<Vec<u8>>, Format) {
+        let input = open(path).unwrap();
+        let ids = input.reader.map(|r| r.unwrap().id().to_vec()).collect();
+        (ids, input.compression)
+    }
+
+    #[test]
+    fn detect_by_content() {
+        for (name, format) in [
+            ("plain.fq.gz", Format::No),
+            ("gzip.fastq", Format::Gzip),
+            ("zstd.txt", Format::Zstd),
+            ("bzip2.fq.bz2", Format::Bzip),
+            ("xz.fq.xz", Format::Lzma),
+        ] {
+            let fixture = write_fixture(name, format);
+            let (ids, compression) = read_ids(fixture.path().to_str().unwrap());
+            assert_eq!(ids, vec![b"read1".to_vec(), b"read2".to_vec()]);
+            assert_eq!(compression, format);
+        }
+    }
+
+    #[test]
+    fn example_input() {
+        let (ids, compression) = read_ids("data/example_v3/example_R1.fq.gz");
+        assert_eq!(ids.len(), 250);
+        assert_eq!(compression_name(compression), "gzip");
+        let input = open("data/example_v3/example_R1.fq.gz").unwrap();
+        let bytes_read = input.bytes_read.clone();
+        input.reader.for_each(drop);
+        let size = std::fs::metadata("data/example_v3/example_R1.fq.gz")
+            .unwrap()
+            .len();
+        assert_eq!(bytes_read.get() as u64, size);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn named_pipe() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fifo");
+        let status = std::process::Command::new("mkfifo")
+            .arg(&path)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let writer = {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                let file = File::create(path).unwrap();
+                let mut writer =
+                    niffler::get_writer(Box::new(file), Format::Gzip, niffler::Level::One).unwrap();
+                writer.write_all(FASTQ).unwrap();
+            })
+        };
+        let (ids, compression) = read_ids(path.to_str().unwrap());
+        writer.join().unwrap();
+        assert_eq!(ids.len(), 2);
+        assert_eq!(compression, Format::Gzip);
+    }
+
+    #[test]
+    fn threaded_input() {
+        let native = InputOptions {
+            parser: ParserBackend::Native,
+            ..InputOptions::default()
+        };
+        let input = open_threaded("data/example_v3/example_R1.fq.gz", 1, native).unwrap();
+        assert_eq!(compression_name(input.compression), "gzip");
+        let bytes_read = input.bytes_read.clone();
+        let threaded = input
+            .reader
+            .map(|r| r.unwrap().id().to_vec())
+            .collect::<Vec<_>>();
+        let (ids, _) = read_ids("data/example_v3/example_R1.fq.gz");
+        assert_eq!(threaded, ids);
+        let size = std::fs::metadata("data/example_v3/example_R1.fq.gz")
+            .unwrap()
+            .len();
+        assert_eq!(bytes_read.get() as u64, size);
+        assert!(open_threaded("data/does_not_exist.fq", CHANNEL_CAPACITY, native).is_err());
+        assert!(open_threaded("Cargo.toml", CHANNEL_CAPACITY, native).is_err());
+    }
+
+    #[test]
+    fn both_mates() {
+        let mut bam = Vec::new();
+        bam::write_header(&mut bam, &bam::header_text(None, None)).unwrap();
+        bam::write_unmapped(&mut bam, b"read1", b"ACGT", b"IIII", &[]).unwrap();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&bam).unwrap();
+        let path = file.path().to_str().unwrap();
+        for capacity in [None, Some(1)] {
+            let (r1, r2) = open_mates(path, capacity).unwrap();
+            let bytes_read = r1.bytes_read.clone();
+            assert_eq!(r1.reader.count(), 1);
+            // an unpaired BAM only holds first mates
+            assert_eq!(r2.reader.count(), 0);
+            assert_eq!(bytes_read.get(), bam.len());
+            assert_eq!(r2.bytes_read.get(), 0);
+        }
+        assert!(open_mates("data/example_v3/example_R1.fq.gz", None).is_err());
+    }
+
+    #[test]
+    fn unaligned_bam() {
+        let mut bam = Vec::new();
+        bam::write_header(&mut bam, &bam::header_text(None, None)).unwrap();
+        bam::write_unmapped(&mut bam, b"read1", b"ACGT", b"IIII", &[]).unwrap();
+        let mut compressed = Vec::new();
+        let mut writer =
+            niffler::get_writer(Box::new(&mut compressed), Format::Gzip, niffler::Level::One)
+                .unwrap();
+        writer.write_all(&bam).unwrap();
+        drop(writer);
+        let input = from_reader(Box::new(Cursor::new(compressed))).unwrap();
+        assert_eq!(input.compression, Format::Gzip);
+        let seqs = input
+            .reader
+            .map(|r| r.unwrap().seq().to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(seqs, [b"ACGT".to_vec()]);
+        assert!(from_reader(Box::new(&b"CRAM\x03\x00"[..])).is_err());
+    }
+
+    #[test]
+    fn truncated_input() {
+        let mut compressed = Vec::new();
+        let mut writer =
+            niffler::get_writer(Box::new(&mut compressed), Format::Gzip, niffler::Level::One)
+                .unwrap();
+        writer.write_all(&FASTQ.repeat(1000)).unwrap();
+        drop(writer);
+        compressed.truncate(compressed.len() / 2);
+        for parser in [ParserBackend::Fxread, ParserBackend::Native] {
+            let opts = InputOptions {
+                parser,
+                ..InputOptions::default()
+            };
+            let input = from_reader_with(Box::new(Cursor::new(compressed.clone())), opts).unwrap();
+            // a truncated stream is an input error rather than a panic
+            let err = input.reader.collect::<Result<Vec<_>>>().unwrap_err();
+            assert_eq!(crate::error::exit_code(&err), crate::error::EXIT_INPUT);
+        }
+    }
+
+    #[test]
+    fn invalid_input() {
+        assert!(from_reader(Box::new(&b""[..])).is_err());
+        assert!(from_reader(Box::new(&b"not a fastq"[..])).is_err());
+        assert!(open("data/does_not_exist.fq").is_err());
+    }
+}
@@ -0,0 +1,45 @@
+/// The longest barcode bustools can represent (2 bits per nucleotide in a 64-bit word)
+pub const BUSTOOLS_MAX_BARCODE_LEN: usize = 32;
+
+/// Returns the `kb count -x` (and `bustools`) custom technology string of the emitted
+/// reads, which carry `[barcode][UMI]` in R1 and the cDNA in R2.
+///
+/// Each segment is `<file>,<start>,<stop>` with 0-indexed files and half-open
+/// positions, where a stop of 0 runs to the end of the read. Reads without a UMI take
+/// `-1` as their UMI segment.
+pub fn tech_string(barcode_len: usize, umi_len: usize) -> String {
+    let umi = if umi_len == 0 {
+        "-1".to_string()
+    } else {
+        format!("0,{},{}", barcode_len, barcode_len + umi_len)
+    };
+    format!("0,0,{}:{}:1,0,0", barcode_len, umi)
+}
+
+/// Returns a warning if the barcode is too long for bustools
+pub fn check_barcode_len(barcode_len: usize) -> Option<String> {
+    (barcode_len > BUSTOOLS_MAX_BARCODE_LEN).then(|| {
+        format!(
+            "The {}bp barcode exceeds the {}bp limit of bustools; run without `--linkers` or translate the barcodes with `--tenx-whitelist`",
+            barcode_len, BUSTOOLS_MAX_BARCODE_LEN
+        )
+    })
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn technology() {
+        assert_eq!(tech_string(28, 12), "0,0,28:0,28,40:1,0,0");
+        assert_eq!(tech_string(16, 10), "0,0,16:0,16,26:1,0,0");
+        assert_eq!(tech_string(28, 0), "0,0,28:-1:1,0,0");
+    }
+
+    #[test]
+    fn barcode_limit() {
+        assert!(check_barcode_len(32).is_none());
+        assert!(check_barcode_len(39).is_some());
+    }
+}
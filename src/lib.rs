@@ -0,0 +1,56 @@
+#[cfg(feature = "io")]
+pub mod bam;
+pub mod barcodes;
+pub mod cells;
+pub mod checksum;
+pub mod chip;
+#[cfg(feature = "io")]
+pub mod compare;
+pub mod composition;
+pub mod config;
+#[cfg(feature = "io")]
+pub mod contaminant;
+pub mod counted;
+pub mod dedup;
+#[cfg(feature = "io")]
+pub mod discover;
+pub mod downsample;
+pub mod error;
+pub mod extsort;
+pub mod features;
+pub mod ffi;
+pub mod hashtags;
+#[cfg(feature = "io")]
+pub mod index;
+#[cfg(feature = "io")]
+pub mod input;
+pub mod kb;
+#[cfg(feature = "io")]
+pub mod log;
+#[cfg(feature = "io")]
+pub mod longread;
+pub mod memory;
+#[cfg(feature = "io")]
+pub mod pairing;
+#[cfg(feature = "io")]
+pub mod parser;
+pub mod preview;
+#[cfg(feature = "io")]
+pub mod process;
+pub mod samplesheet;
+pub mod schema;
+#[cfg(feature = "io")]
+pub mod seqspec;
+pub mod shard;
+pub mod simulate;
+pub mod sketch;
+pub mod structure;
+#[cfg(feature = "io")]
+pub mod tenx;
+pub mod trim;
+pub mod umi;
+#[cfg(feature = "io")]
+pub mod warnings;
+#[cfg(feature = "io")]
+pub mod whitelist;
+pub mod zumis;
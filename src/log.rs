@@ -1,11 +1,132 @@
 use std::{
+    borrow::Cow,
+    collections::BTreeMap,
     fs::File,
-    io::{BufWriter, Write},
+    io::{BufReader, BufWriter, Write},
+    time::{Duration, Instant},
 };
 
-use anyhow::Result;
-use hashbrown::HashSet;
-use serde::Serialize;
+use crate::{
+    barcodes::Ambiguity,
+    cells::{self, CellSummary},
+    composition::Composition,
+    config::{BarcodeKey, Collision, Config, Filtered, ReadMatch},
+    extsort::ExternalCounter,
+    features::FeatureCounts,
+    hashtags::DemuxSummary,
+    longread::{LongReadCounts, LongReadOptions},
+    memory,
+    sketch::HyperLogLog,
+    tenx::TenxTranslation,
+    trim::AdapterHits,
+    whitelist::{self, WhitelistCounts},
+};
+use anyhow::{anyhow, Result};
+use hashbrown::{HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+
+/// The cumulative time spent in each stage of processing the read pairs
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StageTimes {
+    /// Waiting for the next read pair (parsing and any decompression not on a background thread)
+    pub reading: Duration,
+    /// Matching and filtering the read pairs
+    pub matching: Duration,
+    /// Handing the passing read pairs to the writers (and compressing if not in parallel)
+    pub writing: Duration,
+}
+impl StageTimes {
+    /// Returns the time elapsed since the mark and moves the mark to now
+    pub fn lap(mark: &mut Instant) -> Duration {
+        let now = Instant::now();
+        let elapsed = now - *mark;
+        *mark = now;
+        elapsed
+    }
+}
+
+/// The read counts of a single input pair of a run
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct PairStatistics {
+    pub total_reads: usize,
+    pub passing_reads: usize,
+    pub fraction_passing: f64,
+    pub num_filtered_1: usize,
+    pub num_filtered_2: usize,
+    pub num_filtered_3: usize,
+    pub num_filtered_4: usize,
+    pub num_filtered_fixed: usize,
+    pub num_filtered_umi: usize,
+    pub num_filtered_umi_homopolymer: usize,
+    pub num_filtered_umi_entropy: usize,
+    pub num_filtered_r2_len: usize,
+    pub num_empty_r2: usize,
+    pub num_contaminant: usize,
+    pub num_orphans: usize,
+    pub num_corrected_1: usize,
+    pub num_corrected_2: usize,
+    pub num_corrected_3: usize,
+    pub num_corrected_4: usize,
+}
+impl PairStatistics {
+    /// Takes a snapshot of the cumulative read counts of a run
+    pub fn snapshot(statistics: &Statistics) -> Self {
+        Self {
+            total_reads: statistics.total_reads,
+            passing_reads: statistics.passing_reads,
+            fraction_passing: 0.0,
+            num_filtered_1: statistics.num_filtered_1,
+            num_filtered_2: statistics.num_filtered_2,
+            num_filtered_3: statistics.num_filtered_3,
+            num_filtered_4: statistics.num_filtered_4,
+            num_filtered_fixed: statistics.num_filtered_fixed,
+            num_filtered_umi: statistics.num_filtered_umi,
+            num_filtered_umi_homopolymer: statistics.num_filtered_umi_homopolymer,
+            num_filtered_umi_entropy: statistics.num_filtered_umi_entropy,
+            num_filtered_r2_len: statistics.num_filtered_r2_len,
+            num_empty_r2: statistics.num_empty_r2,
+            num_contaminant: statistics.num_contaminant,
+            num_orphans: statistics.num_orphans,
+            num_corrected_1: statistics.num_corrected_1,
+            num_corrected_2: statistics.num_corrected_2,
+            num_corrected_3: statistics.num_corrected_3,
+            num_corrected_4: statistics.num_corrected_4,
+        }
+    }
+    /// Counts the reads of a run processed since an earlier snapshot
+    pub fn since(statistics: &Statistics, start: &Self) -> Self {
+        let end = Self::snapshot(statistics);
+        let total_reads = end.total_reads - start.total_reads;
+        let passing_reads = end.passing_reads - start.passing_reads;
+        Self {
+            total_reads,
+            passing_reads,
+            fraction_passing: if total_reads > 0 {
+                passing_reads as f64 / total_reads as f64
+            } else {
+                0.0
+            },
+            num_filtered_1: end.num_filtered_1 - start.num_filtered_1,
+            num_filtered_2: end.num_filtered_2 - start.num_filtered_2,
+            num_filtered_3: end.num_filtered_3 - start.num_filtered_3,
+            num_filtered_4: end.num_filtered_4 - start.num_filtered_4,
+            num_filtered_fixed: end.num_filtered_fixed - start.num_filtered_fixed,
+            num_filtered_umi: end.num_filtered_umi - start.num_filtered_umi,
+            num_filtered_umi_homopolymer: end.num_filtered_umi_homopolymer
+                - start.num_filtered_umi_homopolymer,
+            num_filtered_umi_entropy: end.num_filtered_umi_entropy - start.num_filtered_umi_entropy,
+            num_filtered_r2_len: end.num_filtered_r2_len - start.num_filtered_r2_len,
+            num_empty_r2: end.num_empty_r2 - start.num_empty_r2,
+            num_contaminant: end.num_contaminant - start.num_contaminant,
+            num_orphans: end.num_orphans - start.num_orphans,
+            num_corrected_1: end.num_corrected_1 - start.num_corrected_1,
+            num_corrected_2: end.num_corrected_2 - start.num_corrected_2,
+            num_corrected_3: end.num_corrected_3 - start.num_corrected_3,
+            num_corrected_4: end.num_corrected_4 - start.num_corrected_4,
+        }
+    }
+}
 
 #[derive(Debug, Default, Serialize, Clone)]
 pub struct Statistics {
@@ -13,65 +134,871 @@ pub struct Statistics {
     pub passing_reads: usize,
     pub fraction_passing: f64,
     pub whitelist_size: usize,
+    pub estimated_cells: Option<usize>,
+    /// The number of cells called against the ambient profile (if requested)
+    pub called_cells: Option<usize>,
+    pub estimated_molecules: usize,
+    /// The number of passing reads whose barcode and UMI were already seen, counted
+    /// exactly with the in-memory whitelist
+    pub num_duplicates: Option<usize>,
+    /// The number of duplicates estimated from the molecule sketch when they are not
+    /// counted exactly (without the in-memory whitelist or across appended deliveries)
+    pub estimated_duplicates: Option<usize>,
+    /// The fraction of passing reads which are duplicates (the sequencing saturation)
+    pub duplication_rate: f64,
+    pub final_whitelist_growth_rate: f64,
     pub num_filtered_1: usize,
     pub num_filtered_2: usize,
     pub num_filtered_3: usize,
     pub num_filtered_4: usize,
+    pub num_filtered_fixed: usize,
     pub num_filtered_umi: usize,
+    pub num_filtered_umi_homopolymer: usize,
+    pub num_filtered_umi_entropy: usize,
+    pub num_filtered_r2_len: usize,
+    /// The number of read pairs with an empty R2 (skipped or kept with a placeholder)
+    pub num_empty_r2: usize,
+    /// The number of read pairs whose R2 hit a contaminant (dropped or flagged)
+    pub num_contaminant: usize,
+    /// The number of matched read pairs whose R1 holds a second tier 1 barcode downstream
+    /// (counted, flagged or dropped with `--chimeras`)
+    pub num_chimeras: usize,
+    /// The number of reads dropped by `--repair` because their mate was missing
+    pub num_orphans: usize,
+    /// The number of passing read pairs not emitted to stay within `--reads-per-cell`
+    pub num_downsampled: usize,
+    /// The number of reads dropped as duplicates of an emitted molecule (before they are
+    /// counted as passing)
+    pub num_deduplicated: usize,
+    /// The number of read pairs whose barcode is in the `--expected-whitelist`
+    pub num_expected: usize,
+    /// The number of read pairs whose barcode is not in the `--expected-whitelist`
+    /// (dropped with `--reject-unexpected`)
+    pub num_unexpected: usize,
+    /// The fraction of validated read pairs whose barcode is expected
+    pub fraction_expected: Option<f64>,
+    /// The fraction of validated read pairs whose barcode falls outside the expected
+    /// whitelist (e.g. contamination from another kit lot)
+    pub fraction_unexpected: Option<f64>,
+    /// The number of passing read pairs whose R2 matched a feature barcode
+    pub num_feature_matched: usize,
+    /// The number of passing read pairs whose R2 matched no feature barcode
+    pub num_feature_unmatched: usize,
+    /// The hashtag classification of the barcodes with `--demux-hashtags`
+    pub hashtags: Option<DemuxSummary>,
+    pub num_corrected_1: usize,
+    pub num_corrected_2: usize,
+    pub num_corrected_3: usize,
+    pub num_corrected_4: usize,
+    pub error_rate_1: f64,
+    pub error_rate_2: f64,
+    pub error_rate_3: f64,
+    pub error_rate_4: f64,
+    pub num_trimmed_reads: usize,
+    pub num_trimmed_bases: usize,
+    pub num_adapter_trimmed_bases: usize,
+    /// The number of reads trimmed by each adapter
+    pub adapter_hits: Vec<AdapterHits>,
+    pub r1_consensus: String,
+    pub umi_consensus: String,
+    /// The mean per-position Shannon entropy (in bits, at most 2) of the passing UMIs
+    pub umi_entropy: f64,
+    /// The number of matched reads whose first anchor (tier 1 by default) was found at
+    /// each shift from its expected position within the offset window
+    pub shift_counts: Vec<usize>,
+    /// The number of reads failing barcode 1 rescanned in a window wider than the offset
+    pub num_offset_scanned: usize,
+    /// The number of rescanned reads matching barcode 1 beyond the offset window
+    pub num_beyond_offset: usize,
+    /// The number of rescanned reads matching barcode 1 at each shift beyond the offset
+    /// window (indexed by the shift)
+    pub beyond_offset_shifts: Vec<usize>,
+    /// The read counts of the emitted barcodes (keyed by their barcode indices)
+    #[serde(skip)]
+    pub whitelist: HashMap<BarcodeKey, usize>,
+    /// The QC metrics of each emitted barcode (if requested)
+    #[serde(skip)]
+    pub cell_summaries: HashMap<BarcodeKey, CellSummary>,
+    /// The number of distinct UMIs summed over the cell summaries
+    #[serde(skip)]
+    pub num_cell_umis: usize,
+    /// The mapping of the emitted barcodes to 10x barcodes with `--tenx-whitelist`
+    #[serde(skip)]
+    pub tenx_translation: Option<TenxTranslation>,
+    #[serde(skip)]
+    pub r1_composition: Composition,
+    /// The per-position nucleotide composition of the UMIs of passing reads
+    #[serde(skip)]
+    pub umi_composition: Composition,
+    /// The distinct UMIs of each barcode and feature
+    #[serde(skip)]
+    pub feature_counts: FeatureCounts,
+    #[serde(skip)]
+    pub whitelist_growth: Vec<(usize, usize)>,
+    /// The number of passing reads observed for each barcode of each tier
+    #[serde(skip)]
+    pub well_counts: [Vec<usize>; 4],
+    /// The on-disk barcode counter used with an external whitelist
     #[serde(skip)]
-    pub whitelist: HashSet<Vec<u8>>,
+    pub external_whitelist: Option<ExternalCounter>,
+    /// A sketch of the distinct barcodes used when the whitelist is not collected
+    #[serde(skip)]
+    pub barcode_sketch: HyperLogLog,
+    /// A sketch of the distinct barcode and UMI combinations
+    #[serde(skip)]
+    pub molecule_sketch: HyperLogLog,
+    /// The hashes of the distinct barcode and UMI combinations with the in-memory whitelist
+    #[serde(skip)]
+    pub molecules: HashSet<u64>,
+    /// The cumulative time spent in each processing stage
+    #[serde(skip)]
+    pub stage_times: StageTimes,
+    /// The length of the longest emitted R2
+    #[serde(skip)]
+    pub max_r2_len: usize,
+    /// The number of reads matching the read structure (before any R2 filtering)
+    #[serde(skip)]
+    pub num_matched: usize,
 }
 impl Statistics {
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// Returns an upper estimate of the memory held by the barcodes, cell summaries and
+    /// feature counts collected in memory
+    pub fn memory_estimate(&self) -> usize {
+        self.whitelist.len() * memory::BARCODE_KEY_BYTES
+            + self.cell_summaries.len() * memory::CELL_SUMMARY_BYTES
+            + self.num_cell_umis * memory::HASH_ENTRY_BYTES
+            + self.molecules.len() * memory::HASH_ENTRY_BYTES
+            + self.feature_counts.memory_estimate()
+    }
     pub fn calculate_metrics(&mut self) {
-        self.fraction_passing = self.passing_reads as f64 / self.total_reads as f64;
-        self.whitelist_size = self.whitelist.len();
+        self.whitelist_size = if self.whitelist.is_empty() {
+            self.barcode_sketch.estimate()
+        } else {
+            self.whitelist.len()
+        };
+        self.calculate_rates();
+    }
+    /// Calculates the fractions and estimates determined by the read counts and sketches
+    fn calculate_rates(&mut self) {
+        self.fraction_passing = if self.total_reads > 0 {
+            self.passing_reads as f64 / self.total_reads as f64
+        } else {
+            0.0
+        };
+        self.estimated_molecules = self.molecule_sketch.estimate();
+        // without a UMI no molecules are sketched and duplicates are not counted
+        self.estimated_duplicates = match self.num_duplicates {
+            None if self.estimated_molecules > 0 => {
+                Some(self.passing_reads.saturating_sub(self.estimated_molecules))
+            }
+            _ => None,
+        };
+        let num_duplicates = self.num_duplicates.or(self.estimated_duplicates);
+        self.duplication_rate = match num_duplicates {
+            Some(n) if self.passing_reads > 0 => n as f64 / self.passing_reads as f64,
+            _ => 0.0,
+        };
+        self.r1_consensus = self.r1_composition.consensus();
+        self.umi_consensus = self.umi_composition.consensus();
+        self.umi_entropy = self.umi_composition.mean_entropy();
+        self.final_whitelist_growth_rate = self.growth_rate();
+    }
+    /// Merges the barcodes, sketches and UMI composition of the earlier deliveries a
+    /// sample is appended to (their in-memory whitelist counts, if any, and their
+    /// [`AppendState`]) and re-estimates the whitelist size and cells from the merge.
+    ///
+    /// The whitelist growth curve keeps the earlier points and ends at the merged
+    /// whitelist after the passing reads of this delivery.
+    pub fn merge_appended(
+        &mut self,
+        state: &AppendState,
+        whitelist: Option<&WhitelistCounts>,
+        config: &Config,
+        expected_cells: Option<usize>,
+    ) -> Result<()> {
+        if let Some(whitelist) = whitelist {
+            for (barcode, count) in whitelist {
+                let key = config.barcode_key(barcode).ok_or_else(|| {
+                    anyhow!(
+                        "Barcode of the earlier whitelist is not built from the config: {}",
+                        String::from_utf8_lossy(barcode)
+                    )
+                })?;
+                *self.whitelist.entry(key).or_default() += count.unwrap_or(0);
+            }
+            self.estimate_cells(expected_cells);
+        }
+        if let (Some(&(reads, _)), false) = (
+            state.whitelist_growth.last(),
+            self.whitelist_growth.is_empty(),
+        ) {
+            self.whitelist_growth = state.whitelist_growth.clone();
+            self.whitelist_growth
+                .push((reads + self.passing_reads, self.whitelist.len()));
+        }
+        self.barcode_sketch.merge(&state.barcode_sketch);
+        self.molecule_sketch.merge(&state.molecule_sketch);
+        self.umi_composition.merge(&state.umi_composition);
+        self.calculate_metrics();
+        Ok(())
+    }
+    /// Returns the state merged into the statistics of a later delivery of the sample
+    pub fn append_state(&self) -> AppendState {
+        AppendState {
+            whitelist_growth: self.whitelist_growth.clone(),
+            barcode_sketch: self.barcode_sketch.clone(),
+            molecule_sketch: self.molecule_sketch.clone(),
+            umi_composition: self.umi_composition.clone(),
+        }
+    }
+    /// Adds the read counts of the `statistics` of an earlier log (of the deliveries a
+    /// sample is appended to) and recalculates the fractions and duplicates they
+    /// determine.
+    ///
+    /// The error rates and R1 consensus describe the latest delivery only; options whose
+    /// outputs cannot be merged are refused with `--append`.
+    pub fn accumulate(&mut self, previous: &Value) {
+        let counts = [
+            ("total_reads", &mut self.total_reads),
+            ("passing_reads", &mut self.passing_reads),
+            ("num_filtered_1", &mut self.num_filtered_1),
+            ("num_filtered_2", &mut self.num_filtered_2),
+            ("num_filtered_3", &mut self.num_filtered_3),
+            ("num_filtered_4", &mut self.num_filtered_4),
+            ("num_filtered_fixed", &mut self.num_filtered_fixed),
+            ("num_filtered_umi", &mut self.num_filtered_umi),
+            (
+                "num_filtered_umi_homopolymer",
+                &mut self.num_filtered_umi_homopolymer,
+            ),
+            (
+                "num_filtered_umi_entropy",
+                &mut self.num_filtered_umi_entropy,
+            ),
+            ("num_filtered_r2_len", &mut self.num_filtered_r2_len),
+            ("num_empty_r2", &mut self.num_empty_r2),
+            ("num_contaminant", &mut self.num_contaminant),
+            ("num_chimeras", &mut self.num_chimeras),
+            ("num_orphans", &mut self.num_orphans),
+            ("num_downsampled", &mut self.num_downsampled),
+            ("num_deduplicated", &mut self.num_deduplicated),
+            ("num_expected", &mut self.num_expected),
+            ("num_unexpected", &mut self.num_unexpected),
+            ("num_feature_matched", &mut self.num_feature_matched),
+            ("num_feature_unmatched", &mut self.num_feature_unmatched),
+            ("num_corrected_1", &mut self.num_corrected_1),
+            ("num_corrected_2", &mut self.num_corrected_2),
+            ("num_corrected_3", &mut self.num_corrected_3),
+            ("num_corrected_4", &mut self.num_corrected_4),
+            ("num_trimmed_reads", &mut self.num_trimmed_reads),
+            ("num_trimmed_bases", &mut self.num_trimmed_bases),
+            (
+                "num_adapter_trimmed_bases",
+                &mut self.num_adapter_trimmed_bases,
+            ),
+            ("num_offset_scanned", &mut self.num_offset_scanned),
+            ("num_beyond_offset", &mut self.num_beyond_offset),
+        ];
+        for (name, count) in counts {
+            *count += previous.get(name).and_then(Value::as_u64).unwrap_or(0) as usize;
+        }
+        // duplicates across the deliveries are only known through the merged sketch
+        self.num_duplicates = None;
+        self.calculate_rates();
+        if self.fraction_expected.is_some() {
+            self.record_expected_fractions();
+        }
+    }
+    /// Calculates the fractions of validated read pairs inside and outside the expected
+    /// whitelist
+    pub fn record_expected_fractions(&mut self) {
+        let num_checked = (self.num_expected + self.num_unexpected) as f64;
+        self.fraction_expected = Some(self.num_expected as f64 / num_checked);
+        self.fraction_unexpected = Some(self.num_unexpected as f64 / num_checked);
+    }
+    /// Estimates the number of cells from the read counts of the whitelist
+    pub fn estimate_cells(&mut self, expected_cells: Option<usize>) {
+        let counts = self.whitelist.values().copied().collect::<Vec<_>>();
+        self.estimated_cells = Some(cells::ordmag(
+            &counts,
+            expected_cells.unwrap_or(cells::DEFAULT_EXPECTED_CELLS),
+        ));
+    }
+    /// Records the reason a read failed to match the read structure
+    pub fn record_filtered(&mut self, filtered: Filtered) {
+        match filtered {
+            Filtered::Tier(0) => self.num_filtered_1 += 1,
+            Filtered::Tier(1) => self.num_filtered_2 += 1,
+            Filtered::Tier(2) => self.num_filtered_3 += 1,
+            Filtered::Tier(_) => self.num_filtered_4 += 1,
+            Filtered::Fixed => self.num_filtered_fixed += 1,
+            Filtered::Umi => self.num_filtered_umi += 1,
+        }
+    }
+    /// Records the corrected tiers and anchor shift of a matched read
+    pub fn record_match(&mut self, read_match: &ReadMatch) {
+        self.num_matched += 1;
+        self.num_corrected_1 += read_match.corrected[0] as usize;
+        self.num_corrected_2 += read_match.corrected[1] as usize;
+        self.num_corrected_3 += read_match.corrected[2] as usize;
+        self.num_corrected_4 += read_match.corrected[3] as usize;
+        if self.shift_counts.len() <= read_match.shift {
+            self.shift_counts.resize(read_match.shift + 1, 0);
+        }
+        self.shift_counts[read_match.shift] += 1;
+    }
+    /// Records the shift at which a read failing barcode 1 matched in the wider diagnostic
+    /// scan (if at all)
+    pub fn record_offset_scan(&mut self, offset: usize, shift: Option<usize>) {
+        self.num_offset_scanned += 1;
+        let Some(shift) = shift.filter(|shift| *shift > offset) else {
+            return;
+        };
+        self.num_beyond_offset += 1;
+        if self.beyond_offset_shifts.len() <= shift {
+            self.beyond_offset_shifts.resize(shift + 1, 0);
+        }
+        self.beyond_offset_shifts[shift] += 1;
+    }
+    /// Estimates the per-base sequencing error rate of each tier from the fraction of
+    /// matched reads requiring a correction.
+    ///
+    /// Reads with more than one error in a tier are never matched so the fraction of
+    /// corrected reads `f` is conditioned on at most one error, from which the per-base
+    /// rate over a tier of length `L` is `f / (L - f (L - 1))`.
+    pub fn estimate_error_rates(&mut self, config: &Config) {
+        let corrected = [
+            self.num_corrected_1,
+            self.num_corrected_2,
+            self.num_corrected_3,
+            self.num_corrected_4,
+        ];
+        let [e1, e2, e3, e4] = [0, 1, 2, 3].map(|tier| {
+            if self.num_matched == 0 {
+                return 0.0;
+            }
+            let len = config.tier_match_len(tier) as f64;
+            let frac = corrected[tier] as f64 / self.num_matched as f64;
+            frac / (len - frac * (len - 1.0))
+        });
+        self.error_rate_1 = e1;
+        self.error_rate_2 = e2;
+        self.error_rate_3 = e3;
+        self.error_rate_4 = e4;
+    }
+    /// Records the current whitelist size at the current number of passing reads
+    pub fn record_growth(&mut self) {
+        let point = (self.passing_reads, self.whitelist.len());
+        if self.whitelist_growth.last() != Some(&point) {
+            self.whitelist_growth.push(point);
+        }
+    }
+    /// Calculates the fraction of passing reads introducing a new barcode
+    /// over the final interval of the whitelist growth curve
+    fn growth_rate(&self) -> f64 {
+        let n = self.whitelist_growth.len();
+        let (reads, size) = match n {
+            0 => return 0.0,
+            1 => self.whitelist_growth[0],
+            _ => {
+                let (r1, s1) = self.whitelist_growth[n - 2];
+                let (r2, s2) = self.whitelist_growth[n - 1];
+                (r2 - r1, s2 - s1)
+            }
+        };
+        if reads == 0 {
+            0.0
+        } else {
+            size as f64 / reads as f64
+        }
+    }
+    /// Writes the whitelist growth curve as a TSV
+    pub fn growth_to_file(&self, file: &str) -> Result<()> {
+        let mut writer = File::create(file).map(BufWriter::new)?;
+        writeln!(writer, "passing_reads\twhitelist_size")?;
+        for (reads, size) in &self.whitelist_growth {
+            writeln!(writer, "{}\t{}", reads, size)?;
+        }
+        Ok(())
+    }
+    pub fn whitelist_to_file(&self, file: &str, config: &Config) -> Result<()> {
+        self.whitelist_to_writer(File::create(file).map(BufWriter::new)?, config)
+    }
+    /// Writes the whitelist (one barcode per line) to any writer
+    pub fn whitelist_to_writer<W: Write>(&self, mut writer: W, config: &Config) -> Result<()> {
+        let mut barcode = Vec::new();
+        for key in self.whitelist.keys() {
+            config.build_barcode_into(key.indices(), &mut barcode);
+            writer.write_all(&barcode)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+    /// Returns the emitted barcodes of a map keyed by barcode indices alongside their
+    /// values, sorted by decreasing read count (and then barcode)
+    fn by_reads<'a, T>(
+        map: impl Iterator<Item = (&'a BarcodeKey, T)>,
+        reads: impl Fn(&T) -> usize,
+        config: &Config,
+    ) -> Vec<(Vec<u8>, T)> {
+        let mut sorted = map
+            .map(|(key, value)| {
+                let [b1_idx, b2_idx, b3_idx, b4_idx] = key.indices();
+                (config.build_barcode(b1_idx, b2_idx, b3_idx, b4_idx), value)
+            })
+            .collect::<Vec<_>>();
+        sorted.sort_unstable_by(|a, b| reads(&b.1).cmp(&reads(&a.1)).then(a.0.cmp(&b.0)));
+        sorted
+    }
+    /// Writes the read counts of the whitelist as `barcode\treads` rows
+    pub fn whitelist_counts_to_writer<W: Write>(&self, writer: W, config: &Config) -> Result<()> {
+        let counts = self
+            .whitelist
+            .iter()
+            .map(|(key, reads)| {
+                let [b1_idx, b2_idx, b3_idx, b4_idx] = key.indices();
+                (
+                    config.build_barcode(b1_idx, b2_idx, b3_idx, b4_idx),
+                    Some(*reads),
+                )
+            })
+            .collect::<WhitelistCounts>();
+        whitelist::write_counts(&counts, writer)
+    }
+    /// Writes the mapping of each full barcode construct to its emitted barcode
+    pub fn translation_to_file(&self, file: &str, config: &Config) -> Result<()> {
+        let mut writer = File::create(file).map(BufWriter::new)?;
+        for key in self.whitelist.keys() {
+            let [b1_idx, b2_idx, b3_idx, b4_idx] = key.indices();
+            let construct = config.build_construct(b1_idx, b2_idx, b3_idx, b4_idx);
+            writer.write_all(&construct)?;
+            writer.write_all(b"\t")?;
+            writer.write_all(&config.build_barcode(b1_idx, b2_idx, b3_idx, b4_idx))?;
+            writer.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+    /// Writes the number of passing reads for each barcode (well) of each tier
+    pub fn well_counts_to_file(&self, file: &str, config: &Config) -> Result<()> {
+        let mut writer = File::create(file).map(BufWriter::new)?;
+        writeln!(writer, "tier\tindex\twell\tbarcode\treads")?;
+        for (tier, counts) in self.well_counts.iter().enumerate() {
+            for (idx, count) in counts.iter().enumerate() {
+                let barcode = config.tier_barcode(tier, idx).unwrap_or_default();
+                writeln!(
+                    writer,
+                    "{}\t{}\t{}\t{}\t{}",
+                    tier + 1,
+                    idx,
+                    config.well(tier, idx).unwrap_or("NA"),
+                    std::str::from_utf8(barcode)?,
+                    count
+                )?;
+            }
+        }
+        Ok(())
+    }
+    /// Writes the QC metrics of each barcode (by decreasing read count)
+    pub fn cell_summary_to_file(&self, file: &str, config: &Config) -> Result<()> {
+        let summaries = Self::by_reads(self.cell_summaries.iter(), |summary| summary.reads, config);
+        let mut writer = File::create(file).map(BufWriter::new)?;
+        writeln!(
+            writer,
+            "barcode\treads\tumis\tmean_barcode_qual\tmean_r2_qual\tfraction_corrected_bases"
+        )?;
+        for (seq, summary) in summaries {
+            writer.write_all(&seq)?;
+            writeln!(
+                writer,
+                "\t{}\t{}\t{:.2}\t{:.2}\t{:.6}",
+                summary.reads,
+                summary.num_umis(),
+                summary.mean_barcode_qual(),
+                summary.mean_r2_qual(),
+                summary.fraction_corrected_bases()
+            )?;
+        }
+        writer.flush()?;
+        Ok(())
     }
-    pub fn whitelist_to_file(&self, file: &str) -> Result<()> {
+    /// Writes a CSV of the barcodes (by decreasing read count) with the well of each tier,
+    /// their reads, distinct UMIs and sample, to be loaded as the cell metadata of
+    /// scanpy (`obs`) or Seurat
+    pub fn cell_metadata_to_file(&self, file: &str, config: &Config, sample: &str) -> Result<()> {
+        let summaries = Self::by_reads(
+            self.cell_summaries
+                .iter()
+                .map(|(key, summary)| (key, (key.indices(), summary))),
+            |(_, summary)| summary.reads,
+            config,
+        );
+        let sample = csv_field(sample);
         let mut writer = File::create(file).map(BufWriter::new)?;
-        for seq in &self.whitelist {
-            writer.write(seq)?;
-            writer.write(b"\n")?;
+        writeln!(
+            writer,
+            "barcode,bc1_well,bc2_well,bc3_well,bc4_well,reads,umis,sample"
+        )?;
+        for (barcode, (indices, summary)) in summaries {
+            writer.write_all(&barcode)?;
+            for (tier, idx) in indices.into_iter().enumerate() {
+                write!(
+                    writer,
+                    ",{}",
+                    csv_field(config.well(tier, idx).unwrap_or("NA"))
+                )?;
+            }
+            writeln!(
+                writer,
+                ",{},{},{}",
+                summary.reads,
+                summary.num_umis(),
+                sample
+            )?;
         }
+        writer.flush()?;
+        Ok(())
+    }
+    /// Calls cells against the ambient profile of the whitelist and writes the calls of
+    /// every tested barcode (by decreasing read count)
+    pub fn cell_calls_to_file(
+        &mut self,
+        file: &str,
+        config: &Config,
+        expected_cells: Option<usize>,
+        ambient_max_reads: usize,
+        fdr: f64,
+    ) -> Result<()> {
+        let (keys, counts): (Vec<_>, Vec<_>) = self
+            .whitelist
+            .iter()
+            .map(|(key, reads)| (key, *reads))
+            .unzip();
+        let calls = cells::call_cells(
+            &counts,
+            expected_cells.unwrap_or(cells::DEFAULT_EXPECTED_CELLS),
+            ambient_max_reads,
+            fdr,
+        );
+        let tested = Self::by_reads(
+            keys.into_iter()
+                .zip(calls)
+                .filter_map(|(key, call)| Some((key, call?))),
+            |call| call.reads,
+            config,
+        );
+
+        let mut writer = File::create(file).map(BufWriter::new)?;
+        writeln!(writer, "barcode\treads\tp_value\tfdr\tis_cell")?;
+        for (seq, call) in &tested {
+            writer.write_all(seq)?;
+            writeln!(
+                writer,
+                "\t{}\t{:e}\t{:e}\t{}",
+                call.reads, call.p_value, call.fdr, call.is_cell
+            )?;
+        }
+        writer.flush()?;
+        self.called_cells = Some(tested.iter().filter(|(_, call)| call.is_cell).count());
+        Ok(())
+    }
+    /// Writes the whitelist alongside the per-tier well coordinates of each barcode
+    pub fn wells_to_file(&self, file: &str, config: &Config) -> Result<()> {
+        let mut writer = File::create(file).map(BufWriter::new)?;
+        let mut barcode = Vec::new();
+        for key in self.whitelist.keys() {
+            config.build_barcode_into(key.indices(), &mut barcode);
+            writer.write_all(&barcode)?;
+            writer.write_all(b"\t")?;
+            writer.write_all(config.wells(key.indices()).as_bytes())?;
+            writer.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+}
+
+/// The statistics of the deliveries of a sample which are merged into a later delivery
+/// with `--append` but cannot be rebuilt from its log, written to
+/// `<prefix>_append_state.json`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct AppendState {
+    pub whitelist_growth: Vec<(usize, usize)>,
+    pub barcode_sketch: HyperLogLog,
+    pub molecule_sketch: HyperLogLog,
+    pub umi_composition: Composition,
+}
+impl AppendState {
+    pub fn from_file(path: &str) -> Result<Self> {
+        let reader = File::open(path).map(BufReader::new)?;
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    pub fn to_writer<W: Write>(&self, mut writer: W) -> Result<()> {
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
         Ok(())
     }
 }
 
+/// Quotes a CSV field if it holds a delimiter, quote or newline
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
 #[derive(Debug, Serialize)]
 pub struct Timing {
     pub timestamp: String,
     pub elapsed_time: f64,
+    pub reads_per_second: f64,
+    pub input_mb_per_second: f64,
+    pub output_mb_per_second: f64,
+    pub reading_seconds: f64,
+    pub matching_seconds: f64,
+    pub writing_seconds: f64,
+}
+impl Timing {
+    /// Derives the throughput of a run from the reads processed and the (compressed)
+    /// bytes read and written
+    pub fn new(
+        timestamp: String,
+        elapsed_time: f64,
+        total_reads: usize,
+        bytes_read: usize,
+        bytes_written: usize,
+    ) -> Self {
+        let per_second = |n: f64| n / elapsed_time.max(f64::EPSILON);
+        Self {
+            timestamp,
+            elapsed_time,
+            reads_per_second: per_second(total_reads as f64),
+            input_mb_per_second: per_second(bytes_read as f64 / 1e6),
+            output_mb_per_second: per_second(bytes_written as f64 / 1e6),
+            reading_seconds: 0.0,
+            matching_seconds: 0.0,
+            writing_seconds: 0.0,
+        }
+    }
+    /// Adds the time spent in each processing stage
+    pub fn with_stages(self, stages: &StageTimes) -> Self {
+        Self {
+            reading_seconds: stages.reading.as_secs_f64(),
+            matching_seconds: stages.matching.as_secs_f64(),
+            writing_seconds: stages.writing.as_secs_f64(),
+            ..self
+        }
+    }
 }
 
 #[derive(Debug, Serialize)]
 pub struct FileIO {
+    pub readpath_r1: Vec<String>,
+    pub readpath_r2: Vec<String>,
+    pub compression_r1: Vec<String>,
+    pub compression_r2: Vec<String>,
+    pub readpath_i1: Vec<String>,
+    pub readpath_i2: Vec<String>,
+    pub writepath_r1: Vec<String>,
+    pub writepath_r2: Vec<String>,
+    pub barcode_table_paths: Vec<String>,
+    pub whitelist_path: Option<String>,
+    pub whitelist_counts_path: Option<String>,
+    pub append_state_path: Option<String>,
+    pub translation_path: Option<String>,
+    pub tenx_translation_path: Option<String>,
+    pub wells_path: Option<String>,
+    pub well_counts_path: Option<String>,
+    pub cell_calls_path: Option<String>,
+    pub cell_summary_path: Option<String>,
+    pub cell_metadata_path: Option<String>,
+    pub composition_path: Option<String>,
+    pub umi_composition_path: Option<String>,
+    pub growth_path: Option<String>,
+    pub seqspec_path: String,
+    pub zumis_path: Option<String>,
+    pub read_names_path: Option<String>,
+    pub feature_matrix_path: Option<String>,
+    pub feature_barcodes_path: Option<String>,
+    pub features_path: Option<String>,
+    pub hashtag_assignments_path: Option<String>,
+    pub hashtag_cells_paths: Vec<String>,
+}
+
+/// An input pair of a run and its read counts
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InputPair {
     pub readpath_r1: String,
     pub readpath_r2: String,
-    pub writepath_r1: String,
-    pub writepath_r2: String,
-    pub whitelist_path: String,
+    pub compression_r1: String,
+    pub compression_r2: String,
+    #[serde(flatten)]
+    pub statistics: PairStatistics,
 }
 
 #[derive(Debug, Serialize)]
 pub struct Parameters {
+    pub sample: Option<String>,
+    pub library_type: Option<String>,
+    pub config: String,
     pub offset: usize,
+    pub auto_offset: bool,
+    pub anchored: bool,
+    pub trim_qual: Option<u8>,
+    pub min_r2_len: usize,
+    pub filter_homopolymer_umi: bool,
+    pub keep_empty_r2: bool,
+    pub reads_per_cell: Option<u64>,
+    pub dedup: Option<String>,
+    pub dedup_r2_prefix: usize,
+    pub shards: usize,
+    pub shard_by: String,
+    pub shard_block_size: usize,
+    pub split_lanes: bool,
+    pub seed: u64,
+    pub expected_whitelist: Option<String>,
+    pub tenx_whitelist: Option<String>,
+    pub reject_unexpected: bool,
+    pub flag_unexpected: bool,
+    pub features: Option<String>,
+    pub feature_offset: usize,
+    pub feature_mismatches: usize,
+    pub demux_hashtags: bool,
+    pub hashtag_min_umis: usize,
+    pub min_umi_entropy: Option<f64>,
+    pub growth_interval: usize,
+    pub no_whitelist: bool,
+    pub external_whitelist: bool,
+    /// The memory budget in bytes
+    pub max_memory: Option<usize>,
+    pub chunk_size: usize,
+    pub min_pass_rate: Option<f64>,
+    pub expected_cells: Option<usize>,
+    pub chip: Option<String>,
+    pub call_cells: bool,
+    pub cell_summary: bool,
+    pub cell_metadata: bool,
+    pub ambient_max_reads: usize,
+    pub cell_fdr: f64,
     pub umi_len: usize,
+    pub naming: String,
+    pub layout: String,
+    pub name_format: String,
+    pub strip_comments: bool,
+    pub rename_reads: bool,
+    pub no_r1_out: bool,
+    pub barcode_table: bool,
+    pub threads: usize,
+    pub decompress_threads: usize,
+    pub compress_threads: usize,
+    pub parser: String,
+    pub output_format: String,
+    pub zumis: bool,
+    pub append: bool,
+    pub barcode_qual: String,
+    pub adapters: Option<String>,
+    pub adapter_error_rate: f64,
+    pub adapter_min_overlap: usize,
+    pub contaminants: Option<String>,
+    pub contaminant_k: usize,
+    pub contaminant_action: String,
+    pub chimeras: Option<String>,
+    pub check_pairing: bool,
+    pub repair: bool,
     pub exact_matching: bool,
+    pub matcher: String,
     pub write_linkers: bool,
+    pub structure: String,
+    /// The `kb count -x`/`bustools` technology string of the emitted reads
+    pub kb_technology: String,
+    pub pipspeak_version: String,
+}
+
+/// The summary of a library of a multi-modal run
+#[derive(Debug, Serialize)]
+pub struct LibrarySummary {
+    pub name: String,
+    pub library_type: Option<String>,
+    pub log_path: String,
+    pub total_reads: usize,
+    pub passing_reads: usize,
+    pub fraction_passing: f64,
+    pub whitelist_size: usize,
+}
+
+/// The combined log of the libraries of a multi-modal run
+#[derive(Debug, Serialize)]
+pub struct LibrariesLog {
+    pub libraries: Vec<LibrarySummary>,
+    /// The number of barcodes observed in any library
+    pub whitelist_size: Option<usize>,
+    /// The number of barcodes observed in every library
+    pub shared_whitelist_size: Option<usize>,
+    pub whitelist_path: Option<String>,
     pub pipspeak_version: String,
 }
+impl LibrariesLog {
+    pub fn to_file(&self, path: &str) -> Result<()> {
+        let yaml = serde_yaml::to_string(&self)?;
+        std::fs::write(path, yaml)?;
+        Ok(())
+    }
+}
+
+/// The log of a run of the `long-read` subcommand
+#[derive(Debug, Serialize)]
+pub struct LongReadLog {
+    pub config: String,
+    pub inputs: Vec<String>,
+    pub parameters: LongReadOptions,
+    pub long_reads: LongReadCounts,
+    pub statistics: Statistics,
+    pub pipspeak_version: String,
+}
+impl LongReadLog {
+    pub fn to_file(&self, path: &str) -> Result<()> {
+        let yaml = serde_yaml::to_string(&self)?;
+        std::fs::write(path, yaml)?;
+        Ok(())
+    }
+}
 
 #[derive(Debug, Serialize)]
 /// A struct to hold the information about the run
 pub struct Log {
     pub parameters: Parameters,
     pub file_io: FileIO,
+    pub inputs: Vec<InputPair>,
     pub statistics: Statistics,
+    pub warnings: Vec<String>,
+    /// The barcodes within the matching distance of a sequence of another tier
+    pub collisions: Vec<Collision>,
+    /// The one-mismatch neighbors of each tier which are not corrected
+    pub ambiguity: BTreeMap<String, Ambiguity>,
     pub timing: Timing,
 }
 impl Log {
+    /// Extends the log with the read counts and inputs of the log of the earlier
+    /// deliveries of a sample (with `--append`)
+    pub fn accumulate(&mut self, previous: &Value) -> Result<()> {
+        let statistics = previous
+            .get("statistics")
+            .ok_or_else(|| anyhow!("Log has no `statistics` section"))?;
+        self.statistics.accumulate(statistics);
+        let inputs = match previous.get("inputs") {
+            Some(inputs) => serde_yaml::from_value::<Vec<InputPair>>(inputs.clone())?,
+            None => Vec::new(),
+        };
+        self.inputs.splice(0..0, inputs);
+        Ok(())
+    }
+
     pub fn stderr(&self) -> Result<()> {
         let yaml = serde_yaml::to_string(&self)?;
         eprint!("{}", yaml);
@@ -84,3 +1011,121 @@ impl Log {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn empty_pair() {
+        let statistics = Statistics::new();
+        let start = PairStatistics::snapshot(&statistics);
+        let pair = PairStatistics::since(&statistics, &start);
+        assert_eq!(pair.total_reads, 0);
+        assert_eq!(pair.fraction_passing, 0.0);
+    }
+
+    #[test]
+    fn cell_metadata() {
+        let config = Config::from_file("data/config_v3.yaml", false, false).unwrap();
+        let mut statistics = Statistics::new();
+        for (indices, umis) in [([0, 0, 0, 0], 1), ([1, 2, 3, 4], 3)] {
+            let summary = statistics
+                .cell_summaries
+                .entry(BarcodeKey::new(indices))
+                .or_default();
+            for umi in 0..umis {
+                summary.add(&[b'A' + umi], b"II", b"II", 0);
+            }
+        }
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let path = file.path().to_str().unwrap();
+        statistics
+            .cell_metadata_to_file(path, &config, "lane, 1")
+            .unwrap();
+        let contents = std::fs::read_to_string(path).unwrap();
+        let lines = contents.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[0],
+            "barcode,bc1_well,bc2_well,bc3_well,bc4_well,reads,umis,sample"
+        );
+        let barcode = config.build_barcode(1, 2, 3, 4);
+        assert_eq!(
+            lines[1],
+            format!(
+                "{},NA,NA,NA,NA,3,3,\"lane, 1\"",
+                String::from_utf8(barcode).unwrap()
+            )
+        );
+        assert!(lines[2].ends_with(",1,1,\"lane, 1\""));
+    }
+
+    #[test]
+    fn accumulate() {
+        let mut statistics = Statistics {
+            total_reads: 100,
+            passing_reads: 80,
+            num_filtered_1: 20,
+            ..Statistics::default()
+        };
+        let previous = serde_yaml::from_str::<Value>(
+            "total_reads: 300\npassing_reads: 120\nnum_filtered_1: 180\nfraction_passing: 0.4\n",
+        )
+        .unwrap();
+        statistics.accumulate(&previous);
+        assert_eq!(statistics.total_reads, 400);
+        assert_eq!(statistics.passing_reads, 200);
+        assert_eq!(statistics.num_filtered_1, 200);
+        assert_eq!(statistics.fraction_passing, 0.5);
+        assert_eq!(statistics.fraction_expected, None);
+    }
+
+    #[test]
+    fn merge_appended() {
+        let config = Config::from_file("data/config_v3.yaml", false, false).unwrap();
+        let mut earlier = Statistics {
+            passing_reads: 3,
+            ..Statistics::default()
+        };
+        earlier.whitelist.insert(BarcodeKey::new([0, 0, 0, 0]), 2);
+        earlier.whitelist.insert(BarcodeKey::new([1, 2, 3, 4]), 1);
+        earlier.molecule_sketch.insert(b"AAAA");
+        earlier.record_growth();
+        let mut buffer = Vec::new();
+        earlier
+            .whitelist_counts_to_writer(&mut buffer, &config)
+            .unwrap();
+        let counts = whitelist::read_counts_from_buffer(buffer.as_slice()).unwrap();
+
+        let mut statistics = Statistics {
+            passing_reads: 2,
+            num_duplicates: Some(0),
+            ..Statistics::default()
+        };
+        statistics
+            .whitelist
+            .insert(BarcodeKey::new([1, 2, 3, 4]), 1);
+        statistics
+            .whitelist
+            .insert(BarcodeKey::new([5, 6, 7, 8]), 1);
+        statistics.molecule_sketch.insert(b"AAAA");
+        statistics.molecule_sketch.insert(b"CCCC");
+        statistics.record_growth();
+        statistics
+            .merge_appended(&earlier.append_state(), Some(&counts), &config, None)
+            .unwrap();
+        assert_eq!(statistics.whitelist.len(), 3);
+        assert_eq!(statistics.whitelist[&BarcodeKey::new([1, 2, 3, 4])], 2);
+        assert_eq!(statistics.whitelist_growth, vec![(3, 2), (5, 3)]);
+        assert!(statistics.estimated_cells.is_some());
+
+        let previous = serde_yaml::from_str::<Value>("total_reads: 5\npassing_reads: 3\n").unwrap();
+        statistics.total_reads = 5;
+        statistics.accumulate(&previous);
+        assert_eq!(statistics.whitelist_size, 3);
+        assert_eq!(statistics.estimated_molecules, 2);
+        assert_eq!(statistics.num_duplicates, None);
+        assert_eq!(statistics.estimated_duplicates, Some(3));
+    }
+}
@@ -0,0 +1,326 @@
+use crate::{
+    config::{Config, ReadMatch},
+    error::Error,
+};
+use anyhow::{bail, Result};
+use fxread::Record;
+use serde::Serialize;
+use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+
+/// The default number of nucleotides searched for the first barcode of a long read
+pub const DEFAULT_LONG_READ_WINDOW: usize = 150;
+
+/// The default number of edits tolerated within each barcode of a long read
+pub const DEFAULT_LONG_READ_DISTANCE: usize = 2;
+
+/// The minimum length of a poly(T) stretch trimmed from the cDNA
+pub const MIN_POLYT_LEN: usize = 8;
+
+/// The quality given to the bases of FASTA long reads
+const FASTA_QUAL: u8 = b'I';
+
+/// The parameters of the barcode search of long reads
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct LongReadOptions {
+    /// The number of nucleotides searched for the first barcode (on each strand)
+    pub window: usize,
+    /// The number of edits tolerated within each barcode and fixed sequence
+    pub max_distance: usize,
+    /// The default length of the UMI
+    pub umi_len: usize,
+}
+impl Default for LongReadOptions {
+    fn default() -> Self {
+        Self {
+            window: DEFAULT_LONG_READ_WINDOW,
+            max_distance: DEFAULT_LONG_READ_DISTANCE,
+            umi_len: 12,
+        }
+    }
+}
+
+/// The strand a long read was matched on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Orientation {
+    Forward,
+    Reverse,
+    Unmatched,
+}
+
+/// The number of long reads matched on each strand
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct LongReadCounts {
+    pub num_forward: usize,
+    pub num_reverse: usize,
+    pub num_unmatched: usize,
+    /// The number of matched reads whose cDNA began with a poly(T) stretch
+    pub num_polyt_trimmed: usize,
+}
+
+/// Returns the reverse complement of a sequence
+pub fn reverse_complement(seq: &[u8]) -> Vec<u8> {
+    seq.iter()
+        .rev()
+        .map(|base| match base {
+            b'A' => b'T',
+            b'C' => b'G',
+            b'G' => b'C',
+            b'T' => b'A',
+            _ => b'N',
+        })
+        .collect()
+}
+
+/// Returns the length of the poly(T) stretch leading a sequence (tolerating isolated
+/// mismatches), or 0 if it is shorter than [`MIN_POLYT_LEN`]
+pub fn polyt_len(seq: &[u8]) -> usize {
+    let mut len = 0;
+    let mut pos = 0;
+    while pos < seq.len() {
+        if seq[pos] == b'T' {
+            len = pos + 1;
+        } else if seq.get(pos + 1) != Some(&b'T') {
+            break;
+        }
+        pos += 1;
+    }
+    if len >= MIN_POLYT_LEN {
+        len
+    } else {
+        0
+    }
+}
+
+/// Searches a long read for the read structure on the forward and then the reverse strand
+fn match_long_read(
+    seq: &[u8],
+    config: &Config,
+    opts: &LongReadOptions,
+) -> Option<(Orientation, Vec<u8>, ReadMatch)> {
+    let search = |seq: &[u8]| {
+        config
+            .match_read_edit(seq, opts.window, opts.max_distance, opts.umi_len)
+            .ok()
+    };
+    if let Some(read_match) = search(seq) {
+        return Some((Orientation::Forward, seq.to_vec(), read_match));
+    }
+    let reverse = reverse_complement(seq);
+    search(&reverse).map(|read_match| (Orientation::Reverse, reverse, read_match))
+}
+
+/// Splits a long read into a short-read style pair: an R1 holding the barcodes and UMI
+/// found (rebuilt on the read structure) and an R2 holding the cDNA following them, with
+/// any poly(T) stretch trimmed, on the strand of a short-read R2.
+///
+/// Unmatched reads are split into a single `N` R1 (which fails matching downstream) and
+/// the original read as R2. Fails on reads without a quality for each base.
+pub fn split_long_read(
+    record: &Record,
+    config: &Config,
+    opts: &LongReadOptions,
+) -> Result<(Record, Record, Orientation, bool)> {
+    let id = record.id();
+    let qual = match record.qual() {
+        Some(qual) => qual.to_vec(),
+        None => vec![FASTA_QUAL; record.seq().len()],
+    };
+    if qual.len() != record.seq().len() {
+        bail!(Error::Input(
+            format!(
+                "Long read {} has {} qualities for {} bases (truncated input?)",
+                String::from_utf8_lossy(id),
+                qual.len(),
+                record.seq().len()
+            )
+            .into()
+        ));
+    }
+    let build = |seq: &[u8], qual: &[u8]| Record::new_fastq_from_parts(id, seq, qual);
+    let Some((orientation, seq, read_match)) = match_long_read(record.seq(), config, opts) else {
+        return Ok((
+            build(b"N", &[FASTA_QUAL])?,
+            build(record.seq(), &qual)?,
+            Orientation::Unmatched,
+            false,
+        ));
+    };
+    let qual = match orientation {
+        Orientation::Reverse => qual.iter().rev().copied().collect(),
+        _ => qual,
+    };
+
+    // the qualities of the structure are resampled onto the rebuilt R1
+    let r1_seq = config.build_read(read_match.indices, &read_match.umi, opts.umi_len);
+    let region = &qual[read_match.shift..read_match.end];
+    let r1_qual = (0..r1_seq.len())
+        .map(|i| region.get(i * region.len() / r1_seq.len()).copied())
+        .map(|q| q.unwrap_or(FASTA_QUAL))
+        .collect::<Vec<_>>();
+
+    let polyt = polyt_len(&seq[read_match.end..]);
+    let cdna = read_match.end + polyt;
+    let r2_seq = reverse_complement(&seq[cdna..]);
+    let r2_qual = qual[cdna..].iter().rev().copied().collect::<Vec<_>>();
+    Ok((
+        build(&r1_seq, &r1_qual)?,
+        build(&r2_seq, &r2_qual)?,
+        orientation,
+        polyt > 0,
+    ))
+}
+
+/// The long reads being split and the split halves not yet consumed by either mate
+struct Splitter<'a, I> {
+    reads: I,
+    config: &'a Config,
+    opts: LongReadOptions,
+    pending: [VecDeque<Record>; 2],
+    counts: LongReadCounts,
+}
+impl<'a, I: Iterator<Item = Result<Record>>> Splitter<'a, I> {
+    fn next_mate(&mut self, mate: usize) -> Option<Result<Record>> {
+        if let Some(record) = self.pending[mate].pop_front() {
+            return Some(Ok(record));
+        }
+        let record = match self.reads.next()? {
+            Ok(record) => record,
+            Err(err) => return Some(Err(err)),
+        };
+        let (r1, r2, orientation, trimmed) = match split_long_read(&record, self.config, &self.opts)
+        {
+            Ok(split) => split,
+            Err(err) => return Some(Err(err)),
+        };
+        match orientation {
+            Orientation::Forward => self.counts.num_forward += 1,
+            Orientation::Reverse => self.counts.num_reverse += 1,
+            Orientation::Unmatched => self.counts.num_unmatched += 1,
+        }
+        self.counts.num_polyt_trimmed += trimmed as usize;
+        let (own, other) = if mate == 0 { (r1, r2) } else { (r2, r1) };
+        self.pending[1 - mate].push_back(other);
+        Some(Ok(own))
+    }
+}
+
+/// Streams long reads as the R1 and R2 record iterators of [`crate::process::process_pair`]
+pub struct LongReads<'a, I> {
+    splitter: Rc<RefCell<Splitter<'a, I>>>,
+}
+impl<'a, I: Iterator<Item = Result<Record>>> LongReads<'a, I> {
+    pub fn new(reads: I, config: &'a Config, opts: LongReadOptions) -> Self {
+        Self {
+            splitter: Rc::new(RefCell::new(Splitter {
+                reads,
+                config,
+                opts,
+                pending: [VecDeque::new(), VecDeque::new()],
+                counts: LongReadCounts::default(),
+            })),
+        }
+    }
+
+    /// Returns the R1 and R2 iterators of the split long reads
+    pub fn halves(&self) -> (LongReadMate<'a, I>, LongReadMate<'a, I>) {
+        let mate = |mate| LongReadMate {
+            splitter: Rc::clone(&self.splitter),
+            mate,
+        };
+        (mate(0), mate(1))
+    }
+
+    /// Returns the number of long reads matched on each strand so far
+    pub fn counts(&self) -> LongReadCounts {
+        self.splitter.borrow().counts
+    }
+}
+
+/// The R1 or R2 records of split long reads
+pub struct LongReadMate<'a, I> {
+    splitter: Rc<RefCell<Splitter<'a, I>>>,
+    mate: usize,
+}
+impl<I: Iterator<Item = Result<Record>>> Iterator for LongReadMate<'_, I> {
+    type Item = Result<Record>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.splitter.borrow_mut().next_mate(self.mate)
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    const TEST_PATH: &str = "data/config_v3.yaml";
+    const STRUCTURE: &[u8] = b"NNAGAAACCAATGTCTGTGGAGAAAGTGTCGAGCTGGGTATACGTACGTACGT";
+
+    fn long_read(seq: &[u8]) -> Record {
+        Record::new_fastq_from_parts(b"read", seq, &vec![b'?'; seq.len()]).unwrap()
+    }
+
+    #[test]
+    fn polyt() {
+        assert_eq!(polyt_len(b"TTTTTTTTTTACGT"), 10);
+        assert_eq!(polyt_len(b"TTTTCTTTTTGACGT"), 10);
+        assert_eq!(polyt_len(b"TTTTACGT"), 0);
+    }
+
+    #[test]
+    fn split_forward() {
+        let config = Config::from_file(TEST_PATH, false, false).unwrap();
+        let seq = [
+            b"CTACACGACGCTCTTCCGATCT".as_slice(),
+            STRUCTURE,
+            b"TTTTTTTTTTTT",
+            b"GGGCCCAAAACCC",
+        ]
+        .concat();
+        let (r1, r2, orientation, trimmed) =
+            split_long_read(&long_read(&seq), &config, &LongReadOptions::default()).unwrap();
+        assert_eq!(orientation, Orientation::Forward);
+        assert!(trimmed);
+        assert_eq!(r1.seq(), &STRUCTURE[2..]);
+        assert_eq!(r1.qual().unwrap().len(), r1.seq().len());
+        assert_eq!(r2.seq(), b"GGGTTTTGGGCCC");
+    }
+
+    #[test]
+    fn truncated_qualities() {
+        let config = Config::from_file(TEST_PATH, false, false).unwrap();
+        let record = fxread::FastqReader::new("@read\nACGTACGT\n+\nIIII\n".as_bytes())
+            .next()
+            .unwrap();
+        let err = split_long_read(&record, &config, &LongReadOptions::default()).unwrap_err();
+        assert_eq!(crate::error::exit_code(&err), crate::error::EXIT_INPUT);
+    }
+
+    #[test]
+    fn split_reverse() {
+        let config = Config::from_file(TEST_PATH, false, false).unwrap();
+        let seq = [STRUCTURE, b"TTTTTTTTTTTT", b"GGGCCCAAAACCC"].concat();
+        let reads = vec![
+            long_read(&reverse_complement(&seq)),
+            long_read(b"ACGTACGTACGTACGT"),
+        ];
+        let long_reads = LongReads::new(
+            reads.into_iter().map(Ok),
+            &config,
+            LongReadOptions::default(),
+        );
+        let (r1, r2) = long_reads.halves();
+        let r2 = r2.collect::<Result<Vec<_>>>().unwrap();
+        let r1 = r1.collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(r1[0].seq(), &STRUCTURE[2..]);
+        assert_eq!(r2[0].seq(), b"GGGTTTTGGGCCC");
+        assert_eq!(
+            (r1[1].seq(), r2[1].seq()),
+            (b"N".as_slice(), b"ACGTACGTACGTACGT".as_slice())
+        );
+        let counts = long_reads.counts();
+        assert_eq!(
+            (counts.num_forward, counts.num_reverse, counts.num_unmatched),
+            (0, 1, 1)
+        );
+    }
+}
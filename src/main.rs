@@ -1,131 +1,161 @@
-mod barcodes;
+mod append;
 mod cli;
-mod config;
-mod log;
+mod progress;
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Context, Result};
+use append::Append;
 use chrono::Local;
-use clap::Parser;
-use cli::Cli;
-use config::Config;
-use fxread::{initialize_reader, FastxRead, Record};
+use clap::{Parser, ValueEnum};
+use cli::{
+    Cli, Command, CompareArgs, Layout, LongReadArgs, Naming, Offset, SimulateArgs, TechStringArgs,
+    WhitelistArgs,
+};
 use gzp::{
-    deflate::Gzip,
+    deflate::{Bgzf, Gzip},
     par::compress::{ParCompress, ParCompressBuilder},
+    syncz::SyncZBuilder,
+    Compression, ZWriter,
+};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use pipspeak::{
+    bam::{Mate, ReadGroup},
+    cells,
+    checksum::{self, ChecksumAlgorithm, Checksums, Hashed},
+    compare::{Comparison, RunMetrics, WhitelistComparison},
+    config::{BarcodeKey, Config},
+    contaminant::ContaminantScreen,
+    counted::Counted,
+    dedup::DedupOptions,
+    discover,
+    error::{self, Classify, Error},
+    features::FeatureReference,
+    hashtags::Demultiplexed,
+    index::{IndexError, Indexed},
+    input::{self, compression_name, InputOptions, Records},
+    kb,
+    log::{FileIO, InputPair, LibrariesLog, LibrarySummary, Log, LongReadLog, Parameters, Timing},
+    longread::{LongReadOptions, LongReads},
+    pairing::Pairing,
+    process::{
+        estimate_offset, process_pair, process_shards_with_progress, NameFormat, Options,
+        OutputFormat, WhitelistMode, AUTO_OFFSET_READS, AUTO_OFFSET_WINDOW,
+    },
+    samplesheet::{self, Sample},
+    seqspec,
+    simulate::{ErrorModel, SimulateOptions, Simulator},
+    tenx::{TenxTranslation, TenxWhitelist},
+    trim::Adapters,
+    warnings,
+    whitelist::{self, ExpectedWhitelist, WhitelistCounts},
+    zumis,
 };
-use indicatif::ProgressBar;
-use log::{FileIO, Log, Parameters, Statistics, Timing};
+use progress::{JsonStream, Progress};
 use std::{
+    collections::HashMap,
     fs::File,
-    io::Write,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
     time::{Duration, Instant},
 };
 
-/// Writes a record to a gzip fastq file
-fn write_to_fastq<W: Write>(writer: &mut W, id: &[u8], seq: &[u8], qual: &[u8]) -> Result<()> {
-    writer.write_all(b"@")?;
-    writer.write_all(id)?;
-    writer.write_all(b"\n")?;
-    writer.write_all(seq)?;
-    writer.write_all(b"\n+\n")?;
-    writer.write_all(qual)?;
-    writer.write_all(b"\n")?;
-    Ok(())
+impl From<&Cli> for WhitelistMode {
+    fn from(args: &Cli) -> Self {
+        if args.no_whitelist {
+            Self::Skip
+        } else if args.external_whitelist {
+            Self::External {
+                tmp_dir: args
+                    .tmp_dir
+                    .as_ref()
+                    .map(PathBuf::from)
+                    .unwrap_or_else(std::env::temp_dir),
+                chunk_size: args.chunk_size(),
+            }
+        } else {
+            Self::InMemory
+        }
+    }
 }
 
-fn parse_records(
-    r1: Box<dyn FastxRead<Item = Record>>,
-    r2: Box<dyn FastxRead<Item = Record>>,
-    r1_out: &mut ParCompress<Gzip>,
-    r2_out: &mut ParCompress<Gzip>,
-    config: &Config,
-    offset: usize,
-    umi_len: usize,
-) -> Result<Statistics> {
-    let mut statistics = Statistics::new();
-    let pb = ProgressBar::new_spinner();
-    pb.enable_steady_tick(Duration::from_millis(100));
-    let record_iter = r1
-        .zip(r2)
-        .inspect(|_| statistics.total_reads += 1)
-        .enumerate()
-        .map(|(idx, pair)| {
-            if idx % 125 == 0 {
-                pb.set_message(format!("Processed {} reads", idx));
-            }
-            pair
-        })
-        .filter_map(|(rec1, rec2)| {
-            if let Some((pos, b1_idx)) = config.match_subsequence(rec1.seq(), 0, 0, Some(offset)) {
-                Some((rec1, rec2, pos, b1_idx))
-            } else {
-                statistics.num_filtered_1 += 1;
-                None
-            }
-        })
-        .filter_map(|(rec1, rec2, pos, b1_idx)| {
-            if let Some((new_pos, b2_idx)) = config.match_subsequence(rec1.seq(), 1, pos, None) {
-                Some((rec1, rec2, pos + new_pos, b1_idx, b2_idx))
-            } else {
-                statistics.num_filtered_2 += 1;
-                None
-            }
-        })
-        .filter_map(|(rec1, rec2, pos, b1_idx, b2_idx)| {
-            if let Some((new_pos, b3_idx)) = config.match_subsequence(&rec1.seq(), 2, pos, None) {
-                Some((rec1, rec2, pos + new_pos, b1_idx, b2_idx, b3_idx))
-            } else {
-                statistics.num_filtered_3 += 1;
-                None
-            }
-        })
-        .filter_map(|(rec1, rec2, pos, b1_idx, b2_idx, b3_idx)| {
-            if let Some((new_pos, b4_idx)) = config.match_subsequence(&rec1.seq(), 3, pos, None) {
-                statistics.passing_reads += 1;
-                Some((rec1, rec2, pos + new_pos, b1_idx, b2_idx, b3_idx, b4_idx))
+impl From<&Cli> for Options {
+    fn from(args: &Cli) -> Self {
+        Self {
+            // an automatic offset is narrowed down per sample before processing
+            offset: match args.offset {
+                _ if args.anchored => None,
+                Offset::Fixed(offset) => Some(offset),
+                Offset::Auto => Some(AUTO_OFFSET_WINDOW),
+            },
+            umi_len: args.umi_len,
+            trim_qual: args.trim_qual,
+            min_r2_len: args.min_r2_len,
+            filter_homopolymer_umi: args.filter_homopolymer_umi,
+            keep_empty_r2: args.keep_empty_r2,
+            reads_per_cell: args.reads_per_cell.map(|n| n as usize),
+            seed: args.seed,
+            tmp_dir: args
+                .tmp_dir
+                .as_ref()
+                .map(PathBuf::from)
+                .unwrap_or_else(std::env::temp_dir),
+            dedup: args.dedup.map(|mode| DedupOptions {
+                mode,
+                r2_prefix: args.dedup_r2_prefix,
+                capacity: args.dedup_capacity,
+            }),
+            shard_by: args.shard_by,
+            shard_block_size: args.shard_block_size as usize,
+            split_inputs: args.split_lanes,
+            max_held_bytes: args.max_memory.map(|budget| budget.held_bytes()),
+            min_umi_entropy: args.min_umi_entropy,
+            growth_interval: args.growth_interval.max(1),
+            whitelist: WhitelistMode::from(args),
+            barcode_qual: args.barcode_qual,
+            expected_cells: args.expected_cells,
+            cell_summary: args.cell_summary || args.cell_metadata,
+            r1_composition: args.r1_composition,
+            well_counts: args.well_counts,
+            output_format: args.output_format,
+            name_format: args.name_format,
+            strip_comments: args.strip_comments,
+            rename_reads: args.rename_reads,
+            barcode_table: args.barcode_table,
+            adapters: None,
+            contaminants: None,
+            tenx_whitelist: None,
+            expected_whitelist: None,
+            reject_unexpected: args.reject_unexpected,
+            flag_unexpected: args.flag_unexpected,
+            features: None,
+            tag_index: !args.i1.is_empty(),
+            read_group: None,
+            command_line: (args.output_format == OutputFormat::DropseqBam)
+                .then(|| std::env::args().collect::<Vec<_>>().join(" ")),
+            contaminant_action: args.contaminant_action,
+            chimeras: args.chimeras,
+            pairing: if args.repair {
+                Pairing::Repair
+            } else if args.check_pairing {
+                Pairing::Check
             } else {
-                statistics.num_filtered_4 += 1;
-                None
-            }
-        })
-        .filter_map(|(rec1, rec2, pos, b1_idx, b2_idx, b3_idx, b4_idx)| {
-            if rec1.seq().len() < pos + umi_len {
-                statistics.num_filtered_umi += 1;
-                None
-            } else {
-                let umi = &rec1.seq()[pos..pos + umi_len];
-                Some((
-                    b1_idx,
-                    b2_idx,
-                    b3_idx,
-                    b4_idx,
-                    umi.to_vec(),
-                    pos + umi_len,
-                    rec1,
-                    rec2,
-                ))
-            }
-        })
-        .map(|(b1_idx, b2_idx, b3_idx, b4_idx, umi, pos, rec1, rec2)| {
-            let mut construct_seq = config.build_barcode(b1_idx, b2_idx, b3_idx, b4_idx);
-            construct_seq.extend_from_slice(&umi);
-            let construct_qual = rec1.qual().unwrap()[pos - construct_seq.len()..pos].to_vec();
-            (construct_seq, construct_qual, rec1, rec2)
-        });
-
-    for (c_seq, c_qual, rec1, rec2) in record_iter {
-        statistics.whitelist.insert(c_seq.clone());
-        write_to_fastq(r1_out, rec1.id(), &c_seq, &c_qual)?;
-        write_to_fastq(r2_out, rec2.id(), rec2.seq(), rec2.qual().unwrap())?;
+                Pairing::Zip
+            },
+        }
     }
-    statistics.calculate_metrics();
-    pb.finish_with_message(format!(
-        "Processed {} reads, {} passed filters ({:.4}%)",
-        statistics.total_reads,
-        statistics.passing_reads,
-        statistics.fraction_passing * 100.0
-    ));
-    Ok(statistics)
+}
+
+/// Returns the command line name of an option value (as logged)
+fn value_name(value: impl ValueEnum) -> String {
+    value
+        .to_possible_value()
+        .expect("Option values are never skipped")
+        .get_name()
+        .to_string()
 }
 
 /// Sets the number of threads to use for writing R1 and R2 files
@@ -135,7 +165,7 @@ fn set_threads(num_threads: usize) -> (usize, usize) {
     } else if num_threads == 1 {
         (1, 1)
     } else {
-        if num_threads % 2 == 0 {
+        if num_threads.is_multiple_of(2) {
             (num_threads / 2, num_threads / 2)
         } else {
             (num_threads / 2, num_threads / 2 + 1)
@@ -143,72 +173,1423 @@ fn set_threads(num_threads: usize) -> (usize, usize) {
     }
 }
 
-fn main() -> Result<()> {
-    let args = Cli::parse();
-    let config = Config::from_file(&args.config, args.exact, args.linkers)?;
-    let r1 = initialize_reader(&args.r1)?;
-    let r2 = initialize_reader(&args.r2)?;
-
-    let r1_filename = args.prefix.clone() + "_R1.fq.gz";
-    let r2_filename = args.prefix.clone() + "_R2.fq.gz";
-    let log_filename = args.prefix.clone() + "_log.yaml";
-    let whitelist_filename = args.prefix.clone() + "_whitelist.txt";
-
-    let (r1_threads, r2_threads) = set_threads(args.threads);
-    let mut r1_writer: ParCompress<Gzip> = ParCompressBuilder::new()
-        .num_threads(r1_threads)?
-        .from_writer(File::create(&r1_filename)?);
-    let mut r2_writer: ParCompress<Gzip> = ParCompressBuilder::new()
-        .num_threads(r2_threads)?
-        .from_writer(File::create(&r2_filename)?);
+/// The suffix of outputs which are still being written
+const PARTIAL_SUFFIX: &str = ".partial";
+
+/// Returns the temporary path an output is written to until the sample is complete
+fn partial(path: &str) -> String {
+    format!("{}{}", path, PARTIAL_SUFFIX)
+}
+
+/// The partial file of an output, which must be finished to write its gzip trailer and
+/// surface any error flushing it
+enum Output {
+    Gzip(Box<dyn ZWriter>),
+    Plain(BufWriter<File>),
+}
+impl Output {
+    fn finish(self) -> Result<()> {
+        match self {
+            Self::Gzip(mut writer) => writer.finish()?,
+            Self::Plain(writer) => {
+                writer.into_inner().map_err(|err| err.into_error())?;
+            }
+        }
+        Ok(())
+    }
+}
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Gzip(writer) => writer.write(buf),
+            Self::Plain(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Gzip(writer) => writer.flush(),
+            Self::Plain(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Creates the partial file of an output, gzip compressing it if the path ends in `.gz`
+fn create_output(path: &str) -> Result<Output> {
+    let file = File::create(partial(path))
+        .with_context(|| format!("Failed to create output: {}", path))
+        .classify(Error::Output)?;
+    if path.ends_with(".gz") {
+        Ok(Output::Gzip(Box::new(
+            SyncZBuilder::<Gzip, File>::new()
+                .compression_level(Compression::new(6))
+                .from_writer(file),
+        )))
+    } else {
+        Ok(Output::Plain(BufWriter::new(file)))
+    }
+}
+
+/// Writes the partial checksum files of each output, taking the checksums hashed while
+/// writing where available and reading the remaining outputs back (from their final path
+/// if they were extended in place), and returns their paths
+fn write_checksums(
+    outputs: &[String],
+    streamed: &HashMap<String, Checksums>,
+    extended: &[String],
+    algorithms: &[ChecksumAlgorithm],
+) -> Result<Vec<String>> {
+    let mut sidecars = Vec::new();
+    if algorithms.is_empty() {
+        return Ok(sidecars);
+    }
+    for path in outputs {
+        let digests = match streamed.get(path) {
+            Some(checksums) => checksums
+                .hex()
+                .with_context(|| format!("Output was not closed before hashing: {}", path))?,
+            None if extended.contains(path) => checksum::file_checksums(path, algorithms)?,
+            None => checksum::file_checksums(&partial(path), algorithms)?,
+        };
+        for (algorithm, digest) in digests {
+            let sidecar = algorithm.sidecar_path(path);
+            checksum::write_sidecar(&partial(&sidecar), path, &digest)
+                .with_context(|| format!("Failed to write checksum: {}", sidecar))
+                .classify(Error::Output)?;
+            sidecars.push(sidecar);
+        }
+    }
+    Ok(sidecars)
+}
+
+/// The outcome of processing a single sample
+struct SampleRun {
+    log: Log,
+    log_path: String,
+    /// False if the fraction of passing reads is below `--min-pass-rate`
+    passed: bool,
+}
+
+/// Processes a single sample and writes all of its outputs.
+///
+/// Outputs are written under a `.partial` suffix and only renamed once every output
+/// (and finally the log) is complete.
+fn run_sample(
+    args: &Cli,
+    config: &Config,
+    base_opts: &Options,
+    sample: &Sample,
+    mp: &MultiProgress,
+) -> Result<SampleRun> {
+    let threads = args.threads();
+    // the budget of the chunks in flight is shared by every decompression thread
+    let capacity = match args.max_memory {
+        Some(budget) => budget.in_flight_chunks(
+            sample.r1.len() * threads.decompress,
+            input::CHANNEL_CAPACITY,
+        ),
+        None => input::CHANNEL_CAPACITY,
+    };
+    let inputs = sample
+        .r1
+        .iter()
+        .zip(&sample.r2)
+        .map(|(r1, r2)| {
+            // an unaligned BAM given for both reads is read once for both mates
+            if r1 == r2 {
+                return input::open_mates(r1, (threads.decompress >= 1).then_some(capacity));
+            }
+            let open = |path: &str, mate: Mate, threaded: bool| {
+                let opts = InputOptions {
+                    parser: args.parser,
+                    mate,
+                };
+                if threaded {
+                    input::open_threaded(path, capacity, opts)
+                } else {
+                    input::open_with(path, opts)
+                }
+            };
+            Ok((
+                open(r1, Mate::R1, threads.decompress >= 2)?,
+                open(r2, Mate::R2, threads.decompress >= 1)?,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let sample_name = sample.name.clone().unwrap_or_else(|| sample.prefix.clone());
+    let (prefix, naming, fastq_prefix, whitelist_filename) = match args.layout {
+        Layout::Flat => (
+            sample.prefix.clone(),
+            args.naming,
+            sample.prefix.clone(),
+            sample.prefix.clone() + "_whitelist.txt",
+        ),
+        Layout::Tenx => {
+            let outs = Path::new(&sample.prefix).join("outs");
+            std::fs::create_dir_all(outs.join("fastq"))?;
+            let name = Path::new(&sample_name)
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or("sample");
+            let fastq_prefix = outs.join("fastq").join(name).display().to_string();
+            (
+                outs.join(name).display().to_string(),
+                Naming::Tenx,
+                fastq_prefix,
+                outs.join("barcodes.tsv.gz").display().to_string(),
+            )
+        }
+    };
+    let log_filename = prefix.clone() + "_log.yaml";
+    let emitted_barcode_len = base_opts
+        .tenx_whitelist
+        .as_ref()
+        .map_or(config.barcode_len(), |whitelist| whitelist.barcode_len());
+    let bam = args.output_format == OutputFormat::DropseqBam;
+    let write_r1 = !args.no_r1_out && !bam;
+    let num_shards = args.shards as usize;
+    if bam && num_shards > 1 {
+        bail!(Error::Usage(
+            "Sharding is only supported for fastq outputs".into()
+        ));
+    }
+    if bam && args.split_lanes {
+        bail!(Error::Usage(
+            "Splitting lanes is only supported for fastq outputs".into()
+        ));
+    }
+    if bam && args.barcode_table {
+        bail!(Error::Usage(
+            "Barcode tables are only supported for fastq outputs".into()
+        ));
+    }
+    if bam && args.append {
+        bail!(Error::Usage(
+            "Appending is only supported for fastq outputs".into()
+        ));
+    }
+    let lanes = if args.split_lanes {
+        discover::lane_numbers(&sample.r1)
+    } else {
+        vec![args.lane]
+    };
+    // each lane has its own group of shards
+    let fastq_filenames = |read: &str| {
+        let mut filenames = Vec::new();
+        for lane in &lanes {
+            let prefix = match naming {
+                Naming::Pipspeak if args.split_lanes => format!("{}_L{:03}", fastq_prefix, lane),
+                _ => fastq_prefix.clone(),
+            };
+            if num_shards == 1 {
+                filenames.push(naming.filename(&prefix, read, *lane));
+            } else {
+                filenames.extend(
+                    (0..num_shards).map(|shard| naming.shard_filename(&prefix, read, *lane, shard)),
+                );
+            }
+        }
+        filenames
+    };
+    let r1_filenames = if write_r1 {
+        fastq_filenames("R1")
+    } else {
+        Vec::new()
+    };
+    // the barcode tables take the place of the R1 outputs
+    let barcode_table_filenames = if args.barcode_table {
+        fastq_filenames("barcodes")
+            .iter()
+            .map(|path| {
+                path.replace(".fastq.gz", ".tsv.gz")
+                    .replace(".fq.gz", ".tsv.gz")
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let r2_filenames = if bam {
+        vec![prefix.clone() + "_unaligned_tagged.bam"]
+    } else {
+        fastq_filenames("R2")
+    };
+    let mut opts = base_opts.clone();
+    let base = |path: &str| {
+        Path::new(path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(path)
+            .to_string()
+    };
+    // the libraries of a multi-modal run are libraries of the same sample
+    let (sample_id, library) = if config.libraries().is_empty() {
+        (base(&sample_name), None)
+    } else {
+        (base(&args.prefix), sample.name.clone())
+    };
+    if bam {
+        opts.read_group = Some(ReadGroup {
+            id: args.rg_id.clone().unwrap_or_else(|| base(&sample_name)),
+            sample: Some(args.rg_sample.clone().unwrap_or(sample_id.clone())),
+            library: args.rg_library.clone().or(library),
+            platform: Some(args.rg_platform.clone()),
+        });
+    }
+    if let Some(path) = &sample.features {
+        opts.features = Some(Arc::new(
+            FeatureReference::from_file(path, args.feature_offset, args.feature_mismatches)
+                .classify(Error::Config)?,
+        ));
+    }
+    let features = sample.features.as_ref().or(args.features.as_ref());
+    let in_memory = matches!(opts.whitelist, WhitelistMode::InMemory);
+    let external = matches!(opts.whitelist, WhitelistMode::External { .. });
+    let whitelist_filename = (in_memory || external).then_some(whitelist_filename);
+    // the counts of an in-memory whitelist are kept to merge it into later deliveries
+    let whitelist_counts_filename =
+        (external || (in_memory && args.append)).then(|| prefix.clone() + "_whitelist_counts.tsv");
+    let append_state_filename = args.append.then(|| prefix.clone() + "_append_state.json");
+    let composition_filename = args
+        .r1_composition
+        .then(|| prefix.clone() + "_r1_composition.tsv");
+    let umi_composition_filename =
+        (config.umi_len(opts.umi_len) > 0).then(|| prefix.clone() + "_umi_composition.tsv");
+    let growth_filename = in_memory.then(|| prefix.clone() + "_whitelist_growth.tsv");
+    let translation_filename = if in_memory && config.translates() {
+        Some(prefix.clone() + "_translation.tsv")
+    } else {
+        None
+    };
+    let well_counts_filename = args
+        .well_counts
+        .then(|| prefix.clone() + "_well_counts.tsv");
+    let tenx_translation_filename = args
+        .tenx_whitelist
+        .as_ref()
+        .filter(|_| in_memory)
+        .map(|_| prefix.clone() + "_tenx_translation.tsv");
+    let ambient_max_reads = args
+        .ambient_max_reads
+        .or(config.chip().map(|chip| chip.ambient_max_reads()))
+        .unwrap_or(cells::DEFAULT_AMBIENT_MAX_READS);
+    let cell_calls_filename =
+        (in_memory && args.call_cells).then(|| prefix.clone() + "_cell_calls.tsv");
+    let cell_summary_filename =
+        (in_memory && args.cell_summary).then(|| prefix.clone() + "_cell_summary.tsv");
+    let cell_metadata_filename =
+        (in_memory && args.cell_metadata).then(|| prefix.clone() + "_cell_metadata.csv");
+    let seqspec_filename = prefix.clone() + "_seqspec.yaml";
+    let zumis_filename = args.zumis.then(|| prefix.clone() + "_zUMIs.yaml");
+    let read_names_filename = args
+        .rename_reads
+        .then(|| prefix.clone() + "_read_names.tsv.gz");
+    let (feature_matrix_filename, feature_barcodes_filename, features_filename) = match features {
+        Some(_) => (
+            Some(prefix.clone() + "_feature_matrix.mtx"),
+            Some(prefix.clone() + "_feature_barcodes.tsv"),
+            Some(prefix.clone() + "_features.tsv"),
+        ),
+        None => (None, None, None),
+    };
+    let wells_filename = if in_memory && config.has_annotations() {
+        Some(prefix.clone() + "_whitelist_wells.tsv")
+    } else {
+        None
+    };
+
+    let mut append = if args.append {
+        Append::open(
+            &log_filename,
+            append_state_filename.as_deref().unwrap_or_default(),
+            whitelist_filename.as_deref().filter(|_| external),
+            whitelist_counts_filename.as_deref(),
+        )?
+    } else {
+        None
+    };
+
+    let (r1_threads, r2_threads) = if write_r1 || args.barcode_table {
+        set_threads(threads.compress)
+    } else {
+        (0, threads.compress)
+    };
+    // the compression threads of each read are divided between its shards (of every lane)
+    let num_outputs = r2_filenames.len();
+    let (r1_threads, r2_threads) = (
+        (r1_threads / num_outputs).max(1),
+        (r2_threads / num_outputs).max(1),
+    );
+    let mut bytes_written = Vec::new();
+    // the fastqs are hashed as they are written rather than read back
+    let mut streamed_checksums = HashMap::new();
+    let mut r1_writers = Vec::new();
+    for filename in r1_filenames.iter().chain(&barcode_table_filenames) {
+        let extended = append
+            .as_mut()
+            .is_some_and(|append| append.extends(filename));
+        let file = Counted::new(File::create(partial(filename))?);
+        bytes_written.push(file.counter());
+        let file = Hashed::new(file, &args.checksums);
+        // the earlier content of an extended output is only hashed once it is complete
+        if !extended {
+            streamed_checksums.insert(filename.clone(), file.checksums());
+        }
+        let writer: ParCompress<Gzip> = ParCompressBuilder::new()
+            .num_threads(r1_threads)?
+            .from_writer(file);
+        r1_writers.push(writer);
+    }
+    let mut r2_writers = Vec::new();
+    for filename in &r2_filenames {
+        let extended = append
+            .as_mut()
+            .is_some_and(|append| append.extends(filename));
+        let file = Counted::new(File::create(partial(filename))?);
+        bytes_written.push(file.counter());
+        let file = Hashed::new(file, &args.checksums);
+        // the earlier content of an extended output is only hashed once it is complete
+        if !extended {
+            streamed_checksums.insert(filename.clone(), file.checksums());
+        }
+        let writer: Box<dyn ZWriter> = if bam {
+            Box::new(
+                ParCompressBuilder::<Bgzf>::new()
+                    .num_threads(r2_threads)?
+                    .from_writer(file),
+            )
+        } else {
+            Box::new(
+                ParCompressBuilder::<Gzip>::new()
+                    .num_threads(r2_threads)?
+                    .from_writer(file),
+            )
+        };
+        r2_writers.push(writer);
+    }
 
     let timestamp = Local::now().to_string();
     let start_time = Instant::now();
 
-    let statistics = parse_records(
-        r1,
-        r2,
-        &mut r1_writer,
-        &mut r2_writer,
-        &config,
-        args.offset,
-        args.umi_len,
+    let mut progress = if args.tui {
+        Progress::tui(sample_name.clone())
+    } else {
+        let pb = mp.add(ProgressBar::new_spinner());
+        if let Some(name) = &sample.name {
+            pb.set_style(ProgressStyle::with_template("{spinner} [{prefix}] {msg}")?);
+            pb.set_prefix(name.clone());
+        }
+        Progress::spinner(pb)
+    };
+    if let Some(path) = &args.progress_json {
+        progress = progress.with_stream(JsonStream::new(
+            path,
+            sample_name.clone(),
+            Duration::from_secs_f64(args.progress_interval),
+        )?);
+    }
+    let bytes_read = inputs
+        .iter()
+        .flat_map(|(r1, r2)| [r1.bytes_read.clone(), r2.bytes_read.clone()])
+        .collect::<Vec<_>>();
+    let compressions = inputs
+        .iter()
+        .map(|(r1, r2)| {
+            (
+                compression_name(r1.compression),
+                compression_name(r2.compression),
+            )
+        })
+        .collect::<Vec<_>>();
+    let index_error = IndexError::default();
+    let mut pairs = inputs
+        .into_iter()
+        .enumerate()
+        .map(|(idx, (r1, r2))| -> Result<(Records, Records)> {
+            let Some(i1) = sample.i1.get(idx) else {
+                return Ok((Box::new(r1.reader), Box::new(r2.reader)));
+            };
+            let opts = InputOptions {
+                parser: args.parser,
+                mate: Mate::R1,
+            };
+            // the index reads are read once and folded into both read inputs
+            let i1: Records = Box::new(input::open_with(i1, opts)?.reader);
+            let i2: Option<Records> = match sample.i2.get(idx) {
+                Some(path) => Some(Box::new(input::open_with(path, opts)?.reader)),
+                None => None,
+            };
+            let (r1, r2) = Indexed::pair(r1.reader, r2.reader, i1, i2, index_error.clone());
+            Ok((Box::new(r1), Box::new(r2)))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    // the reads used to estimate the offset are buffered so inputs are only read once
+    if args.offset == Offset::Auto && !args.anchored {
+        let (r1_reader, _) = &mut pairs[0];
+        let leading_reads = r1_reader
+            .by_ref()
+            .take(AUTO_OFFSET_READS)
+            .collect::<Result<Vec<_>>>()?;
+        let offset = estimate_offset(leading_reads.iter().map(|r| r.seq()), config, opts.umi_len)
+            .ok_or_else(|| {
+                anyhow!(
+                    "Could not estimate the offset of {}: no reads matched barcode 1 within {} nucleotides",
+                    sample.prefix,
+                    AUTO_OFFSET_WINDOW
+                )
+            })?;
+        opts.offset = Some(offset);
+        let remaining = std::mem::replace(r1_reader, Box::new(std::iter::empty()));
+        *r1_reader = Box::new(leading_reads.into_iter().map(Ok).chain(remaining));
+    }
+    let logged_offset = match args.offset {
+        Offset::Fixed(offset) => offset,
+        Offset::Auto => opts.offset.unwrap_or(0),
+    };
+    if let Some(append) = &append {
+        append.check_parameters(&[
+            ("config", args.config.as_deref().unwrap_or_default().into()),
+            ("structure", config.structure().to_string().into()),
+            ("umi_len", args.umi_len.into()),
+            ("offset", logged_offset.into()),
+            ("write_linkers", args.linkers.into()),
+        ])?;
+    }
+    let mut r1_sinks = vec![std::io::sink(); num_outputs];
+    let mut r1_outs = if write_r1 || args.barcode_table {
+        r1_writers
+            .iter_mut()
+            .map(|writer| writer as &mut dyn Write)
+            .collect::<Vec<_>>()
+    } else {
+        r1_sinks
+            .iter_mut()
+            .map(|sink| sink as &mut dyn Write)
+            .collect::<Vec<_>>()
+    };
+    let mut names_writer = read_names_filename
+        .as_deref()
+        .map(create_output)
+        .transpose()?;
+    let (mut statistics, pair_statistics) = match process_shards_with_progress(
+        pairs,
+        &mut r1_outs,
+        &mut r2_writers,
+        names_writer.as_mut().map(|w| w as &mut dyn Write),
+        config,
+        &opts,
+        |statistics| progress.update(statistics),
+    ) {
+        Ok(statistics) => statistics,
+        Err(err) => {
+            progress.abandon();
+            return Err(err);
+        }
+    };
+    progress.finish(&statistics)?;
+    index_error.check()?;
+    // flushing the compression threads is part of writing
+    let flush_start = Instant::now();
+    drop(r1_outs);
+    for writer in r1_writers.iter_mut() {
+        writer.finish()?;
+    }
+    for writer in r2_writers.iter_mut() {
+        writer.finish()?;
+    }
+    if let Some(writer) = names_writer.take() {
+        writer.finish()?;
+    }
+    statistics.stage_times.writing += flush_start.elapsed();
+    if let Some(append) = append.as_mut() {
+        append.merge_statistics(&mut statistics, config, opts.expected_cells, in_memory)?;
+    }
+    if let Some(counter) = statistics.external_whitelist.take() {
+        let mut whitelist = create_output(whitelist_filename.as_deref().unwrap_or_default())?;
+        let mut counts = create_output(whitelist_counts_filename.as_deref().unwrap_or_default())?;
+        statistics.whitelist_size = counter.finish(&mut whitelist, &mut counts)?;
+        whitelist.finish()?;
+        counts.finish()?;
+    } else if let Some(path) = &whitelist_filename {
+        let mut output = create_output(path)?;
+        statistics.whitelist_to_writer(&mut output, config)?;
+        output.finish()?;
+        if let Some(path) = &whitelist_counts_filename {
+            let mut output = create_output(path)?;
+            statistics.whitelist_counts_to_writer(&mut output, config)?;
+            output.finish()?;
+        }
+    }
+    if let Some(append) = append.as_mut() {
+        append.merge_whitelists(
+            &mut statistics,
+            whitelist_counts_filename.as_deref(),
+            whitelist_filename.as_deref().filter(|_| external),
+        )?;
+    }
+    if let Some(path) = &append_state_filename {
+        let mut output = create_output(path)?;
+        statistics.append_state().to_writer(&mut output)?;
+        output.finish()?;
+    }
+    if let Some(path) = &composition_filename {
+        statistics.r1_composition.to_file(&partial(path))?;
+    }
+    if let Some(path) = &umi_composition_filename {
+        statistics.umi_composition.to_file(&partial(path))?;
+    }
+    if let Some(path) = &growth_filename {
+        statistics.growth_to_file(&partial(path))?;
+    }
+    if let (Some(path), Some(translation)) =
+        (&tenx_translation_filename, &statistics.tenx_translation)
+    {
+        translation.to_file(statistics.whitelist.keys(), config, &partial(path))?;
+    }
+    if let Some(path) = &translation_filename {
+        statistics.translation_to_file(&partial(path), config)?;
+    }
+    if let Some(path) = &well_counts_filename {
+        statistics.well_counts_to_file(&partial(path), config)?;
+    }
+    if let Some(path) = &cell_summary_filename {
+        statistics.cell_summary_to_file(&partial(path), config)?;
+    }
+    if let Some(path) = &cell_metadata_filename {
+        statistics.cell_metadata_to_file(&partial(path), config, &sample_id)?;
+    }
+    if let Some(path) = &cell_calls_filename {
+        statistics.cell_calls_to_file(
+            &partial(path),
+            config,
+            opts.expected_cells,
+            ambient_max_reads,
+            args.cell_fdr,
+        )?;
+    }
+    if let Some(path) = &wells_filename {
+        statistics.wells_to_file(&partial(path), config)?;
+    }
+    seqspec::to_file(
+        &partial(&seqspec_filename),
+        config,
+        opts.umi_len,
+        &sample_name,
+        &r1_filenames,
+        if bam { &[] } else { &r2_filenames },
+        args.tenx_whitelist
+            .as_deref()
+            .zip(opts.tenx_whitelist.as_ref().map(|w| w.barcode_len())),
     )?;
-    statistics.whitelist_to_file(&whitelist_filename)?;
+
+    if let Some(path) = &zumis_filename {
+        zumis::to_file(
+            &partial(path),
+            config,
+            opts.umi_len,
+            &r1_filenames[0],
+            &r2_filenames[0],
+            statistics.max_r2_len,
+            whitelist_filename.as_deref(),
+        )?;
+    }
+
+    if let (Some(reference), Some(matrix), Some(barcodes), Some(features)) = (
+        &opts.features,
+        &feature_matrix_filename,
+        &feature_barcodes_filename,
+        &features_filename,
+    ) {
+        statistics.feature_counts.to_files(
+            reference,
+            &partial(matrix),
+            &partial(barcodes),
+            &partial(features),
+        )?;
+    }
+
+    let mut hashtag_assignments_filename = None;
+    let mut hashtag_cells_filenames = Vec::new();
+    if let Some(reference) = opts.features.as_ref().filter(|_| args.demux_hashtags) {
+        let demux =
+            Demultiplexed::new(&statistics.feature_counts, reference, args.hashtag_min_umis);
+        let path = prefix.clone() + "_hashtags.tsv";
+        demux.assignments_to_file(&partial(&path))?;
+        hashtag_assignments_filename = Some(path);
+        for (idx, hashtag) in demux.hashtags().iter().enumerate() {
+            let name = hashtag
+                .chars()
+                .map(|c| match c {
+                    'A'..='Z' | 'a'..='z' | '0'..='9' | '-' | '.' => c,
+                    _ => '_',
+                })
+                .collect::<String>();
+            let path = format!("{}_hashtag_{}_cells.txt", prefix, name);
+            demux.cells_to_file(idx, &partial(&path))?;
+            hashtag_cells_filenames.push(path);
+        }
+        statistics.hashtags = Some(demux.summary());
+    }
 
     let elapsed_time = start_time.elapsed().as_secs_f64();
-    let timing = Timing {
+    let timing = Timing::new(
         timestamp,
         elapsed_time,
-    };
+        statistics.total_reads,
+        bytes_read.iter().map(|b| b.get()).sum(),
+        bytes_written.iter().map(|counter| counter.get()).sum(),
+    )
+    .with_stages(&statistics.stage_times);
 
     let parameters = Parameters {
-        offset: args.offset,
+        sample: sample.name.clone(),
+        library_type: sample.library_type.clone(),
+        config: args.config.clone().unwrap_or_default(),
+        offset: logged_offset,
+        auto_offset: args.offset == Offset::Auto,
+        anchored: args.anchored,
+        trim_qual: args.trim_qual,
+        min_r2_len: args.min_r2_len,
+        filter_homopolymer_umi: args.filter_homopolymer_umi,
+        keep_empty_r2: args.keep_empty_r2,
+        reads_per_cell: args.reads_per_cell,
+        dedup: args.dedup.map(value_name),
+        dedup_r2_prefix: args.dedup_r2_prefix,
+        shards: num_shards,
+        shard_by: value_name(args.shard_by),
+        shard_block_size: args.shard_block_size as usize,
+        split_lanes: args.split_lanes,
+        seed: args.seed,
+        expected_whitelist: args
+            .expected_whitelist
+            .as_deref()
+            .or(config.expected_whitelist())
+            .map(str::to_string),
+        tenx_whitelist: args.tenx_whitelist.clone(),
+        reject_unexpected: args.reject_unexpected,
+        flag_unexpected: args.flag_unexpected,
+        features: features.cloned(),
+        feature_offset: args.feature_offset,
+        feature_mismatches: args.feature_mismatches,
+        demux_hashtags: args.demux_hashtags,
+        hashtag_min_umis: args.hashtag_min_umis,
+        min_umi_entropy: args.min_umi_entropy,
+        growth_interval: args.growth_interval,
+        no_whitelist: args.no_whitelist,
+        external_whitelist: args.external_whitelist,
+        max_memory: args.max_memory.map(|budget| budget.bytes()),
+        chunk_size: args.chunk_size(),
+        min_pass_rate: args.min_pass_rate,
+        expected_cells: opts.expected_cells,
+        chip: config.chip().map(|chip| chip.to_string()),
+        call_cells: args.call_cells,
+        cell_summary: args.cell_summary,
+        cell_metadata: args.cell_metadata,
+        ambient_max_reads,
+        cell_fdr: args.cell_fdr,
         umi_len: args.umi_len,
+        naming: value_name(args.naming),
+        layout: value_name(args.layout),
+        name_format: value_name(args.name_format),
+        strip_comments: args.strip_comments,
+        rename_reads: args.rename_reads,
+        no_r1_out: args.no_r1_out,
+        barcode_table: args.barcode_table,
+        threads: args.threads,
+        decompress_threads: threads.decompress,
+        compress_threads: threads.compress,
+        parser: value_name(args.parser),
+        output_format: value_name(args.output_format),
+        zumis: args.zumis,
+        append: args.append,
+        barcode_qual: opts.barcode_qual.to_string(),
+        adapters: args.adapters.clone(),
+        adapter_error_rate: args.adapter_error_rate,
+        adapter_min_overlap: args.adapter_min_overlap,
+        contaminants: args.contaminants.clone(),
+        contaminant_k: args.contaminant_k,
+        contaminant_action: value_name(args.contaminant_action),
+        chimeras: args.chimeras.map(value_name),
+        check_pairing: args.check_pairing,
+        repair: args.repair,
         exact_matching: args.exact,
+        matcher: value_name(config.matcher()),
         write_linkers: args.linkers,
+        structure: config.structure().to_string(),
+        kb_technology: kb::tech_string(emitted_barcode_len, config.umi_len(opts.umi_len)),
         pipspeak_version: env!("CARGO_PKG_VERSION").to_string(),
     };
 
+    // the log is renamed last so that its presence marks a complete sample
+    let mut outputs = [
+        whitelist_filename.as_ref(),
+        whitelist_counts_filename.as_ref(),
+        append_state_filename.as_ref(),
+        translation_filename.as_ref(),
+        tenx_translation_filename.as_ref(),
+        wells_filename.as_ref(),
+        well_counts_filename.as_ref(),
+        cell_calls_filename.as_ref(),
+        cell_summary_filename.as_ref(),
+        cell_metadata_filename.as_ref(),
+        composition_filename.as_ref(),
+        umi_composition_filename.as_ref(),
+        growth_filename.as_ref(),
+        Some(&seqspec_filename),
+        zumis_filename.as_ref(),
+        read_names_filename.as_ref(),
+        feature_matrix_filename.as_ref(),
+        feature_barcodes_filename.as_ref(),
+        features_filename.as_ref(),
+        hashtag_assignments_filename.as_ref(),
+    ]
+    .into_iter()
+    .flatten()
+    .chain(&r1_filenames)
+    .chain(&barcode_table_filenames)
+    .chain(&r2_filenames)
+    .chain(&hashtag_cells_filenames)
+    .chain([&log_filename])
+    .cloned()
+    .collect::<Vec<_>>();
+
     let file_io = FileIO {
-        readpath_r1: args.r1,
-        readpath_r2: args.r2,
-        writepath_r1: r1_filename,
-        writepath_r2: r2_filename,
+        readpath_r1: sample.r1.clone(),
+        readpath_r2: sample.r2.clone(),
+        compression_r1: compressions.iter().map(|(r1, _)| r1.to_string()).collect(),
+        compression_r2: compressions.iter().map(|(_, r2)| r2.to_string()).collect(),
+        readpath_i1: sample.i1.clone(),
+        readpath_i2: sample.i2.clone(),
+        writepath_r1: r1_filenames,
+        writepath_r2: r2_filenames,
+        barcode_table_paths: barcode_table_filenames,
         whitelist_path: whitelist_filename,
+        whitelist_counts_path: whitelist_counts_filename,
+        append_state_path: append_state_filename,
+        translation_path: translation_filename,
+        tenx_translation_path: tenx_translation_filename,
+        wells_path: wells_filename,
+        well_counts_path: well_counts_filename,
+        cell_calls_path: cell_calls_filename,
+        cell_summary_path: cell_summary_filename,
+        cell_metadata_path: cell_metadata_filename,
+        composition_path: composition_filename,
+        umi_composition_path: umi_composition_filename,
+        growth_path: growth_filename,
+        seqspec_path: seqspec_filename,
+        zumis_path: zumis_filename,
+        read_names_path: read_names_filename,
+        feature_matrix_path: feature_matrix_filename,
+        feature_barcodes_path: feature_barcodes_filename,
+        features_path: features_filename,
+        hashtag_assignments_path: hashtag_assignments_filename,
+        hashtag_cells_paths: hashtag_cells_filenames,
     };
 
-    let log = Log {
+    // an automatic offset sits at the tail of the observed shifts by construction
+    let checked_offset = opts.offset.filter(|_| args.offset != Offset::Auto);
+    let mut warnings = warnings::sanity_check(&statistics, checked_offset, opts.expected_cells);
+    warnings.extend(kb::check_barcode_len(emitted_barcode_len));
+    warnings.extend(
+        config
+            .chip()
+            .and_then(|chip| chip.check_capacity(&statistics)),
+    );
+    let inputs = sample
+        .r1
+        .iter()
+        .zip(&sample.r2)
+        .zip(compressions)
+        .zip(pair_statistics)
+        .map(
+            |(((r1, r2), (compression_r1, compression_r2)), statistics)| InputPair {
+                readpath_r1: r1.clone(),
+                readpath_r2: r2.clone(),
+                compression_r1: compression_r1.to_string(),
+                compression_r2: compression_r2.to_string(),
+                statistics,
+            },
+        )
+        .collect();
+    let mut log = Log {
         parameters,
+        inputs,
         timing,
         statistics,
+        warnings,
+        collisions: config.collisions().to_vec(),
+        ambiguity: config.ambiguity().clone(),
         file_io,
     };
 
+    if let Some(append) = &append {
+        append.accumulate(&mut log)?;
+    }
     if !args.quiet {
         log.stderr()?;
     }
-    log.to_file(&log_filename)?;
+    log.to_file(&partial(&log_filename))?;
+    let extended_outputs = append.as_ref().map_or(&[][..], Append::extended);
+    let mut finalize = || -> Result<()> {
+        let sidecars = write_checksums(
+            &outputs,
+            &streamed_checksums,
+            extended_outputs,
+            &args.checksums,
+        )?;
+        let log_index = outputs.len() - 1;
+        outputs.splice(log_index..log_index, sidecars);
+        for path in outputs
+            .iter()
+            .filter(|path| !extended_outputs.contains(path))
+        {
+            std::fs::rename(partial(path), path)?;
+        }
+        Ok(())
+    };
+    // the earlier fastqs are only extended once the delivery is complete
+    match &append {
+        Some(append) => append.commit(finalize)?,
+        None => finalize()?,
+    }
+
+    let passed = match args.min_pass_rate {
+        Some(min) if log.statistics.fraction_passing < min => {
+            if !args.quiet {
+                eprintln!(
+                    "Fraction of passing reads ({:.4}) for {} is below the minimum pass rate ({})",
+                    log.statistics.fraction_passing, sample.prefix, min
+                );
+            }
+            false
+        }
+        _ => true,
+    };
+    Ok(SampleRun {
+        log,
+        log_path: log_filename,
+        passed,
+    })
+}
+
+/// Writes the combined log of the libraries of a multi-modal run and, if whitelists were
+/// collected, the union of the barcodes observed across the libraries
+fn write_libraries(
+    args: &Cli,
+    config: &Config,
+    samples: &[Sample],
+    runs: &[SampleRun],
+) -> Result<()> {
+    let in_memory = !args.no_whitelist && !args.external_whitelist;
+    let mut observed = hashbrown::HashMap::<BarcodeKey, usize>::new();
+    for run in runs {
+        for key in run.log.statistics.whitelist.keys() {
+            *observed.entry(*key).or_default() += 1;
+        }
+    }
+    let whitelist_path = in_memory.then(|| args.prefix.clone() + "_whitelist.txt");
+    if let Some(path) = &whitelist_path {
+        let mut barcodes = observed
+            .keys()
+            .map(|key| {
+                let [b1_idx, b2_idx, b3_idx, b4_idx] = key.indices();
+                config.build_barcode(b1_idx, b2_idx, b3_idx, b4_idx)
+            })
+            .collect::<Vec<_>>();
+        barcodes.sort_unstable();
+        let mut writer = create_output(path)?;
+        for barcode in barcodes {
+            writer.write_all(&barcode)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.finish()?;
+    }
+    let log_path = args.prefix.clone() + "_libraries.yaml";
+    let log = LibrariesLog {
+        libraries: samples
+            .iter()
+            .zip(runs)
+            .map(|(sample, run)| LibrarySummary {
+                name: sample.name.clone().unwrap_or_default(),
+                library_type: sample.library_type.clone(),
+                log_path: run.log_path.clone(),
+                total_reads: run.log.statistics.total_reads,
+                passing_reads: run.log.statistics.passing_reads,
+                fraction_passing: run.log.statistics.fraction_passing,
+                whitelist_size: run.log.statistics.whitelist_size,
+            })
+            .collect(),
+        whitelist_size: in_memory.then_some(observed.len()),
+        shared_whitelist_size: in_memory
+            .then(|| observed.values().filter(|n| **n == runs.len()).count()),
+        whitelist_path,
+        pipspeak_version: env!("CARGO_PKG_VERSION").to_string(),
+    };
+    log.to_file(&partial(&log_path))?;
+    // the log is renamed last as for a sample
+    for path in log.whitelist_path.iter().chain([&log_path]) {
+        std::fs::rename(partial(path), path)?;
+    }
+    Ok(())
+}
+
+/// Writes synthetic fastqs of a chemistry along with their ground truth
+fn simulate(args: &SimulateArgs) -> Result<()> {
+    let config = Config::from_file(&args.config, true, args.linkers).classify(Error::Config)?;
+    let simulator = Simulator::new(
+        &config,
+        SimulateOptions {
+            num_cells: args.cells,
+            num_reads: args.reads,
+            umis_per_cell: args.umis_per_cell,
+            umi_len: args.umi_len,
+            r2_len: args.r2_len,
+            max_offset: args.max_offset,
+            errors: ErrorModel {
+                substitution_rate: args.error_rate,
+                final_substitution_rate: args.final_error_rate.unwrap_or(args.error_rate),
+                insertion_rate: args.insertion_rate,
+                deletion_rate: args.deletion_rate,
+                homopolymer_factor: args.homopolymer_factor,
+            },
+            seed: args.seed,
+        },
+    )?;
+    let barcode = |[b1, b2, b3, b4]: [usize; 4]| config.build_barcode(b1, b2, b3, b4);
+    let r1_path = format!("{}_R1.fq.gz", args.prefix);
+    let r2_path = format!("{}_R2.fq.gz", args.prefix);
+    let truth_path = format!("{}_truth.tsv.gz", args.prefix);
+    let cells_path = format!("{}_cells.txt", args.prefix);
 
+    let mut cells = create_output(&cells_path)?;
+    for indices in simulator.cells() {
+        cells.write_all(&barcode(indices))?;
+        cells.write_all(b"\n")?;
+    }
+    let mut r1 = create_output(&r1_path)?;
+    let mut r2 = create_output(&r2_path)?;
+    let mut truth = create_output(&truth_path)?;
+    writeln!(
+        truth,
+        "read\tcell\tbarcode\tumi\toffset\tsubstitutions\tinsertions\tdeletions"
+    )?;
+    let (r1_qual, r2_qual) = simulator.qualities();
+    for pair in simulator {
+        pair.write_fastq(&mut r1, &mut r2, &r1_qual, &r2_qual)?;
+        write!(truth, "{}\t{}\t", pair.name, pair.truth.cell)?;
+        truth.write_all(&barcode(pair.truth.indices))?;
+        truth.write_all(b"\t")?;
+        truth.write_all(&pair.truth.umi)?;
+        writeln!(
+            truth,
+            "\t{}\t{}\t{}\t{}",
+            pair.truth.offset,
+            pair.truth.substitutions,
+            pair.truth.insertions,
+            pair.truth.deletions
+        )?;
+    }
+    for writer in [cells, r1, r2, truth] {
+        writer.finish()?;
+    }
+    for path in [&cells_path, &r1_path, &r2_path, &truth_path] {
+        std::fs::rename(partial(path), path)?;
+    }
     Ok(())
 }
+
+/// Compares the logs (and whitelists) of two runs
+fn compare(args: &CompareArgs) -> Result<()> {
+    let read_log = |path: &str| {
+        std::fs::read_to_string(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|contents| RunMetrics::from_log(&contents))
+            .with_context(|| format!("Failed to read log: {}", path))
+            .classify(Error::Input)
+    };
+    let a = read_log(&args.log_a)?;
+    let b = read_log(&args.log_b)?;
+    let whitelists = match (&args.whitelist_a, &args.whitelist_b) {
+        (Some(a), Some(b)) => Some(WhitelistComparison::new(
+            &read_whitelist(a)?,
+            &read_whitelist(b)?,
+        )),
+        _ => None,
+    };
+    let comparison = Comparison::new(a, b, whitelists);
+    match &args.output {
+        Some(path) => File::create(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|file| Ok(serde_yaml::to_writer(BufWriter::new(file), &comparison)?))
+            .with_context(|| format!("Failed to write comparison: {}", path))
+            .classify(Error::Output),
+        None => serde_yaml::to_writer(std::io::stdout(), &comparison).classify(Error::Output),
+    }
+}
+
+/// Prints the kallisto/bustools technology string of the outputs of a config
+fn tech_string(args: &TechStringArgs) -> Result<()> {
+    let config = Config::from_file(&args.config, false, args.linkers).classify(Error::Config)?;
+    let barcode_len = match &args.tenx_whitelist {
+        Some(path) => {
+            let whitelist = TenxWhitelist::from_file(path)
+                .with_context(|| format!("Failed to read 10x whitelist: {}", path))
+                .classify(Error::Config)
+                .map(Arc::new)?;
+            TenxTranslation::new(whitelist.clone(), config.tier_sizes())?;
+            whitelist.barcode_len()
+        }
+        None => config.barcode_len(),
+    };
+    if let Some(warning) = kb::check_barcode_len(barcode_len) {
+        eprintln!("Warning: {}", warning);
+    }
+    println!(
+        "{}",
+        kb::tech_string(barcode_len, config.umi_len(args.umi_len))
+    );
+    Ok(())
+}
+
+/// Extracts the barcodes, UMI and cDNA of long reads into R1/R2 fastqs
+fn long_read(args: &LongReadArgs) -> Result<()> {
+    let config = Config::from_file(&args.config, false, args.linkers).classify(Error::Config)?;
+    let long_read_opts = LongReadOptions {
+        window: args.window,
+        max_distance: args.max_distance,
+        umi_len: args.umi_len,
+    };
+    // the emitted R1 is rebuilt on the read structure so it is matched anchored
+    let opts = Options {
+        offset: None,
+        umi_len: args.umi_len,
+        ..Options::default()
+    };
+    let r1_path = format!("{}_R1.fq.gz", args.prefix);
+    let r2_path = format!("{}_R2.fq.gz", args.prefix);
+    let whitelist_path = format!("{}_whitelist.txt", args.prefix);
+    let log_path = format!("{}_log.yaml", args.prefix);
+
+    let reads = args
+        .inputs
+        .iter()
+        .map(|path| input::open(path).map(|input| input.reader))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .flatten();
+    let long_reads = LongReads::new(reads, &config, long_read_opts);
+    let (r1, r2) = long_reads.halves();
+    let mut r1_out = create_output(&r1_path)?;
+    let mut r2_out = create_output(&r2_path)?;
+    let statistics = process_pair(r1, r2, &mut r1_out, &mut r2_out, &config, &opts)?;
+    for writer in [r1_out, r2_out] {
+        writer.finish()?;
+    }
+    statistics.whitelist_to_file(&partial(&whitelist_path), &config)?;
+    for path in [&r1_path, &r2_path, &whitelist_path] {
+        std::fs::rename(partial(path), path)?;
+    }
+    LongReadLog {
+        config: args.config.clone(),
+        inputs: args.inputs.clone(),
+        parameters: long_read_opts,
+        long_reads: long_reads.counts(),
+        statistics,
+        pipspeak_version: env!("CARGO_PKG_VERSION").to_string(),
+    }
+    .to_file(&log_path)
+}
+
+/// Combines whitelists with a set operation
+fn combine_whitelists(args: &WhitelistArgs) -> Result<()> {
+    let whitelists = args
+        .inputs
+        .iter()
+        .map(|path| read_whitelist(path))
+        .collect::<Result<Vec<_>>>()?;
+    let combined = whitelist::combine(args.operation(), &whitelists);
+    match &args.output {
+        Some(path) => File::create(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|file| whitelist::write_counts(&combined, BufWriter::new(file)))
+            .with_context(|| format!("Failed to write whitelist: {}", path))
+            .classify(Error::Output),
+        None => {
+            whitelist::write_counts(&combined, std::io::stdout().lock()).classify(Error::Output)
+        }
+    }
+}
+
+/// Reads a whitelist (with optional read counts) given to a subcommand
+fn read_whitelist(path: &str) -> Result<WhitelistCounts> {
+    whitelist::read_counts(path)
+        .with_context(|| format!("Failed to read whitelist: {}", path))
+        .classify(Error::Input)
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("Error: {:?}", err);
+            ExitCode::from(error::exit_code(&err))
+        }
+    }
+}
+
+fn run() -> Result<ExitCode> {
+    let args = Cli::parse();
+    match &args.command {
+        Some(Command::Simulate(simulate_args)) => {
+            simulate(simulate_args)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Compare(compare_args)) => {
+            compare(compare_args)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Whitelist(whitelist_args)) => {
+            combine_whitelists(whitelist_args)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::TechString(tech_args)) => {
+            tech_string(tech_args)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::LongRead(long_read_args)) => {
+            long_read(long_read_args)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        None => {}
+    }
+    let config = Config::from_file(
+        args.config
+            .as_deref()
+            .expect("`--config` is required without a subcommand"),
+        args.exact,
+        args.linkers,
+    )
+    .classify(Error::Config)?
+    .with_matcher(args.matcher);
+    let mut opts = Options::from(&args);
+    opts.expected_cells = args
+        .expected_cells
+        .or(config.chip().map(|chip| chip.expected_cells()));
+    opts.adapters = args
+        .adapters
+        .as_deref()
+        .map(|path| {
+            Adapters::from_file(path, args.adapter_error_rate, args.adapter_min_overlap)
+                .map(Arc::new)
+        })
+        .transpose()
+        .classify(Error::Config)?;
+    opts.contaminants = args
+        .contaminants
+        .as_deref()
+        .map(|path| ContaminantScreen::from_file(path, args.contaminant_k).map(Arc::new))
+        .transpose()
+        .classify(Error::Config)?;
+    opts.features = args
+        .features
+        .as_deref()
+        .map(|path| {
+            FeatureReference::from_file(path, args.feature_offset, args.feature_mismatches)
+                .map(Arc::new)
+        })
+        .transpose()
+        .classify(Error::Config)?;
+    opts.tenx_whitelist = args
+        .tenx_whitelist
+        .as_deref()
+        .map(|path| TenxWhitelist::from_file(path).map(Arc::new))
+        .transpose()
+        .classify(Error::Config)?;
+    if let Some(whitelist) = &opts.tenx_whitelist {
+        // refuse a whitelist too small for the config before any output is written
+        TenxTranslation::new(whitelist.clone(), config.tier_sizes())?;
+    }
+    let expected_whitelist = args
+        .expected_whitelist
+        .as_deref()
+        .or(config.expected_whitelist());
+    opts.expected_whitelist = expected_whitelist
+        .map(|path| ExpectedWhitelist::from_file(path, config.barcode_len()).map(Arc::new))
+        .transpose()
+        .classify(Error::Config)?;
+    if (args.reject_unexpected || args.flag_unexpected) && expected_whitelist.is_none() {
+        bail!(Error::Usage(
+            "`--reject-unexpected` and `--flag-unexpected` require an expected whitelist (`--expected-whitelist` or `expected_whitelist` in the config)"
+                .into()
+        ));
+    }
+    if config.umi_len(args.umi_len) == 0 {
+        if args.filter_homopolymer_umi || args.min_umi_entropy.is_some() {
+            bail!(Error::Usage(
+                "The UMI filters (`--filter-homopolymer-umi`, `--min-umi-entropy`) require a UMI"
+                    .into()
+            ));
+        }
+        if args.dedup.is_some() && args.dedup_r2_prefix == 0 {
+            bail!(Error::Usage(
+                "`--dedup` without a UMI requires `--dedup-r2-prefix` to tell molecules of a barcode apart"
+                    .into()
+            ));
+        }
+    }
+    if args.no_r1_out && args.name_format == NameFormat::Original && !args.barcode_table {
+        bail!(Error::Usage(
+            "`--no-r1-out` requires the barcode and UMI in the read names (`--name-format umitools`) or a `--barcode-table`"
+                .into()
+        ));
+    }
+    let (r1, r2) = match args.fastq.as_deref() {
+        Some(path) => {
+            let discovered = discover::discover(path).classify(Error::Input)?;
+            (discovered.r1, discovered.r2)
+        }
+        None => {
+            let (r1, r2) = match (&args.r1_fofn, &args.r2_fofn) {
+                (Some(r1), Some(r2)) => (
+                    discover::read_fofn(r1).classify(Error::Input)?,
+                    discover::read_fofn(r2).classify(Error::Input)?,
+                ),
+                // both mates are read from each unaligned BAM
+                _ if !args.ubam.is_empty() => (args.ubam.clone(), args.ubam.clone()),
+                _ => (args.r1.clone(), args.r2.clone()),
+            };
+            let globbed =
+                args.ubam.is_empty() && r1.iter().chain(&r2).any(|i| discover::is_glob(i));
+            let r1 = discover::expand_globs(&r1).classify(Error::Input)?;
+            let r2 = discover::expand_globs(&r2).classify(Error::Input)?;
+            if globbed {
+                discover::check_mates(&r1, &r2).classify(Error::Input)?;
+            }
+            (r1, r2)
+        }
+    };
+    let libraries = config.libraries();
+    let samples = match &args.samplesheet {
+        _ if !libraries.is_empty() => {
+            if args.samplesheet.is_some() || !r1.is_empty() || !r2.is_empty() {
+                bail!(Error::Usage(
+                    "The `libraries` of the config cannot be combined with `-i`/`-I` or `--samplesheet`"
+                        .into()
+                ));
+            }
+            libraries
+                .iter()
+                .map(|library| Sample {
+                    name: Some(library.name.clone()),
+                    r1: library.r1.clone(),
+                    r2: library.r2.clone(),
+                    i1: Vec::new(),
+                    i2: Vec::new(),
+                    prefix: format!("{}_{}", args.prefix, library.name),
+                    library_type: library.library_type.clone(),
+                    features: library.features.clone(),
+                })
+                .collect()
+        }
+        Some(path) => samplesheet::from_file(path).classify(Error::Input)?,
+        None => {
+            if r1.is_empty() || r2.is_empty() {
+                bail!(Error::Usage(
+                    "Expected R1 and R2 inputs (`-i`/`-I`), a `--samplesheet` or `libraries` in the config"
+                        .into()
+                ));
+            }
+            if !args.i1.is_empty() && args.i1.len() != r1.len()
+                || !args.i2.is_empty() && args.i2.len() != r1.len()
+            {
+                bail!(Error::Usage(
+                    "Expected an I1 (and I2) input for each R1 input".into()
+                ));
+            }
+            if r1.len() != r2.len() {
+                bail!(Error::Usage(
+                    format!(
+                        "Expected the same number of R1 and R2 inputs (found {} and {})",
+                        r1.len(),
+                        r2.len()
+                    )
+                    .into()
+                ));
+            }
+            vec![Sample {
+                name: None,
+                r1,
+                r2,
+                i1: args.i1.clone(),
+                i2: args.i2.clone(),
+                prefix: args.prefix.clone(),
+                library_type: None,
+                features: None,
+            }]
+        }
+    };
+
+    if args.tui && args.jobs > 1 {
+        bail!(Error::Usage(
+            "`--tui` cannot be combined with parallel `--jobs`".into()
+        ));
+    }
+    if let Some(path) = args.progress_json.as_deref().filter(|p| *p != "-") {
+        // samples append to the stream so it is truncated once up front
+        File::create(path)?;
+    }
+    let mp = MultiProgress::new();
+    let all_passed = AtomicBool::new(true);
+    // the runs of libraries are kept for their combined log
+    let runs = Mutex::new(Vec::new());
+    let record = |idx: usize, run: SampleRun| {
+        if !run.passed {
+            all_passed.store(false, Ordering::Relaxed);
+        }
+        if !libraries.is_empty() {
+            runs.lock()
+                .expect("Sample runs lock poisoned")
+                .push((idx, run));
+        }
+    };
+    if args.jobs <= 1 {
+        for (idx, sample) in samples.iter().enumerate() {
+            record(idx, run_sample(&args, &config, &opts, sample, &mp)?);
+        }
+    } else {
+        // process samples in parallel sharing the loaded config
+        let next_sample = AtomicUsize::new(0);
+        std::thread::scope(|scope| {
+            let handles = (0..args.jobs.min(samples.len()))
+                .map(|_| {
+                    scope.spawn(|| -> Result<()> {
+                        loop {
+                            let idx = next_sample.fetch_add(1, Ordering::Relaxed);
+                            let Some(sample) = samples.get(idx) else {
+                                break;
+                            };
+                            record(idx, run_sample(&args, &config, &opts, sample, &mp)?);
+                        }
+                        Ok(())
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .try_for_each(|handle| handle.join().expect("Sample processing thread panicked"))
+        })?;
+    }
+
+    if !libraries.is_empty() {
+        let mut runs = runs.into_inner().expect("Sample runs lock poisoned");
+        runs.sort_unstable_by_key(|(idx, _)| *idx);
+        let runs = runs.into_iter().map(|(_, run)| run).collect::<Vec<_>>();
+        write_libraries(&args, &config, &samples, &runs)?;
+    }
+
+    if all_passed.into_inner() {
+        Ok(ExitCode::SUCCESS)
+    } else {
+        Ok(ExitCode::from(error::EXIT_LOW_PASS_RATE))
+    }
+}
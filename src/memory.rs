@@ -0,0 +1,132 @@
+#[cfg(feature = "io")]
+use crate::input::BUFFER_SIZE;
+use std::{fmt, str::FromStr};
+
+/// An upper estimate of the memory held per distinct barcode of a whitelist (the sequence,
+/// its count and the overhead of the hash table)
+pub const WHITELIST_ENTRY_BYTES: usize = 128;
+
+/// An upper estimate of the memory held per distinct barcode of the in-memory whitelist,
+/// which keys its read counts by the packed barcode indices rather than the sequence
+pub const BARCODE_KEY_BYTES: usize = 32;
+
+/// An upper estimate of the memory held per hash in a hash set (the hash, its control
+/// byte and the spare capacity of the table)
+pub const HASH_ENTRY_BYTES: usize = 16;
+
+/// An upper estimate of the memory held per barcode of the cell summaries, besides the
+/// hashes of its UMIs
+pub const CELL_SUMMARY_BYTES: usize = 128;
+
+/// An upper estimate of the memory held per barcode of the downsampler, besides its
+/// sequence and the ordinals of its sampled reads
+pub const RESERVOIR_BYTES: usize = 96;
+
+/// An upper estimate of the memory held per distinct barcode, feature and UMI of the
+/// feature counts, besides the barcode and UMI sequences
+pub const FEATURE_UMI_BYTES: usize = 96;
+
+/// The fraction of the budget given to the decompressed chunks in flight between the
+/// decompression threads and matching
+const IN_FLIGHT_FRACTION: f64 = 0.25;
+
+/// A memory budget (e.g. `--max-memory 4G`) split between the records in flight between
+/// pipeline stages and the barcodes held in memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryBudget {
+    bytes: usize,
+}
+impl MemoryBudget {
+    pub fn new(bytes: usize) -> Self {
+        Self { bytes }
+    }
+
+    /// Returns the budget in bytes
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Returns the number of decompressed chunks each of `num_inputs` background
+    /// decompression threads may buffer ahead (at least 1 and at most `max_chunks`)
+    #[cfg(feature = "io")]
+    pub fn in_flight_chunks(&self, num_inputs: usize, max_chunks: usize) -> usize {
+        let share = (self.bytes as f64 * IN_FLIGHT_FRACTION) as usize;
+        (share / (num_inputs.max(1) * BUFFER_SIZE)).clamp(1, max_chunks)
+    }
+
+    /// Returns the number of bytes the barcodes, molecules and sampled reads held in memory
+    /// may grow to
+    pub fn held_bytes(&self) -> usize {
+        (self.bytes as f64 * (1.0 - IN_FLIGHT_FRACTION)) as usize
+    }
+
+    /// Returns the number of distinct barcodes the whitelist may hold in memory before
+    /// spilling a chunk to disk
+    pub fn whitelist_entries(&self) -> usize {
+        (self.held_bytes() / WHITELIST_ENTRY_BYTES).max(1)
+    }
+}
+impl FromStr for MemoryBudget {
+    type Err = String;
+    /// Parses a size in bytes with an optional `K`, `M`, `G` or `T` suffix (powers of 1024)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let upper = s.trim().to_ascii_uppercase();
+        let number = upper.trim_end_matches('B');
+        let (number, scale) = match number.chars().last() {
+            Some('K') => (&number[..number.len() - 1], 1u64 << 10),
+            Some('M') => (&number[..number.len() - 1], 1 << 20),
+            Some('G') => (&number[..number.len() - 1], 1 << 30),
+            Some('T') => (&number[..number.len() - 1], 1 << 40),
+            _ => (number, 1),
+        };
+        match number.parse::<f64>() {
+            Ok(value) if value > 0.0 && value.is_finite() => Ok(Self {
+                bytes: (value * scale as f64) as usize,
+            }),
+            _ => Err(format!(
+                "expected a positive size such as `512M` or `4G`, found '{}'",
+                s
+            )),
+        }
+    }
+}
+impl fmt::Display for MemoryBudget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.bytes)
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn parse_size() {
+        assert_eq!("512".parse::<MemoryBudget>().unwrap().bytes(), 512);
+        assert_eq!("4k".parse::<MemoryBudget>().unwrap().bytes(), 4096);
+        assert_eq!("1.5G".parse::<MemoryBudget>().unwrap().bytes(), 3 << 29);
+        assert_eq!("2GB".parse::<MemoryBudget>().unwrap().bytes(), 2 << 30);
+        assert!("0".parse::<MemoryBudget>().is_err());
+        assert!("G".parse::<MemoryBudget>().is_err());
+        assert!("lots".parse::<MemoryBudget>().is_err());
+    }
+
+    #[test]
+    fn shares() {
+        let budget = MemoryBudget::new(1 << 30);
+        assert_eq!(budget.held_bytes(), 3 << 28);
+        assert_eq!(
+            budget.whitelist_entries(),
+            (3 << 28) / WHITELIST_ENTRY_BYTES
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "io")]
+    fn in_flight() {
+        let budget = MemoryBudget::new(1 << 30);
+        assert_eq!(budget.in_flight_chunks(2, 16), 16);
+        // a small budget still keeps a chunk in flight
+        assert_eq!(MemoryBudget::new(1 << 20).in_flight_chunks(2, 16), 1);
+    }
+}
@@ -0,0 +1,312 @@
+use crate::error::Error;
+use anyhow::Result;
+use fxread::Record;
+use hashbrown::HashMap;
+use std::collections::VecDeque;
+
+/// The maximum number of reads of each input held while waiting for their mates
+/// before they are dropped as orphans
+const REPAIR_WINDOW: usize = 1_000_000;
+
+/// How the records of R1 and R2 are paired
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pairing {
+    /// Pair the records in order without looking at their names
+    Zip,
+    /// Pair the records in order and fail if the names of a pair (or the number of
+    /// records) differ
+    Check,
+    /// Pair the records by name, dropping reads whose mate is missing as orphans
+    Repair,
+}
+
+/// Returns the name of a read: its ID up to the first whitespace without any `/1` or `/2`
+/// mate suffix
+pub fn read_name(id: &[u8]) -> &[u8] {
+    let end = id
+        .iter()
+        .position(|b| b.is_ascii_whitespace())
+        .unwrap_or(id.len());
+    let name = &id[..end];
+    match name {
+        [head @ .., b'/', b'1' | b'2'] => head,
+        _ => name,
+    }
+}
+
+/// Reads of one input waiting for their mates in the other input
+#[derive(Default)]
+struct Pending {
+    records: HashMap<Vec<u8>, Record>,
+    order: VecDeque<Vec<u8>>,
+}
+impl Pending {
+    /// Takes the pending read with a name if there is one
+    fn take(&mut self, name: &[u8]) -> Option<Record> {
+        self.records.remove(name)
+    }
+
+    /// Holds a read until its mate arrives. Returns the number of reads dropped to keep
+    /// within the repair window.
+    fn insert(&mut self, name: Vec<u8>, record: Record) -> usize {
+        let mut num_dropped = self.records.insert(name.clone(), record).is_some() as usize;
+        self.order.push_back(name);
+        while self.records.len() > REPAIR_WINDOW {
+            if let Some(oldest) = self.order.pop_front() {
+                num_dropped += self.records.remove(&oldest).is_some() as usize;
+            }
+        }
+        // forget the names of reads which found their mates
+        if self.order.len() > 2 * self.records.len() + 1024 {
+            let records = &self.records;
+            self.order.retain(|name| records.contains_key(name));
+        }
+        num_dropped
+    }
+
+    /// Drops every pending read and returns how many there were
+    fn clear(&mut self) -> usize {
+        let num_pending = self.records.len();
+        self.records.clear();
+        self.order.clear();
+        num_pending
+    }
+}
+
+/// Wraps the message of a pairing failure as a [`Error::Pairing`]
+fn pairing_error(message: String) -> anyhow::Error {
+    Error::Pairing(message.into()).into()
+}
+
+/// Pairs the records of R1 and R2 according to a pairing mode
+pub struct Pairs<R1, R2> {
+    r1: R1,
+    r2: R2,
+    mode: Pairing,
+    num_pairs: usize,
+    num_orphans: usize,
+    pending_r1: Pending,
+    pending_r2: Pending,
+    ready: VecDeque<(Record, Record)>,
+}
+impl<R1, R2> Pairs<R1, R2>
+where
+    R1: Iterator<Item = Result<Record>>,
+    R2: Iterator<Item = Result<Record>>,
+{
+    pub fn new(r1: R1, r2: R2, mode: Pairing) -> Self {
+        Self {
+            r1,
+            r2,
+            mode,
+            num_pairs: 0,
+            num_orphans: 0,
+            pending_r1: Pending::default(),
+            pending_r2: Pending::default(),
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// Returns the number of reads dropped because their mate was missing
+    pub fn num_orphans(&self) -> usize {
+        self.num_orphans
+    }
+
+    /// Reads the next record of each input
+    fn next_records(&mut self) -> Result<(Option<Record>, Option<Record>)> {
+        Ok((self.r1.next().transpose()?, self.r2.next().transpose()?))
+    }
+
+    fn next_zipped(&mut self) -> Result<Option<(Record, Record)>> {
+        let (rec1, rec2) = self.next_records()?;
+        Ok(rec1.zip(rec2))
+    }
+
+    fn next_checked(&mut self) -> Result<Option<(Record, Record)>> {
+        match self.next_records()? {
+            (Some(rec1), Some(rec2)) => {
+                if read_name(rec1.id()) != read_name(rec2.id()) {
+                    return Err(pairing_error(format!(
+                        "R1 and R2 are out of sync at read pair {}: '{}' and '{}' (try `--repair`)",
+                        self.num_pairs + 1,
+                        String::from_utf8_lossy(read_name(rec1.id())),
+                        String::from_utf8_lossy(read_name(rec2.id())),
+                    )));
+                }
+                Ok(Some((rec1, rec2)))
+            }
+            (Some(_), None) => Err(pairing_error(format!(
+                "R1 has more records than R2 ({} pairs read; try `--repair`)",
+                self.num_pairs
+            ))),
+            (None, Some(_)) => Err(pairing_error(format!(
+                "R2 has more records than R1 ({} pairs read; try `--repair`)",
+                self.num_pairs
+            ))),
+            (None, None) => Ok(None),
+        }
+    }
+
+    fn next_repaired(&mut self) -> Result<Option<(Record, Record)>> {
+        loop {
+            if let Some(pair) = self.ready.pop_front() {
+                return Ok(Some(pair));
+            }
+            match self.next_records()? {
+                (Some(rec1), Some(rec2)) => {
+                    if read_name(rec1.id()) == read_name(rec2.id()) {
+                        return Ok(Some((rec1, rec2)));
+                    }
+                    // each read either completes a waiting mate or waits for its own
+                    let name1 = read_name(rec1.id()).to_vec();
+                    match self.pending_r2.take(&name1) {
+                        Some(mate) => self.ready.push_back((rec1, mate)),
+                        None => self.num_orphans += self.pending_r1.insert(name1, rec1),
+                    }
+                    let name2 = read_name(rec2.id()).to_vec();
+                    match self.pending_r1.take(&name2) {
+                        Some(mate) => self.ready.push_back((mate, rec2)),
+                        None => self.num_orphans += self.pending_r2.insert(name2, rec2),
+                    }
+                }
+                (Some(rec1), None) => match self.pending_r2.take(read_name(rec1.id())) {
+                    Some(mate) => return Ok(Some((rec1, mate))),
+                    None => self.num_orphans += 1,
+                },
+                (None, Some(rec2)) => match self.pending_r1.take(read_name(rec2.id())) {
+                    Some(mate) => return Ok(Some((mate, rec2))),
+                    None => self.num_orphans += 1,
+                },
+                (None, None) => {
+                    self.num_orphans += self.pending_r1.clear() + self.pending_r2.clear();
+                    return Ok(None);
+                }
+            }
+        }
+    }
+}
+impl<R1, R2> Iterator for Pairs<R1, R2>
+where
+    R1: Iterator<Item = Result<Record>>,
+    R2: Iterator<Item = Result<Record>>,
+{
+    type Item = Result<(Record, Record)>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let pair = match self.mode {
+            Pairing::Zip => self.next_zipped(),
+            Pairing::Check => self.next_checked(),
+            Pairing::Repair => self.next_repaired(),
+        };
+        if matches!(pair, Ok(Some(_))) {
+            self.num_pairs += 1;
+        }
+        pair.transpose()
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use fxread::FastqReader;
+
+    /// Parses the records of a FASTQ with the given read names
+    fn records(names: &[&str]) -> Vec<Result<Record>> {
+        let fastq = names
+            .iter()
+            .map(|n| format!("@{}\nACGT\n+\nIIII\n", n))
+            .collect::<String>();
+        FastqReader::new(fastq.as_bytes()).map(Ok).collect()
+    }
+
+    fn pair_names(pairs: &[(Record, Record)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(a, b)| {
+                (
+                    String::from_utf8(a.id().to_vec()).unwrap(),
+                    String::from_utf8(b.id().to_vec()).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(read_name(b"read1 1:N:0:ACGT"), b"read1");
+        assert_eq!(read_name(b"read1/1"), b"read1");
+        assert_eq!(read_name(b"read1/2 extra"), b"read1");
+        assert_eq!(read_name(b"read1/3"), b"read1/3");
+    }
+
+    #[test]
+    fn check() {
+        let r1 = records(&["a 1:N", "b 1:N"]);
+        let r2 = records(&["a 2:N", "b 2:N"]);
+        let pairs = Pairs::new(r1.into_iter(), r2.into_iter(), Pairing::Check)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(pairs.len(), 2);
+
+        let r1 = records(&["a", "c"]);
+        let r2 = records(&["a", "b"]);
+        let err = Pairs::new(r1.into_iter(), r2.into_iter(), Pairing::Check)
+            .collect::<Result<Vec<_>>>()
+            .unwrap_err();
+        assert!(err.to_string().contains("pair 2"));
+
+        let r1 = records(&["a", "b"]);
+        let r2 = records(&["a"]);
+        assert!(Pairs::new(r1.into_iter(), r2.into_iter(), Pairing::Check)
+            .collect::<Result<Vec<_>>>()
+            .is_err());
+    }
+
+    #[test]
+    fn repair() {
+        // R1 is missing `b` and R2 is missing `d`
+        let r1 = records(&["a/1", "c/1", "d/1", "e/1"]);
+        let r2 = records(&["a/2", "b/2", "c/2", "e/2"]);
+        let mut pairs = Pairs::new(r1.into_iter(), r2.into_iter(), Pairing::Repair);
+        let repaired = pairs.by_ref().collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(
+            pair_names(&repaired),
+            vec![
+                ("a/1".into(), "a/2".into()),
+                ("c/1".into(), "c/2".into()),
+                ("e/1".into(), "e/2".into()),
+            ]
+        );
+        assert_eq!(pairs.num_orphans(), 2);
+    }
+
+    #[test]
+    fn repair_trailing() {
+        let r1 = records(&["a", "b", "c"]);
+        let r2 = records(&["b"]);
+        let mut pairs = Pairs::new(r1.into_iter(), r2.into_iter(), Pairing::Repair);
+        let repaired = pairs.by_ref().collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(pair_names(&repaired), vec![("b".into(), "b".into())]);
+        assert_eq!(pairs.num_orphans(), 2);
+    }
+
+    #[test]
+    fn zip() {
+        let r1 = records(&["a", "b", "c"]);
+        let r2 = records(&["x", "y"]);
+        let pairs = Pairs::new(r1.into_iter(), r2.into_iter(), Pairing::Zip)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(pairs.len(), 2);
+    }
+
+    #[test]
+    fn read_error() {
+        let mut r1 = records(&["a", "b"]);
+        r1[1] = Err(anyhow::anyhow!("truncated"));
+        let r2 = records(&["a", "b"]);
+        let err = Pairs::new(r1.into_iter(), r2.into_iter(), Pairing::Repair)
+            .collect::<Result<Vec<_>>>()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "truncated");
+    }
+}
@@ -0,0 +1,201 @@
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use fxread::{FastxRead, Record};
+use std::io::BufRead;
+
+/// The number of lines of a FASTQ record
+const FASTQ_LINES: usize = 4;
+
+/// How FASTQ records are parsed from the decompressed input
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ParserBackend {
+    /// The line-by-line reader of `fxread`
+    #[default]
+    Fxread,
+    /// Parses whole records out of the read buffer with a single scan and allocation
+    Native,
+}
+
+/// Returns the length of the leading record of a buffer (up to and including the newline
+/// ending its quality line) if the buffer holds the whole record
+fn record_len(buf: &[u8]) -> Option<usize> {
+    memchr::memchr_iter(b'\n', buf)
+        .nth(FASTQ_LINES - 1)
+        .map(|pos| pos + 1)
+}
+
+/// Reads FASTQ records by scanning the buffer of the underlying reader for the end of each
+/// record and copying it into an allocation of exactly its size.
+///
+/// Records straddling the end of the buffer are read line by line instead. Unlike the
+/// `fxread` reader, malformed records are reported rather than silently misparsed.
+pub struct FastqParser<R> {
+    reader: R,
+    num_records: usize,
+}
+impl<R: BufRead> FastqParser<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            num_records: 0,
+        }
+    }
+
+    /// Skips the empty lines between (or trailing) records, returning false at the end of
+    /// the input
+    fn skip_empty_lines(&mut self) -> Result<bool> {
+        loop {
+            let buf = self.reader.fill_buf()?;
+            if buf.is_empty() {
+                return Ok(false);
+            }
+            let num_empty = buf
+                .iter()
+                .take_while(|b| matches!(b, b'\n' | b'\r'))
+                .count();
+            if num_empty < buf.len() {
+                self.reader.consume(num_empty);
+                return Ok(true);
+            }
+            let num_bytes = buf.len();
+            self.reader.consume(num_bytes);
+        }
+    }
+
+    /// Reads the raw bytes of the next record
+    fn next_data(&mut self) -> Result<Option<Vec<u8>>> {
+        if !self.skip_empty_lines()? {
+            return Ok(None);
+        }
+        let buf = self.reader.fill_buf()?;
+        if let Some(len) = record_len(buf) {
+            let data = buf[..len].to_vec();
+            self.reader.consume(len);
+            return Ok(Some(data));
+        }
+        let mut data = Vec::with_capacity(buf.len());
+        for _ in 0..FASTQ_LINES {
+            if self.reader.read_until(b'\n', &mut data)? == 0 {
+                break;
+            }
+        }
+        Ok(Some(data))
+    }
+
+    /// Validates the raw bytes of a record and wraps them as a [`Record`]
+    fn parse(&self, mut data: Vec<u8>) -> Result<Record> {
+        if data.last() != Some(&b'\n') {
+            data.push(b'\n');
+        }
+        let mut lens = [0; FASTQ_LINES];
+        let mut start = 0;
+        for (num_lines, end) in memchr::memchr_iter(b'\n', &data).enumerate() {
+            if num_lines == FASTQ_LINES {
+                break;
+            }
+            lens[num_lines] = end + 1 - start;
+            start = end + 1;
+        }
+        let [id, seq, plus, qual] = lens;
+        if qual == 0 {
+            bail!("FASTQ record {} is truncated", self.num_records);
+        }
+        if data[0] != b'@' {
+            bail!("FASTQ record {} does not start with '@'", self.num_records);
+        }
+        if data[id + seq] != b'+' {
+            bail!(
+                "FASTQ record {} is missing its '+' separator line",
+                self.num_records
+            );
+        }
+        if seq != qual {
+            bail!(
+                "FASTQ record {} has {} bases but {} qualities",
+                self.num_records,
+                seq - 1,
+                qual - 1
+            );
+        }
+        // the record offsets exclude the leading '@' of the id
+        Ok(Record::new_fastq(data, id - 1, seq, plus, qual))
+    }
+}
+impl<R: BufRead> FastxRead for FastqParser<R> {
+    fn next_record(&mut self) -> Result<Option<Record>> {
+        let Some(data) = self.next_data()? else {
+            return Ok(None);
+        };
+        self.num_records += 1;
+        self.parse(data).map(Some)
+    }
+}
+impl<R: BufRead> Iterator for FastqParser<R> {
+    type Item = Result<Record>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use fxread::FastqReader;
+    use std::io::BufReader;
+
+    const FASTQ: &[u8] = b"@read1 comment\nACGT\n+\nIIII\n@read2\nTTTTT\n+read2\nIIIII\n";
+
+    fn parse(fastq: &[u8], capacity: usize) -> Result<Vec<Record>> {
+        let mut parser = FastqParser::new(BufReader::with_capacity(capacity, fastq));
+        std::iter::from_fn(|| parser.next_record().transpose()).collect()
+    }
+
+    #[test]
+    fn matches_fxread() {
+        let expected = FastqReader::new(FASTQ).collect::<Vec<_>>();
+        // small buffers split records across refills
+        for capacity in [4, 16, 1024] {
+            let records = parse(FASTQ, capacity).unwrap();
+            assert_eq!(records.len(), expected.len());
+            for (record, expected) in records.iter().zip(&expected) {
+                assert_eq!(record.data(), expected.data());
+                assert_eq!(record.id(), expected.id());
+                assert_eq!(record.seq(), expected.seq());
+                assert_eq!(record.plus(), expected.plus());
+                assert_eq!(record.qual(), expected.qual());
+            }
+        }
+    }
+
+    #[test]
+    fn missing_newlines() {
+        let records = parse(b"@read1\nACGT\n+\nIIII\n\n@read2\nAC\n+\nII", 1024).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].qual(), Some(b"II".as_slice()));
+        assert!(parse(b"", 1024).unwrap().is_empty());
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(parse(b"@read1\nACGT\n+\nIII\n", 1024).is_err());
+        assert!(parse(b"read1\nACGT\n+\nIIII\n", 1024).is_err());
+        assert!(parse(b"@read1\nACGT\nIIII\n@read2\n", 1024).is_err());
+        assert!(parse(b"@read1\nACGT\n", 1024).is_err());
+    }
+
+    #[test]
+    fn parse_parser() {
+        assert_eq!(
+            ParserBackend::from_str("native", false),
+            Ok(ParserBackend::Native)
+        );
+        assert_eq!(
+            ParserBackend::default()
+                .to_possible_value()
+                .unwrap()
+                .get_name(),
+            "fxread"
+        );
+        assert!(ParserBackend::from_str("seq_io", false).is_err());
+    }
+}
@@ -0,0 +1,157 @@
+use crate::config::{Config, Filtered};
+#[cfg(feature = "io")]
+use crate::input;
+#[cfg(not(feature = "io"))]
+use anyhow::bail;
+use anyhow::Result;
+use serde::Serialize;
+#[cfg(feature = "io")]
+use std::io::Cursor;
+
+/// The default number of reads matched in a preview
+pub const DEFAULT_PREVIEW_READS: usize = 100_000;
+
+/// The projected outcome of a run from the leading reads of an R1 file
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Preview {
+    /// The number of reads matched against the read structure
+    pub total_reads: usize,
+    /// The number of reads matching the read structure
+    pub passing_reads: usize,
+    /// The projected fraction of passing reads
+    pub fraction_passing: f64,
+    /// The number of reads failing each barcode tier
+    pub num_filtered: [usize; 4],
+    /// The number of reads failing a fixed sequence
+    pub num_filtered_fixed: usize,
+    /// The number of reads too short to contain the UMI
+    pub num_filtered_umi: usize,
+    /// The number of matched reads corrected in each barcode tier
+    pub num_corrected: [usize; 4],
+}
+
+/// The sequences of the records of an in-memory R1 file
+type Sequences = Box<dyn Iterator<Item = Result<Vec<u8>>>>;
+
+/// Reads the sequences of a (possibly compressed) FASTQ, FASTA or unaligned BAM file
+#[cfg(feature = "io")]
+fn sequences(r1: Vec<u8>) -> Result<Sequences> {
+    let reader = input::from_reader(Box::new(Cursor::new(r1)))?.reader;
+    Ok(Box::new(
+        reader.map(|record| record.map(|record| record.seq().to_vec())),
+    ))
+}
+
+/// Reads the sequences of an uncompressed FASTQ file (other inputs need the `io` feature)
+#[cfg(not(feature = "io"))]
+fn sequences(r1: Vec<u8>) -> Result<Sequences> {
+    if r1.first().is_some_and(|byte| *byte != b'@') {
+        bail!("Only uncompressed FASTQ can be previewed without the `io` feature");
+    }
+    let lines = r1
+        .split(|byte| *byte == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line).to_vec())
+        .collect::<Vec<_>>();
+    if lines.len() % 4 != 0 {
+        bail!("The FASTQ file ends in a truncated record");
+    }
+    Ok(Box::new(lines.into_iter().skip(1).step_by(4).map(Ok)))
+}
+
+/// Runs the first `max_reads` reads of an in-memory R1 file through the matcher.
+///
+/// Compressed FASTQ, FASTA and unaligned BAM files are read with the `io` feature and only
+/// uncompressed FASTQ without it. Only the read structure is matched, so filters applied
+/// to R2 or the UMI content are not reflected in the projected pass rate.
+pub fn preview(
+    config: &Config,
+    r1: Vec<u8>,
+    max_reads: usize,
+    offset: Option<usize>,
+    umi_len: usize,
+) -> Result<Preview> {
+    let mut sequences = sequences(r1)?;
+    let mut preview = Preview::default();
+    while preview.total_reads < max_reads {
+        let Some(seq) = sequences.next().transpose()? else {
+            break;
+        };
+        preview.total_reads += 1;
+        match config.match_read(&seq, offset, umi_len) {
+            Ok(read_match) => {
+                preview.passing_reads += 1;
+                for (count, corrected) in preview.num_corrected.iter_mut().zip(read_match.corrected)
+                {
+                    *count += corrected as usize;
+                }
+            }
+            Err(Filtered::Tier(tier)) => preview.num_filtered[tier] += 1,
+            Err(Filtered::Fixed) => preview.num_filtered_fixed += 1,
+            Err(Filtered::Umi) => preview.num_filtered_umi += 1,
+        }
+    }
+    if preview.total_reads > 0 {
+        preview.fraction_passing = preview.passing_reads as f64 / preview.total_reads as f64;
+    }
+    Ok(preview)
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use hashbrown::HashMap;
+
+    fn config() -> Config {
+        let contents = std::fs::read_to_string("data/config_v3.yaml").unwrap();
+        let barcodes = (1..=4)
+            .map(|tier| {
+                let path = format!("data/barcodes_v3/fb_v3_bc{}.tsv", tier);
+                let buffer = std::fs::read(&path).unwrap();
+                (path, buffer)
+            })
+            .collect::<HashMap<_, _>>();
+        Config::from_buffers(&contents, &barcodes, false, false).unwrap()
+    }
+
+    #[test]
+    #[cfg(feature = "io")]
+    fn preview_example() {
+        let r1 = std::fs::read("data/example_v3/example_R1.fq.gz").unwrap();
+        let preview = preview(&config(), r1, DEFAULT_PREVIEW_READS, Some(5), 12).unwrap();
+        assert_eq!(preview.total_reads, 250);
+        assert_eq!(preview.passing_reads, 198);
+        assert_eq!(preview.num_filtered, [41, 6, 3, 2]);
+        assert_eq!(preview.num_corrected, [7, 3, 2, 5]);
+    }
+
+    #[test]
+    #[cfg(feature = "io")]
+    fn preview_max_reads() {
+        let r1 = std::fs::read("data/example_v3/example_R1.fq.gz").unwrap();
+        let preview = preview(&config(), r1, 10, Some(5), 12).unwrap();
+        assert_eq!(preview.total_reads, 10);
+    }
+
+    #[test]
+    fn preview_plain() {
+        let r1 = [
+            b"@read\n",
+            &[b'N'; 60][..],
+            b"\n+\n",
+            &[b'I'; 60][..],
+            b"\n",
+        ]
+        .concat();
+        let preview = preview(&config(), r1.repeat(3), DEFAULT_PREVIEW_READS, Some(5), 12).unwrap();
+        assert_eq!(preview.total_reads, 3);
+        assert_eq!(preview.passing_reads, 0);
+        assert_eq!(preview.num_filtered[0], 3);
+    }
+
+    #[test]
+    fn missing_buffer() {
+        let contents = std::fs::read_to_string("data/config_v3.yaml").unwrap();
+        assert!(Config::from_buffers(&contents, &HashMap::new(), false, false).is_err());
+    }
+}
@@ -0,0 +1,2034 @@
+use crate::{
+    bam::{self, ReadGroup},
+    cells::hash_sequence,
+    config::{BarcodeKey, Config, Filtered, ReadMatch},
+    contaminant::{self, ContaminantAction, ContaminantScreen},
+    dedup::{DedupOptions, Deduplicator},
+    downsample::{self, Downsampler},
+    error::Error,
+    extsort::ExternalCounter,
+    features::FeatureReference,
+    index,
+    log::{PairStatistics, StageTimes, Statistics},
+    pairing::{Pairing, Pairs},
+    shard::{self, ShardMode, Sharder},
+    tenx::{self, TenxTranslation, TenxWhitelist},
+    trim::{self, Adapters},
+    umi,
+    whitelist::{self, ExpectedWhitelist},
+};
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use fxread::Record;
+use std::{borrow::Cow, fmt, io::Write, path::PathBuf, str::FromStr, sync::Arc, time::Instant};
+
+/// Writes a record to a fastq writer
+fn write_to_fastq<W: Write + ?Sized>(
+    writer: &mut W,
+    id: &[u8],
+    seq: &[u8],
+    qual: &[u8],
+) -> Result<()> {
+    writer.write_all(b"@")?;
+    writer.write_all(id)?;
+    writer.write_all(b"\n")?;
+    writer.write_all(seq)?;
+    writer.write_all(b"\n+\n")?;
+    writer.write_all(qual)?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+/// The format of the emitted reads
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// The `[barcode][UMI]` construct in R1 and the cDNA in R2
+    Fastq,
+    /// An unaligned BAM of the cDNA (written to the R2 output) with the barcode and UMI in
+    /// the `XC` and `XM` tags as expected by Drop-seq tools
+    DropseqBam,
+}
+
+/// The format of the read names of both outputs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum NameFormat {
+    /// Keep the original read names
+    Original,
+    /// Append `_<barcode>_<UMI>` to the read names as expected by UMI-tools (`_<barcode>`
+    /// without a UMI)
+    Umitools,
+}
+impl NameFormat {
+    /// Formats the read name of a record given its barcode and UMI.
+    /// Any description following the first whitespace of the name is kept as is.
+    pub fn format<'a>(&self, id: &'a [u8], barcode: &[u8], umi: &[u8]) -> Cow<'a, [u8]> {
+        match self {
+            Self::Original => Cow::Borrowed(id),
+            Self::Umitools => {
+                let end = id
+                    .iter()
+                    .position(|b| b.is_ascii_whitespace())
+                    .unwrap_or(id.len());
+                let umi: &[u8] = if umi.is_empty() {
+                    b""
+                } else {
+                    &[b"_", umi].concat()
+                };
+                Cow::Owned([&id[..end], b"_", barcode, umi, &id[end..]].concat())
+            }
+        }
+    }
+}
+
+/// Returns a read ID without its description (everything after the first whitespace)
+fn strip_comment(id: &[u8]) -> &[u8] {
+    let end = id
+        .iter()
+        .position(|b| b.is_ascii_whitespace())
+        .unwrap_or(id.len());
+    &id[..end]
+}
+
+/// Writes the name (up to the first whitespace), barcode and UMI (if any) of a read pair
+/// as a row of a barcode table
+fn write_to_table<W: Write + ?Sized>(
+    writer: &mut W,
+    id: &[u8],
+    barcode: &[u8],
+    umi: &[u8],
+) -> Result<()> {
+    writer.write_all(strip_comment(id))?;
+    writer.write_all(b"\t")?;
+    writer.write_all(barcode)?;
+    if !umi.is_empty() {
+        writer.write_all(b"\t")?;
+        writer.write_all(umi)?;
+    }
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Returns the qualities of a read, failing on records without any (e.g. FASTA inputs) or
+/// without a quality for each base
+fn qualities(record: &Record) -> Result<&[u8]> {
+    match record.qual() {
+        Some(qual) if qual.len() == record.seq().len() => Ok(qual),
+        Some(qual) => bail!(Error::Input(
+            format!(
+                "Read {} has {} qualities for {} bases (truncated input?)",
+                String::from_utf8_lossy(strip_comment(record.id())),
+                qual.len(),
+                record.seq().len()
+            )
+            .into()
+        )),
+        None => bail!(Error::Input(
+            format!(
+                "Read {} has no qualities (FASTA inputs cannot be converted to fastqs)",
+                String::from_utf8_lossy(strip_comment(record.id()))
+            )
+            .into()
+        )),
+    }
+}
+
+/// Builds the emitted name of a read ID into a reused buffer: optionally without its
+/// description (keeping a folded sample index as a `BC:Z:` comment), with its name (up to
+/// the first whitespace) replaced by a serial ID and formatted with its barcode and UMI
+fn build_name(
+    name: &mut Vec<u8>,
+    id: &[u8],
+    serial: Option<&[u8]>,
+    barcode: &[u8],
+    umi: &[u8],
+    opts: &Options,
+) {
+    let (id, sample_index) = if opts.strip_comments {
+        (
+            strip_comment(id),
+            index::index_of(id).filter(|_| opts.tag_index),
+        )
+    } else {
+        (id, None)
+    };
+    let (head, description) = id.split_at(strip_comment(id).len());
+    name.clear();
+    name.extend_from_slice(serial.unwrap_or(head));
+    if opts.name_format == NameFormat::Umitools {
+        name.extend_from_slice(b"_");
+        name.extend_from_slice(barcode);
+        if !umi.is_empty() {
+            name.extend_from_slice(b"_");
+            name.extend_from_slice(umi);
+        }
+    }
+    name.extend_from_slice(description);
+    if let Some(sample_index) = sample_index {
+        name.extend_from_slice(b" BC:Z:");
+        name.extend_from_slice(sample_index);
+    }
+}
+
+/// How the whitelist of observed barcodes is collected
+#[derive(Clone)]
+pub enum WhitelistMode {
+    /// Hold the whitelist in memory
+    InMemory,
+    /// Spill sorted chunks of at most `chunk_size` barcodes to `tmp_dir` and merge them at the end
+    External { tmp_dir: PathBuf, chunk_size: usize },
+    /// Do not collect the whitelist
+    Skip,
+}
+/// The qualities written for the barcode region of the R1 construct
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BarcodeQual {
+    /// Copy the R1 qualities of the bases each barcode tier was matched at
+    Keep,
+    /// Write a constant (phred+33) quality character
+    Constant(u8),
+}
+impl FromStr for BarcodeQual {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.as_bytes() {
+            b"keep" => Ok(Self::Keep),
+            [c] if (b'!'..=b'~').contains(c) => Ok(Self::Constant(*c)),
+            _ => Err(format!(
+                "expected `keep` or a single quality character, found '{}'",
+                s
+            )),
+        }
+    }
+}
+impl fmt::Display for BarcodeQual {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Keep => write!(f, "keep"),
+            Self::Constant(c) => write!(f, "{}", *c as char),
+        }
+    }
+}
+
+/// The SAM tag flagging the unaligned BAM records of `--chimeras flag` hits (`XJ:Z:1`),
+/// as BAM read names cannot carry the ` chimera` flag of fastq headers
+pub const CHIMERA_TAG: &[u8; 2] = b"XJ";
+
+/// What happens to read pairs whose R1 holds a second tier 1 barcode downstream of the
+/// matched one (a ligation chimera)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ChimeraAction {
+    /// Keep the read pair unchanged and only count it
+    Count,
+    /// Keep the read pair and append ` chimera` to the R2 header (or tag it with `XJ:Z:1`
+    /// in BAM output)
+    Flag,
+    /// Drop the read pair
+    Drop,
+}
+
+/// Parameters controlling how read pairs are processed
+#[derive(Clone)]
+pub struct Options {
+    /// The window to search for the first barcode in (None for anchored matching)
+    pub offset: Option<usize>,
+    /// The default length of the UMI
+    pub umi_len: usize,
+    /// The quality cutoff for 3' trimming of R2
+    pub trim_qual: Option<u8>,
+    /// The minimum length of R2 (after trimming) to keep a pair
+    pub min_r2_len: usize,
+    /// Drop reads whose UMI is a single-base homopolymer
+    pub filter_homopolymer_umi: bool,
+    /// Keep read pairs with an empty R2, writing a placeholder `N` read instead
+    pub keep_empty_r2: bool,
+    /// The minimum Shannon entropy (in bits) of a UMI to keep a read
+    pub min_umi_entropy: Option<f64>,
+    /// The number of passing reads between whitelist growth measurements
+    pub growth_interval: usize,
+    /// How the distinct barcodes observed are collected
+    pub whitelist: WhitelistMode,
+    /// The qualities written for the barcode region of the R1 construct
+    pub barcode_qual: BarcodeQual,
+    /// The expected number of cells used to estimate the number of cells
+    pub expected_cells: Option<usize>,
+    /// Accumulate the per-cell QC metrics of the in-memory whitelist
+    pub cell_summary: bool,
+    /// Accumulate the per-position nucleotide composition of R1
+    pub r1_composition: bool,
+    /// Accumulate the number of passing reads of each barcode (well) of each tier
+    pub well_counts: bool,
+    /// The format of the emitted reads
+    pub output_format: OutputFormat,
+    /// The format of the read names of both outputs
+    pub name_format: NameFormat,
+    /// Drop the descriptions (e.g. `1:N:0:TAAGGCGA`) of the read names of both outputs,
+    /// keeping only a sample index folded in from the index reads
+    pub strip_comments: bool,
+    /// Replace the read names with serial IDs (the ordinal of each emitted read pair)
+    pub rename_reads: bool,
+    /// Write the read name, barcode and UMI of each read pair as a TSV row to the R1 output
+    /// instead of the synthetic barcode read
+    pub barcode_table: bool,
+    /// The adapters trimmed from the 3' end of R2
+    pub adapters: Option<Arc<Adapters>>,
+    /// The k-mer screen of contaminant sequences applied to R2
+    pub contaminants: Option<Arc<ContaminantScreen>>,
+    /// What happens to read pairs whose R2 hits a contaminant
+    pub contaminant_action: ContaminantAction,
+    /// What happens to chimeric read pairs (None to not detect them)
+    pub chimeras: Option<ChimeraAction>,
+    /// How the records of R1 and R2 are paired
+    pub pairing: Pairing,
+    /// The maximum number of read pairs emitted per barcode (sampled uniformly)
+    pub reads_per_cell: Option<usize>,
+    /// The seed of the per-cell downsampling
+    pub seed: u64,
+    /// The directory of temporary files (e.g. the reads spilled while downsampling)
+    pub tmp_dir: PathBuf,
+    /// Emit only the first read pair of each molecule
+    pub dedup: Option<DedupOptions>,
+    /// How passing read pairs are assigned to shards
+    pub shard_by: ShardMode,
+    /// The number of consecutive read pairs written to a round-robin shard before the next
+    pub shard_block_size: usize,
+    /// The number of bytes the barcodes, molecules and sampled reads held in memory may
+    /// grow to
+    pub max_held_bytes: Option<usize>,
+    /// Write the read pairs of each input pair (e.g. lane) to its own group of shards
+    pub split_inputs: bool,
+    /// The 10x whitelist whose entries replace the emitted barcodes
+    pub tenx_whitelist: Option<Arc<TenxWhitelist>>,
+    /// The barcodes expected in the run that passing constructs are validated against
+    pub expected_whitelist: Option<Arc<ExpectedWhitelist>>,
+    /// Drop read pairs whose barcode is not in the expected whitelist
+    pub reject_unexpected: bool,
+    /// Append ` unexpected` to the R2 names of read pairs whose barcode is not in the
+    /// expected whitelist (or tag them with `XU:Z:1` in BAM output)
+    pub flag_unexpected: bool,
+    /// The feature barcodes (e.g. antibodies or hashtags) counted from R2
+    pub features: Option<Arc<FeatureReference>>,
+    /// Tag BAM records with the sample index folded into their read names (and keep it
+    /// when stripping their descriptions)
+    pub tag_index: bool,
+    /// The read group of BAM output
+    pub read_group: Option<ReadGroup>,
+    /// The command line recorded in the `@PG` line of BAM output
+    pub command_line: Option<String>,
+}
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            offset: Some(5),
+            umi_len: 12,
+            trim_qual: None,
+            min_r2_len: 0,
+            filter_homopolymer_umi: false,
+            keep_empty_r2: false,
+            min_umi_entropy: None,
+            growth_interval: 1_000_000,
+            whitelist: WhitelistMode::InMemory,
+            barcode_qual: BarcodeQual::Keep,
+            expected_cells: None,
+            cell_summary: false,
+            r1_composition: false,
+            well_counts: false,
+            output_format: OutputFormat::Fastq,
+            name_format: NameFormat::Original,
+            strip_comments: false,
+            rename_reads: false,
+            barcode_table: false,
+            adapters: None,
+            contaminants: None,
+            contaminant_action: ContaminantAction::Drop,
+            chimeras: None,
+            pairing: Pairing::Zip,
+            reads_per_cell: None,
+            seed: downsample::DEFAULT_SEED,
+            tmp_dir: std::env::temp_dir(),
+            dedup: None,
+            shard_by: ShardMode::RoundRobin,
+            shard_block_size: shard::DEFAULT_SHARD_BLOCK_SIZE,
+            max_held_bytes: None,
+            split_inputs: false,
+            tenx_whitelist: None,
+            expected_whitelist: None,
+            reject_unexpected: false,
+            flag_unexpected: false,
+            features: None,
+            tag_index: false,
+            read_group: None,
+            command_line: None,
+        }
+    }
+}
+
+/// The sequence and quality written in place of an empty R2 with `keep_empty_r2`
+const EMPTY_R2_PLACEHOLDER: (&[u8], &[u8]) = (b"N", b"!");
+
+/// The window searched for the first barcode when estimating the offset
+pub const AUTO_OFFSET_WINDOW: usize = 32;
+
+/// The fraction of first barcode matches the estimated offset must capture
+pub const AUTO_OFFSET_QUANTILE: f64 = 0.995;
+
+/// The number of leading reads scanned to estimate the offset
+pub const AUTO_OFFSET_READS: usize = 100_000;
+
+/// Estimates the offset from a sample of R1 sequences.
+///
+/// The first barcode is searched for within a wide window and the smallest offset
+/// capturing `AUTO_OFFSET_QUANTILE` of the matches is returned (None if nothing matched).
+pub fn estimate_offset<'a, I>(sequences: I, config: &Config, umi_len: usize) -> Option<usize>
+where
+    I: Iterator<Item = &'a [u8]>,
+{
+    let mut histogram = vec![0usize; AUTO_OFFSET_WINDOW + 1];
+    for seq in sequences {
+        if let Ok(read_match) = config.match_read(seq, Some(AUTO_OFFSET_WINDOW), umi_len) {
+            histogram[read_match.shift] += 1;
+        }
+    }
+    let total = histogram.iter().sum::<usize>();
+    if total == 0 {
+        return None;
+    }
+    let mut captured = 0;
+    histogram.iter().position(|count| {
+        captured += count;
+        captured as f64 >= AUTO_OFFSET_QUANTILE * total as f64
+    })
+}
+
+/// The maximum number of reads failing barcode 1 rescanned in a wider window to detect
+/// matches clipped by the offset window
+pub const OFFSET_SCAN_READS: usize = 10_000;
+
+/// The number of nucleotides beyond the offset window searched by the diagnostic scan
+pub const OFFSET_SCAN_EXTENSION: usize = 16;
+
+/// The number of reads after which a run without any barcode matches is aborted
+const EARLY_ABORT_READS: usize = 100_000;
+
+/// Processes paired reads, writing the `[barcode][UMI]` construct of each passing R1 and the
+/// passing R2 to the given writers, and returns the statistics of the run.
+///
+/// The writers can be any sink (files, compressors, in-memory buffers or sockets).
+pub fn process_pair<R1, R2, W1, W2>(
+    r1: R1,
+    r2: R2,
+    r1_out: &mut W1,
+    r2_out: &mut W2,
+    config: &Config,
+    opts: &Options,
+) -> Result<Statistics>
+where
+    R1: Iterator<Item = Result<Record>>,
+    R2: Iterator<Item = Result<Record>>,
+    W1: Write,
+    W2: Write,
+{
+    process_pair_with_progress(r1, r2, r1_out, r2_out, config, opts, |_| Ok(()))
+}
+
+/// Processes paired reads as [`process_pair`], calling `progress` with the cumulative statistics
+/// periodically throughout the run
+pub fn process_pair_with_progress<R1, R2, W1, W2, F>(
+    r1: R1,
+    r2: R2,
+    r1_out: &mut W1,
+    r2_out: &mut W2,
+    config: &Config,
+    opts: &Options,
+    progress: F,
+) -> Result<Statistics>
+where
+    R1: Iterator<Item = Result<Record>>,
+    R2: Iterator<Item = Result<Record>>,
+    W1: Write,
+    W2: Write,
+    F: FnMut(&mut Statistics) -> Result<()>,
+{
+    process_pairs_with_progress([(r1, r2)], r1_out, r2_out, None, config, opts, progress)
+        .map(|(statistics, _)| statistics)
+}
+
+/// Processes multiple input pairs (e.g. lanes) of a single sample in order as
+/// [`process_pair_with_progress`], returning the aggregated statistics alongside the
+/// read counts of each input pair.
+///
+/// With `rename_reads` the serial ID and original name of each emitted read pair are
+/// written to `names_out` as a TSV.
+pub fn process_pairs_with_progress<I, R1, R2, W1, W2, F>(
+    pairs: I,
+    r1_out: &mut W1,
+    r2_out: &mut W2,
+    names_out: Option<&mut dyn Write>,
+    config: &Config,
+    opts: &Options,
+    progress: F,
+) -> Result<(Statistics, Vec<PairStatistics>)>
+where
+    I: IntoIterator<Item = (R1, R2)>,
+    R1: Iterator<Item = Result<Record>>,
+    R2: Iterator<Item = Result<Record>>,
+    W1: Write,
+    W2: Write,
+    F: FnMut(&mut Statistics) -> Result<()>,
+{
+    process_shards_with_progress(
+        pairs,
+        std::slice::from_mut(r1_out),
+        std::slice::from_mut(r2_out),
+        names_out,
+        config,
+        opts,
+        progress,
+    )
+}
+
+/// Processes multiple input pairs of a single sample as [`process_pairs_with_progress`],
+/// splitting the passing read pairs across shards (one R1 and one R2 writer per shard) in
+/// round-robin blocks of `shard_block_size` read pairs or by a hash of their barcode.
+///
+/// With `split_inputs` the shards are divided into consecutive groups, one per input
+/// pair, and each input pair is only written to the shards of its group.
+pub fn process_shards_with_progress<I, R1, R2, W1, W2, F>(
+    pairs: I,
+    r1_outs: &mut [W1],
+    r2_outs: &mut [W2],
+    mut names_out: Option<&mut dyn Write>,
+    config: &Config,
+    opts: &Options,
+    mut progress: F,
+) -> Result<(Statistics, Vec<PairStatistics>)>
+where
+    I: IntoIterator<Item = (R1, R2)>,
+    R1: Iterator<Item = Result<Record>>,
+    R2: Iterator<Item = Result<Record>>,
+    W1: Write,
+    W2: Write,
+    F: FnMut(&mut Statistics) -> Result<()>,
+{
+    if r1_outs.len() != r2_outs.len() {
+        bail!(
+            "Expected the same number of R1 and R2 shards (found {} and {})",
+            r1_outs.len(),
+            r2_outs.len()
+        );
+    }
+    let pairs = pairs.into_iter().collect::<Vec<_>>();
+    let num_groups = if opts.split_inputs { pairs.len() } else { 1 };
+    if num_groups == 0 || !r1_outs.len().is_multiple_of(num_groups) {
+        bail!(
+            "Expected a group of shards for each of the {} input pairs (found {} shards)",
+            num_groups,
+            r1_outs.len()
+        );
+    }
+    let group_size = r1_outs.len() / num_groups;
+    let mut sharder = Sharder::new(opts.shard_by, group_size, opts.shard_block_size)?;
+    let mut statistics = Statistics::new();
+    statistics.tenx_translation = opts
+        .tenx_whitelist
+        .clone()
+        .map(|whitelist| TenxTranslation::new(whitelist, config.tier_sizes()))
+        .transpose()?;
+    if opts.well_counts {
+        statistics.well_counts = config.tier_sizes().map(|n| vec![0; n]);
+    }
+    if opts.output_format == OutputFormat::DropseqBam {
+        let header = bam::header_text(opts.read_group.as_ref(), opts.command_line.as_deref());
+        for r2_out in r2_outs.iter_mut() {
+            bam::write_header(r2_out, &header)?;
+        }
+    }
+    if let Some(adapters) = &opts.adapters {
+        statistics.adapter_hits = adapters.hits();
+    }
+    if let WhitelistMode::External {
+        tmp_dir,
+        chunk_size,
+    } = &opts.whitelist
+    {
+        statistics.external_whitelist = Some(ExternalCounter::new(tmp_dir.clone(), *chunk_size));
+    }
+    let mut downsampler = opts
+        .reads_per_cell
+        .map(|target| Downsampler::new(target, opts.seed, &opts.tmp_dir))
+        .transpose()?;
+    // the reads offered to the downsampler are formatted into reused buffers
+    let (mut downsample_r1, mut downsample_r2) = (Vec::new(), Vec::new());
+    let mut deduplicator = opts.dedup.as_ref().map(Deduplicator::new).transpose()?;
+    let mut num_matched = 0;
+    let mut pair_statistics = Vec::new();
+    // the buffers of a passing read pair are reused so the hot path does not allocate
+    let mut read_match = ReadMatch::default();
+    // without a UMI the UMI filters and statistics are skipped
+    let has_umi = config.umi_len(opts.umi_len) > 0;
+    // duplicates are counted exactly alongside the in-memory whitelist
+    if has_umi && matches!(opts.whitelist, WhitelistMode::InMemory) {
+        statistics.num_duplicates = Some(0);
+    }
+    let mut barcode_buffer = Vec::new();
+    let mut construct_seq = Vec::new();
+    let mut qual_buffer = Vec::new();
+    let mut serial_buffer = Vec::new();
+    let mut num_renamed = 0usize;
+    let (mut r1_id, mut r2_id) = (Vec::new(), Vec::new());
+    for (pair_idx, (r1, r2)) in pairs.into_iter().enumerate() {
+        let group = if opts.split_inputs {
+            pair_idx * group_size
+        } else {
+            0
+        };
+        let start = PairStatistics::snapshot(&statistics);
+        let mut records = Pairs::new(r1, r2, opts.pairing);
+        let mut mark = Instant::now();
+        loop {
+            // the time since the last lap was spent filtering the previous pair
+            statistics.stage_times.matching += StageTimes::lap(&mut mark);
+            let Some(pair) = records.next() else {
+                break;
+            };
+            let (rec1, rec2) = pair?;
+            statistics.stage_times.reading += StageTimes::lap(&mut mark);
+            if statistics.total_reads == EARLY_ABORT_READS && num_matched == 0 {
+                bail!(Error::Config(
+                    format!(
+                        "No reads matched the barcodes within the first {} reads; check the config, read structure and `--offset`",
+                        EARLY_ABORT_READS
+                    )
+                    .into()
+                ));
+            }
+            if statistics.total_reads.is_multiple_of(125) {
+                progress(&mut statistics)?;
+                if let Some(limit) = opts.max_held_bytes {
+                    let held = statistics.memory_estimate()
+                        + deduplicator
+                            .as_ref()
+                            .map_or(0, Deduplicator::memory_estimate)
+                        + downsampler.as_ref().map_or(0, Downsampler::memory_estimate);
+                    if held > limit {
+                        bail!(Error::Usage(
+                            format!(
+                                "The barcodes and molecules held in memory outgrew their {} byte share of `--max-memory` after {} reads; rerun with a larger budget, `--external-whitelist` or `--no-whitelist`",
+                                limit,
+                                statistics.total_reads
+                            )
+                            .into()
+                        ));
+                    }
+                }
+            }
+            statistics.total_reads += 1;
+            if opts.r1_composition {
+                statistics.r1_composition.add(rec1.seq());
+            }
+            let empty_r2 = rec2.seq().is_empty();
+            if empty_r2 {
+                statistics.num_empty_r2 += 1;
+                if !opts.keep_empty_r2 {
+                    continue;
+                }
+            }
+
+            if let Err(filtered) =
+                config.match_read_into(rec1.seq(), opts.offset, opts.umi_len, &mut read_match)
+            {
+                statistics.record_filtered(filtered);
+                // a sample of the reads missing barcode 1 is rescanned in a wider window
+                if let Some(offset) = opts.offset.filter(|_| {
+                    filtered == Filtered::Tier(0)
+                        && statistics.num_offset_scanned < OFFSET_SCAN_READS
+                }) {
+                    let window = Some(offset + OFFSET_SCAN_EXTENSION);
+                    let shift = config
+                        .match_read(rec1.seq(), window, opts.umi_len)
+                        .ok()
+                        .map(|read_match| read_match.shift);
+                    statistics.record_offset_scan(offset, shift);
+                }
+                continue;
+            }
+            num_matched += 1;
+            if has_umi && opts.filter_homopolymer_umi && umi::is_homopolymer(&read_match.umi) {
+                statistics.num_filtered_umi_homopolymer += 1;
+                continue;
+            }
+            if has_umi
+                && opts
+                    .min_umi_entropy
+                    .is_some_and(|min| umi::shannon_entropy(&read_match.umi) < min)
+            {
+                statistics.num_filtered_umi_entropy += 1;
+                continue;
+            }
+            statistics.record_match(&read_match);
+            let chimeric = opts.chimeras.is_some() && config.is_chimeric(rec1.seq(), &read_match);
+            if chimeric {
+                statistics.num_chimeras += 1;
+                if opts.chimeras == Some(ChimeraAction::Drop) {
+                    continue;
+                }
+            }
+
+            config.build_barcode_into(read_match.indices, &mut barcode_buffer);
+            let barcode = barcode_buffer.as_slice();
+            construct_seq.clear();
+            construct_seq.extend_from_slice(barcode);
+            construct_seq.extend_from_slice(&read_match.umi);
+            qual_buffer.clear();
+            config.build_qual_into(&read_match, qualities(&rec1)?, &mut qual_buffer);
+
+            let (r2_seq, r2_qual) = if empty_r2 {
+                EMPTY_R2_PLACEHOLDER
+            } else {
+                (rec2.seq(), qualities(&rec2)?)
+            };
+            let r2_len = match opts.trim_qual.filter(|_| !empty_r2) {
+                Some(cutoff) => {
+                    let keep = trim::quality_trim_index(r2_qual, cutoff);
+                    if keep < r2_seq.len() {
+                        statistics.num_trimmed_reads += 1;
+                        statistics.num_trimmed_bases += r2_seq.len() - keep;
+                    }
+                    keep
+                }
+                None => r2_seq.len(),
+            };
+            let r2_len = match opts
+                .adapters
+                .as_ref()
+                .and_then(|adapters| adapters.trim_index(&r2_seq[..r2_len]))
+            {
+                Some((idx, keep)) => {
+                    statistics.adapter_hits[idx].reads += 1;
+                    statistics.num_adapter_trimmed_bases += r2_len - keep;
+                    keep
+                }
+                None => r2_len,
+            };
+            if r2_len < opts.min_r2_len && !empty_r2 {
+                statistics.num_filtered_r2_len += 1;
+                continue;
+            }
+            let contaminant = opts
+                .contaminants
+                .as_ref()
+                .is_some_and(|screen| screen.hits(&r2_seq[..r2_len]));
+            if contaminant {
+                statistics.num_contaminant += 1;
+                if opts.contaminant_action == ContaminantAction::Drop {
+                    continue;
+                }
+            }
+
+            // duplicates are dropped before they are counted as passing
+            if let Some(deduplicator) = deduplicator.as_mut() {
+                if deduplicator.is_duplicate(barcode, &read_match.umi, &r2_seq[..r2_len]) {
+                    statistics.num_deduplicated += 1;
+                    continue;
+                }
+            }
+
+            let unexpected = match &opts.expected_whitelist {
+                Some(expected) if expected.contains(barcode) => {
+                    statistics.num_expected += 1;
+                    false
+                }
+                Some(_) => {
+                    statistics.num_unexpected += 1;
+                    if opts.reject_unexpected {
+                        continue;
+                    }
+                    true
+                }
+                None => false,
+            };
+
+            statistics.passing_reads += 1;
+            statistics.max_r2_len = statistics.max_r2_len.max(r2_len);
+            if has_umi {
+                statistics.umi_composition.add(&read_match.umi);
+                statistics.molecule_sketch.insert(&construct_seq);
+            }
+            if let Some(reference) = &opts.features {
+                match reference.match_read(r2_seq) {
+                    Some(feature) => {
+                        statistics.num_feature_matched += 1;
+                        statistics
+                            .feature_counts
+                            .insert(barcode, feature, &read_match.umi);
+                    }
+                    None => statistics.num_feature_unmatched += 1,
+                }
+            }
+            if opts.well_counts {
+                read_match
+                    .indices
+                    .iter()
+                    .zip(statistics.well_counts.iter_mut())
+                    .for_each(|(idx, counts)| counts[*idx] += 1);
+            }
+            if let WhitelistMode::InMemory = opts.whitelist {
+                if let Some(num_duplicates) = statistics.num_duplicates.as_mut() {
+                    if !statistics.molecules.insert(hash_sequence(&construct_seq)) {
+                        *num_duplicates += 1;
+                    }
+                }
+                let key = BarcodeKey::new(read_match.indices);
+                if opts.cell_summary {
+                    let new_umi = statistics.cell_summaries.entry(key).or_default().add(
+                        &read_match.umi,
+                        &qual_buffer[..barcode.len()],
+                        &r2_qual[..r2_len],
+                        read_match.corrected.iter().filter(|c| **c).count(),
+                    );
+                    statistics.num_cell_umis += new_umi as usize;
+                }
+                *statistics.whitelist.entry(key).or_default() += 1;
+                if statistics
+                    .passing_reads
+                    .is_multiple_of(opts.growth_interval)
+                {
+                    statistics.record_growth();
+                }
+            } else {
+                statistics.barcode_sketch.insert(barcode);
+                if let Some(counter) = statistics.external_whitelist.as_mut() {
+                    counter.insert(barcode)?;
+                }
+            }
+            match (statistics.tenx_translation.as_ref(), opts.barcode_qual) {
+                (Some(translation), _) => {
+                    let tenx_barcode = translation.translate(BarcodeKey::new(read_match.indices));
+                    construct_seq.clear();
+                    construct_seq.extend_from_slice(tenx_barcode);
+                    construct_seq.extend_from_slice(&read_match.umi);
+                    qual_buffer.splice(
+                        ..barcode.len(),
+                        std::iter::repeat_n(tenx::TENX_BARCODE_QUAL, tenx_barcode.len()),
+                    );
+                }
+                (None, BarcodeQual::Keep) => {}
+                (None, BarcodeQual::Constant(c)) => qual_buffer[..barcode.len()].fill(c),
+            }
+            let construct_qual = qual_buffer.as_slice();
+            let (barcode, umi) = construct_seq.split_at(construct_seq.len() - read_match.umi.len());
+            let serial = if opts.rename_reads {
+                num_renamed += 1;
+                serial_buffer.clear();
+                write!(serial_buffer, "{}", num_renamed)?;
+                if let Some(names_out) = names_out.as_mut() {
+                    names_out.write_all(&serial_buffer)?;
+                    names_out.write_all(b"\t")?;
+                    names_out.write_all(strip_comment(rec1.id()))?;
+                    names_out.write_all(b"\n")?;
+                }
+                Some(serial_buffer.as_slice())
+            } else {
+                None
+            };
+            build_name(&mut r1_id, rec1.id(), serial, barcode, umi, opts);
+            build_name(&mut r2_id, rec2.id(), serial, barcode, umi, opts);
+            if contaminant {
+                r2_id.extend_from_slice(b" contaminant");
+            }
+            if chimeric && opts.chimeras == Some(ChimeraAction::Flag) {
+                r2_id.extend_from_slice(b" chimera");
+            }
+            if unexpected && opts.flag_unexpected {
+                r2_id.extend_from_slice(b" unexpected");
+            }
+            statistics.stage_times.matching += StageTimes::lap(&mut mark);
+            // downsampled reads are spilled until the reservoirs are final
+            downsample_r1.clear();
+            downsample_r2.clear();
+            let (w1, w2): (&mut dyn Write, &mut dyn Write) = match downsampler {
+                Some(_) => (&mut downsample_r1, &mut downsample_r2),
+                None => {
+                    let shard = group + sharder.next_shard(barcode);
+                    (&mut r1_outs[shard], &mut r2_outs[shard])
+                }
+            };
+            match opts.output_format {
+                OutputFormat::Fastq => {
+                    if opts.barcode_table {
+                        write_to_table(w1, &r2_id, barcode, umi)?;
+                    } else {
+                        write_to_fastq(w1, &r1_id, &construct_seq, construct_qual)?;
+                    }
+                    write_to_fastq(w2, &r2_id, &r2_seq[..r2_len], &r2_qual[..r2_len])?;
+                }
+                OutputFormat::DropseqBam => {
+                    let mut tags = vec![(b"XC", barcode)];
+                    if has_umi {
+                        tags.push((b"XM", umi));
+                    }
+                    if let Some(read_group) = &opts.read_group {
+                        tags.push((bam::READ_GROUP_TAG, read_group.id.as_bytes()));
+                    }
+                    if let Some(sample_index) = index::index_of(&r2_id).filter(|_| opts.tag_index) {
+                        tags.push((index::INDEX_TAG, sample_index));
+                    }
+                    if contaminant {
+                        tags.push((contaminant::CONTAMINANT_TAG, b"1"));
+                    }
+                    if chimeric && opts.chimeras == Some(ChimeraAction::Flag) {
+                        tags.push((CHIMERA_TAG, b"1"));
+                    }
+                    if unexpected && opts.flag_unexpected {
+                        tags.push((whitelist::UNEXPECTED_TAG, b"1"));
+                    }
+                    bam::write_unmapped(w2, &r2_id, &r2_seq[..r2_len], &r2_qual[..r2_len], &tags)?
+                }
+            }
+            if let Some(downsampler) = downsampler.as_mut() {
+                downsampler.insert(group, barcode, &downsample_r1, &downsample_r2)?;
+            }
+            statistics.stage_times.writing += StageTimes::lap(&mut mark);
+        }
+        statistics.num_orphans += records.num_orphans();
+        pair_statistics.push(PairStatistics::since(&statistics, &start));
+    }
+    if let Some(downsampler) = downsampler {
+        let mark = Instant::now();
+        statistics.num_downsampled = downsampler.num_dropped();
+        downsampler.finish(|group, barcode, r1, r2| {
+            let shard = group + sharder.next_shard(barcode);
+            r1_outs[shard].write_all(r1)?;
+            r2_outs[shard].write_all(r2)?;
+            Ok(())
+        })?;
+        statistics.stage_times.writing += mark.elapsed();
+    }
+    if let Some(offset) = opts.offset {
+        // report every shift of the window even if never observed
+        if statistics.shift_counts.len() <= offset {
+            statistics.shift_counts.resize(offset + 1, 0);
+        }
+    }
+    if let WhitelistMode::InMemory = opts.whitelist {
+        statistics.record_growth();
+        statistics.estimate_cells(opts.expected_cells);
+    }
+    statistics.estimate_error_rates(config);
+    if opts.expected_whitelist.is_some() {
+        statistics.record_expected_fractions();
+    }
+    statistics.calculate_metrics();
+    Ok((statistics, pair_statistics))
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::dedup::DedupMode;
+    use crate::features::FeatureReference;
+    use crate::input;
+
+    const CONFIG_PATH: &str = "data/config_v3.yaml";
+    const R1_PATH: &str = "data/example_v3/example_R1.fq.gz";
+    const R2_PATH: &str = "data/example_v3/example_R2.fq.gz";
+
+    #[test]
+    fn process_to_memory() {
+        let config = Config::from_file(CONFIG_PATH, false, false).unwrap();
+        let r1 = input::open(R1_PATH).unwrap().reader;
+        let r2 = input::open(R2_PATH).unwrap().reader;
+        let mut r1_out = Vec::new();
+        let mut r2_out = Vec::new();
+        let statistics = process_pair(
+            r1,
+            r2,
+            &mut r1_out,
+            &mut r2_out,
+            &config,
+            &Options::default(),
+        )
+        .unwrap();
+        assert_eq!(statistics.total_reads, 250);
+        assert_eq!(statistics.passing_reads, 198);
+        assert_eq!(statistics.whitelist_size, 189);
+        assert_eq!(statistics.whitelist.values().sum::<usize>(), 198);
+        assert!(statistics.estimated_cells.is_some_and(|c| c <= 189));
+        assert!(statistics.stage_times.matching > std::time::Duration::ZERO);
+        assert!(statistics.stage_times.writing > std::time::Duration::ZERO);
+        assert_eq!(statistics.umi_composition.len(), 12);
+        assert!(statistics.umi_entropy > 1.5 && statistics.umi_entropy <= 2.0);
+        assert_eq!(statistics.shift_counts.len(), 6);
+        assert_eq!(statistics.shift_counts.iter().sum::<usize>(), 198);
+        assert_eq!(r1_out.split(|b| *b == b'\n').count() - 1, 198 * 4);
+        assert_eq!(r2_out.split(|b| *b == b'\n').count() - 1, 198 * 4);
+
+        // every construct is the 28bp barcode and the 12bp UMI
+        let construct = r1_out.split(|b| *b == b'\n').nth(1).unwrap();
+        assert_eq!(construct.len(), 40);
+    }
+
+    #[test]
+    fn error_rates() {
+        let config = Config::from_file(CONFIG_PATH, false, false).unwrap();
+        let r1 = input::open(R1_PATH).unwrap().reader;
+        let r2 = input::open(R2_PATH).unwrap().reader;
+        let statistics = process_pair(
+            r1,
+            r2,
+            &mut Vec::new(),
+            &mut Vec::new(),
+            &config,
+            &Options::default(),
+        )
+        .unwrap();
+        let rates = [
+            statistics.error_rate_1,
+            statistics.error_rate_2,
+            statistics.error_rate_3,
+            statistics.error_rate_4,
+        ];
+        let corrected = [
+            statistics.num_corrected_1,
+            statistics.num_corrected_2,
+            statistics.num_corrected_3,
+            statistics.num_corrected_4,
+        ];
+        for (rate, corrected) in rates.iter().zip(corrected) {
+            assert!((0.0..1.0).contains(rate));
+            assert_eq!(*rate > 0.0, corrected > 0);
+        }
+    }
+
+    #[test]
+    fn contaminants() {
+        let config = Config::from_file(CONFIG_PATH, false, false).unwrap();
+        let r2 = input::open(R2_PATH)
+            .unwrap()
+            .reader
+            .map(Result::unwrap)
+            .take(10)
+            .map(|r| r.seq().to_vec())
+            .collect::<Vec<_>>();
+        let screen = Arc::new(ContaminantScreen::from_sequences(&r2, 21).unwrap());
+        let process = |contaminant_action| {
+            let opts = Options {
+                contaminants: Some(screen.clone()),
+                contaminant_action,
+                ..Options::default()
+            };
+            let r1 = input::open(R1_PATH).unwrap().reader;
+            let r2 = input::open(R2_PATH).unwrap().reader;
+            let mut r2_out = Vec::new();
+            let statistics =
+                process_pair(r1, r2, &mut Vec::new(), &mut r2_out, &config, &opts).unwrap();
+            (statistics, String::from_utf8(r2_out).unwrap())
+        };
+
+        let (dropped, r2_out) = process(ContaminantAction::Drop);
+        assert!(dropped.num_contaminant > 0);
+        assert_eq!(dropped.passing_reads, 198 - dropped.num_contaminant);
+        assert!(!r2_out.contains(" contaminant"));
+
+        let (flagged, r2_out) = process(ContaminantAction::Flag);
+        assert_eq!(flagged.num_contaminant, dropped.num_contaminant);
+        assert_eq!(flagged.passing_reads, 198);
+        assert_eq!(
+            r2_out.matches(" contaminant\n").count(),
+            flagged.num_contaminant
+        );
+
+        // BAM read names cannot carry the flag so it is tagged instead
+        let opts = Options {
+            contaminants: Some(screen.clone()),
+            contaminant_action: ContaminantAction::Flag,
+            output_format: OutputFormat::DropseqBam,
+            ..Options::default()
+        };
+        let r1 = input::open(R1_PATH).unwrap().reader;
+        let r2 = input::open(R2_PATH).unwrap().reader;
+        let mut r2_out = Vec::new();
+        process_pair(r1, r2, &mut Vec::new(), &mut r2_out, &config, &opts).unwrap();
+        let num_tagged = r2_out.windows(5).filter(|w| w == b"XKZ1\0").count();
+        assert_eq!(num_tagged, flagged.num_contaminant);
+    }
+
+    #[test]
+    fn chimeras() {
+        let config = Config::from_file(CONFIG_PATH, false, false).unwrap();
+        // every other R1 is followed by the first tier 1 barcode and its linker
+        let tail = [config.tier_barcode(0, 0).unwrap(), config.tier_linker(0)].concat();
+        let r1_fastq = input::open(R1_PATH)
+            .unwrap()
+            .reader
+            .map(Result::unwrap)
+            .enumerate()
+            .flat_map(|(i, r)| {
+                let (mut seq, mut qual) = (r.seq().to_vec(), r.qual().unwrap().to_vec());
+                if i % 2 == 0 {
+                    seq.extend_from_slice(&tail);
+                    qual.resize(seq.len(), b'I');
+                }
+                [b"@", r.id(), b"\n", &seq, b"\n+\n", &qual, b"\n"].concat()
+            })
+            .collect::<Vec<_>>();
+        let process = |chimeras| {
+            let opts = Options {
+                chimeras,
+                ..Options::default()
+            };
+            let r1 = fxread::FastqReader::new(r1_fastq.as_slice()).map(Ok);
+            let r2 = input::open(R2_PATH).unwrap().reader;
+            let mut r2_out = Vec::new();
+            let statistics =
+                process_pair(r1, r2, &mut Vec::new(), &mut r2_out, &config, &opts).unwrap();
+            (statistics, String::from_utf8(r2_out).unwrap())
+        };
+
+        let (ignored, _) = process(None);
+        assert_eq!(ignored.num_chimeras, 0);
+        assert_eq!(ignored.passing_reads, 198);
+
+        let (counted, r2_out) = process(Some(ChimeraAction::Count));
+        assert!(counted.num_chimeras > 0);
+        assert_eq!(counted.passing_reads, 198);
+        assert!(!r2_out.contains(" chimera"));
+
+        let (flagged, r2_out) = process(Some(ChimeraAction::Flag));
+        assert_eq!(flagged.passing_reads, 198);
+        assert_eq!(r2_out.matches(" chimera\n").count(), counted.num_chimeras);
+
+        let (dropped, _) = process(Some(ChimeraAction::Drop));
+        assert_eq!(dropped.num_chimeras, counted.num_chimeras);
+        assert_eq!(dropped.passing_reads, 198 - counted.num_chimeras);
+
+        // BAM read names cannot carry the flag so it is tagged instead
+        let opts = Options {
+            chimeras: Some(ChimeraAction::Flag),
+            output_format: OutputFormat::DropseqBam,
+            ..Options::default()
+        };
+        let r1 = fxread::FastqReader::new(r1_fastq.as_slice()).map(Ok);
+        let r2 = input::open(R2_PATH).unwrap().reader;
+        let mut r2_out = Vec::new();
+        process_pair(r1, r2, &mut Vec::new(), &mut r2_out, &config, &opts).unwrap();
+        let num_tagged = r2_out.windows(5).filter(|w| w == b"XJZ1\0").count();
+        assert_eq!(num_tagged, counted.num_chimeras);
+        assert_eq!(
+            ChimeraAction::from_str("drop", false),
+            Ok(ChimeraAction::Drop)
+        );
+        assert!(ChimeraAction::from_str("keep", false).is_err());
+    }
+
+    #[test]
+    fn adapter_trimming() {
+        let config = Config::from_file(CONFIG_PATH, false, false).unwrap();
+        let r1 = input::open(R1_PATH).unwrap().reader;
+        let r2 = input::open(R2_PATH).unwrap().reader;
+        let adapters = Adapters::parse(">polyA\nAAAAAAAAAAAAAAAAAAAA\n", 0.1, 3).unwrap();
+        let opts = Options {
+            adapters: Some(Arc::new(adapters)),
+            min_r2_len: 1,
+            ..Options::default()
+        };
+        let mut r2_out = Vec::new();
+        let statistics =
+            process_pair(r1, r2, &mut Vec::new(), &mut r2_out, &config, &opts).unwrap();
+        assert_eq!(statistics.adapter_hits.len(), 1);
+        assert!(statistics.adapter_hits[0].reads > 0);
+        assert!(statistics.num_adapter_trimmed_bases >= statistics.adapter_hits[0].reads * 3);
+        // no emitted R2 ends with the adapter
+        for seq in r2_out.split(|b| *b == b'\n').skip(1).step_by(4) {
+            assert!(!seq.ends_with(b"AAA"));
+        }
+    }
+
+    #[test]
+    fn empty_r2() {
+        let config = Config::from_file(CONFIG_PATH, false, false).unwrap();
+        // blank every other R2
+        let r2_fastq = input::open(R2_PATH)
+            .unwrap()
+            .reader
+            .map(Result::unwrap)
+            .enumerate()
+            .flat_map(|(i, r)| {
+                let (seq, qual) = if i % 2 == 0 {
+                    (&[][..], &[][..])
+                } else {
+                    (r.seq(), r.qual().unwrap())
+                };
+                [b"@", r.id(), b"\n", seq, b"\n+\n", qual, b"\n"].concat()
+            })
+            .collect::<Vec<_>>();
+        let process = |keep_empty_r2| {
+            let opts = Options {
+                keep_empty_r2,
+                min_r2_len: 10,
+                ..Options::default()
+            };
+            let r1 = input::open(R1_PATH).unwrap().reader;
+            let r2 = fxread::FastqReader::new(r2_fastq.as_slice()).map(Ok);
+            let mut r2_out = Vec::new();
+            let statistics =
+                process_pair(r1, r2, &mut Vec::new(), &mut r2_out, &config, &opts).unwrap();
+            (statistics, String::from_utf8(r2_out).unwrap())
+        };
+
+        let (skipped, _) = process(false);
+        assert_eq!(skipped.total_reads, 250);
+        assert_eq!(skipped.num_empty_r2, 125);
+        assert!(skipped.passing_reads < 198);
+
+        let (kept, r2_out) = process(true);
+        assert_eq!(kept.num_empty_r2, 125);
+        assert_eq!(kept.passing_reads, 198);
+        assert_eq!(
+            r2_out.matches("\nN\n+\n!\n").count(),
+            kept.passing_reads - skipped.passing_reads
+        );
+    }
+
+    #[test]
+    fn duplicates() {
+        let config = Config::from_file(CONFIG_PATH, false, false).unwrap();
+        let twice = |path| {
+            let fastq = input::open(path)
+                .unwrap()
+                .reader
+                .map(Result::unwrap)
+                .flat_map(|r| {
+                    [
+                        b"@",
+                        r.id(),
+                        b"\n",
+                        r.seq(),
+                        b"\n+\n",
+                        r.qual().unwrap(),
+                        b"\n",
+                    ]
+                    .concat()
+                })
+                .collect::<Vec<_>>();
+            [fastq.as_slice(), fastq.as_slice()].concat()
+        };
+        // the distinct molecules are the distinct constructs of a single pass
+        let mut r1_out = Vec::new();
+        let r1 = input::open(R1_PATH).unwrap().reader;
+        let r2 = input::open(R2_PATH).unwrap().reader;
+        process_pair(
+            r1,
+            r2,
+            &mut r1_out,
+            &mut Vec::new(),
+            &config,
+            &Options::default(),
+        )
+        .unwrap();
+        let num_molecules = String::from_utf8(r1_out)
+            .unwrap()
+            .lines()
+            .skip(1)
+            .step_by(4)
+            .collect::<hashbrown::HashSet<_>>()
+            .len();
+
+        let (r1, r2) = (twice(R1_PATH), twice(R2_PATH));
+        let process = |opts: &Options| {
+            process_pair(
+                fxread::FastqReader::new(r1.as_slice()).map(Ok),
+                fxread::FastqReader::new(r2.as_slice()).map(Ok),
+                &mut Vec::new(),
+                &mut Vec::new(),
+                &config,
+                opts,
+            )
+            .unwrap()
+        };
+        let statistics = process(&Options::default());
+        assert_eq!(statistics.passing_reads, 2 * 198);
+        assert_eq!(statistics.num_duplicates, Some(2 * 198 - num_molecules));
+        assert_eq!(statistics.estimated_duplicates, None);
+        assert_eq!(
+            statistics.duplication_rate,
+            (2 * 198 - num_molecules) as f64 / (2 * 198) as f64
+        );
+
+        // without the in-memory whitelist they are estimated from the sketch
+        let statistics = process(&Options {
+            whitelist: WhitelistMode::Skip,
+            ..Options::default()
+        });
+        assert_eq!(statistics.num_duplicates, None);
+        assert_eq!(
+            statistics.estimated_duplicates,
+            Some(2 * 198 - statistics.estimated_molecules)
+        );
+    }
+
+    #[test]
+    fn shards() {
+        let config = Config::from_file(CONFIG_PATH, false, false).unwrap();
+        let process = |block_size| {
+            let r1 = input::open(R1_PATH).unwrap().reader;
+            let r2 = input::open(R2_PATH).unwrap().reader;
+            let mut r1_outs = vec![Vec::new(); 3];
+            let mut r2_outs = vec![Vec::new(); 3];
+            let opts = Options {
+                shard_block_size: block_size,
+                ..Options::default()
+            };
+            process_shards_with_progress(
+                [(r1, r2)],
+                &mut r1_outs,
+                &mut r2_outs,
+                None,
+                &config,
+                &opts,
+                |_| Ok(()),
+            )
+            .unwrap();
+            let num_reads = |out: &Vec<u8>| out.iter().filter(|b| **b == b'\n').count() / 4;
+            (
+                r1_outs.iter().map(num_reads).collect::<Vec<_>>(),
+                r2_outs.iter().map(num_reads).collect::<Vec<_>>(),
+            )
+        };
+        assert_eq!(process(1), (vec![66, 66, 66], vec![66, 66, 66]));
+        // 198 reads are 20 blocks of 10 (the last block holding 8 reads)
+        assert_eq!(process(10).0, [70, 68, 60]);
+    }
+
+    #[test]
+    fn max_held_bytes() {
+        let config = Config::from_file(CONFIG_PATH, false, false).unwrap();
+        let process = |limit| {
+            let r1 = input::open(R1_PATH).unwrap().reader;
+            let r2 = input::open(R2_PATH).unwrap().reader;
+            let opts = Options {
+                max_held_bytes: Some(limit),
+                ..Options::default()
+            };
+            process_pair(r1, r2, &mut Vec::new(), &mut Vec::new(), &config, &opts)
+        };
+        assert!(process(1 << 20).is_ok());
+        let err = process(1024).unwrap_err();
+        assert_eq!(crate::error::exit_code(&err), crate::error::EXIT_USAGE);
+
+        // the molecules of an exact deduplication count without a whitelist
+        let process = |dedup| {
+            let r1 = input::open(R1_PATH).unwrap().reader;
+            let r2 = input::open(R2_PATH).unwrap().reader;
+            let opts = Options {
+                max_held_bytes: Some(1024),
+                whitelist: WhitelistMode::Skip,
+                dedup,
+                ..Options::default()
+            };
+            process_pair(r1, r2, &mut Vec::new(), &mut Vec::new(), &config, &opts)
+        };
+        assert!(process(None).is_ok());
+        assert!(process(Some(DedupOptions {
+            mode: DedupMode::Exact,
+            r2_prefix: 0,
+            capacity: 0,
+        }))
+        .is_err());
+    }
+
+    #[test]
+    fn split_inputs() {
+        let config = Config::from_file(CONFIG_PATH, false, false).unwrap();
+        let open = || {
+            (
+                input::open(R1_PATH).unwrap().reader,
+                input::open(R2_PATH).unwrap().reader,
+            )
+        };
+        let mut r1_outs = vec![Vec::new(); 4];
+        let mut r2_outs = vec![Vec::new(); 4];
+        let opts = Options {
+            split_inputs: true,
+            ..Options::default()
+        };
+        let (statistics, _) = process_shards_with_progress(
+            [open(), open()],
+            &mut r1_outs,
+            &mut r2_outs,
+            None,
+            &config,
+            &opts,
+            |_| Ok(()),
+        )
+        .unwrap();
+        assert_eq!(statistics.passing_reads, 396);
+        // the shared whitelist spans both input pairs
+        assert_eq!(statistics.whitelist.values().sum::<usize>(), 396);
+        let num_reads = |out: &Vec<u8>| out.iter().filter(|b| **b == b'\n').count() / 4;
+        assert_eq!(
+            r2_outs.iter().map(num_reads).collect::<Vec<_>>(),
+            [99, 99, 99, 99]
+        );
+        // both (identical) input pairs are split the same way within their groups
+        assert_eq!(r1_outs[0][..], r1_outs[2][..]);
+        assert!(process_shards_with_progress(
+            [open(), open()],
+            &mut r1_outs[..3],
+            &mut r2_outs[..3],
+            None,
+            &config,
+            &opts,
+            |_| Ok(()),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn barcode_shards() {
+        let config = Config::from_file(CONFIG_PATH, false, false).unwrap();
+        let r1 = input::open(R1_PATH).unwrap().reader;
+        let r2 = input::open(R2_PATH).unwrap().reader;
+        let mut r1_outs = vec![Vec::new(); 4];
+        let mut r2_outs = vec![Vec::new(); 4];
+        let opts = Options {
+            shard_by: ShardMode::Barcode,
+            ..Options::default()
+        };
+        process_shards_with_progress(
+            [(r1, r2)],
+            &mut r1_outs,
+            &mut r2_outs,
+            None,
+            &config,
+            &opts,
+            |_| Ok(()),
+        )
+        .unwrap();
+        let barcodes = r1_outs
+            .iter()
+            .map(|out| {
+                out.split(|b| *b == b'\n')
+                    .skip(1)
+                    .step_by(4)
+                    .filter(|seq| !seq.is_empty())
+                    .map(|seq| seq[..seq.len() - opts.umi_len].to_vec())
+                    .collect::<hashbrown::HashSet<_>>()
+            })
+            .collect::<Vec<_>>();
+        // every barcode is written to a single shard
+        let all = barcodes.iter().flatten().collect::<hashbrown::HashSet<_>>();
+        assert_eq!(barcodes.iter().map(|b| b.len()).sum::<usize>(), all.len());
+        assert!(barcodes.iter().filter(|b| !b.is_empty()).count() > 1);
+    }
+
+    #[test]
+    fn tenx_whitelist() {
+        let config = Config::from_file(CONFIG_PATH, false, false).unwrap();
+        let r1 = input::open(R1_PATH).unwrap().reader;
+        let r2 = input::open(R2_PATH).unwrap().reader;
+        let whitelist = (0..1000u32)
+            .map(|i| format!("{:016b}\n", i).replace('0', "A").replace('1', "C"))
+            .collect::<String>();
+        let opts = Options {
+            tenx_whitelist: Some(Arc::new(
+                TenxWhitelist::from_buffer(whitelist.as_bytes()).unwrap(),
+            )),
+            ..Options::default()
+        };
+        // the 96^4 possible barcodes would fold onto the 1000 entries
+        let err =
+            process_pair(r1, r2, &mut Vec::new(), &mut Vec::new(), &config, &opts).unwrap_err();
+        assert_eq!(crate::error::exit_code(&err), crate::error::EXIT_USAGE);
+    }
+
+    #[test]
+    fn reads_per_cell() {
+        let config = Config::from_file(CONFIG_PATH, false, false).unwrap();
+        let process = |seed| {
+            let opts = Options {
+                reads_per_cell: Some(1),
+                seed,
+                ..Options::default()
+            };
+            let r1 = input::open(R1_PATH).unwrap().reader;
+            let r2 = input::open(R2_PATH).unwrap().reader;
+            let mut r1_out = Vec::new();
+            let statistics =
+                process_pair(r1, r2, &mut r1_out, &mut Vec::new(), &config, &opts).unwrap();
+            (statistics, r1_out)
+        };
+        let (statistics, r1_out) = process(downsample::DEFAULT_SEED);
+        let emitted = r1_out.iter().filter(|b| **b == b'\n').count() / 4;
+        assert_eq!(emitted, statistics.whitelist.len());
+        assert_eq!(
+            statistics.num_downsampled,
+            statistics.passing_reads - emitted
+        );
+        assert!(statistics.num_downsampled > 0);
+        assert_eq!(process(downsample::DEFAULT_SEED).1, r1_out);
+    }
+
+    #[test]
+    fn expected_whitelist() {
+        let config = Config::from_file(CONFIG_PATH, false, false).unwrap();
+        let process = |opts: &Options| {
+            let r1 = input::open(R1_PATH).unwrap().reader;
+            let r2 = input::open(R2_PATH).unwrap().reader;
+            process_pair(r1, r2, &mut Vec::new(), &mut Vec::new(), &config, opts).unwrap()
+        };
+        let baseline = process(&Options::default());
+        assert_eq!(baseline.fraction_expected, None);
+
+        // expect only the barcode with the most reads
+        let (top_key, top_reads) = baseline
+            .whitelist
+            .iter()
+            .max_by_key(|(_, reads)| **reads)
+            .unwrap();
+        let [b1_idx, b2_idx, b3_idx, b4_idx] = top_key.indices();
+        let top = config.build_barcode(b1_idx, b2_idx, b3_idx, b4_idx);
+        let expected = ExpectedWhitelist::from_buffer(top.as_slice(), top.len()).unwrap();
+        let mut opts = Options {
+            expected_whitelist: Some(Arc::new(expected)),
+            ..Options::default()
+        };
+        let checked = process(&opts);
+        assert_eq!(checked.passing_reads, baseline.passing_reads);
+        assert_eq!(checked.num_expected, *top_reads);
+        assert_eq!(checked.num_unexpected, baseline.passing_reads - *top_reads);
+        assert_eq!(
+            checked.fraction_expected,
+            Some(*top_reads as f64 / baseline.passing_reads as f64)
+        );
+        assert_eq!(
+            checked.fraction_unexpected,
+            Some(1.0 - checked.fraction_expected.unwrap())
+        );
+
+        opts.flag_unexpected = true;
+        let mut r2_out = Vec::new();
+        let r1 = input::open(R1_PATH).unwrap().reader;
+        let r2 = input::open(R2_PATH).unwrap().reader;
+        let flagged = process_pair(r1, r2, &mut Vec::new(), &mut r2_out, &config, &opts).unwrap();
+        let r2_out = String::from_utf8(r2_out).unwrap();
+        let num_flagged = r2_out
+            .lines()
+            .step_by(4)
+            .filter(|name| name.ends_with(" unexpected"))
+            .count();
+        assert_eq!(num_flagged, flagged.num_unexpected);
+
+        // BAM read names cannot carry the flag so it is tagged instead
+        opts.output_format = OutputFormat::DropseqBam;
+        let mut r2_out = Vec::new();
+        let r1 = input::open(R1_PATH).unwrap().reader;
+        let r2 = input::open(R2_PATH).unwrap().reader;
+        process_pair(r1, r2, &mut Vec::new(), &mut r2_out, &config, &opts).unwrap();
+        let num_tagged = r2_out.windows(5).filter(|w| w == b"XUZ1\0").count();
+        assert_eq!(num_tagged, flagged.num_unexpected);
+        opts.output_format = OutputFormat::Fastq;
+        opts.flag_unexpected = false;
+
+        opts.reject_unexpected = true;
+        let rejected = process(&opts);
+        assert_eq!(rejected.passing_reads, *top_reads);
+        assert_eq!(rejected.whitelist.len(), 1);
+        // the rejected reads were matched, so they still weigh on the sanity checks
+        assert!(rejected.num_matched > rejected.passing_reads);
+        let warnings = crate::warnings::sanity_check(&rejected, opts.offset, None);
+        assert!(warnings.iter().all(|w| !w.contains("matched barcode")));
+    }
+
+    #[test]
+    fn feature_counts() {
+        let config = Config::from_file(CONFIG_PATH, false, false).unwrap();
+        // use the leading bases of a few R2s as feature barcodes
+        let reference = input::open(R2_PATH)
+            .unwrap()
+            .reader
+            .map(Result::unwrap)
+            .filter_map(|r| r.seq().get(..15).map(|s| s.to_vec()))
+            .filter(|s| !s.contains(&b'N'))
+            .collect::<hashbrown::HashSet<_>>()
+            .into_iter()
+            .take(20)
+            .enumerate()
+            .map(|(i, s)| format!("F{},F{},{}\n", i, i, std::str::from_utf8(&s).unwrap()))
+            .collect::<String>();
+        let reference = FeatureReference::from_buffer(reference.as_bytes(), 0, 1).unwrap();
+        let opts = Options {
+            features: Some(Arc::new(reference)),
+            ..Options::default()
+        };
+        let r1 = input::open(R1_PATH).unwrap().reader;
+        let r2 = input::open(R2_PATH).unwrap().reader;
+        let statistics =
+            process_pair(r1, r2, &mut Vec::new(), &mut Vec::new(), &config, &opts).unwrap();
+        assert!(statistics.num_feature_matched > 0);
+        assert_eq!(
+            statistics.num_feature_matched + statistics.num_feature_unmatched,
+            statistics.passing_reads
+        );
+        let (barcodes, entries) = statistics.feature_counts.matrix();
+        assert!(!barcodes.is_empty());
+        let umis = entries.iter().map(|(_, _, umis)| umis).sum::<usize>();
+        assert!(umis > 0 && umis <= statistics.num_feature_matched);
+    }
+
+    #[test]
+    fn reused_names() {
+        let mut name = b"a much longer previous name".to_vec();
+        let opts = Options {
+            name_format: NameFormat::Umitools,
+            ..Options::default()
+        };
+        build_name(&mut name, b"read1 1:N", Some(b"7"), b"ACGT", b"TT", &opts);
+        assert_eq!(name, b"7_ACGT_TT 1:N");
+        let opts = Options {
+            strip_comments: true,
+            ..Options::default()
+        };
+        build_name(&mut name, b"read1 1:N", None, b"ACGT", b"TT", &opts);
+        assert_eq!(name, b"read1");
+    }
+
+    #[test]
+    fn umitools_names() {
+        assert_eq!(
+            NameFormat::Umitools.format(b"read1 1:N:0:TAAGGCGA", b"ACGT", b"TTAA"),
+            b"read1_ACGT_TTAA 1:N:0:TAAGGCGA".as_slice()
+        );
+        assert_eq!(
+            NameFormat::Umitools.format(b"read1", b"ACGT", b"TTAA"),
+            b"read1_ACGT_TTAA".as_slice()
+        );
+        assert_eq!(
+            NameFormat::Umitools.format(b"read1 1:N", b"ACGT", b""),
+            b"read1_ACGT 1:N".as_slice()
+        );
+        assert_eq!(
+            NameFormat::Original.format(b"read1 1:N", b"ACGT", b"TTAA"),
+            b"read1 1:N".as_slice()
+        );
+
+        let config = Config::from_file(CONFIG_PATH, false, false).unwrap();
+        let r1 = input::open(R1_PATH).unwrap().reader;
+        let r2 = input::open(R2_PATH).unwrap().reader;
+        let opts = Options {
+            name_format: NameFormat::Umitools,
+            ..Options::default()
+        };
+        let mut r1_out = Vec::new();
+        let mut r2_out = Vec::new();
+        process_pair(r1, r2, &mut r1_out, &mut r2_out, &config, &opts).unwrap();
+        let r1_lines = r1_out.split(|b| *b == b'\n').collect::<Vec<_>>();
+        let r2_lines = r2_out.split(|b| *b == b'\n').collect::<Vec<_>>();
+        let name = r1_lines[0].split(|b| *b == b' ').next().unwrap();
+        let construct = r1_lines[1];
+        let expected = [b"_", &construct[..28], b"_", &construct[28..]].concat();
+        assert!(name.ends_with(&expected));
+        assert_eq!(name, r2_lines[0].split(|b| *b == b' ').next().unwrap());
+    }
+
+    #[test]
+    fn no_umi() {
+        let config = Config::from_file(CONFIG_PATH, false, false).unwrap();
+        let r1 = input::open(R1_PATH).unwrap().reader;
+        let r2 = input::open(R2_PATH).unwrap().reader;
+        let opts = Options {
+            umi_len: 0,
+            name_format: NameFormat::Umitools,
+            ..Options::default()
+        };
+        let mut r1_out = Vec::new();
+        let statistics =
+            process_pair(r1, r2, &mut r1_out, &mut Vec::new(), &config, &opts).unwrap();
+        assert_eq!(statistics.passing_reads, 198);
+        assert_eq!(statistics.whitelist_size, 189);
+        // the UMI statistics are skipped rather than computed over empty UMIs
+        assert_eq!(statistics.umi_composition.len(), 0);
+        assert_eq!(statistics.num_duplicates, None);
+        assert_eq!(statistics.estimated_duplicates, None);
+        assert_eq!(statistics.estimated_molecules, 0);
+
+        // R1 carries the barcode alone and the names end with it
+        let r1_lines = r1_out.split(|b| *b == b'\n').collect::<Vec<_>>();
+        for record in r1_lines.chunks(4).filter(|record| record.len() == 4) {
+            assert_eq!(record[1].len(), 28);
+            assert_eq!(record[3].len(), 28);
+            let name = record[0].split(|b| *b == b' ').next().unwrap();
+            assert!(name.ends_with(&[b"_", record[1]].concat()));
+        }
+    }
+
+    #[test]
+    fn fasta_input() {
+        let config = Config::from_file(CONFIG_PATH, false, false).unwrap();
+        let fasta = |path| {
+            input::open(path).unwrap().reader.map(|r| {
+                let r = r?;
+                Record::new_fasta_from_parts(r.id(), r.seq())
+            })
+        };
+        let err = process_pair(
+            fasta(R1_PATH),
+            fasta(R2_PATH),
+            &mut Vec::new(),
+            &mut Vec::new(),
+            &config,
+            &Options::default(),
+        )
+        .unwrap_err();
+        assert_eq!(crate::error::exit_code(&err), crate::error::EXIT_INPUT);
+    }
+
+    #[test]
+    fn truncated_qualities() {
+        let config = Config::from_file(CONFIG_PATH, false, false).unwrap();
+        let truncated = input::open(R1_PATH).unwrap().reader.map(|r| {
+            let r = r?;
+            let qual = r.qual().unwrap();
+            let fastq = [
+                b"@".as_slice(),
+                r.id(),
+                b"\n",
+                r.seq(),
+                b"\n+\n",
+                &qual[..qual.len() / 2],
+                b"\n",
+            ]
+            .concat();
+            Ok(fxread::FastqReader::new(fastq.as_slice()).next().unwrap())
+        });
+        let err = process_pair(
+            truncated,
+            input::open(R2_PATH).unwrap().reader,
+            &mut Vec::new(),
+            &mut Vec::new(),
+            &config,
+            &Options::default(),
+        )
+        .unwrap_err();
+        assert_eq!(crate::error::exit_code(&err), crate::error::EXIT_INPUT);
+    }
+
+    #[test]
+    fn early_abort() {
+        let config = Config::from_file(CONFIG_PATH, false, false).unwrap();
+        let unmatched = |_| {
+            let seq = vec![b'A'; 60];
+            let qual = vec![b'I'; 60];
+            Ok(Record::new_fastq_from_parts(b"read", &seq, &qual).unwrap())
+        };
+        let err = process_pair(
+            (0..EARLY_ABORT_READS + 1).map(unmatched),
+            (0..EARLY_ABORT_READS + 1).map(unmatched),
+            &mut Vec::new(),
+            &mut Vec::new(),
+            &config,
+            &Options::default(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().starts_with("No reads matched the barcodes"));
+        assert_eq!(crate::error::exit_code(&err), crate::error::EXIT_CONFIG);
+    }
+
+    #[test]
+    fn strip_comments() {
+        let config = Config::from_file(CONFIG_PATH, false, false).unwrap();
+        let r1 = input::open(R1_PATH).unwrap().reader;
+        let r2 = input::open(R2_PATH).unwrap().reader;
+        let opts = Options {
+            strip_comments: true,
+            name_format: NameFormat::Umitools,
+            ..Options::default()
+        };
+        let mut r1_out = Vec::new();
+        let mut r2_out = Vec::new();
+        let statistics = process_pair(r1, r2, &mut r1_out, &mut r2_out, &config, &opts).unwrap();
+        let names = |out: &[u8]| {
+            out.split(|b| *b == b'\n')
+                .step_by(4)
+                .filter(|line| !line.is_empty())
+                .map(|line| line.to_vec())
+                .collect::<Vec<_>>()
+        };
+        let (r1_names, r2_names) = (names(&r1_out), names(&r2_out));
+        assert_eq!(r1_names.len(), statistics.passing_reads);
+        assert_eq!(r1_names, r2_names);
+        assert!(r1_names.iter().all(|name| !name.contains(&b' ')));
+
+        // a sample index folded into the description is kept
+        let opts = Options {
+            tag_index: true,
+            ..opts
+        };
+        let mut name = Vec::new();
+        build_name(
+            &mut name,
+            b"read1 1:N:0:ACGT+TTGG",
+            None,
+            b"AA",
+            b"CC",
+            &opts,
+        );
+        assert_eq!(name, b"read1_AA_CC BC:Z:ACGT+TTGG");
+    }
+
+    #[test]
+    fn barcode_table() {
+        let config = Config::from_file(CONFIG_PATH, false, false).unwrap();
+        let r1 = input::open(R1_PATH).unwrap().reader;
+        let r2 = input::open(R2_PATH).unwrap().reader;
+        let opts = Options {
+            barcode_table: true,
+            ..Options::default()
+        };
+        let mut table = Vec::new();
+        let mut r2_out = Vec::new();
+        let statistics = process_pair(r1, r2, &mut table, &mut r2_out, &config, &opts).unwrap();
+        let table = String::from_utf8(table).unwrap();
+        let r2_lines = String::from_utf8(r2_out).unwrap();
+        assert_eq!(table.lines().count(), statistics.passing_reads);
+        // each row names the R2 record it annotates
+        for (row, r2_name) in table.lines().zip(r2_lines.lines().step_by(4)) {
+            let fields = row.split('\t').collect::<Vec<_>>();
+            assert_eq!(fields.len(), 3);
+            assert_eq!(Some(fields[0]), r2_name[1..].split(' ').next());
+            assert_eq!((fields[1].len(), fields[2].len()), (28, 12));
+        }
+    }
+
+    #[test]
+    fn dedup_before_statistics() {
+        let config = Config::from_file(CONFIG_PATH, false, false).unwrap();
+        let process = |num_lanes| {
+            let pairs = (0..num_lanes).map(|_| {
+                (
+                    input::open(R1_PATH).unwrap().reader,
+                    input::open(R2_PATH).unwrap().reader,
+                )
+            });
+            let opts = Options {
+                cell_summary: true,
+                dedup: Some(DedupOptions::default()),
+                ..Options::default()
+            };
+            process_pairs_with_progress(
+                pairs,
+                &mut Vec::new(),
+                &mut Vec::new(),
+                None,
+                &config,
+                &opts,
+                |_| Ok(()),
+            )
+            .unwrap()
+            .0
+        };
+        // a second lane of duplicates leaves the statistics of the emitted reads unchanged
+        let once = process(1);
+        let twice = process(2);
+        assert_eq!(
+            twice.num_deduplicated,
+            once.num_deduplicated + once.passing_reads
+        );
+        assert_eq!(twice.passing_reads, once.passing_reads);
+        assert_eq!(twice.whitelist, once.whitelist);
+        assert_eq!(twice.num_cell_umis, once.num_cell_umis);
+    }
+
+    #[test]
+    fn rename_reads() {
+        let config = Config::from_file(CONFIG_PATH, false, false).unwrap();
+        // the second lane only holds duplicates, which are dropped without a serial ID
+        let pairs = (0..2).map(|_| {
+            (
+                input::open(R1_PATH).unwrap().reader,
+                input::open(R2_PATH).unwrap().reader,
+            )
+        });
+        let opts = Options {
+            rename_reads: true,
+            dedup: Some(DedupOptions::default()),
+            ..Options::default()
+        };
+        let mut r2_out = Vec::new();
+        let mut names_out = Vec::new();
+        let (statistics, _) = process_pairs_with_progress(
+            pairs,
+            &mut Vec::new(),
+            &mut r2_out,
+            Some(&mut names_out),
+            &config,
+            &opts,
+            |_| Ok(()),
+        )
+        .unwrap();
+        let r2_lines = r2_out.split(|b| *b == b'\n').collect::<Vec<_>>();
+        assert!(r2_lines[0].starts_with(b"@1 2:N:0:"));
+        assert!(r2_lines[4].starts_with(b"@2 2:N:0:"));
+        let names = String::from_utf8(names_out).unwrap();
+        // duplicates are not counted as passing
+        let num_emitted = statistics.passing_reads;
+        assert!(statistics.num_deduplicated > 0);
+        assert_eq!(names.lines().count(), num_emitted);
+        assert!(names
+            .lines()
+            .last()
+            .unwrap()
+            .starts_with(&format!("{}\t", num_emitted)));
+        assert!(names.starts_with("1\tA01831:50:HCLHTDRX3:1:2101:"));
+    }
+
+    #[test]
+    fn dropseq_bam() {
+        let config = Config::from_file(CONFIG_PATH, false, false).unwrap();
+        let r1 = input::open(R1_PATH).unwrap().reader;
+        let r2 = input::open(R2_PATH).unwrap().reader;
+        let opts = Options {
+            output_format: OutputFormat::DropseqBam,
+            ..Options::default()
+        };
+        let mut r1_out = Vec::new();
+        let mut r2_out = Vec::new();
+        let statistics = process_pair(r1, r2, &mut r1_out, &mut r2_out, &config, &opts).unwrap();
+        assert!(r1_out.is_empty());
+        assert!(r2_out.starts_with(b"BAM\x01"));
+
+        // walk the records after the header
+        let l_text = u32::from_le_bytes(r2_out[4..8].try_into().unwrap()) as usize;
+        let mut pos = 12 + l_text;
+        let mut num_records = 0;
+        while pos < r2_out.len() {
+            let block_size = u32::from_le_bytes(r2_out[pos..pos + 4].try_into().unwrap());
+            let record = &r2_out[pos + 4..pos + 4 + block_size as usize];
+            let tags = &record[record.len() - 48..];
+            assert!(tags.starts_with(b"XCZ"));
+            assert_eq!(&tags[32..35], b"XMZ");
+            pos += 4 + block_size as usize;
+            num_records += 1;
+        }
+        assert_eq!(num_records, statistics.passing_reads);
+    }
+
+    #[test]
+    fn construct_qual() {
+        let r1_records = |linkers: bool| {
+            let config = Config::from_file(CONFIG_PATH, false, linkers).unwrap();
+            let r1 = input::open(R1_PATH).unwrap().reader;
+            let r2 = input::open(R2_PATH).unwrap().reader;
+            let mut r1_out = Vec::new();
+            process_pair(
+                r1,
+                r2,
+                &mut r1_out,
+                &mut Vec::new(),
+                &config,
+                &Options::default(),
+            )
+            .unwrap();
+            let lines = r1_out
+                .split(|b| *b == b'\n')
+                .map(|line| line.to_vec())
+                .collect::<Vec<_>>();
+            (config, lines)
+        };
+        let (config, with_linkers) = r1_records(true);
+        let (_, without_linkers) = r1_records(false);
+        assert_eq!(with_linkers.len(), without_linkers.len());
+        for (on, off) in with_linkers.chunks(4).zip(without_linkers.chunks(4)) {
+            let [_, seq, _, qual] = on else { continue };
+            // stripping the linkers drops exactly their bases and qualities
+            let (mut expected_seq, mut expected_qual) = (Vec::new(), Vec::new());
+            let mut pos = 0;
+            for tier in 0..4 {
+                let len = config.tier_len(tier);
+                expected_seq.extend_from_slice(&seq[pos..pos + len]);
+                expected_qual.extend_from_slice(&qual[pos..pos + len]);
+                pos += len + config.tier_linker(tier).len();
+            }
+            expected_seq.extend_from_slice(&seq[pos..]);
+            expected_qual.extend_from_slice(&qual[pos..]);
+            assert_eq!(off[1], expected_seq);
+            assert_eq!(off[3], expected_qual);
+        }
+    }
+
+    #[test]
+    fn constant_barcode_qual() {
+        let config = Config::from_file(CONFIG_PATH, false, false).unwrap();
+        let r1 = input::open(R1_PATH).unwrap().reader;
+        let r2 = input::open(R2_PATH).unwrap().reader;
+        let mut r1_out = Vec::new();
+        let opts = Options {
+            barcode_qual: "~".parse().unwrap(),
+            ..Options::default()
+        };
+        process_pair(r1, r2, &mut r1_out, &mut Vec::new(), &config, &opts).unwrap();
+        let qual = r1_out.split(|b| *b == b'\n').nth(3).unwrap();
+        assert_eq!(qual.len(), 40);
+        assert!(qual[..28].iter().all(|q| *q == b'~'));
+    }
+
+    #[test]
+    fn parse_barcode_qual() {
+        assert_eq!("keep".parse::<BarcodeQual>(), Ok(BarcodeQual::Keep));
+        assert_eq!("I".parse::<BarcodeQual>(), Ok(BarcodeQual::Constant(b'I')));
+        assert!("II".parse::<BarcodeQual>().is_err());
+        assert!(" ".parse::<BarcodeQual>().is_err());
+        assert_eq!(BarcodeQual::Constant(b'~').to_string(), "~");
+    }
+
+    #[test]
+    fn multiple_pairs() {
+        let config = Config::from_file(CONFIG_PATH, false, false).unwrap();
+        let pairs = (0..2).map(|_| {
+            (
+                input::open(R1_PATH).unwrap().reader,
+                input::open(R2_PATH).unwrap().reader,
+            )
+        });
+        let (statistics, pair_statistics) = process_pairs_with_progress(
+            pairs,
+            &mut Vec::new(),
+            &mut Vec::new(),
+            None,
+            &config,
+            &Options::default(),
+            |_| Ok(()),
+        )
+        .unwrap();
+        assert_eq!(statistics.total_reads, 500);
+        assert_eq!(statistics.passing_reads, 396);
+        assert_eq!(statistics.whitelist_size, 189);
+        assert_eq!(pair_statistics.len(), 2);
+        assert_eq!(pair_statistics[0], pair_statistics[1]);
+        assert_eq!(pair_statistics[1].total_reads, 250);
+        assert_eq!(pair_statistics[1].passing_reads, 198);
+        assert_eq!(pair_statistics[1].num_filtered_1, 41);
+    }
+
+    #[test]
+    fn auto_offset() {
+        let config = Config::from_file(CONFIG_PATH, false, false).unwrap();
+        let reads = input::open(R1_PATH)
+            .unwrap()
+            .reader
+            .collect::<Result<Vec<_>>>();
+        let reads = reads.unwrap();
+        let offset = estimate_offset(reads.iter().map(|r| r.seq()), &config, 12).unwrap();
+        assert!(offset <= AUTO_OFFSET_WINDOW);
+
+        // shifting every read by two bases shifts the estimate accordingly
+        let shifted = reads
+            .iter()
+            .map(|r| [b"AC", r.seq()].concat())
+            .collect::<Vec<_>>();
+        let shifted_offset =
+            estimate_offset(shifted.iter().map(|s| s.as_slice()), &config, 12).unwrap();
+        assert_eq!(shifted_offset, offset + 2);
+
+        assert_eq!(estimate_offset(std::iter::empty(), &config, 12), None);
+    }
+}
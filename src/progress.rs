@@ -0,0 +1,362 @@
+use anyhow::{bail, Result};
+use indicatif::ProgressBar;
+use pipspeak::log::Statistics;
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyModifiers},
+    layout::{Constraint, Layout},
+    widgets::{Block, Gauge, Row, Table},
+    DefaultTerminal, Frame,
+};
+use serde::Serialize;
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    time::{Duration, Instant},
+};
+
+/// The minimum time between redraws of the TUI
+const TUI_REFRESH: Duration = Duration::from_millis(250);
+
+/// Reports the progress of a run while reads are being processed
+pub struct Progress {
+    display: Display,
+    stream: Option<JsonStream>,
+}
+impl Progress {
+    /// Starts a spinner on an existing progress bar
+    pub fn spinner(pb: ProgressBar) -> Self {
+        pb.enable_steady_tick(Duration::from_millis(100));
+        Self {
+            display: Display::Spinner(pb),
+            stream: None,
+        }
+    }
+
+    /// Takes over the terminal with the TUI dashboard
+    pub fn tui(title: String) -> Self {
+        Self {
+            display: Display::Tui(Tui::new(title)),
+            stream: None,
+        }
+    }
+
+    /// Additionally emits periodic JSON snapshots of the statistics
+    pub fn with_stream(mut self, stream: JsonStream) -> Self {
+        self.stream = Some(stream);
+        self
+    }
+
+    /// Reports the current statistics of the run.
+    ///
+    /// The derived metrics are refreshed whenever a JSON snapshot is due.
+    pub fn update(&mut self, statistics: &mut Statistics) -> Result<()> {
+        if let Some(stream) = self.stream.as_mut().filter(|s| s.is_due()) {
+            statistics.calculate_metrics();
+            stream.write(statistics, false)?;
+        }
+        self.display.update(statistics)
+    }
+
+    /// Reports the final statistics of the run
+    pub fn finish(&mut self, statistics: &Statistics) -> Result<()> {
+        if let Some(stream) = self.stream.as_mut() {
+            stream.write(statistics, true)?;
+        }
+        self.display.finish(statistics);
+        Ok(())
+    }
+
+    /// Clears the progress report when a run is aborted
+    pub fn abandon(&mut self) {
+        self.display.abandon()
+    }
+}
+
+/// The interactive display of the progress of a run
+enum Display {
+    /// A single spinner line reporting the number of processed reads
+    Spinner(ProgressBar),
+    /// An interactive terminal dashboard
+    Tui(Tui),
+}
+impl Display {
+    fn update(&mut self, statistics: &Statistics) -> Result<()> {
+        match self {
+            Self::Spinner(pb) => {
+                pb.set_message(format!("Processed {} reads", statistics.total_reads));
+                Ok(())
+            }
+            Self::Tui(tui) => tui.update(statistics),
+        }
+    }
+
+    fn finish(&mut self, statistics: &Statistics) {
+        match self {
+            Self::Spinner(pb) => pb.finish_with_message(format!(
+                "Processed {} reads, {} passed filters ({:.4}%)",
+                statistics.total_reads,
+                statistics.passing_reads,
+                statistics.fraction_passing * 100.0
+            )),
+            Self::Tui(tui) => tui.restore(),
+        }
+    }
+
+    fn abandon(&mut self) {
+        match self {
+            Self::Spinner(pb) => pb.finish_and_clear(),
+            Self::Tui(tui) => tui.restore(),
+        }
+    }
+}
+
+/// A single line of the JSON progress stream
+#[derive(Serialize)]
+struct Snapshot<'a> {
+    sample: &'a str,
+    elapsed_time: f64,
+    finished: bool,
+    statistics: &'a Statistics,
+}
+
+/// Periodically writes the cumulative statistics as JSON lines
+pub struct JsonStream {
+    writer: Box<dyn Write + Send>,
+    sample: String,
+    interval: Duration,
+    start: Instant,
+    last_write: Instant,
+}
+impl JsonStream {
+    /// Streams to stderr if the path is `-` and otherwise appends to the file at the path
+    pub fn new(path: &str, sample: String, interval: Duration) -> Result<Self> {
+        let writer: Box<dyn Write + Send> = if path == "-" {
+            Box::new(std::io::stderr())
+        } else {
+            Box::new(OpenOptions::new().create(true).append(true).open(path)?)
+        };
+        Ok(Self::from_writer(writer, sample, interval))
+    }
+
+    fn from_writer(writer: Box<dyn Write + Send>, sample: String, interval: Duration) -> Self {
+        let start = Instant::now();
+        Self {
+            writer,
+            sample,
+            interval,
+            start,
+            last_write: start,
+        }
+    }
+
+    fn is_due(&self) -> bool {
+        self.last_write.elapsed() >= self.interval
+    }
+
+    fn write(&mut self, statistics: &Statistics, finished: bool) -> Result<()> {
+        let snapshot = Snapshot {
+            sample: &self.sample,
+            elapsed_time: self.start.elapsed().as_secs_f64(),
+            finished,
+            statistics,
+        };
+        // lines are written whole so that concurrent samples can share a file
+        let mut line = serde_json::to_vec(&snapshot)?;
+        line.push(b'\n');
+        self.writer.write_all(&line)?;
+        self.writer.flush()?;
+        self.last_write = Instant::now();
+        Ok(())
+    }
+}
+
+/// A live dashboard of the pass rate, filter rates, whitelist size and throughput
+pub struct Tui {
+    terminal: Option<DefaultTerminal>,
+    title: String,
+    start: Instant,
+    last_draw: Option<Instant>,
+}
+impl Tui {
+    fn new(title: String) -> Self {
+        Self {
+            terminal: Some(ratatui::init()),
+            title,
+            start: Instant::now(),
+            last_draw: None,
+        }
+    }
+
+    fn update(&mut self, statistics: &Statistics) -> Result<()> {
+        if self.last_draw.is_some_and(|t| t.elapsed() < TUI_REFRESH) {
+            return Ok(());
+        }
+        // the terminal is in raw mode so interrupts arrive as key events
+        while event::poll(Duration::ZERO)? {
+            if let Event::Key(key) = event::read()? {
+                let interrupt =
+                    key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+                if interrupt || key.code == KeyCode::Char('q') {
+                    self.restore();
+                    bail!("Interrupted by user");
+                }
+            }
+        }
+        if let Some(terminal) = self.terminal.as_mut() {
+            let elapsed = self.start.elapsed();
+            terminal.draw(|frame| render(frame, &self.title, statistics, elapsed))?;
+        }
+        self.last_draw = Some(Instant::now());
+        Ok(())
+    }
+
+    fn restore(&mut self) {
+        if self.terminal.take().is_some() {
+            ratatui::restore();
+        }
+    }
+}
+impl Drop for Tui {
+    fn drop(&mut self) {
+        self.restore();
+    }
+}
+
+/// Formats a count alongside its percentage of a total
+fn with_rate(count: usize, total: usize) -> String {
+    format!(
+        "{} ({:.2}%)",
+        count,
+        count as f64 / total.max(1) as f64 * 100.0
+    )
+}
+
+/// Draws the dashboard for the current statistics
+fn render(frame: &mut Frame, title: &str, statistics: &Statistics, elapsed: Duration) {
+    let total = statistics.total_reads;
+    let pass_rate = statistics.passing_reads as f64 / total.max(1) as f64;
+    let throughput = total as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+    let rows = [
+        ("Processed reads", total.to_string()),
+        ("Throughput", format!("{:.0} reads/s", throughput)),
+        ("Elapsed", format!("{:.1}s", elapsed.as_secs_f64())),
+        ("Passing reads", with_rate(statistics.passing_reads, total)),
+        ("Whitelist size", statistics.whitelist.len().to_string()),
+        (
+            "Filtered (barcode 1)",
+            with_rate(statistics.num_filtered_1, total),
+        ),
+        (
+            "Filtered (barcode 2)",
+            with_rate(statistics.num_filtered_2, total),
+        ),
+        (
+            "Filtered (barcode 3)",
+            with_rate(statistics.num_filtered_3, total),
+        ),
+        (
+            "Filtered (barcode 4)",
+            with_rate(statistics.num_filtered_4, total),
+        ),
+        (
+            "Filtered (fixed)",
+            with_rate(statistics.num_filtered_fixed, total),
+        ),
+        (
+            "Filtered (UMI)",
+            with_rate(statistics.num_filtered_umi, total),
+        ),
+        (
+            "Filtered (UMI homopolymer)",
+            with_rate(statistics.num_filtered_umi_homopolymer, total),
+        ),
+        (
+            "Filtered (UMI entropy)",
+            with_rate(statistics.num_filtered_umi_entropy, total),
+        ),
+        (
+            "Filtered (R2 length)",
+            with_rate(statistics.num_filtered_r2_len, total),
+        ),
+    ]
+    .map(|(name, value)| Row::new([name.to_string(), value]));
+
+    let [gauge_area, table_area] =
+        Layout::vertical([Constraint::Length(3), Constraint::Min(0)]).areas(frame.area());
+    let gauge = Gauge::default()
+        .block(Block::bordered().title("Pass rate"))
+        .ratio(pass_rate.clamp(0.0, 1.0))
+        .label(format!("{:.2}%", pass_rate * 100.0));
+    let table = Table::new(rows, [Constraint::Length(28), Constraint::Min(0)])
+        .block(Block::bordered().title(format!(" pipspeak: {} (q to quit) ", title)));
+    frame.render_widget(gauge, gauge_area);
+    frame.render_widget(table, table_area);
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use ratatui::{backend::TestBackend, Terminal};
+
+    #[test]
+    fn render_dashboard() {
+        let mut statistics = Statistics::new();
+        statistics.total_reads = 200;
+        statistics.passing_reads = 150;
+        statistics.num_filtered_1 = 50;
+        let mut terminal = Terminal::new(TestBackend::new(60, 20)).unwrap();
+        terminal
+            .draw(|frame| render(frame, "sample", &statistics, Duration::from_secs(2)))
+            .unwrap();
+        let content = terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect::<String>();
+        assert!(content.contains("pipspeak: sample"));
+        assert!(content.contains("75.00%"));
+        assert!(content.contains("100 reads/s"));
+        assert!(content.contains("50 (25.00%)"));
+    }
+
+    /// A writer whose contents can be inspected after being boxed
+    #[derive(Clone, Default)]
+    struct SharedBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_stream() {
+        let buffer = SharedBuffer::default();
+        let stream = JsonStream::from_writer(
+            Box::new(buffer.clone()),
+            "sample".to_string(),
+            Duration::ZERO,
+        );
+        let mut progress = Progress::spinner(ProgressBar::hidden()).with_stream(stream);
+        let mut statistics = Statistics::new();
+        statistics.total_reads = 4;
+        statistics.passing_reads = 2;
+        progress.update(&mut statistics).unwrap();
+        progress.finish(&statistics).unwrap();
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines = output
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["sample"], "sample");
+        assert_eq!(lines[0]["finished"], false);
+        assert_eq!(lines[0]["statistics"]["fraction_passing"], 0.5);
+        assert_eq!(lines[1]["finished"], true);
+    }
+}
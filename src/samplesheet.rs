@@ -0,0 +1,154 @@
+use anyhow::{bail, Result};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+};
+
+/// A single sample to process
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sample {
+    /// The name of the sample
+    pub name: Option<String>,
+    /// The input files for R1 (one per lane)
+    pub r1: Vec<String>,
+    /// The input files for R2 (one per lane)
+    pub r2: Vec<String>,
+    /// The index read files for I1 (one per lane, if any)
+    pub i1: Vec<String>,
+    /// The index read files for I2 (one per lane, if any)
+    pub i2: Vec<String>,
+    /// The output file prefix
+    pub prefix: String,
+    /// The type of the library for a library of a multi-modal run
+    pub library_type: Option<String>,
+    /// A feature reference used instead of `--features`
+    pub features: Option<String>,
+}
+
+/// Reads a samplesheet from a file
+pub fn from_file(path: &str) -> Result<Vec<Sample>> {
+    let reader = File::open(path).map(BufReader::new)?;
+    from_buffer(reader)
+}
+
+/// Reads a samplesheet of `sample, R1, R2[, prefix]` rows (tab or comma separated).
+///
+/// If the prefix is omitted the sample name is used as the prefix.
+/// Rows sharing a sample name are merged as lanes of a single sample.
+/// An optional header line starting with `sample` is skipped, as are blank lines.
+pub fn from_buffer<R: BufRead>(reader: R) -> Result<Vec<Sample>> {
+    let mut samples = Vec::new();
+    for (line_num, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields = line
+            .trim()
+            .split(['\t', ','])
+            .map(|f| f.trim())
+            .collect::<Vec<_>>();
+        if line_num == 0 && fields[0].eq_ignore_ascii_case("sample") {
+            continue;
+        }
+        let (name, r1, r2, prefix) = match fields.as_slice() {
+            [name, r1, r2] => (*name, *r1, *r2, *name),
+            [name, r1, r2, prefix] => (*name, *r1, *r2, *prefix),
+            _ => bail!(
+                "Malformed samplesheet line {}: expected `sample, R1, R2[, prefix]`",
+                line_num + 1
+            ),
+        };
+        if name.is_empty() || r1.is_empty() || r2.is_empty() || prefix.is_empty() {
+            bail!("Malformed samplesheet line {}: empty field", line_num + 1);
+        }
+        match samples
+            .iter_mut()
+            .find(|s: &&mut Sample| s.name.as_deref() == Some(name))
+        {
+            Some(sample) if sample.prefix != prefix => bail!(
+                "Samplesheet line {}: lanes of sample {} have different prefixes",
+                line_num + 1,
+                name
+            ),
+            Some(sample) => {
+                sample.r1.push(r1.to_string());
+                sample.r2.push(r2.to_string());
+            }
+            None => samples.push(Sample {
+                name: Some(name.to_string()),
+                r1: vec![r1.to_string()],
+                r2: vec![r2.to_string()],
+                i1: Vec::new(),
+                i2: Vec::new(),
+                prefix: prefix.to_string(),
+                library_type: None,
+                features: None,
+            }),
+        }
+    }
+    if samples.is_empty() {
+        bail!("Samplesheet contains no samples");
+    }
+    let mut prefixes = samples.iter().map(|s| &s.prefix).collect::<Vec<_>>();
+    prefixes.sort_unstable();
+    if let Some(w) = prefixes.windows(2).find(|w| w[0] == w[1]) {
+        bail!("Samplesheet contains duplicate output prefix: {}", w[0]);
+    }
+    Ok(samples)
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn parse_tsv() {
+        let buffer = b"sample\tr1\tr2\tprefix\nA\ta_R1.fq.gz\ta_R2.fq.gz\tout/a\nB\tb_R1.fq.gz\tb_R2.fq.gz\n";
+        let samples = from_buffer(&buffer[..]).unwrap();
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].name.as_deref(), Some("A"));
+        assert_eq!(samples[0].r1, vec!["a_R1.fq.gz"]);
+        assert_eq!(samples[0].r2, vec!["a_R2.fq.gz"]);
+        assert_eq!(samples[0].prefix, "out/a");
+        assert_eq!(samples[1].prefix, "B");
+    }
+
+    #[test]
+    fn parse_csv() {
+        let buffer = b"A,a_R1.fq.gz,a_R2.fq.gz\n\nB, b_R1.fq.gz, b_R2.fq.gz\n";
+        let samples = from_buffer(&buffer[..]).unwrap();
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[1].r1, vec!["b_R1.fq.gz"]);
+    }
+
+    #[test]
+    fn parse_lanes() {
+        let buffer = b"A	a_L1_R1.fq.gz	a_L1_R2.fq.gz
+B	b_R1.fq.gz	b_R2.fq.gz
+A	a_L2_R1.fq.gz	a_L2_R2.fq.gz
+";
+        let samples = from_buffer(&buffer[..]).unwrap();
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].r1, vec!["a_L1_R1.fq.gz", "a_L2_R1.fq.gz"]);
+        assert_eq!(samples[0].r2, vec!["a_L1_R2.fq.gz", "a_L2_R2.fq.gz"]);
+
+        let buffer = b"A	a_L1_R1.fq.gz	a_L1_R2.fq.gz	x
+A	a_L2_R1.fq.gz	a_L2_R2.fq.gz	y
+";
+        assert!(from_buffer(&buffer[..]).is_err());
+    }
+
+    #[test]
+    fn parse_malformed() {
+        assert!(from_buffer(&b"A\ta_R1.fq.gz\n"[..]).is_err());
+        assert!(from_buffer(&b"A\ta_R1.fq.gz\t\n"[..]).is_err());
+        assert!(from_buffer(&b"sample\tr1\tr2\n"[..]).is_err());
+    }
+
+    #[test]
+    fn parse_duplicate_prefix() {
+        let buffer = b"A\ta_R1.fq.gz\ta_R2.fq.gz\tout\nB\tb_R1.fq.gz\tb_R2.fq.gz\tout\n";
+        assert!(from_buffer(&buffer[..]).is_err());
+    }
+}
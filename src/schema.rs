@@ -0,0 +1,451 @@
+use crate::{
+    barcodes::Barcodes,
+    chip::Chip,
+    structure::{Element, ReadStructure},
+};
+use anyhow::{bail, Result};
+use serde_yaml::{Mapping, Value};
+use std::{
+    fmt,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+/// The keys accepted at the top level of the config
+const TOP_LEVEL_KEYS: [&str; 7] = [
+    "barcodes",
+    "spacers",
+    "structure",
+    "libraries",
+    "expected_whitelist",
+    "chip",
+    "capture_random_spacers",
+];
+
+/// The keys accepted for each entry of the `libraries` section of the config
+const LIBRARY_KEYS: [&str; 5] = ["name", "type", "r1", "r2", "features"];
+
+/// The keys accepted in the `barcodes` section of the config
+const BARCODE_KEYS: [&str; 4] = ["bc1", "bc2", "bc3", "bc4"];
+
+/// The maximum number of invalid sequences reported per barcode file
+const MAX_SEQUENCE_PROBLEMS: usize = 3;
+
+/// A single problem found in a config
+#[derive(Debug, PartialEq, Eq)]
+pub struct Problem {
+    /// The dotted path of the offending field
+    pub field: String,
+    /// The line of the config the field is defined on
+    pub line: Option<usize>,
+    /// A description of the problem
+    pub message: String,
+}
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{} (line {}): {}", self.field, line, self.message),
+            None => write!(f, "{}: {}", self.field, self.message),
+        }
+    }
+}
+
+/// Validates the contents of a config file and reports all problems found at once
+pub fn validate(path: &str, contents: &str) -> Result<()> {
+    let problems = find_problems(contents)?;
+    if problems.is_empty() {
+        return Ok(());
+    }
+    let report = problems
+        .iter()
+        .map(|p| format!("  - {}", p))
+        .collect::<Vec<_>>()
+        .join("\n");
+    bail!("Invalid config '{}':\n{}", path, report)
+}
+
+/// Collects every problem in the contents of a config file.
+///
+/// Only malformed YAML is reported as an error directly.
+pub fn find_problems(contents: &str) -> Result<Vec<Problem>> {
+    let yaml = serde_yaml::from_str::<Value>(contents)
+        .map_err(|e| anyhow::anyhow!("Config is not valid YAML: {}", e))?;
+    let mut problems = Vec::new();
+    let mut report = |fields: &[&str], message: String| {
+        problems.push(Problem {
+            field: fields.join("."),
+            line: find_line(contents, fields),
+            message,
+        })
+    };
+
+    let Some(root) = yaml.as_mapping() else {
+        report(&["config"], "expected a mapping of sections".to_string());
+        return Ok(problems);
+    };
+    for key in unexpected_keys(root, &TOP_LEVEL_KEYS) {
+        report(&[&key], "unexpected key".to_string());
+    }
+
+    match root.get("barcodes").map(|v| v.as_mapping()) {
+        None => report(&["barcodes"], "missing section".to_string()),
+        Some(None) => report(&["barcodes"], "expected a mapping of bc1-bc4".to_string()),
+        Some(Some(barcodes)) => {
+            for key in unexpected_keys(barcodes, &BARCODE_KEYS) {
+                report(&["barcodes", &key], "unexpected key".to_string());
+            }
+            for key in BARCODE_KEYS {
+                match barcodes.get(key) {
+                    None => report(&["barcodes", key], "missing barcode file".to_string()),
+                    Some(Value::String(path)) => {
+                        for message in check_barcode_file(path) {
+                            report(&["barcodes", key], message);
+                        }
+                    }
+                    Some(_) => report(&["barcodes", key], "expected a file path".to_string()),
+                }
+            }
+        }
+    }
+
+    match root.get("spacers") {
+        None => {}
+        Some(Value::Mapping(spacers)) => {
+            for (name, seq) in spacers {
+                let name = key_name(name);
+                match seq.as_str() {
+                    Some(seq) if seq.bytes().all(|b| b"ACGTN".contains(&b)) => {}
+                    Some(seq) => report(
+                        &["spacers", &name],
+                        format!("non-ACGTN character in spacer '{}'", seq),
+                    ),
+                    None => report(&["spacers", &name], "expected a sequence".to_string()),
+                }
+            }
+        }
+        Some(_) => report(
+            &["spacers"],
+            "expected a mapping of spacer names to sequences".to_string(),
+        ),
+    }
+
+    let structure = match root.get("structure") {
+        None => Some(ReadStructure::default()),
+        Some(Value::String(structure)) => match structure.parse::<ReadStructure>() {
+            Ok(structure) => Some(structure),
+            Err(e) => {
+                report(&["structure"], e.to_string());
+                None
+            }
+        },
+        Some(_) => {
+            report(
+                &["structure"],
+                "expected a read structure string".to_string(),
+            );
+            None
+        }
+    };
+    match root.get("libraries") {
+        None => {}
+        Some(Value::Sequence(libraries)) => {
+            let mut names = Vec::new();
+            for (idx, library) in libraries.iter().enumerate() {
+                let field = format!("libraries[{}]", idx);
+                let Some(library) = library.as_mapping() else {
+                    report(&["libraries"], format!("{}: expected a mapping", field));
+                    continue;
+                };
+                for key in unexpected_keys(library, &LIBRARY_KEYS) {
+                    report(
+                        &["libraries"],
+                        format!("{}: unexpected key '{}'", field, key),
+                    );
+                }
+                match library.get("name").and_then(|v| v.as_str()) {
+                    Some(name) if names.contains(&name) => report(
+                        &["libraries"],
+                        format!("{}: duplicate library name '{}'", field, name),
+                    ),
+                    Some(name) if !name.is_empty() => names.push(name),
+                    _ => report(&["libraries"], format!("{}: missing name", field)),
+                }
+                let inputs = ["r1", "r2"].map(|read| match library.get(read) {
+                    Some(Value::String(_)) => Some(1),
+                    Some(Value::Sequence(paths))
+                        if !paths.is_empty() && paths.iter().all(|p| p.is_string()) =>
+                    {
+                        Some(paths.len())
+                    }
+                    _ => {
+                        report(
+                            &["libraries"],
+                            format!("{}: expected a path or list of paths for {}", field, read),
+                        );
+                        None
+                    }
+                });
+                if let [Some(r1), Some(r2)] = inputs {
+                    if r1 != r2 {
+                        report(
+                            &["libraries"],
+                            format!(
+                                "{}: expected the same number of R1 and R2 inputs (found {} and {})",
+                                field, r1, r2
+                            ),
+                        );
+                    }
+                }
+            }
+        }
+        Some(_) => report(&["libraries"], "expected a list of libraries".to_string()),
+    }
+
+    match root.get("expected_whitelist") {
+        None => {}
+        Some(Value::String(path)) if !Path::new(path).is_file() => {
+            report(&["expected_whitelist"], format!("file not found: {}", path))
+        }
+        Some(Value::String(_)) => {}
+        Some(_) => report(&["expected_whitelist"], "expected a file path".to_string()),
+    }
+
+    match root.get("chip").map(|v| v.as_str().map(str::parse::<Chip>)) {
+        None | Some(Some(Ok(_))) => {}
+        Some(Some(Err(e))) => report(&["chip"], e),
+        Some(None) => report(&["chip"], "expected a chip scale".to_string()),
+    }
+
+    match root.get("capture_random_spacers") {
+        None | Some(Value::Bool(_)) => {}
+        Some(_) => report(
+            &["capture_random_spacers"],
+            "expected `true` or `false`".to_string(),
+        ),
+    }
+
+    if let Some(structure) = structure {
+        let spacers = root.get("spacers").and_then(|v| v.as_mapping());
+        for element in structure.elements() {
+            if let Element::Spacer(name) = element {
+                if !spacers.is_some_and(|s| s.contains_key(name.as_str())) {
+                    report(
+                        &["structure"],
+                        format!("spacer '{}' is not defined in `spacers`", name),
+                    );
+                }
+            }
+        }
+    }
+    Ok(problems)
+}
+
+/// Returns the keys of a mapping not in the set of expected keys
+fn unexpected_keys(mapping: &Mapping, expected: &[&str]) -> Vec<String> {
+    mapping
+        .keys()
+        .map(key_name)
+        .filter(|k| !expected.contains(&k.as_str()))
+        .collect()
+}
+
+/// Formats a mapping key for reporting
+fn key_name(key: &Value) -> String {
+    match key {
+        Value::String(s) => s.clone(),
+        other => serde_yaml::to_string(other)
+            .map(|s| s.trim().to_string())
+            .unwrap_or_default(),
+    }
+}
+
+/// Finds the (1-based) line a nested key is defined on by scanning for each key in turn
+fn find_line(contents: &str, fields: &[&str]) -> Option<usize> {
+    let lines = contents.lines().collect::<Vec<_>>();
+    let mut start = 0;
+    let mut found = None;
+    for field in fields {
+        let idx = lines[start..].iter().position(|line| {
+            let line = line.trim_start();
+            [format!("{}:", field), format!("\"{}\":", field)]
+                .iter()
+                .any(|k| line.starts_with(k.as_str()))
+        })?;
+        found = Some(start + idx);
+        start += idx + 1;
+    }
+    found.map(|idx| idx + 1)
+}
+
+/// Checks that a barcode file exists, is readable, and only contains nucleotide sequences
+fn check_barcode_file(path: &str) -> Vec<String> {
+    if !Path::new(path).exists() {
+        return vec![format!("file not found: {}", path)];
+    }
+    if !Path::new(path).is_file() {
+        return vec![format!("not a file: {}", path)];
+    }
+    let reader = match File::open(path) {
+        Ok(file) => BufReader::new(file),
+        Err(e) => return vec![format!("unreadable file {}: {}", path, e)],
+    };
+    let mut problems = Vec::new();
+    for (line_num, line) in reader.lines().enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                problems.push(format!("unreadable file {}: {}", path, e));
+                break;
+            }
+        };
+        let sequence = line.trim().split(['\t', ',']).next().unwrap_or_default();
+        // a header is only accepted on the first line
+        if Barcodes::is_nucleotide(sequence) || line_num == 0 {
+            continue;
+        }
+        if problems.len() == MAX_SEQUENCE_PROBLEMS {
+            problems.push(format!("{}: further invalid sequences omitted", path));
+            break;
+        }
+        problems.push(format!(
+            "{} line {}: invalid barcode sequence '{}'",
+            path,
+            line_num + 1,
+            sequence
+        ));
+    }
+    problems
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn valid_config() {
+        let contents = std::fs::read_to_string("data/config_v3.yaml").unwrap();
+        assert!(find_problems(&contents).unwrap().is_empty());
+        assert!(validate("data/config_v3.yaml", &contents).is_ok());
+    }
+
+    #[test]
+    fn reports_all_problems() {
+        let contents = "\
+barcodes:
+  bc1: \"data/barcodes_v3/fb_v3_bc1.tsv\"
+  bc2: \"data/barcodes_v3/missing.tsv\"
+  bc3: \"data/barcodes_v3/fb_v3_bc3.tsv\"
+  bc5: \"data/barcodes_v3/fb_v3_bc4.tsv\"
+spacers:
+  s1: \"ATX\"
+structure: \"bc1-s1-bc2-s9-bc3-bc4-umi\"
+extra: 1
+";
+        let problems = find_problems(contents).unwrap();
+        let summary = problems
+            .iter()
+            .map(|p| (p.field.as_str(), p.line))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                ("extra", Some(9)),
+                ("barcodes.bc5", Some(5)),
+                ("barcodes.bc2", Some(3)),
+                ("barcodes.bc4", None),
+                ("spacers.s1", Some(7)),
+                ("structure", Some(8)),
+            ]
+        );
+        let err = validate("config.yaml", contents).unwrap_err().to_string();
+        assert!(err.contains("barcodes.bc2 (line 3): file not found"));
+        assert!(err.contains("spacers.s1 (line 7): non-ACGTN character"));
+    }
+
+    #[test]
+    fn invalid_structure() {
+        let contents = std::fs::read_to_string("data/config_v3.yaml").unwrap()
+            + "structure: \"bc1-bc2-bc3-umi\"\n";
+        let problems = find_problems(&contents).unwrap();
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].field, "structure");
+    }
+
+    #[test]
+    fn expected_whitelist() {
+        let config = std::fs::read_to_string("data/config_v3.yaml").unwrap();
+        let contents = config.clone() + "expected_whitelist: data/barcodes_v3/fb_v3_bc1.tsv\n";
+        assert!(find_problems(&contents).unwrap().is_empty());
+        let contents = config + "expected_whitelist: missing.txt\n";
+        let problems = find_problems(&contents).unwrap();
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].field, "expected_whitelist");
+    }
+
+    #[test]
+    fn chip() {
+        let config = std::fs::read_to_string("data/config_v3.yaml").unwrap();
+        assert!(find_problems(&(config.clone() + "chip: T20\n"))
+            .unwrap()
+            .is_empty());
+        let problems = find_problems(&(config + "chip: T7\n")).unwrap();
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].field, "chip");
+    }
+
+    #[test]
+    fn random_spacers() {
+        let config = std::fs::read_to_string("data/config_v3.yaml").unwrap();
+        let contents = config.replace("s2: \"", "s2: \"NN") + "capture_random_spacers: true\n";
+        assert!(find_problems(&contents).unwrap().is_empty());
+        let problems = find_problems(&(config + "capture_random_spacers: 3\n")).unwrap();
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].field, "capture_random_spacers");
+    }
+
+    #[test]
+    fn invalid_libraries() {
+        let contents = std::fs::read_to_string("data/config_v3.yaml").unwrap()
+            + "libraries:
+  - name: gex
+    r1: gex_R1.fq.gz
+    r2: gex_R2.fq.gz
+  - name: gex
+    r1: [a_R1.fq.gz, b_R1.fq.gz]
+    r2: a_R2.fq.gz
+    lane: 1
+";
+        let problems = find_problems(&contents)
+            .unwrap()
+            .into_iter()
+            .map(|p| p.message)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            problems,
+            vec![
+                "libraries[1]: unexpected key 'lane'",
+                "libraries[1]: duplicate library name 'gex'",
+                "libraries[1]: expected the same number of R1 and R2 inputs (found 2 and 1)",
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_yaml() {
+        assert!(find_problems("barcodes: [").is_err());
+        let problems = find_problems("- a\n- b\n").unwrap();
+        assert_eq!(problems[0].field, "config");
+    }
+
+    #[test]
+    fn invalid_barcode_sequences() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bc.tsv");
+        std::fs::write(&path, "sequence\tname\nACGT\tA1\nAXGT\tA2\n\nACGT\tA4\n").unwrap();
+        let problems = check_barcode_file(path.to_str().unwrap());
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].contains("line 3: invalid barcode sequence 'AXGT'"));
+        assert!(problems[1].contains("line 4: invalid barcode sequence ''"));
+    }
+}
@@ -0,0 +1,317 @@
+use crate::config::Config;
+use anyhow::Result;
+use serde_yaml::{
+    value::{Tag, TaggedValue},
+    Mapping, Value,
+};
+use std::path::Path;
+
+/// The version of the seqspec specification the description follows
+const SEQSPEC_VERSION: &str = "0.3.0";
+
+/// The modality of the emitted reads
+const MODALITY: &str = "rna";
+
+/// The maximum length of the cDNA (which is not fixed) following the 10x convention
+const CDNA_MAX_LEN: usize = 98;
+
+/// Returns the final component of a path
+fn basename(path: &str) -> &str {
+    Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(path)
+}
+
+/// Builds a seqspec file (or onlist) entry for a local path
+fn file(tag: &str, id: &str, path: &str, filetype: &str) -> Value {
+    tagged(
+        tag,
+        vec![
+            ("file_id", id.into()),
+            ("filename", basename(path).into()),
+            ("filetype", filetype.into()),
+            ("filesize", 0.into()),
+            ("url", path.into()),
+            ("urltype", "local".into()),
+            ("md5", "".into()),
+        ],
+    )
+}
+
+/// Builds a tagged seqspec mapping (e.g. `!Region`) from its fields
+fn tagged(tag: &str, fields: Vec<(&str, Value)>) -> Value {
+    let mapping = fields
+        .into_iter()
+        .map(|(k, v)| (Value::from(k), v))
+        .collect::<Mapping>();
+    Value::Tagged(Box::new(TaggedValue {
+        tag: Tag::new(tag),
+        value: Value::Mapping(mapping),
+    }))
+}
+
+/// Builds a seqspec region
+fn region(
+    id: &str,
+    region_type: &str,
+    sequence_type: &str,
+    sequence: &str,
+    (min_len, max_len): (usize, usize),
+    onlist: Value,
+    regions: Value,
+) -> Value {
+    tagged(
+        "Region",
+        vec![
+            ("region_id", id.into()),
+            ("region_type", region_type.into()),
+            ("name", id.into()),
+            ("sequence_type", sequence_type.into()),
+            ("sequence", sequence.into()),
+            ("min_len", min_len.into()),
+            ("max_len", max_len.into()),
+            ("onlist", onlist),
+            ("regions", regions),
+        ],
+    )
+}
+
+/// Builds a seqspec read covering a length of the library
+fn read(id: &str, strand: &str, min_len: usize, max_len: usize, files: &[String]) -> Value {
+    tagged(
+        "Read",
+        vec![
+            ("read_id", id.into()),
+            ("name", id.into()),
+            ("modality", MODALITY.into()),
+            ("primer_id", id.into()),
+            ("min_len", min_len.into()),
+            ("max_len", max_len.into()),
+            ("strand", strand.into()),
+            (
+                "files",
+                files.iter().map(|f| file("File", f, f, "fastq")).collect(),
+            ),
+        ],
+    )
+}
+
+/// Describes the emitted reads as a seqspec assay.
+///
+/// R1 holds the barcode tiers (and linkers if they are emitted) followed by the UMI
+/// and R2 holds the cDNA. With a 10x whitelist (its path and barcode length) the tiers
+/// are replaced by a single barcode from that whitelist.
+pub fn describe(
+    config: &Config,
+    umi_len: usize,
+    sample: &str,
+    r1_files: &[String],
+    r2_files: &[String],
+    tenx_whitelist: Option<(&str, usize)>,
+) -> Value {
+    let mut regions = Vec::new();
+    let mut r1_len = 0;
+    if let Some((path, len)) = tenx_whitelist {
+        regions.push(region(
+            "barcode",
+            "barcode",
+            "onlist",
+            &"N".repeat(len),
+            (len, len),
+            file("Onlist", "barcode", path, "txt"),
+            Value::Null,
+        ));
+        r1_len += len;
+    }
+    for tier in (0..4).filter(|_| tenx_whitelist.is_none()) {
+        let len = config.tier_len(tier);
+        let onlist = file(
+            "Onlist",
+            &format!("bc{}", tier + 1),
+            config.tier_path(tier),
+            "tsv",
+        );
+        regions.push(region(
+            &format!("bc{}", tier + 1),
+            "barcode",
+            "onlist",
+            &"N".repeat(len),
+            (len, len),
+            onlist,
+            Value::Null,
+        ));
+        r1_len += len;
+        let linker = std::str::from_utf8(config.tier_linker(tier)).unwrap_or_default();
+        if config.linkers() && !linker.is_empty() {
+            regions.push(region(
+                &format!("linker{}", tier + 1),
+                "linker",
+                "fixed",
+                linker,
+                (linker.len(), linker.len()),
+                Value::Null,
+                Value::Null,
+            ));
+            r1_len += linker.len();
+        }
+    }
+    let umi_len = config.umi_len(umi_len);
+    if umi_len > 0 {
+        regions.push(region(
+            "umi",
+            "umi",
+            "random",
+            &"X".repeat(umi_len),
+            (umi_len, umi_len),
+            Value::Null,
+            Value::Null,
+        ));
+        r1_len += umi_len;
+    }
+    regions.push(region(
+        "cdna",
+        "cdna",
+        "random",
+        &"X".repeat(CDNA_MAX_LEN),
+        (1, CDNA_MAX_LEN),
+        Value::Null,
+        Value::Null,
+    ));
+    let library = region(
+        MODALITY,
+        MODALITY,
+        "joined",
+        "",
+        (r1_len + 1, r1_len + CDNA_MAX_LEN),
+        Value::Null,
+        Value::Sequence(regions),
+    );
+    tagged(
+        "Assay",
+        vec![
+            ("seqspec_version", SEQSPEC_VERSION.into()),
+            ("assay_id", format!("pipspeak-{}", basename(sample)).into()),
+            ("name", basename(sample).into()),
+            ("doi", "".into()),
+            (
+                "date",
+                chrono::Local::now().format("%d %B %Y").to_string().into(),
+            ),
+            (
+                "description",
+                format!(
+                    "PIPseq reads converted by pipspeak {} ({} linkers)",
+                    env!("CARGO_PKG_VERSION"),
+                    if config.linkers() { "with" } else { "without" }
+                )
+                .into(),
+            ),
+            ("modalities", vec![MODALITY].into()),
+            ("lib_struct", "".into()),
+            ("sequence_protocol", "Illumina".into()),
+            ("sequence_kit", "".into()),
+            ("library_protocol", "PIPseq".into()),
+            ("library_kit", "".into()),
+            (
+                "sequence_spec",
+                Value::Sequence(vec![
+                    read("R1", "pos", r1_len, r1_len, r1_files),
+                    read("R2", "neg", 1, CDNA_MAX_LEN, r2_files),
+                ]),
+            ),
+            ("library_spec", Value::Sequence(vec![library])),
+        ],
+    )
+}
+
+/// Writes the seqspec description of the emitted reads
+pub fn to_file(
+    path: &str,
+    config: &Config,
+    umi_len: usize,
+    sample: &str,
+    r1_files: &[String],
+    r2_files: &[String],
+    tenx_whitelist: Option<(&str, usize)>,
+) -> Result<()> {
+    let yaml = serde_yaml::to_string(&describe(
+        config,
+        umi_len,
+        sample,
+        r1_files,
+        r2_files,
+        tenx_whitelist,
+    ))?;
+    std::fs::write(path, yaml)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    fn region_ids(spec: &Value) -> Vec<String> {
+        let Value::Tagged(assay) = spec else {
+            panic!("Expected a tagged assay")
+        };
+        let Value::Tagged(library) = &assay.value["library_spec"][0] else {
+            panic!("Expected a tagged region")
+        };
+        library.value["regions"]
+            .as_sequence()
+            .unwrap()
+            .iter()
+            .map(|r| match r {
+                Value::Tagged(t) => t.value["region_id"].as_str().unwrap().to_string(),
+                _ => panic!("Expected a tagged region"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn describe_without_linkers() {
+        let config = Config::from_file("data/config_v3.yaml", false, false).unwrap();
+        let spec = describe(
+            &config,
+            12,
+            "out/sample",
+            &["out/a_R1.fq.gz".into()],
+            &[],
+            None,
+        );
+        assert_eq!(
+            region_ids(&spec),
+            vec!["bc1", "bc2", "bc3", "bc4", "umi", "cdna"]
+        );
+        let yaml = serde_yaml::to_string(&spec).unwrap();
+        assert!(yaml.starts_with("!Assay"));
+        assert!(yaml.contains("- !Read\n  read_id: R1"));
+        assert!(yaml.contains("name: sample\n"));
+        assert!(yaml.contains("filename: a_R1.fq.gz\n"));
+        assert!(yaml.contains("url: out/a_R1.fq.gz\n"));
+        assert!(yaml.contains("min_len: 40"));
+    }
+
+    #[test]
+    fn describe_tenx_whitelist() {
+        let config = Config::from_file("data/config_v3.yaml", false, false).unwrap();
+        let spec = describe(&config, 12, "sample", &[], &[], Some(("737K.txt", 16)));
+        assert_eq!(region_ids(&spec), vec!["barcode", "umi", "cdna"]);
+        let yaml = serde_yaml::to_string(&spec).unwrap();
+        assert!(yaml.contains("filename: 737K.txt\n"));
+        assert!(yaml.contains("min_len: 28"));
+    }
+
+    #[test]
+    fn describe_with_linkers() {
+        let config = Config::from_file("data/config_v3.yaml", false, true).unwrap();
+        let spec = describe(&config, 12, "sample", &[], &[], None);
+        assert_eq!(
+            region_ids(&spec),
+            vec!["bc1", "linker1", "bc2", "linker2", "bc3", "linker3", "bc4", "umi", "cdna"]
+        );
+        let yaml = serde_yaml::to_string(&spec).unwrap();
+        assert!(yaml.contains("min_len: 51"));
+    }
+}
@@ -0,0 +1,118 @@
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+
+/// The default number of consecutive read pairs written to a shard before the next
+pub const DEFAULT_SHARD_BLOCK_SIZE: usize = 1;
+
+/// How passing read pairs are assigned to shards
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ShardMode {
+    /// Round-robin blocks of consecutive read pairs
+    RoundRobin,
+    /// A hash of the cell barcode, so all reads of a cell share a shard
+    Barcode,
+}
+
+/// Returns the 64-bit FNV-1a hash of a barcode.
+///
+/// Unlike the hashers of the standard library it is stable across builds, so separate
+/// runs (e.g. of different lanes) place a cell in the same shard.
+pub fn barcode_hash(barcode: &[u8]) -> u64 {
+    barcode.iter().fold(0xcbf2_9ce4_8422_2325, |hash, base| {
+        (hash ^ *base as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Assigns passing read pairs to output shards, either in round-robin blocks of read
+/// pairs or by a hash of their barcode.
+///
+/// A block size of 1 alternates shards with every read pair, while larger blocks keep
+/// runs of consecutive read pairs together.
+#[derive(Debug, Clone)]
+pub struct Sharder {
+    mode: ShardMode,
+    num_shards: usize,
+    block_size: usize,
+    num_reads: usize,
+}
+impl Sharder {
+    pub fn new(mode: ShardMode, num_shards: usize, block_size: usize) -> Result<Self> {
+        if num_shards == 0 {
+            bail!("The number of shards must be positive");
+        }
+        if block_size == 0 {
+            bail!("The shard block size must be positive");
+        }
+        Ok(Self {
+            mode,
+            num_shards,
+            block_size,
+            num_reads: 0,
+        })
+    }
+
+    /// Returns the shard of the next read pair
+    pub fn next_shard(&mut self, barcode: &[u8]) -> usize {
+        match self.mode {
+            ShardMode::RoundRobin => {
+                let shard = (self.num_reads / self.block_size) % self.num_shards;
+                self.num_reads += 1;
+                shard
+            }
+            ShardMode::Barcode => (barcode_hash(barcode) % self.num_shards as u64) as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    fn assign(num_shards: usize, block_size: usize, num_reads: usize) -> Vec<usize> {
+        let mut sharder = Sharder::new(ShardMode::RoundRobin, num_shards, block_size).unwrap();
+        (0..num_reads)
+            .map(|_| sharder.next_shard(b"ACGT"))
+            .collect()
+    }
+
+    #[test]
+    fn round_robin() {
+        assert_eq!(assign(3, 1, 7), [0, 1, 2, 0, 1, 2, 0]);
+        assert_eq!(assign(1, 1, 3), [0, 0, 0]);
+    }
+
+    #[test]
+    fn blocks() {
+        assert_eq!(assign(2, 3, 8), [0, 0, 0, 1, 1, 1, 0, 0]);
+        assert!(Sharder::new(ShardMode::RoundRobin, 0, 1).is_err());
+        assert!(Sharder::new(ShardMode::RoundRobin, 2, 0).is_err());
+    }
+
+    #[test]
+    fn barcodes() {
+        let mut sharder = Sharder::new(ShardMode::Barcode, 4, 1).unwrap();
+        let barcodes = [b"AAAA", b"CCCC", b"GGGG", b"TTTT", b"ACGT"];
+        let first = barcodes.map(|barcode| sharder.next_shard(barcode));
+        assert_eq!(barcodes.map(|barcode| sharder.next_shard(barcode)), first);
+        assert!(first.iter().all(|shard| *shard < 4));
+        // the hash is stable across builds
+        assert_eq!(barcode_hash(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(barcode_hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn parse_mode() {
+        assert_eq!(
+            ShardMode::from_str("barcode", false),
+            Ok(ShardMode::Barcode)
+        );
+        assert_eq!(
+            ShardMode::RoundRobin
+                .to_possible_value()
+                .unwrap()
+                .get_name(),
+            "round-robin"
+        );
+        assert!(ShardMode::from_str("random", false).is_err());
+    }
+}
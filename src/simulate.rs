@@ -0,0 +1,411 @@
+use crate::{config::Config, downsample::SplitMix64};
+use anyhow::{bail, Result};
+use hashbrown::HashSet;
+use std::io::Write;
+
+const NUCLEOTIDES: &[u8; 4] = b"ACGT";
+
+/// The minimum length of a homopolymer whose indel rates are scaled by the
+/// homopolymer factor
+const HOMOPOLYMER_MIN_LEN: usize = 3;
+
+/// The sequencing errors introduced in simulated reads
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorModel {
+    /// The probability of a substitution at the first cycle
+    pub substitution_rate: f64,
+    /// The probability of a substitution at the last cycle (the rate is interpolated
+    /// linearly across the cycles in between)
+    pub final_substitution_rate: f64,
+    /// The probability of an insertion after each nucleotide
+    pub insertion_rate: f64,
+    /// The probability of each nucleotide being deleted
+    pub deletion_rate: f64,
+    /// The factor scaling the indel rates within homopolymers of at least 3 nucleotides
+    pub homopolymer_factor: f64,
+}
+impl ErrorModel {
+    /// A model of uniform substitutions only
+    pub fn substitutions(rate: f64) -> Self {
+        Self {
+            substitution_rate: rate,
+            final_substitution_rate: rate,
+            insertion_rate: 0.0,
+            deletion_rate: 0.0,
+            homopolymer_factor: 1.0,
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        for (name, rate) in [
+            ("substitution", self.substitution_rate),
+            ("final substitution", self.final_substitution_rate),
+            ("insertion", self.insertion_rate),
+            ("deletion", self.deletion_rate),
+        ] {
+            if !(0.0..=1.0).contains(&rate) {
+                bail!("Expected a {} rate within [0, 1], found {}", name, rate);
+            }
+        }
+        if self.homopolymer_factor.is_nan() || self.homopolymer_factor < 0.0 {
+            bail!(
+                "Expected a non-negative homopolymer factor, found {}",
+                self.homopolymer_factor
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the substitution rate of a cycle of a read of `len` cycles
+    pub fn substitution_rate(&self, cycle: usize, len: usize) -> f64 {
+        let fraction = cycle as f64 / len.saturating_sub(1).max(1) as f64;
+        self.substitution_rate
+            + (self.final_substitution_rate - self.substitution_rate) * fraction.min(1.0)
+    }
+
+    /// Returns the phred+33 quality of each cycle of a read of `len` cycles
+    pub fn qualities(&self, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|cycle| {
+                let rate = self.substitution_rate(cycle, len);
+                let phred = if rate > 0.0 {
+                    (-10.0 * rate.log10()).round().clamp(2.0, 41.0) as u8
+                } else {
+                    41
+                };
+                phred + 33
+            })
+            .collect()
+    }
+}
+
+/// The parameters of a simulated run
+#[derive(Debug, Clone)]
+pub struct SimulateOptions {
+    /// The number of cells (distinct barcodes)
+    pub num_cells: usize,
+    /// The number of read pairs
+    pub num_reads: usize,
+    /// The number of distinct UMIs (molecules) of each cell
+    pub umis_per_cell: usize,
+    /// The length of unsized UMI segments of the read structure
+    pub umi_len: usize,
+    /// The length of the R2 (cDNA) reads
+    pub r2_len: usize,
+    /// The maximum number of random nucleotides preceding the barcode construct
+    pub max_offset: usize,
+    /// The errors introduced in R1
+    pub errors: ErrorModel,
+    pub seed: u64,
+}
+
+/// The ground truth of a simulated read pair
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Truth {
+    /// The index of the cell of the read
+    pub cell: usize,
+    /// The barcode indices of each tier
+    pub indices: [usize; 4],
+    pub umi: Vec<u8>,
+    /// The number of random nucleotides preceding the barcode construct
+    pub offset: usize,
+    /// The number of substitutions introduced in R1
+    pub substitutions: usize,
+    /// The number of insertions introduced in R1
+    pub insertions: usize,
+    /// The number of deletions introduced in R1
+    pub deletions: usize,
+}
+
+/// A simulated read pair
+#[derive(Debug, Clone)]
+pub struct SimulatedPair {
+    pub name: String,
+    pub r1: Vec<u8>,
+    pub r2: Vec<u8>,
+    pub truth: Truth,
+}
+impl SimulatedPair {
+    /// Writes both reads as fastq records with the qualities of their cycles
+    pub fn write_fastq(
+        &self,
+        r1: &mut dyn Write,
+        r2: &mut dyn Write,
+        r1_qual: &[u8],
+        r2_qual: &[u8],
+    ) -> Result<()> {
+        self.write_record(r1, 1, &self.r1, r1_qual)?;
+        self.write_record(r2, 2, &self.r2, r2_qual)
+    }
+
+    fn write_record(
+        &self,
+        writer: &mut dyn Write,
+        read: usize,
+        seq: &[u8],
+        qual: &[u8],
+    ) -> Result<()> {
+        writeln!(writer, "@{} {}:N:0:1", self.name, read)?;
+        writer.write_all(seq)?;
+        writer.write_all(b"\n+\n")?;
+        writer.write_all(&qual[..seq.len()])?;
+        writer.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+/// Generates synthetic read pairs of a chemistry with known barcodes and UMIs.
+///
+/// Each cell draws a distinct barcode combination and a pool of random UMIs, and each read
+/// pair picks a cell and one of its UMIs uniformly. R1 is laid out following the read
+/// structure after up to `max_offset` random nucleotides, mutated following the error
+/// model and padded with a poly-T tail (or truncated) to a constant length. R2 is a random
+/// sequence.
+pub struct Simulator<'a> {
+    config: &'a Config,
+    opts: SimulateOptions,
+    rng: SplitMix64,
+    cells: Vec<([usize; 4], Vec<Vec<u8>>)>,
+    r1_len: usize,
+    num_reads: usize,
+}
+impl<'a> Simulator<'a> {
+    pub fn new(config: &'a Config, opts: SimulateOptions) -> Result<Self> {
+        let sizes = config.tier_sizes();
+        let combinations = sizes.iter().map(|n| *n as u128).product::<u128>();
+        if opts.num_cells as u128 > combinations {
+            bail!(
+                "Cannot simulate {} cells from {} barcode combinations",
+                opts.num_cells,
+                combinations
+            );
+        }
+        if opts.num_cells == 0 || opts.umis_per_cell == 0 {
+            bail!("Expected at least one cell and one UMI per cell");
+        }
+        opts.errors.validate()?;
+        let mut rng = SplitMix64(opts.seed);
+        let umi_len = config.umi_len(opts.umi_len);
+        let mut seen = HashSet::new();
+        let mut cells = Vec::with_capacity(opts.num_cells);
+        while cells.len() < opts.num_cells {
+            let indices = sizes.map(|n| rng.below(n));
+            if !seen.insert(indices) {
+                continue;
+            }
+            let umis = (0..opts.umis_per_cell)
+                .map(|_| random_seq(&mut rng, umi_len))
+                .collect();
+            cells.push((indices, umis));
+        }
+        let r1_len = opts.max_offset + config.build_read([0; 4], &[], opts.umi_len).len() + umi_len;
+        Ok(Self {
+            config,
+            opts,
+            rng,
+            cells,
+            r1_len,
+            num_reads: 0,
+        })
+    }
+
+    /// Returns the barcode indices of each cell
+    pub fn cells(&self) -> impl Iterator<Item = [usize; 4]> + '_ {
+        self.cells.iter().map(|(indices, _)| *indices)
+    }
+
+    /// Returns the qualities of the cycles of R1 and R2
+    pub fn qualities(&self) -> (Vec<u8>, Vec<u8>) {
+        (
+            self.opts.errors.qualities(self.r1_len),
+            self.opts.errors.qualities(self.opts.r2_len),
+        )
+    }
+
+    /// Introduces the errors of the model into a sequence.
+    /// Returns the number of substitutions, insertions and deletions
+    fn mutate(&mut self, seq: &[u8]) -> (Vec<u8>, [usize; 3]) {
+        let model = &self.opts.errors;
+        let mut mutated = Vec::with_capacity(seq.len());
+        let mut counts = [0; 3];
+        let mut run = 0;
+        for (idx, base) in seq.iter().enumerate() {
+            run = if idx > 0 && seq[idx - 1] == *base {
+                run + 1
+            } else {
+                1
+            };
+            let factor = if run >= HOMOPOLYMER_MIN_LEN {
+                model.homopolymer_factor
+            } else {
+                1.0
+            };
+            if self.rng.uniform() < model.deletion_rate * factor {
+                counts[2] += 1;
+                continue;
+            }
+            if self.rng.uniform() < model.substitution_rate(mutated.len(), self.r1_len) {
+                mutated.push(substitute(&mut self.rng, *base));
+                counts[0] += 1;
+            } else {
+                mutated.push(*base);
+            }
+            if self.rng.uniform() < model.insertion_rate * factor {
+                // homopolymers stutter, elsewhere any nucleotide is inserted
+                let inserted = if run >= HOMOPOLYMER_MIN_LEN {
+                    *base
+                } else {
+                    NUCLEOTIDES[self.rng.below(4)]
+                };
+                mutated.push(inserted);
+                counts[1] += 1;
+            }
+        }
+        (mutated, counts)
+    }
+
+    fn next_pair(&mut self) -> SimulatedPair {
+        let cell = self.rng.below(self.cells.len());
+        let (indices, umis) = &self.cells[cell];
+        let (indices, umi) = (*indices, umis[self.rng.below(umis.len())].clone());
+        let offset = self.rng.below(self.opts.max_offset + 1);
+
+        let mut r1 = random_seq(&mut self.rng, offset);
+        r1.extend(self.config.build_read(indices, &umi, self.opts.umi_len));
+        r1.resize(self.r1_len, b'T');
+        let (mut r1, [substitutions, insertions, deletions]) = self.mutate(&r1);
+        r1.resize(self.r1_len, b'T');
+        let r2 = random_seq(&mut self.rng, self.opts.r2_len);
+        self.num_reads += 1;
+        SimulatedPair {
+            name: format!("sim:{}", self.num_reads),
+            r1,
+            r2,
+            truth: Truth {
+                cell,
+                indices,
+                umi,
+                offset,
+                substitutions,
+                insertions,
+                deletions,
+            },
+        }
+    }
+}
+impl Iterator for Simulator<'_> {
+    type Item = SimulatedPair;
+    fn next(&mut self) -> Option<Self::Item> {
+        (self.num_reads < self.opts.num_reads).then(|| self.next_pair())
+    }
+}
+
+fn random_seq(rng: &mut SplitMix64, len: usize) -> Vec<u8> {
+    (0..len).map(|_| NUCLEOTIDES[rng.below(4)]).collect()
+}
+
+/// Replaces a nucleotide with one of the three others
+fn substitute(rng: &mut SplitMix64, base: u8) -> u8 {
+    let others = NUCLEOTIDES
+        .iter()
+        .filter(|n| **n != base)
+        .collect::<Vec<_>>();
+    *others[rng.below(others.len())]
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    fn options(errors: ErrorModel) -> SimulateOptions {
+        SimulateOptions {
+            num_cells: 20,
+            num_reads: 500,
+            umis_per_cell: 10,
+            umi_len: 12,
+            r2_len: 30,
+            max_offset: 3,
+            errors,
+            seed: 42,
+        }
+    }
+
+    /// Returns the fraction of reads whose barcode and UMI are recovered
+    fn recovery(config: &Config, pairs: &[SimulatedPair]) -> f64 {
+        let recovered = pairs
+            .iter()
+            .filter(|pair| {
+                config
+                    .match_read(&pair.r1, Some(3), 12)
+                    .is_ok_and(|m| m.indices == pair.truth.indices && m.umi == pair.truth.umi)
+            })
+            .count();
+        recovered as f64 / pairs.len() as f64
+    }
+
+    #[test]
+    fn ground_truth() {
+        let config = Config::from_file("data/config_v3.yaml", false, false).unwrap();
+        let simulator = Simulator::new(&config, options(ErrorModel::substitutions(0.0))).unwrap();
+        assert_eq!(simulator.cells().count(), 20);
+        let mut lengths = HashSet::new();
+        for pair in simulator {
+            lengths.insert(pair.r1.len());
+            assert_eq!(pair.r2.len(), 30);
+            assert_eq!(pair.truth.substitutions, 0);
+            let matched = config.match_read(&pair.r1, Some(3), 12).unwrap();
+            assert_eq!(matched.indices, pair.truth.indices);
+            assert_eq!(matched.umi, pair.truth.umi);
+        }
+        assert_eq!(lengths.len(), 1);
+    }
+
+    #[test]
+    fn errors() {
+        let config = Config::from_file("data/config_v3.yaml", false, false).unwrap();
+        let pairs = Simulator::new(&config, options(ErrorModel::substitutions(0.05)))
+            .unwrap()
+            .collect::<Vec<_>>();
+        let errors = pairs.iter().map(|p| p.truth.substitutions).sum::<usize>() as f64;
+        let bases = pairs.iter().map(|p| p.r1.len()).sum::<usize>() as f64;
+        assert!((0.04..0.06).contains(&(errors / bases)));
+
+        let seeded = Simulator::new(&config, options(ErrorModel::substitutions(0.05))).unwrap();
+        assert!(seeded.zip(&pairs).all(|(a, b)| a.r1 == b.r1));
+        assert!(Simulator::new(
+            &config,
+            SimulateOptions {
+                num_cells: 0,
+                ..options(ErrorModel::substitutions(0.0))
+            }
+        )
+        .is_err());
+        assert!(Simulator::new(&config, options(ErrorModel::substitutions(1.5))).is_err());
+    }
+
+    #[test]
+    fn error_models() {
+        let decay = ErrorModel {
+            final_substitution_rate: 0.1,
+            ..ErrorModel::substitutions(0.001)
+        };
+        assert_eq!(decay.qualities(3), vec![b'!' + 30, b'!' + 13, b'!' + 10]);
+
+        let config = Config::from_file("data/config_v3.yaml", false, false).unwrap();
+        let exact = Config::from_file("data/config_v3.yaml", true, false).unwrap();
+        let indels = ErrorModel {
+            insertion_rate: 0.01,
+            deletion_rate: 0.01,
+            homopolymer_factor: 5.0,
+            ..ErrorModel::substitutions(0.01)
+        };
+        let pairs = Simulator::new(&config, options(indels))
+            .unwrap()
+            .collect::<Vec<_>>();
+        assert!(pairs.iter().all(|p| p.r1.len() == pairs[0].r1.len()));
+        assert!(pairs.iter().any(|p| p.truth.insertions > 0));
+        assert!(pairs.iter().any(|p| p.truth.deletions > 0));
+        let (exact, mismatch) = (recovery(&exact, &pairs), recovery(&config, &pairs));
+        assert!(exact < mismatch && mismatch < 1.0);
+    }
+}
@@ -0,0 +1,119 @@
+use serde::{Deserialize, Serialize};
+use std::hash::{DefaultHasher, Hasher};
+
+/// The number of bits of the hash used to select a register (2^14 registers, ~0.8% error)
+const PRECISION: u32 = 14;
+
+/// The number of registers in the sketch
+const NUM_REGISTERS: usize = 1 << PRECISION;
+
+/// A HyperLogLog sketch estimating the number of distinct sequences in constant memory
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HyperLogLog {
+    registers: Vec<u8>,
+}
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        Self {
+            registers: vec![0; NUM_REGISTERS],
+        }
+    }
+}
+impl HyperLogLog {
+    /// Adds a sequence to the sketch
+    pub fn insert(&mut self, seq: &[u8]) {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(seq);
+        let hash = hasher.finish();
+        let idx = (hash >> (64 - PRECISION)) as usize;
+        // the sentinel bit bounds the rank when the remaining bits are all zero
+        let rest = (hash << PRECISION) | (1 << (PRECISION - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+        if rank > self.registers[idx] {
+            self.registers[idx] = rank;
+        }
+    }
+
+    /// Adds the sequences of another sketch, as if they had been inserted into this one
+    pub fn merge(&mut self, other: &Self) {
+        self.registers
+            .iter_mut()
+            .zip(&other.registers)
+            .for_each(|(r, o)| *r = (*r).max(*o));
+    }
+
+    /// Estimates the number of distinct sequences added to the sketch
+    pub fn estimate(&self) -> usize {
+        let m = NUM_REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum = self
+            .registers
+            .iter()
+            .map(|r| 2f64.powi(-(*r as i32)))
+            .sum::<f64>();
+        let raw = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|r| **r == 0).count();
+        if raw <= 2.5 * m && zeros > 0 {
+            // linear counting is more accurate for small cardinalities
+            (m * (m / zeros as f64).ln()).round() as usize
+        } else {
+            raw.round() as usize
+        }
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    /// Builds a distinct 16bp sequence for an integer
+    fn sequence(mut n: usize) -> Vec<u8> {
+        (0..16)
+            .map(|_| {
+                let base = b"ACGT"[n % 4];
+                n /= 4;
+                base
+            })
+            .collect()
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(HyperLogLog::default().estimate(), 0);
+    }
+
+    #[test]
+    fn small_cardinality() {
+        let mut sketch = HyperLogLog::default();
+        for n in 0..100 {
+            sketch.insert(&sequence(n));
+            sketch.insert(&sequence(n));
+        }
+        assert_eq!(sketch.estimate(), 100);
+    }
+
+    #[test]
+    fn large_cardinality() {
+        let mut sketch = HyperLogLog::default();
+        let num = 200_000;
+        for n in 0..num {
+            sketch.insert(&sequence(n));
+        }
+        let error = (sketch.estimate() as f64 - num as f64).abs() / num as f64;
+        assert!(error < 0.03, "relative error {}", error);
+    }
+
+    #[test]
+    fn merge() {
+        let (mut first, mut second) = (HyperLogLog::default(), HyperLogLog::default());
+        let mut union = HyperLogLog::default();
+        for n in 0..100 {
+            first.insert(&sequence(n));
+            second.insert(&sequence(n + 50));
+            union.insert(&sequence(n));
+            union.insert(&sequence(n + 50));
+        }
+        first.merge(&second);
+        assert_eq!(first.registers, union.registers);
+    }
+}
@@ -0,0 +1,224 @@
+use anyhow::{bail, Result};
+use std::{fmt, str::FromStr};
+
+/// The default PIPSeq v3 R1 read structure
+pub const DEFAULT_STRUCTURE: &str = "bc1-s1-bc2-s2-bc3-s3-bc4-umi";
+
+/// A single element of the R1 read structure
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Element {
+    /// A barcode tier (0-indexed, i.e. `bc1` is tier 0)
+    Barcode(usize),
+    /// A named spacer whose sequence is given in the config
+    Spacer(String),
+    /// A literal fixed sequence
+    Fixed(String),
+    /// The UMI with an optional explicit length (otherwise `--umi-len` is used)
+    Umi(Option<usize>),
+}
+impl Element {
+    fn parse(token: &str) -> Result<Self> {
+        if token.is_empty() {
+            bail!("Empty element in read structure");
+        }
+        if let Some(tier) = token.strip_prefix("bc") {
+            if let Ok(tier @ 1..=4) = tier.parse::<usize>() {
+                return Ok(Self::Barcode(tier - 1));
+            }
+            bail!("Invalid barcode element in read structure: {}", token);
+        }
+        if token == "umi" {
+            return Ok(Self::Umi(None));
+        }
+        if let Some(len) = token.strip_prefix("umi(").and_then(|t| t.strip_suffix(')')) {
+            return match len.parse::<usize>() {
+                Ok(len) => Ok(Self::Umi(Some(len))),
+                Err(_) => bail!("Invalid UMI length in read structure: {}", token),
+            };
+        }
+        if token.bytes().all(|c| c.is_ascii_uppercase()) {
+            if let Some(c) = token.bytes().find(|c| !b"ACGTN".contains(c)) {
+                bail!(
+                    "Invalid nucleotide '{}' in fixed sequence of read structure: {}",
+                    c as char,
+                    token
+                );
+            }
+            return Ok(Self::Fixed(token.to_string()));
+        }
+        if token
+            .bytes()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == b'_')
+        {
+            return Ok(Self::Spacer(token.to_string()));
+        }
+        bail!("Unrecognized element in read structure: {}", token)
+    }
+}
+impl fmt::Display for Element {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Barcode(tier) => write!(f, "bc{}", tier + 1),
+            Self::Spacer(name) => write!(f, "{}", name),
+            Self::Fixed(seq) => write!(f, "{}", seq),
+            Self::Umi(None) => write!(f, "umi"),
+            Self::Umi(Some(len)) => write!(f, "umi({})", len),
+        }
+    }
+}
+
+/// An explicit description of the R1 read layout
+///
+/// Elements are separated by `-`, e.g. `bc1-s1-bc2-s2-bc3-s3-bc4-umi(12)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadStructure {
+    elements: Vec<Element>,
+}
+impl ReadStructure {
+    pub fn elements(&self) -> &[Element] {
+        &self.elements
+    }
+
+    /// Checks that every barcode tier is present exactly once and that there is at least one
+    /// UMI segment, of which at most one takes its length from `--umi-len`
+    fn validate(&self) -> Result<()> {
+        for tier in 0..4 {
+            let count = self
+                .elements
+                .iter()
+                .filter(|e| **e == Element::Barcode(tier))
+                .count();
+            if count != 1 {
+                bail!(
+                    "Read structure must contain bc{} exactly once (found {})",
+                    tier + 1,
+                    count
+                );
+            }
+        }
+        let num_umi = self
+            .elements
+            .iter()
+            .filter(|e| matches!(e, Element::Umi(_)))
+            .count();
+        if num_umi == 0 {
+            bail!("Read structure must contain at least one UMI");
+        }
+        let num_unsized = self
+            .elements
+            .iter()
+            .filter(|e| **e == Element::Umi(None))
+            .count();
+        if num_unsized > 1 {
+            bail!(
+                "Read structure may contain at most one UMI without a length (found {}); use `umi(N)` for split UMIs",
+                num_unsized
+            );
+        }
+        Ok(())
+    }
+}
+impl Default for ReadStructure {
+    fn default() -> Self {
+        DEFAULT_STRUCTURE.parse().unwrap()
+    }
+}
+impl FromStr for ReadStructure {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let elements = s
+            .trim()
+            .split('-')
+            .map(|token| Element::parse(token.trim()))
+            .collect::<Result<Vec<_>>>()?;
+        let structure = Self { elements };
+        structure.validate()?;
+        Ok(structure)
+    }
+}
+impl fmt::Display for ReadStructure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (idx, element) in self.elements.iter().enumerate() {
+            if idx > 0 {
+                write!(f, "-")?;
+            }
+            write!(f, "{}", element)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn parse_default() {
+        let structure = ReadStructure::default();
+        assert_eq!(
+            structure.elements(),
+            &[
+                Element::Barcode(0),
+                Element::Spacer("s1".to_string()),
+                Element::Barcode(1),
+                Element::Spacer("s2".to_string()),
+                Element::Barcode(2),
+                Element::Spacer("s3".to_string()),
+                Element::Barcode(3),
+                Element::Umi(None),
+            ]
+        );
+        assert_eq!(structure.to_string(), DEFAULT_STRUCTURE);
+    }
+
+    #[test]
+    fn parse_umi_first() {
+        let structure: ReadStructure = "umi(8)-bc1-ACG-bc2-bc3-s3-bc4".parse().unwrap();
+        assert_eq!(structure.elements()[0], Element::Umi(Some(8)));
+        assert_eq!(structure.elements()[2], Element::Fixed("ACG".to_string()));
+        assert_eq!(structure.to_string(), "umi(8)-bc1-ACG-bc2-bc3-s3-bc4");
+    }
+
+    #[test]
+    fn parse_missing_tier() {
+        assert!("bc1-s1-bc2-s2-bc3-umi".parse::<ReadStructure>().is_err());
+    }
+
+    #[test]
+    fn parse_duplicate_tier() {
+        assert!("bc1-bc1-bc2-bc3-bc4-umi".parse::<ReadStructure>().is_err());
+    }
+
+    #[test]
+    fn parse_missing_umi() {
+        assert!("bc1-bc2-bc3-bc4".parse::<ReadStructure>().is_err());
+    }
+
+    #[test]
+    fn parse_split_umi() {
+        let structure: ReadStructure = "bc1-s1-bc2-s2-bc3-s3-umi(4)-bc4-umi".parse().unwrap();
+        assert_eq!(structure.elements()[6], Element::Umi(Some(4)));
+        assert_eq!(structure.elements()[8], Element::Umi(None));
+        assert_eq!(structure.to_string(), "bc1-s1-bc2-s2-bc3-s3-umi(4)-bc4-umi");
+        // only one UMI segment may take its length from `--umi-len`
+        assert!("umi-bc1-bc2-bc3-bc4-umi".parse::<ReadStructure>().is_err());
+    }
+
+    #[test]
+    fn parse_random_fixed() {
+        let structure: ReadStructure = "bc1-s1-bc2-s2-bc3-s3-bc4-NNN-umi".parse().unwrap();
+        assert_eq!(structure.elements()[7], Element::Fixed("NNN".to_string()));
+    }
+
+    #[test]
+    fn parse_invalid_elements() {
+        assert!("bc5-bc1-bc2-bc3-bc4-umi".parse::<ReadStructure>().is_err());
+        assert!("bc1-bc2-bc3-bc4-umi(x)".parse::<ReadStructure>().is_err());
+        assert!("bc1-ACGU-bc2-bc3-bc4-umi".parse::<ReadStructure>().is_err());
+        assert!("bc1--bc2-bc3-bc4-umi".parse::<ReadStructure>().is_err());
+        assert!("bc1-Spacer-bc2-bc3-bc4-umi"
+            .parse::<ReadStructure>()
+            .is_err());
+    }
+}
@@ -0,0 +1,213 @@
+use crate::{
+    barcodes::Barcodes,
+    config::{BarcodeKey, Config},
+    error::Error,
+};
+use anyhow::{bail, Result};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    sync::Arc,
+};
+
+/// The quality written for the bases of a translated barcode, which is a whitelist entry
+/// by construction
+pub const TENX_BARCODE_QUAL: u8 = b'F';
+
+/// A fixed 10x barcode whitelist (e.g. `737K-august-2016.txt`) whose entries are handed
+/// out to the emitted barcodes
+#[derive(Debug, Clone)]
+pub struct TenxWhitelist {
+    barcodes: Vec<Vec<u8>>,
+}
+impl TenxWhitelist {
+    /// Reads a (possibly compressed) 10x whitelist
+    pub fn from_file(path: &str) -> Result<Self> {
+        let (reader, _format) = niffler::from_path(path)?;
+        Self::from_buffer(BufReader::new(reader))
+    }
+
+    /// Reads a 10x whitelist with one barcode per line, all of the same length
+    pub fn from_buffer<R: BufRead>(reader: R) -> Result<Self> {
+        let mut barcodes: Vec<Vec<u8>> = Vec::new();
+        for (line_num, line) in reader.lines().enumerate() {
+            let line = line?;
+            let barcode = line.trim();
+            if barcode.is_empty() {
+                continue;
+            }
+            if !Barcodes::is_nucleotide(barcode) {
+                bail!(
+                    "10x whitelist line {} is not a barcode: {}",
+                    line_num + 1,
+                    barcode
+                );
+            }
+            if let Some(first) = barcodes.first() {
+                if first.len() != barcode.len() {
+                    bail!(
+                        "10x whitelist barcode on line {} is {}bp but the first barcode is {}bp",
+                        line_num + 1,
+                        barcode.len(),
+                        first.len()
+                    );
+                }
+            }
+            barcodes.push(barcode.to_ascii_uppercase().into_bytes());
+        }
+        if barcodes.is_empty() {
+            bail!("The 10x whitelist is empty");
+        }
+        Ok(Self { barcodes })
+    }
+
+    /// Returns the number of barcodes of the whitelist
+    pub fn len(&self) -> usize {
+        self.barcodes.len()
+    }
+
+    /// Returns true if the whitelist has no barcodes
+    pub fn is_empty(&self) -> bool {
+        self.barcodes.is_empty()
+    }
+
+    /// Returns the length of the barcodes of the whitelist
+    pub fn barcode_len(&self) -> usize {
+        self.barcodes[0].len()
+    }
+}
+
+/// Maps each barcode to an entry of a 10x whitelist derived from its barcode indices.
+///
+/// The indices are ranked in the mixed radix of the tier sizes and the rank is the index
+/// of the 10x barcode, so the mapping is independent of the order the reads are seen in
+/// (e.g. across lanes, reruns or the libraries of the same cells). Distinct barcodes
+/// always get distinct 10x barcodes: a chemistry with more possible barcodes than
+/// whitelist entries is refused.
+#[derive(Debug, Clone)]
+pub struct TenxTranslation {
+    whitelist: Arc<TenxWhitelist>,
+    tier_sizes: [usize; 4],
+}
+impl TenxTranslation {
+    pub fn new(whitelist: Arc<TenxWhitelist>, tier_sizes: [usize; 4]) -> Result<Self> {
+        let translation = Self {
+            whitelist,
+            tier_sizes,
+        };
+        if translation.folds() {
+            bail!(Error::Usage(
+                format!(
+                    "The 10x whitelist has {} barcodes but the config has {} possible barcodes",
+                    translation.whitelist.len(),
+                    tier_sizes
+                        .iter()
+                        .map(|size| (*size).max(1) as u128)
+                        .product::<u128>()
+                )
+                .into()
+            ));
+        }
+        Ok(translation)
+    }
+
+    /// Returns the whitelist entry of a barcode
+    fn entry(&self, key: BarcodeKey) -> usize {
+        key.indices()
+            .iter()
+            .zip(self.tier_sizes)
+            .fold(0, |rank, (idx, size)| rank * size.max(1) + idx)
+    }
+
+    /// Returns the 10x barcode of a barcode
+    pub fn translate(&self, key: BarcodeKey) -> &[u8] {
+        &self.whitelist.barcodes[self.entry(key)]
+    }
+
+    /// Returns true if distinct barcodes can share a 10x barcode
+    pub fn folds(&self) -> bool {
+        self.tier_sizes
+            .iter()
+            .try_fold(1usize, |total, size| total.checked_mul((*size).max(1)))
+            .is_none_or(|total| total > self.whitelist.len())
+    }
+
+    /// Writes each emitted barcode alongside its 10x barcode (sorted by the emitted
+    /// barcode)
+    pub fn to_writer<'a, I, W>(&self, keys: I, config: &Config, mut writer: W) -> Result<()>
+    where
+        I: IntoIterator<Item = &'a BarcodeKey>,
+        W: Write,
+    {
+        let mut translated = keys
+            .into_iter()
+            .map(|key| {
+                let [b1_idx, b2_idx, b3_idx, b4_idx] = key.indices();
+                (
+                    config.build_barcode(b1_idx, b2_idx, b3_idx, b4_idx),
+                    self.translate(*key),
+                )
+            })
+            .collect::<Vec<_>>();
+        translated.sort_unstable();
+        for (barcode, tenx_barcode) in translated {
+            writer.write_all(&barcode)?;
+            writer.write_all(b"\t")?;
+            writer.write_all(tenx_barcode)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Writes the translation of the given barcodes to a file
+    pub fn to_file<'a, I>(&self, keys: I, config: &Config, path: &str) -> Result<()>
+    where
+        I: IntoIterator<Item = &'a BarcodeKey>,
+    {
+        self.to_writer(keys, config, File::create(path).map(BufWriter::new)?)
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn translate() {
+        let whitelist = TenxWhitelist::from_buffer("AAAA\nCCCC\n\nGGGG\n".as_bytes()).unwrap();
+        assert_eq!((whitelist.len(), whitelist.barcode_len()), (3, 4));
+        let translation = TenxTranslation::new(Arc::new(whitelist), [2, 1, 1, 1]).unwrap();
+        assert!(!translation.folds());
+        let first = BarcodeKey::new([0, 0, 0, 0]);
+        let second = BarcodeKey::new([1, 0, 0, 0]);
+        // the mapping only depends on the barcode indices
+        assert_eq!(translation.translate(second), b"CCCC");
+        assert_eq!(translation.translate(first), b"AAAA");
+
+        let config = Config::from_file("data/config_v3.yaml", false, false).unwrap();
+        let mut out = Vec::new();
+        translation
+            .to_writer(&[second, first], &config, &mut out)
+            .unwrap();
+        let lines = String::from_utf8(out).unwrap();
+        let lines = lines.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0] < lines[1]);
+    }
+
+    #[test]
+    fn fold() {
+        let whitelist = TenxWhitelist::from_buffer("AAAA\nCCCC\nGGGG\n".as_bytes()).unwrap();
+        // more possible barcodes than whitelist entries would share 10x barcodes
+        let err = TenxTranslation::new(Arc::new(whitelist), [96, 96, 96, 96]).unwrap_err();
+        assert_eq!(crate::error::exit_code(&err), crate::error::EXIT_USAGE);
+    }
+
+    #[test]
+    fn invalid_whitelist() {
+        assert!(TenxWhitelist::from_buffer("AAAA\nCCC\n".as_bytes()).is_err());
+        assert!(TenxWhitelist::from_buffer("barcode\nAAAA\n".as_bytes()).is_err());
+        assert!(TenxWhitelist::from_buffer("\n".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,238 @@
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+
+/// The phred offset of the quality scores (Illumina 1.8+)
+const PHRED_OFFSET: i32 = 33;
+
+/// The default maximum fraction of mismatches in an adapter match
+pub const DEFAULT_ADAPTER_ERROR_RATE: f64 = 0.1;
+
+/// The default minimum overlap of an adapter with the 3' end of a read
+pub const DEFAULT_ADAPTER_MIN_OVERLAP: usize = 3;
+
+/// Finds the position to trim the 3' end of a read at using the BWA algorithm.
+///
+/// Scanning from the 3' end this accumulates `cutoff - qual` and returns the position
+/// which maximizes the sum, stopping once the sum becomes negative.
+/// Returns the length of the read to keep.
+pub fn quality_trim_index(qual: &[u8], cutoff: u8) -> usize {
+    let mut sum = 0;
+    let mut max_sum = 0;
+    let mut keep = qual.len();
+    for (idx, q) in qual.iter().enumerate().rev() {
+        sum += cutoff as i32 - (*q as i32 - PHRED_OFFSET);
+        if sum < 0 {
+            break;
+        }
+        if sum > max_sum {
+            max_sum = sum;
+            keep = idx;
+        }
+    }
+    keep
+}
+
+/// The number of reads trimmed by an adapter
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct AdapterHits {
+    pub name: String,
+    pub sequence: String,
+    pub reads: usize,
+}
+
+/// A set of 3' adapters matched with a mismatch tolerance
+#[derive(Debug, Clone)]
+pub struct Adapters {
+    names: Vec<String>,
+    sequences: Vec<Vec<u8>>,
+    max_error_rate: f64,
+    min_overlap: usize,
+}
+impl Adapters {
+    /// Reads adapters from a FASTA file or a plain list with one sequence per line
+    /// (plain sequences are named after themselves)
+    pub fn from_file(path: &str, max_error_rate: f64, min_overlap: usize) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read adapters: {}", path))?;
+        Self::parse(&contents, max_error_rate, min_overlap)
+            .with_context(|| format!("Failed to parse adapters: {}", path))
+    }
+
+    /// Parses adapters from the contents of a FASTA file or a plain list
+    pub fn parse(contents: &str, max_error_rate: f64, min_overlap: usize) -> Result<Self> {
+        let mut names = Vec::new();
+        let mut sequences: Vec<Vec<u8>> = Vec::new();
+        let mut in_record = false;
+        for line in contents.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            if let Some(name) = line.strip_prefix('>') {
+                names.push(
+                    name.split_whitespace()
+                        .next()
+                        .unwrap_or_default()
+                        .to_string(),
+                );
+                sequences.push(Vec::new());
+                in_record = true;
+            } else if in_record {
+                sequences
+                    .last_mut()
+                    .unwrap()
+                    .extend(line.bytes().map(|b| b.to_ascii_uppercase()));
+            } else {
+                names.push(line.to_string());
+                sequences.push(line.bytes().map(|b| b.to_ascii_uppercase()).collect());
+            }
+        }
+        if sequences.is_empty() {
+            bail!("No adapters found");
+        }
+        if let Some(idx) = sequences.iter().position(|s| s.is_empty()) {
+            bail!("Adapter '{}' has no sequence", names[idx]);
+        }
+        Ok(Self {
+            names,
+            sequences,
+            max_error_rate,
+            min_overlap: min_overlap.max(1),
+        })
+    }
+
+    /// Returns the number of adapters
+    pub fn len(&self) -> usize {
+        self.sequences.len()
+    }
+
+    /// Returns true if there are no adapters
+    pub fn is_empty(&self) -> bool {
+        self.sequences.is_empty()
+    }
+
+    /// Returns an empty hit count for each adapter
+    pub fn hits(&self) -> Vec<AdapterHits> {
+        self.names
+            .iter()
+            .zip(&self.sequences)
+            .map(|(name, seq)| AdapterHits {
+                name: name.clone(),
+                sequence: String::from_utf8_lossy(seq).to_string(),
+                reads: 0,
+            })
+            .collect()
+    }
+
+    /// Finds the position an adapter starts at (possibly running off the 3' end of the
+    /// read) with at most `max_error_rate` mismatches over an overlap of at least
+    /// `min_overlap` bases.
+    ///
+    /// As in cutadapt the match with the most matching bases is kept rather than the
+    /// leftmost one, so an earlier match with mismatches does not trim the cDNA bases
+    /// preceding the adapter. Equal matches (e.g. along a poly-A tail) keep the leftmost.
+    fn find(&self, adapter: &[u8], seq: &[u8]) -> Option<usize> {
+        let last = seq.len().checked_sub(self.min_overlap)?;
+        let mut best: Option<(usize, usize)> = None;
+        for start in 0..=last {
+            let overlap = adapter.len().min(seq.len() - start);
+            let max_mismatches = (overlap as f64 * self.max_error_rate) as usize;
+            let mismatches = seq[start..start + overlap]
+                .iter()
+                .zip(adapter)
+                .filter(|(a, b)| !a.eq_ignore_ascii_case(b))
+                .count();
+            let matches = overlap - mismatches;
+            if mismatches <= max_mismatches && best.is_none_or(|(most, _)| matches > most) {
+                best = Some((matches, start));
+            }
+        }
+        best.map(|(_, start)| start)
+    }
+
+    /// Finds the adapter trimming the most of the 3' end of a read.
+    /// Returns the index of the adapter and the length of the read to keep.
+    pub fn trim_index(&self, seq: &[u8]) -> Option<(usize, usize)> {
+        self.sequences
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, adapter)| self.find(adapter, seq).map(|keep| (idx, keep)))
+            .min_by_key(|(_, keep)| *keep)
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    /// Converts phred scores into a quality string
+    fn to_qual(scores: &[u8]) -> Vec<u8> {
+        scores.iter().map(|q| q + PHRED_OFFSET as u8).collect()
+    }
+
+    #[test]
+    fn no_trimming() {
+        let qual = to_qual(&[30, 30, 30, 30, 30]);
+        assert_eq!(quality_trim_index(&qual, 20), 5);
+    }
+
+    #[test]
+    fn trim_tail() {
+        let qual = to_qual(&[30, 30, 30, 10, 5]);
+        assert_eq!(quality_trim_index(&qual, 20), 3);
+    }
+
+    #[test]
+    fn trim_through_single_high_base() {
+        // the high quality base does not offset the low quality tail
+        let qual = to_qual(&[30, 30, 10, 25, 5, 2]);
+        assert_eq!(quality_trim_index(&qual, 20), 2);
+    }
+
+    #[test]
+    fn trim_everything() {
+        let qual = to_qual(&[2, 2, 2]);
+        assert_eq!(quality_trim_index(&qual, 20), 0);
+    }
+
+    #[test]
+    fn trim_empty() {
+        assert_eq!(quality_trim_index(b"", 20), 0);
+    }
+
+    const ADAPTERS: &str =
+        ">truseq description\nAGATCGGAAG\nAGCACACGTC\n>polyA\nAAAAAAAAAAAAAAAAAAAA\n";
+
+    #[test]
+    fn parse_adapters() {
+        let adapters = Adapters::parse(ADAPTERS, 0.1, 3).unwrap();
+        assert_eq!(adapters.len(), 2);
+        let hits = adapters.hits();
+        assert_eq!(hits[0].name, "truseq");
+        assert_eq!(hits[0].sequence, "AGATCGGAAGAGCACACGTC");
+
+        let list = Adapters::parse("AGATCGGAAG\nctgtctctta\n", 0.1, 3).unwrap();
+        assert_eq!(list.hits()[1].name, "ctgtctctta");
+        assert_eq!(list.hits()[1].sequence, "CTGTCTCTTA");
+
+        assert!(Adapters::parse("", 0.1, 3).is_err());
+        assert!(Adapters::parse(">empty\n>full\nACGT\n", 0.1, 3).is_err());
+    }
+
+    #[test]
+    fn adapter_trimming() {
+        let adapters = Adapters::parse(ADAPTERS, 0.1, 3).unwrap();
+        // full adapter with a single mismatch
+        assert_eq!(
+            adapters.trim_index(b"CCTTGGCCTTAGATCGGTAGAGCACACGTCTT"),
+            Some((0, 10))
+        );
+        // partial adapter at the 3' end
+        assert_eq!(adapters.trim_index(b"CCTTGGCCTTAGAT"), Some((0, 10)));
+        // the overlap is too short to tolerate a mismatch
+        assert_eq!(adapters.trim_index(b"CCTTGGCCTTAGTT"), None);
+        // the adapter trimming the most wins, at its best match rather than the leftmost
+        // one (which would trim the preceding GG with two mismatches)
+        assert_eq!(
+            adapters.trim_index(b"CCGGCCGGAAAAAAAAAAAAAAAAAAAAGATCGGAAG"),
+            Some((1, 8))
+        );
+        assert_eq!(adapters.trim_index(b"CC"), None);
+    }
+}
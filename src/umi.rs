@@ -0,0 +1,49 @@
+use crate::composition::base_index;
+
+/// Returns true if the UMI consists of a single repeated nucleotide
+pub fn is_homopolymer(umi: &[u8]) -> bool {
+    match umi.first() {
+        Some(first) => umi.iter().all(|b| b == first),
+        None => false,
+    }
+}
+
+/// Calculates the Shannon entropy (in bits) of the nucleotide composition of a UMI
+pub fn shannon_entropy(umi: &[u8]) -> f64 {
+    if umi.is_empty() {
+        return 0.0;
+    }
+    let mut counts = [0usize; 5];
+    umi.iter().for_each(|b| counts[base_index(*b)] += 1);
+    let total = umi.len() as f64;
+    counts
+        .iter()
+        .filter(|c| **c > 0)
+        .map(|c| {
+            let p = *c as f64 / total;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn homopolymer() {
+        assert!(is_homopolymer(b"AAAAAAAAAAAA"));
+        assert!(is_homopolymer(b"TTTT"));
+        assert!(!is_homopolymer(b"AAAAAAAAAAAT"));
+        assert!(!is_homopolymer(b""));
+    }
+
+    #[test]
+    fn entropy() {
+        assert_eq!(shannon_entropy(b"AAAAAAAA"), 0.0);
+        assert_eq!(shannon_entropy(b"AAAATTTT"), 1.0);
+        assert_eq!(shannon_entropy(b"ACGTACGT"), 2.0);
+        assert_eq!(shannon_entropy(b""), 0.0);
+        assert!(shannon_entropy(b"AAAAAAAAAAAC") < 0.5);
+    }
+}
@@ -0,0 +1,213 @@
+use crate::log::Statistics;
+
+/// The factor above the expected number of cells at which the whitelist size is suspicious
+const WHITELIST_SIZE_FACTOR: usize = 10;
+
+/// The minimum fraction of matched reads with an exact match at each tier
+const MIN_EXACT_FRACTION: f64 = 0.5;
+
+/// The maximum fraction of matched reads whose first anchor sits at the edge of the offset window
+const MAX_EDGE_FRACTION: f64 = 0.1;
+
+/// The minimum fraction of passing reads before the run is considered suspicious
+const MIN_PASS_FRACTION: f64 = 0.5;
+
+/// The maximum fraction of rescanned reads failing barcode 1 which match it beyond the
+/// offset window
+const MAX_BEYOND_OFFSET_FRACTION: f64 = 0.2;
+
+/// The minimum number of rescanned reads before matches beyond the offset are reported
+const MIN_OFFSET_SCANNED: usize = 100;
+
+/// The fraction of the matches beyond the offset window the suggested offset captures
+const SUGGESTED_OFFSET_QUANTILE: f64 = 0.99;
+
+/// The maximum fraction of validated read pairs outside the expected whitelist
+const MAX_UNEXPECTED_FRACTION: f64 = 0.05;
+
+/// Runs heuristic sanity checks over the run statistics and returns a warning for each
+/// that looks suspicious
+pub fn sanity_check(
+    statistics: &Statistics,
+    offset: Option<usize>,
+    expected_cells: Option<usize>,
+) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if statistics.total_reads == 0 {
+        warnings.push("No reads were found in the input".to_string());
+        return warnings;
+    }
+    if statistics.fraction_passing < MIN_PASS_FRACTION {
+        warnings.push(format!(
+            "Only {:.2}% of reads passed filters; check the config and read structure",
+            statistics.fraction_passing * 100.0
+        ));
+    }
+    if let Some(cells) = expected_cells {
+        if statistics.whitelist_size > cells * WHITELIST_SIZE_FACTOR {
+            warnings.push(format!(
+                "Whitelist size ({}) is more than {}x the expected number of cells ({})",
+                statistics.whitelist_size, WHITELIST_SIZE_FACTOR, cells
+            ));
+        }
+    }
+    if let Some(warning) = check_beyond_offset(statistics) {
+        warnings.push(warning);
+    }
+    if let Some(unexpected) = statistics
+        .fraction_unexpected
+        .filter(|f| *f > MAX_UNEXPECTED_FRACTION)
+    {
+        warnings.push(format!(
+            "{:.2}% of read pairs carry barcodes outside the expected whitelist; check for contamination or a kit lot mismatch",
+            unexpected * 100.0
+        ));
+    }
+    // the corrections and shifts are recorded for every matched read, including those
+    // dropped by the later filters
+    if statistics.num_matched == 0 {
+        return warnings;
+    }
+    let corrected = [
+        statistics.num_corrected_1,
+        statistics.num_corrected_2,
+        statistics.num_corrected_3,
+        statistics.num_corrected_4,
+    ];
+    for (tier, num_corrected) in corrected.iter().enumerate() {
+        let exact = 1.0 - *num_corrected as f64 / statistics.num_matched as f64;
+        if exact < MIN_EXACT_FRACTION {
+            warnings.push(format!(
+                "Only {:.2}% of matched reads matched barcode {} exactly",
+                exact * 100.0,
+                tier + 1
+            ));
+        }
+    }
+    if let Some(offset) = offset.filter(|o| *o > 0) {
+        let at_edge = statistics.shift_counts.get(offset).copied().unwrap_or(0);
+        let edge = at_edge as f64 / statistics.num_matched as f64;
+        if edge > MAX_EDGE_FRACTION {
+            warnings.push(format!(
+                "{:.2}% of matched reads matched barcode 1 at the edge of the offset window ({}); consider increasing `--offset`",
+                edge * 100.0,
+                offset
+            ));
+        }
+    }
+    warnings
+}
+
+/// Returns a warning if a noticeable fraction of the reads failing barcode 1 match it
+/// just beyond the offset window, suggesting an offset capturing most of them
+fn check_beyond_offset(statistics: &Statistics) -> Option<String> {
+    if statistics.num_offset_scanned < MIN_OFFSET_SCANNED {
+        return None;
+    }
+    let beyond = statistics.num_beyond_offset as f64 / statistics.num_offset_scanned as f64;
+    if beyond <= MAX_BEYOND_OFFSET_FRACTION {
+        return None;
+    }
+    let mut captured = 0;
+    let suggested = statistics.beyond_offset_shifts.iter().position(|count| {
+        captured += count;
+        captured as f64 >= SUGGESTED_OFFSET_QUANTILE * statistics.num_beyond_offset as f64
+    })?;
+    Some(format!(
+        "{:.2}% of the reads failing barcode 1 matched it beyond the offset window; consider `--offset {}`",
+        beyond * 100.0,
+        suggested
+    ))
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    fn statistics() -> Statistics {
+        let mut statistics = Statistics::new();
+        statistics.total_reads = 100;
+        statistics.passing_reads = 90;
+        statistics.num_matched = 90;
+        statistics.shift_counts = vec![50, 30, 10];
+        statistics.calculate_metrics();
+        statistics.whitelist_size = 20;
+        statistics
+    }
+
+    #[test]
+    fn no_warnings() {
+        assert!(sanity_check(&statistics(), Some(5), Some(10)).is_empty());
+    }
+
+    #[test]
+    fn whitelist_size() {
+        let warnings = sanity_check(&statistics(), Some(5), Some(1));
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("Whitelist size"));
+    }
+
+    #[test]
+    fn inexact_tier() {
+        let mut statistics = statistics();
+        statistics.num_corrected_3 = 60;
+        let warnings = sanity_check(&statistics, Some(5), None);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("barcode 3"));
+    }
+
+    #[test]
+    fn filtered_matches() {
+        // matched reads dropped by later filters still count towards the corrections
+        let mut statistics = statistics();
+        statistics.passing_reads = 60;
+        statistics.num_corrected_3 = 80;
+        statistics.calculate_metrics();
+        let warnings = sanity_check(&statistics, Some(5), None);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("Only 11.11% of matched reads"));
+    }
+
+    #[test]
+    fn offset_edge() {
+        let warnings = sanity_check(&statistics(), Some(2), None);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("offset window"));
+        assert!(sanity_check(&statistics(), None, None).is_empty());
+    }
+
+    #[test]
+    fn unexpected_barcodes() {
+        let mut statistics = statistics();
+        statistics.num_expected = 80;
+        statistics.num_unexpected = 10;
+        statistics.record_expected_fractions();
+        let warnings = sanity_check(&statistics, Some(5), None);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("outside the expected whitelist"));
+    }
+
+    #[test]
+    fn beyond_offset() {
+        let mut statistics = statistics();
+        for shift in [None, Some(3), Some(7), Some(8)].repeat(50) {
+            statistics.record_offset_scan(5, shift);
+        }
+        assert_eq!(statistics.num_offset_scanned, 200);
+        assert_eq!(statistics.num_beyond_offset, 100);
+        let warnings = sanity_check(&statistics, Some(5), None);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("50.00% of the reads failing barcode 1"));
+        assert!(warnings[0].ends_with("`--offset 8`"));
+    }
+
+    #[test]
+    fn low_pass_rate() {
+        let mut statistics = statistics();
+        statistics.passing_reads = 10;
+        statistics.calculate_metrics();
+        let warnings = sanity_check(&statistics, Some(5), None);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("Only"));
+    }
+}
@@ -0,0 +1,245 @@
+use crate::barcodes::Barcodes;
+use anyhow::{bail, Result};
+use hashbrown::{hash_map::Entry, HashMap, HashSet};
+use std::io::{BufRead, BufReader, Write};
+
+/// The SAM tag flagging the unaligned BAM records of `--flag-unexpected` hits
+/// (`XU:Z:1`), as BAM read names cannot carry the ` unexpected` flag of fastq headers
+pub const UNEXPECTED_TAG: &[u8; 2] = b"XU";
+
+/// A whitelist of the barcodes expected in a run (e.g. from a kit lot or a previous run)
+/// that passing constructs are validated against
+#[derive(Debug, Clone)]
+pub struct ExpectedWhitelist {
+    barcodes: HashSet<Vec<u8>>,
+}
+impl ExpectedWhitelist {
+    /// Reads a (possibly compressed) whitelist of emitted barcodes of a given length
+    pub fn from_file(path: &str, barcode_len: usize) -> Result<Self> {
+        let (reader, _format) = niffler::from_path(path)?;
+        Self::from_buffer(BufReader::new(reader), barcode_len)
+    }
+
+    /// Reads a whitelist with one barcode per line.
+    ///
+    /// Lines may be tab or comma separated (e.g. a whitelist with read counts), in which
+    /// case the first column is taken as the barcode. A header line is skipped.
+    pub fn from_buffer<R: BufRead>(reader: R, barcode_len: usize) -> Result<Self> {
+        let mut barcodes = HashSet::new();
+        for (line_num, line) in reader.lines().enumerate() {
+            let line = line?;
+            let barcode = line.split(['\t', ',']).next().unwrap_or_default().trim();
+            if barcode.is_empty() || (line_num == 0 && !Barcodes::is_nucleotide(barcode)) {
+                continue;
+            }
+            if barcode.len() != barcode_len {
+                bail!(
+                    "Expected whitelist barcode on line {} is {}bp but emitted barcodes are {}bp (is `--linkers` set as in the run it came from?)",
+                    line_num + 1,
+                    barcode.len(),
+                    barcode_len
+                );
+            }
+            barcodes.insert(barcode.to_ascii_uppercase().into_bytes());
+        }
+        if barcodes.is_empty() {
+            bail!("The expected whitelist is empty");
+        }
+        Ok(Self { barcodes })
+    }
+
+    /// Returns true if a barcode is expected
+    pub fn contains(&self, barcode: &[u8]) -> bool {
+        self.barcodes.contains(barcode)
+    }
+
+    /// Returns the number of expected barcodes
+    pub fn len(&self) -> usize {
+        self.barcodes.len()
+    }
+
+    /// Returns true if no barcodes are expected
+    pub fn is_empty(&self) -> bool {
+        self.barcodes.is_empty()
+    }
+}
+
+/// The barcodes of a whitelist with their read counts, if given
+pub type WhitelistCounts = HashMap<Vec<u8>, Option<usize>>;
+
+/// Reads a (possibly compressed) whitelist with optional read counts
+pub fn read_counts(path: &str) -> Result<WhitelistCounts> {
+    let (reader, _format) = niffler::from_path(path)?;
+    read_counts_from_buffer(BufReader::new(reader))
+}
+
+/// Reads a whitelist with one barcode per line and an optional second column of read
+/// counts (e.g. `<prefix>_whitelist_counts.tsv`). A header line is skipped and the counts
+/// of repeated barcodes are summed.
+pub fn read_counts_from_buffer<R: BufRead>(reader: R) -> Result<WhitelistCounts> {
+    let mut counts = WhitelistCounts::new();
+    for (line_num, line) in reader.lines().enumerate() {
+        let line = line?;
+        let mut fields = line.split(['\t', ',']).map(|f| f.trim());
+        let barcode = fields.next().unwrap_or_default();
+        if barcode.is_empty() || (line_num == 0 && !Barcodes::is_nucleotide(barcode)) {
+            continue;
+        }
+        let count = match fields.next() {
+            Some(count) => match count.parse::<usize>() {
+                Ok(count) => Some(count),
+                Err(_) => bail!(
+                    "Invalid read count on whitelist line {}: {}",
+                    line_num + 1,
+                    count
+                ),
+            },
+            None => None,
+        };
+        match counts.entry(barcode.to_ascii_uppercase().into_bytes()) {
+            Entry::Occupied(mut entry) => {
+                if let (Some(total), Some(count)) = (entry.get_mut(), count) {
+                    *total += count;
+                }
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(count);
+            }
+        }
+    }
+    Ok(counts)
+}
+
+/// A set operation combining whitelists
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetOperation {
+    /// The barcodes present in every whitelist
+    Intersect,
+    /// The barcodes present in any whitelist
+    Union,
+    /// The barcodes of the first whitelist absent from all others
+    Subtract,
+}
+
+/// Combines whitelists with a set operation.
+///
+/// The counts of a barcode are summed over the whitelists it is kept from (only the
+/// first whitelist when subtracting), and are only given if any of them has a count.
+pub fn combine(operation: SetOperation, whitelists: &[WhitelistCounts]) -> WhitelistCounts {
+    let Some((first, rest)) = whitelists.split_first() else {
+        return WhitelistCounts::new();
+    };
+    let sum = |a: Option<usize>, b: Option<usize>| match (a, b) {
+        (None, None) => None,
+        (a, b) => Some(a.unwrap_or(0) + b.unwrap_or(0)),
+    };
+    match operation {
+        SetOperation::Intersect => first
+            .iter()
+            .filter_map(|(barcode, count)| {
+                rest.iter()
+                    .try_fold(*count, |total, other| {
+                        other.get(barcode).map(|count| sum(total, *count))
+                    })
+                    .map(|total| (barcode.clone(), total))
+            })
+            .collect(),
+        SetOperation::Union => {
+            let mut union = first.clone();
+            for other in rest {
+                for (barcode, count) in other {
+                    let total = union.entry(barcode.clone()).or_insert(None);
+                    *total = sum(*total, *count);
+                }
+            }
+            union
+        }
+        SetOperation::Subtract => first
+            .iter()
+            .filter(|(barcode, _)| rest.iter().all(|other| !other.contains_key(*barcode)))
+            .map(|(barcode, count)| (barcode.clone(), *count))
+            .collect(),
+    }
+}
+
+/// Writes a whitelist sorted by barcode, as `barcode\treads` rows if it has counts
+pub fn write_counts<W: Write>(counts: &WhitelistCounts, mut writer: W) -> Result<()> {
+    let mut barcodes = counts.iter().collect::<Vec<_>>();
+    barcodes.sort_unstable();
+    let with_counts = counts.values().any(|count| count.is_some());
+    if with_counts {
+        writeln!(writer, "barcode\treads")?;
+    }
+    for (barcode, count) in barcodes {
+        writer.write_all(barcode)?;
+        if with_counts {
+            write!(writer, "\t{}", count.unwrap_or(0))?;
+        }
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let whitelist =
+            ExpectedWhitelist::from_buffer("barcode\treads\nACGT\t10\nTTGG\t3\n\n".as_bytes(), 4)
+                .unwrap();
+        assert_eq!(whitelist.len(), 2);
+        assert!(whitelist.contains(b"ACGT"));
+        assert!(!whitelist.contains(b"AAAA"));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(ExpectedWhitelist::from_buffer("ACGT\nACG\n".as_bytes(), 4).is_err());
+        assert!(ExpectedWhitelist::from_buffer("".as_bytes(), 4).is_err());
+    }
+
+    #[test]
+    fn counts() {
+        let counts =
+            read_counts_from_buffer("barcode\treads\nACGT\t10\nTTGG\t3\nACGT\t2\n".as_bytes())
+                .unwrap();
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts[&b"ACGT".to_vec()], Some(12));
+        let counts = read_counts_from_buffer("ACGT\nTTGG\n".as_bytes()).unwrap();
+        assert_eq!(counts[&b"TTGG".to_vec()], None);
+        assert!(read_counts_from_buffer("ACGT\tmany\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn set_operations() {
+        let a = read_counts_from_buffer("AAAA\t10\nCCCC\t20\nGGGG\t30\n".as_bytes()).unwrap();
+        let b = read_counts_from_buffer("CCCC\t2\nGGGG\t3\nTTTT\t4\n".as_bytes()).unwrap();
+        let c = read_counts_from_buffer("GGGG\nTTTT\n".as_bytes()).unwrap();
+        let whitelists = [a, b, c];
+
+        let intersect = combine(SetOperation::Intersect, &whitelists);
+        assert_eq!(intersect.len(), 1);
+        assert_eq!(intersect[&b"GGGG".to_vec()], Some(33));
+
+        let union = combine(SetOperation::Union, &whitelists);
+        assert_eq!(union.len(), 4);
+        assert_eq!(union[&b"TTTT".to_vec()], Some(4));
+
+        let subtract = combine(SetOperation::Subtract, &whitelists);
+        assert_eq!(subtract.len(), 1);
+        assert_eq!(subtract[&b"AAAA".to_vec()], Some(10));
+
+        let mut output = Vec::new();
+        write_counts(&union, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "barcode\treads\nAAAA\t10\nCCCC\t22\nGGGG\t33\nTTTT\t4\n"
+        );
+        let mut output = Vec::new();
+        write_counts(&combine(SetOperation::Union, &whitelists[2..]), &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "GGGG\nTTTT\n");
+    }
+}
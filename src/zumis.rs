@@ -0,0 +1,126 @@
+use crate::config::Config;
+use anyhow::Result;
+use serde_yaml::{Mapping, Value};
+
+/// Builds a YAML mapping from its fields
+fn mapping(fields: Vec<(&str, Value)>) -> Value {
+    Value::Mapping(
+        fields
+            .into_iter()
+            .map(|(k, v)| (Value::from(k), v))
+            .collect::<Mapping>(),
+    )
+}
+
+/// Describes the emitted reads as the `sequence_files` and `barcodes` sections of a
+/// zUMIs config.
+///
+/// R1 holds the barcode in `BC(1-<barcode length>)` followed by the UMI and R2 holds the
+/// cDNA up to the longest emitted R2. zUMIs always detects the cell barcodes
+/// automatically, restricted to the whitelist of observed constructs if one is given (which
+/// also holds the noise constructs and so cannot be used as the list of cells).
+pub fn describe(
+    config: &Config,
+    umi_len: usize,
+    r1_path: &str,
+    r2_path: &str,
+    max_r2_len: usize,
+    whitelist_path: Option<&str>,
+) -> Value {
+    let barcode_len = config.barcode_len();
+    let umi_len = config.umi_len(umi_len);
+    let file1 = mapping(vec![
+        ("name", r1_path.into()),
+        (
+            "base_definition",
+            std::iter::once(format!("BC(1-{})", barcode_len))
+                .chain(
+                    (umi_len > 0)
+                        .then(|| format!("UMI({}-{})", barcode_len + 1, barcode_len + umi_len)),
+                )
+                .collect::<Vec<_>>()
+                .into(),
+        ),
+    ]);
+    let file2 = mapping(vec![
+        ("name", r2_path.into()),
+        (
+            "base_definition",
+            vec![format!("cDNA(1-{})", max_r2_len.max(1))].into(),
+        ),
+    ]);
+    mapping(vec![
+        (
+            "sequence_files",
+            mapping(vec![("file1", file1), ("file2", file2)]),
+        ),
+        (
+            "barcodes",
+            mapping(vec![
+                ("barcode_num", Value::Null),
+                (
+                    "barcode_file",
+                    whitelist_path.map_or(Value::Null, Value::from),
+                ),
+                ("automatic", true.into()),
+                ("BarcodeBinning", 0.into()),
+            ]),
+        ),
+    ])
+}
+
+/// Writes the zUMIs config snippet describing the emitted reads
+pub fn to_file(
+    path: &str,
+    config: &Config,
+    umi_len: usize,
+    r1_path: &str,
+    r2_path: &str,
+    max_r2_len: usize,
+    whitelist_path: Option<&str>,
+) -> Result<()> {
+    let yaml = serde_yaml::to_string(&describe(
+        config,
+        umi_len,
+        r1_path,
+        r2_path,
+        max_r2_len,
+        whitelist_path,
+    ))?;
+    std::fs::write(path, yaml)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn ranges() {
+        let config = Config::from_file("data/config_v3.yaml", false, false).unwrap();
+        let yaml = serde_yaml::to_string(&describe(
+            &config,
+            12,
+            "out_R1.fq.gz",
+            "out_R2.fq.gz",
+            150,
+            Some("out_whitelist.txt"),
+        ))
+        .unwrap();
+        assert!(yaml.contains("- BC(1-28)\n"));
+        assert!(yaml.contains("- UMI(29-40)\n"));
+        assert!(yaml.contains("- cDNA(1-150)\n"));
+        assert!(yaml.contains("barcode_file: out_whitelist.txt\n"));
+        assert!(yaml.contains("automatic: true\n"));
+    }
+
+    #[test]
+    fn ranges_with_linkers() {
+        let config = Config::from_file("data/config_v3.yaml", false, true).unwrap();
+        let spec = describe(&config, 12, "r1", "r2", 0, None);
+        let yaml = serde_yaml::to_string(&spec).unwrap();
+        assert!(yaml.contains("- BC(1-39)\n"));
+        assert!(yaml.contains("- UMI(40-51)\n"));
+        assert!(yaml.contains("automatic: true\n"));
+    }
+}